@@ -0,0 +1,186 @@
+use crate::state::impl_state;
+
+/// Timer periods of the DMC in CPU cycles.
+const DMC_RATES: [u16; 16] = [
+    428, 380, 340, 320, 286, 254, 226, 214, 190, 160, 142, 128, 106, 84, 72, 54,
+];
+
+/// The delta modulation channel, $4010-$4013. It plays 1-bit delta encoded samples that it reads
+/// directly from CPU memory, or outputs whatever is written to its 7-bit output level.
+#[derive(Clone, Debug)]
+pub struct Dmc {
+    irq_enabled: bool,
+    loop_flag: bool,
+    pub irq: bool,
+
+    timer_period: u16,
+    timer: u16,
+    output_level: u8,
+
+    sample_address: u16,
+    sample_length: u16,
+    current_address: u16,
+    bytes_remaining: u16,
+    sample_buffer: Option<u8>,
+
+    shift_register: u8,
+    bits_remaining: u8,
+    silence: bool,
+}
+
+impl_state!(Dmc {
+    irq_enabled,
+    loop_flag,
+    irq,
+    timer_period,
+    timer,
+    output_level,
+    sample_address,
+    sample_length,
+    current_address,
+    bytes_remaining,
+    sample_buffer,
+    shift_register,
+    bits_remaining,
+    silence
+});
+
+impl Dmc {
+    pub fn new() -> Dmc {
+        Dmc {
+            irq_enabled: false,
+            loop_flag: false,
+            irq: false,
+            timer_period: DMC_RATES[0],
+            timer: 0,
+            output_level: 0,
+            sample_address: 0xC000,
+            sample_length: 1,
+            current_address: 0xC000,
+            bytes_remaining: 0,
+            sample_buffer: None,
+            shift_register: 0,
+            bits_remaining: 8,
+            silence: true,
+        }
+    }
+
+    /// Writes to one of the four registers of the channel, `reg` is in the range 0..4.
+    pub fn write(&mut self, reg: u16, value: u8) {
+        match reg {
+            0 => {
+                self.irq_enabled = value & 0b1000_0000 != 0;
+                self.loop_flag = value & 0b0100_0000 != 0;
+                self.timer_period = DMC_RATES[(value & 0b1111) as usize];
+                if !self.irq_enabled {
+                    self.irq = false;
+                }
+            }
+            1 => {
+                self.output_level = value & 0b0111_1111;
+            }
+            2 => {
+                self.sample_address = 0xC000 | ((value as u16) << 6);
+            }
+            _ => {
+                self.sample_length = ((value as u16) << 4) | 1;
+            }
+        }
+    }
+
+    /// Enabling the DMC restarts the sample if it has finished playing, disabling it stops the
+    /// sample once the bits in the output unit have been shifted out.
+    pub fn set_enabled(&mut self, enabled: bool) {
+        if !enabled {
+            self.bytes_remaining = 0;
+        } else if self.bytes_remaining == 0 {
+            self.restart();
+        }
+    }
+
+    fn restart(&mut self) {
+        self.current_address = self.sample_address;
+        self.bytes_remaining = self.sample_length;
+    }
+
+    pub fn active(&self) -> bool {
+        self.bytes_remaining > 0
+    }
+
+    /// Returns the address the memory reader wants to fetch, if the sample buffer is empty and
+    /// there are bytes left of the sample.
+    pub fn pending_read(&self) -> Option<u16> {
+        if self.sample_buffer.is_none() && self.bytes_remaining > 0 {
+            Some(self.current_address)
+        } else {
+            None
+        }
+    }
+
+    /// Fills the sample buffer with the byte fetched from the address given by `pending_read`.
+    pub fn fill(&mut self, value: u8) {
+        self.sample_buffer = Some(value);
+
+        // The address wraps around to 0x8000 rather than 0x0000.
+        self.current_address = if self.current_address == 0xFFFF {
+            0x8000
+        } else {
+            self.current_address + 1
+        };
+
+        self.bytes_remaining -= 1;
+        if self.bytes_remaining == 0 {
+            if self.loop_flag {
+                self.restart();
+            } else if self.irq_enabled {
+                self.irq = true;
+            }
+        }
+    }
+
+    /// Clocked every CPU cycle since the rate table is in CPU cycles.
+    pub fn clock_timer(&mut self) {
+        if self.timer == 0 {
+            self.timer = self.timer_period - 1;
+            self.clock_output();
+        } else {
+            self.timer -= 1;
+        }
+    }
+
+    fn clock_output(&mut self) {
+        if !self.silence {
+            if self.shift_register & 1 == 1 {
+                if self.output_level <= 125 {
+                    self.output_level += 2;
+                }
+            } else if self.output_level >= 2 {
+                self.output_level -= 2;
+            }
+        }
+
+        self.shift_register >>= 1;
+        self.bits_remaining -= 1;
+
+        if self.bits_remaining == 0 {
+            self.bits_remaining = 8;
+            match self.sample_buffer.take() {
+                Some(value) => {
+                    self.silence = false;
+                    self.shift_register = value;
+                }
+                None => self.silence = true,
+            }
+        }
+    }
+
+    pub fn output(&self) -> u8 {
+        self.output_level
+    }
+}
+
+impl Default for Dmc {
+    fn default() -> Self {
+        Dmc::new()
+    }
+}
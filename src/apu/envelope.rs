@@ -0,0 +1,64 @@
+use crate::state::impl_state;
+
+/// The envelope generator used by the pulse and noise channels. It either outputs a constant
+/// volume or a sawtooth that starts at 15 and decays by one every time its divider runs out.
+#[derive(Clone, Debug, Default)]
+pub struct Envelope {
+    start: bool,
+    loop_flag: bool,
+    constant_volume: bool,
+    /// Used both as the constant volume and as the period of the divider.
+    volume: u8,
+    divider: u8,
+    decay: u8,
+}
+
+impl_state!(Envelope {
+    start,
+    loop_flag,
+    constant_volume,
+    volume,
+    divider,
+    decay
+});
+
+impl Envelope {
+    /// Handles the lower six bits of the first register of the channel.
+    pub fn write_control(&mut self, value: u8) {
+        self.loop_flag = value & 0b0010_0000 != 0;
+        self.constant_volume = value & 0b0001_0000 != 0;
+        self.volume = value & 0b0000_1111;
+    }
+
+    /// Restarts the envelope on the next quarter frame. This happens when the fourth register of
+    /// the channel is written to.
+    pub fn restart(&mut self) {
+        self.start = true;
+    }
+
+    /// Clocked by the frame counter every quarter frame.
+    pub fn clock(&mut self) {
+        if self.start {
+            self.start = false;
+            self.decay = 15;
+            self.divider = self.volume;
+        } else if self.divider == 0 {
+            self.divider = self.volume;
+            if self.decay > 0 {
+                self.decay -= 1;
+            } else if self.loop_flag {
+                self.decay = 15;
+            }
+        } else {
+            self.divider -= 1;
+        }
+    }
+
+    pub fn output(&self) -> u8 {
+        if self.constant_volume {
+            self.volume
+        } else {
+            self.decay
+        }
+    }
+}
@@ -0,0 +1,330 @@
+use super::ExpansionAudio;
+use crate::state::impl_state;
+
+/// How much the modulation counter changes for each of the values in the modulation table. 4
+/// resets the counter instead.
+const MOD_ADJUST: [i8; 8] = [0, 1, 2, 4, 0, -4, -2, -1];
+
+/// The master volume divides the output by 2/2, 2/3, 2/4 or 2/5.
+const MASTER_VOLUME: [f32; 4] = [1.0, 2.0 / 3.0, 2.0 / 4.0, 2.0 / 5.0];
+
+/// The FDS at full volume is roughly twice as loud as an APU pulse channel.
+const FDS_SCALE: f32 = 0.3 / (63.0 * 32.0);
+
+/// The volume and modulation envelopes of the FDS work the same way.
+#[derive(Clone, Debug, Default)]
+struct FdsEnvelope {
+    disabled: bool,
+    increase: bool,
+    speed: u8,
+    gain: u8,
+    timer: u32,
+}
+
+impl_state!(FdsEnvelope {
+    disabled,
+    increase,
+    speed,
+    gain,
+    timer
+});
+
+impl FdsEnvelope {
+    fn write(&mut self, value: u8) {
+        self.disabled = value & 0b1000_0000 != 0;
+        self.increase = value & 0b0100_0000 != 0;
+        self.speed = value & 0b0011_1111;
+        self.timer = 0;
+
+        if self.disabled {
+            self.gain = self.speed;
+        }
+    }
+
+    fn clock(&mut self, master_speed: u8) {
+        if self.disabled {
+            return;
+        }
+
+        let period = 8 * (self.speed as u32 + 1) * master_speed as u32;
+        self.timer += 1;
+        if self.timer < period {
+            return;
+        }
+        self.timer = 0;
+
+        if self.increase && self.gain < 32 {
+            self.gain += 1;
+        } else if !self.increase && self.gain > 0 {
+            self.gain -= 1;
+        }
+    }
+}
+
+/// The sound channel of the Famicom Disk System. A single wavetable channel with 64 6-bit
+/// samples, a volume envelope and a frequency modulator that has its own table.
+#[derive(Clone, Debug)]
+pub struct Fds {
+    wave_table: [u8; 64],
+    wave_write_enabled: bool,
+    wave_halted: bool,
+    wave_frequency: u16,
+    wave_accumulator: u32,
+    wave_position: u8,
+    master_volume: u8,
+    output: u8,
+
+    envelopes_halted: bool,
+    envelope_speed: u8,
+    volume: FdsEnvelope,
+
+    mod_table: [u8; 64],
+    mod_halted: bool,
+    mod_frequency: u16,
+    mod_accumulator: u32,
+    mod_position: u8,
+    /// A 7-bit signed counter that is adjusted by the values of the modulation table.
+    mod_counter: i8,
+    modulation: FdsEnvelope,
+}
+
+impl_state!(Fds {
+    wave_table,
+    wave_write_enabled,
+    wave_halted,
+    wave_frequency,
+    wave_accumulator,
+    wave_position,
+    master_volume,
+    output,
+    envelopes_halted,
+    envelope_speed,
+    volume,
+    mod_table,
+    mod_halted,
+    mod_frequency,
+    mod_accumulator,
+    mod_position,
+    mod_counter,
+    modulation
+});
+
+impl Fds {
+    pub fn new() -> Fds {
+        Fds {
+            wave_table: [0; 64],
+            wave_write_enabled: false,
+            wave_halted: true,
+            wave_frequency: 0,
+            wave_accumulator: 0,
+            wave_position: 0,
+            master_volume: 0,
+            output: 0,
+            envelopes_halted: true,
+            envelope_speed: 0xE8,
+            volume: FdsEnvelope::default(),
+            mod_table: [0; 64],
+            mod_halted: true,
+            mod_frequency: 0,
+            mod_accumulator: 0,
+            mod_position: 0,
+            mod_counter: 0,
+            modulation: FdsEnvelope::default(),
+        }
+    }
+
+    /// Wraps a value into the range of the 7-bit signed modulation counter.
+    fn wrap_counter(value: i16) -> i8 {
+        (((value + 64) & 0x7F) - 64) as i8
+    }
+
+    fn clock_modulator(&mut self) {
+        if self.mod_halted || self.mod_frequency == 0 {
+            return;
+        }
+
+        self.mod_accumulator += self.mod_frequency as u32;
+        if self.mod_accumulator < 0x10000 {
+            return;
+        }
+        self.mod_accumulator &= 0xFFFF;
+
+        let value = self.mod_table[self.mod_position as usize];
+        self.mod_counter = if value == 4 {
+            0
+        } else {
+            Fds::wrap_counter(self.mod_counter as i16 + MOD_ADJUST[value as usize] as i16)
+        };
+        self.mod_position = (self.mod_position + 1) & 63;
+    }
+
+    /// The pitch of the wave after the modulation has been applied. This follows the integer math
+    /// of the real chip, including its rounding quirks, since games depend on them for tuning.
+    fn pitch(&self) -> u32 {
+        let mut temp = self.mod_counter as i32 * self.modulation.gain as i32;
+        let remainder = temp & 0x0F;
+        temp >>= 4;
+        if remainder > 0 && temp & 0x80 == 0 {
+            if self.mod_counter < 0 {
+                temp -= 1;
+            } else {
+                temp += 2;
+            }
+        }
+
+        if temp >= 192 {
+            temp -= 256;
+        } else if temp < -64 {
+            temp += 256;
+        }
+
+        temp *= self.wave_frequency as i32;
+        let remainder = temp & 0x3F;
+        temp >>= 6;
+        if remainder >= 32 {
+            temp += 1;
+        }
+
+        (self.wave_frequency as i32 + temp).max(0) as u32
+    }
+}
+
+impl Default for Fds {
+    fn default() -> Self {
+        Fds::new()
+    }
+}
+
+impl ExpansionAudio for Fds {
+    fn write(&mut self, addr: u16, value: u8) {
+        match addr {
+            0x4040..=0x407F if self.wave_write_enabled => {
+                self.wave_table[(addr - 0x4040) as usize] = value & 0b0011_1111;
+            }
+            0x4080 => self.volume.write(value),
+            0x4082 => self.wave_frequency = (self.wave_frequency & 0x0F00) | value as u16,
+            0x4083 => {
+                self.wave_frequency =
+                    (self.wave_frequency & 0x00FF) | ((value as u16 & 0b1111) << 8);
+                self.envelopes_halted = value & 0b0100_0000 != 0;
+                self.wave_halted = value & 0b1000_0000 != 0;
+                if self.wave_halted {
+                    self.wave_accumulator = 0;
+                    self.wave_position = 0;
+                }
+            }
+            0x4084 => self.modulation.write(value),
+            0x4085 => self.mod_counter = Fds::wrap_counter((value & 0x7F) as i16),
+            0x4086 => self.mod_frequency = (self.mod_frequency & 0x0F00) | value as u16,
+            0x4087 => {
+                self.mod_frequency = (self.mod_frequency & 0x00FF) | ((value as u16 & 0b1111) << 8);
+                self.mod_halted = value & 0b1000_0000 != 0;
+                if self.mod_halted {
+                    self.mod_accumulator = 0;
+                }
+            }
+            // Every value is written twice since the table holds 32 entries stepped twice.
+            0x4088 if self.mod_halted => {
+                let position = self.mod_position as usize;
+                self.mod_table[position] = value & 0b111;
+                self.mod_table[(position + 1) & 63] = value & 0b111;
+                self.mod_position = ((position + 2) & 63) as u8;
+            }
+            0x4089 => {
+                self.wave_write_enabled = value & 0b1000_0000 != 0;
+                self.master_volume = value & 0b11;
+            }
+            0x408A => self.envelope_speed = value,
+            _ => {}
+        }
+    }
+
+    fn read(&mut self, addr: u16) -> Option<u8> {
+        match addr {
+            0x4040..=0x407F => Some(self.wave_table[(addr - 0x4040) as usize] | 0b0100_0000),
+            0x4090 => Some(self.volume.gain | 0b0100_0000),
+            0x4092 => Some(self.modulation.gain | 0b0100_0000),
+            _ => None,
+        }
+    }
+
+    fn clock(&mut self) {
+        if !self.envelopes_halted && !self.wave_halted && self.envelope_speed != 0 {
+            self.volume.clock(self.envelope_speed);
+            self.modulation.clock(self.envelope_speed);
+        }
+
+        self.clock_modulator();
+
+        if self.wave_halted {
+            return;
+        }
+
+        self.wave_accumulator += self.pitch();
+        if self.wave_accumulator >= 0x10000 {
+            self.wave_accumulator &= 0xFFFF;
+            self.wave_position = (self.wave_position + 1) & 63;
+        }
+
+        // The output is held while the wave table is being written to.
+        if !self.wave_write_enabled {
+            self.output = self.wave_table[self.wave_position as usize];
+        }
+    }
+
+    fn output(&self) -> f32 {
+        let gain = self.volume.gain.min(32) as f32;
+        self.output as f32 * gain * MASTER_VOLUME[self.master_volume as usize] * FDS_SCALE
+    }
+
+    fn box_clone(&self) -> Box<dyn ExpansionAudio> {
+        Box::new(self.clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_wave_table_write_protect() {
+        let mut fds = Fds::new();
+        fds.write(0x4040, 0x3F);
+        assert_eq!(fds.read(0x4040), Some(0x40));
+
+        fds.write(0x4089, 0b1000_0000);
+        fds.write(0x4040, 0x3F);
+        assert_eq!(fds.read(0x4040), Some(0x7F));
+    }
+
+    #[test]
+    fn test_plays_wave() {
+        let mut fds = Fds::new();
+        fds.write(0x4089, 0b1000_0000);
+        for i in 0..64 {
+            fds.write(0x4040 + i, if i < 32 { 0x3F } else { 0x00 });
+        }
+        fds.write(0x4089, 0b0000_0000);
+        // Volume envelope disabled with a gain of 32.
+        fds.write(0x4080, 0b1010_0000);
+        fds.write(0x4082, 0x00);
+        fds.write(0x4083, 0x04);
+
+        let mut high = false;
+        let mut low = false;
+        for _ in 0..0x4000 {
+            fds.clock();
+            high |= fds.output() > 0.0;
+            low |= fds.output() == 0.0;
+        }
+        assert!(high && low);
+        assert_eq!(fds.read(0x4090), Some(32 | 0x40));
+    }
+
+    #[test]
+    fn test_mod_counter_wraps() {
+        assert_eq!(Fds::wrap_counter(63), 63);
+        assert_eq!(Fds::wrap_counter(64), -64);
+        assert_eq!(Fds::wrap_counter(-65), 63);
+    }
+}
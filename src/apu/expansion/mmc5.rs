@@ -0,0 +1,138 @@
+use super::super::pulse::Pulse;
+use super::ExpansionAudio;
+use crate::state::impl_state;
+
+/// The MMC5 has no frame counter, the envelopes and length counters are clocked at a fixed rate
+/// of 240 Hz.
+const FRAME_PERIOD: u32 = 7457;
+
+/// Raw PCM at full level is a bit quieter than the DMC at full level.
+const PCM_SCALE: f32 = 0.002;
+
+/// Nintendo MMC5, used by mapper 5. Has two pulse channels that work like the ones in the APU
+/// without the sweep unit, and an 8-bit PCM channel.
+#[derive(Clone, Debug)]
+pub struct Mmc5 {
+    pulse1: Pulse,
+    pulse2: Pulse,
+    pcm: u8,
+    pcm_read_mode: bool,
+
+    cycle: u32,
+}
+
+impl_state!(Mmc5 {
+    pulse1,
+    pulse2,
+    pcm,
+    pcm_read_mode,
+    cycle
+});
+
+impl Mmc5 {
+    pub fn new() -> Mmc5 {
+        Mmc5 {
+            pulse1: Pulse::without_sweep(),
+            pulse2: Pulse::without_sweep(),
+            pcm: 0,
+            pcm_read_mode: false,
+            cycle: 0,
+        }
+    }
+}
+
+impl Default for Mmc5 {
+    fn default() -> Self {
+        Mmc5::new()
+    }
+}
+
+impl ExpansionAudio for Mmc5 {
+    fn write(&mut self, addr: u16, value: u8) {
+        match addr {
+            0x5000..=0x5003 => self.pulse1.write(addr - 0x5000, value),
+            0x5004..=0x5007 => self.pulse2.write(addr - 0x5004, value),
+            0x5010 => self.pcm_read_mode = value & 0b0000_0001 != 0,
+            // Writing 0 does nothing, it's reserved for the IRQ in read mode.
+            0x5011 if !self.pcm_read_mode && value != 0 => self.pcm = value,
+            0x5015 => {
+                self.pulse1.length.set_enabled(value & 0b01 != 0);
+                self.pulse2.length.set_enabled(value & 0b10 != 0);
+            }
+            _ => {}
+        }
+    }
+
+    fn read(&mut self, addr: u16) -> Option<u8> {
+        match addr {
+            0x5015 => {
+                let mut status = 0;
+                if self.pulse1.length.active() {
+                    status |= 0b01;
+                }
+                if self.pulse2.length.active() {
+                    status |= 0b10;
+                }
+                Some(status)
+            }
+            _ => None,
+        }
+    }
+
+    fn clock(&mut self) {
+        self.cycle += 1;
+        if self.cycle.is_multiple_of(2) {
+            self.pulse1.clock_timer();
+            self.pulse2.clock_timer();
+        }
+
+        if self.cycle == FRAME_PERIOD {
+            self.cycle = 0;
+            self.pulse1.clock_quarter_frame();
+            self.pulse1.clock_half_frame();
+            self.pulse2.clock_quarter_frame();
+            self.pulse2.clock_half_frame();
+        }
+    }
+
+    fn output(&self) -> f32 {
+        // The pulse channels go through the same kind of non-linear mixing as the APU pulses.
+        let pulse = (self.pulse1.output() + self.pulse2.output()) as f32;
+        let pulse = if pulse == 0.0 {
+            0.0
+        } else {
+            95.52 / (8128.0 / pulse + 100.0)
+        };
+
+        pulse + self.pcm as f32 * PCM_SCALE
+    }
+
+    fn box_clone(&self) -> Box<dyn ExpansionAudio> {
+        Box::new(self.clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_status() {
+        let mut mmc5 = Mmc5::new();
+        mmc5.write(0x5015, 0b11);
+        mmc5.write(0x5003, 0b0000_1000);
+
+        assert_eq!(mmc5.read(0x5015), Some(0b01));
+        assert_eq!(mmc5.read(0x5016), None);
+    }
+
+    #[test]
+    fn test_pcm() {
+        let mut mmc5 = Mmc5::new();
+        mmc5.write(0x5011, 0x80);
+        assert!(mmc5.output() > 0.0);
+
+        mmc5.write(0x5011, 0x00);
+        assert_eq!(mmc5.output(), 0x80 as f32 * PCM_SCALE);
+    }
+}
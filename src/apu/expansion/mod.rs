@@ -0,0 +1,65 @@
+mod fds;
+mod mmc5;
+mod namco163;
+mod sunsoft5b;
+mod vrc6;
+mod vrc7;
+
+pub use fds::Fds;
+pub use mmc5::Mmc5;
+pub use namco163::Namco163;
+pub use sunsoft5b::Sunsoft5b;
+pub use vrc6::Vrc6;
+pub use vrc7::Vrc7;
+
+use crate::state::State;
+use std::fmt::Debug;
+
+/// A sound chip on the cartridge. The Famicom routes the audio through the cartridge connector
+/// so the chips are mixed in with the APU, the NES lacks these pins which is why most of these
+/// games only have this audio in Japan.
+///
+/// The chips are mapped somewhere in cartridge space ($4020-$FFFF), the CPU forwards every
+/// access there to the chip.
+///
+/// The state of the chip is saved in save states, see `State`.
+pub trait ExpansionAudio: Debug + Send + State {
+    fn write(&mut self, addr: u16, value: u8);
+
+    /// Returns `None` when `addr` isn't readable from the chip, in which case the read goes to
+    /// the cartridge as usual.
+    fn read(&mut self, _addr: u16) -> Option<u8> {
+        None
+    }
+
+    /// Advances the chip by one CPU cycle.
+    fn clock(&mut self);
+
+    /// The current output, in the same scale as the output of the APU mixer.
+    fn output(&self) -> f32;
+
+    fn box_clone(&self) -> Box<dyn ExpansionAudio>;
+}
+
+impl Clone for Box<dyn ExpansionAudio> {
+    fn clone(&self) -> Self {
+        self.box_clone()
+    }
+}
+
+/// Creates the sound chip used by the given iNES mapper number, if it has one.
+///
+/// The FDS isn't a mapper in the iNES sense, it has to be created directly with `Fds::new`.
+/// None of these mappers can be loaded yet since they switch banks, so the chip has to be
+/// plugged in with `Apu::set_expansion_audio`.
+pub fn for_mapper(mapper: u16) -> Option<Box<dyn ExpansionAudio>> {
+    match mapper {
+        5 => Some(Box::new(Mmc5::new())),
+        19 => Some(Box::new(Namco163::new())),
+        24 => Some(Box::new(Vrc6::new(false))),
+        26 => Some(Box::new(Vrc6::new(true))),
+        69 => Some(Box::new(Sunsoft5b::new())),
+        85 => Some(Box::new(Vrc7::new())),
+        _ => None,
+    }
+}
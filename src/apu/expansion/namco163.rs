@@ -0,0 +1,205 @@
+use super::ExpansionAudio;
+use crate::state::impl_state;
+
+/// The chip updates one channel every 15 CPU cycles.
+const CYCLES_PER_CHANNEL: u8 = 15;
+
+/// A single channel at full volume is a little louder than an APU pulse channel.
+const NAMCO163_SCALE: f32 = 0.0015;
+
+/// Namco 163, used by mapper 19. Up to eight wavetable channels that share 128 bytes of sound
+/// RAM with their own registers.
+///
+/// Only one channel is updated at a time, so the more channels are enabled the lower the sample
+/// rate of each one. The real chip outputs the channels one after the other which is heard as a
+/// whine when many channels are enabled, here they are averaged instead.
+#[derive(Clone, Debug)]
+pub struct Namco163 {
+    ram: [u8; 0x80],
+    address: u8,
+    auto_increment: bool,
+    disabled: bool,
+
+    cycle: u8,
+    /// The channel that is updated next, counting down from 7.
+    current: usize,
+    outputs: [i16; 8],
+}
+
+impl_state!(Namco163 {
+    ram,
+    address,
+    auto_increment,
+    disabled,
+    cycle,
+    current,
+    outputs
+});
+
+impl Namco163 {
+    pub fn new() -> Namco163 {
+        Namco163 {
+            ram: [0; 0x80],
+            address: 0,
+            auto_increment: false,
+            disabled: false,
+            cycle: 0,
+            current: 7,
+            outputs: [0; 8],
+        }
+    }
+
+    /// Number of enabled channels is stored in the high nibble of the very last byte of RAM.
+    fn enabled_channels(&self) -> usize {
+        (((self.ram[0x7F] >> 4) & 0b111) + 1) as usize
+    }
+
+    fn advance_address(&mut self) {
+        if self.auto_increment {
+            self.address = (self.address + 1) & 0x7F;
+        }
+    }
+
+    /// The registers of channel `n` live at $40 + 8n in sound RAM.
+    fn update_channel(&mut self, n: usize) {
+        let base = 0x40 + n * 8;
+        let regs = &self.ram[base..base + 8];
+
+        let frequency = regs[0] as u32 | (regs[2] as u32) << 8 | ((regs[4] & 0b11) as u32) << 16;
+        let mut phase = regs[1] as u32 | (regs[3] as u32) << 8 | (regs[5] as u32) << 16;
+        let length = 256 - (regs[4] & 0b1111_1100) as u32;
+        let wave_address = regs[6] as u32;
+        let volume = (regs[7] & 0b1111) as i16;
+
+        phase = (phase + frequency) % (length << 16);
+
+        let sample_index = ((phase >> 16) + wave_address) & 0xFF;
+        let byte = self.ram[(sample_index >> 1) as usize];
+        let sample = if sample_index & 1 == 0 {
+            byte & 0b1111
+        } else {
+            byte >> 4
+        };
+        self.outputs[n] = (sample as i16 - 8) * volume;
+
+        self.ram[base + 1] = phase as u8;
+        self.ram[base + 3] = (phase >> 8) as u8;
+        self.ram[base + 5] = (phase >> 16) as u8;
+    }
+}
+
+impl Default for Namco163 {
+    fn default() -> Self {
+        Namco163::new()
+    }
+}
+
+impl ExpansionAudio for Namco163 {
+    fn write(&mut self, addr: u16, value: u8) {
+        match addr & 0xF800 {
+            0x4800 => {
+                self.ram[self.address as usize] = value;
+                self.advance_address();
+            }
+            // Bit 6 of the mapper register at $E000 disables the sound.
+            0xE000 => self.disabled = value & 0b0100_0000 != 0,
+            0xF800 => {
+                self.address = value & 0x7F;
+                self.auto_increment = value & 0b1000_0000 != 0;
+            }
+            _ => {}
+        }
+    }
+
+    fn read(&mut self, addr: u16) -> Option<u8> {
+        if addr & 0xF800 != 0x4800 {
+            return None;
+        }
+
+        let value = self.ram[self.address as usize];
+        self.advance_address();
+        Some(value)
+    }
+
+    fn clock(&mut self) {
+        self.cycle += 1;
+        if self.cycle < CYCLES_PER_CHANNEL {
+            return;
+        }
+        self.cycle = 0;
+
+        if self.disabled {
+            return;
+        }
+
+        self.update_channel(self.current);
+
+        let first = 8 - self.enabled_channels();
+        self.current = if self.current <= first {
+            7
+        } else {
+            self.current - 1
+        };
+    }
+
+    fn output(&self) -> f32 {
+        if self.disabled {
+            return 0.0;
+        }
+
+        let enabled = self.enabled_channels();
+        let sum: i16 = self.outputs[8 - enabled..].iter().sum();
+        sum as f32 / enabled as f32 * NAMCO163_SCALE
+    }
+
+    fn box_clone(&self) -> Box<dyn ExpansionAudio> {
+        Box::new(self.clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_ram_auto_increment() {
+        let mut n163 = Namco163::new();
+        n163.write(0xF800, 0b1000_0000);
+        n163.write(0x4800, 0x12);
+        n163.write(0x4800, 0x34);
+
+        n163.write(0xF800, 0b1000_0000);
+        assert_eq!(n163.read(0x4800), Some(0x12));
+        assert_eq!(n163.read(0x4800), Some(0x34));
+        assert_eq!(n163.read(0x8000), None);
+    }
+
+    #[test]
+    fn test_channel_plays_wave() {
+        let mut n163 = Namco163::new();
+        // A square wave of 16 samples at address 0.
+        n163.write(0xF800, 0b1000_0000);
+        for _ in 0..4 {
+            n163.write(0x4800, 0xFF);
+        }
+        for _ in 0..4 {
+            n163.write(0x4800, 0x00);
+        }
+
+        // Channel 7 advancing one sample per update, length 16, wave address 0, volume 15 and
+        // one channel enabled.
+        n163.write(0xF800, 0b1000_0000 | 0x78);
+        for value in [0x00, 0x00, 0x00, 0x00, 0xF1, 0x00, 0x00, 0x0F] {
+            n163.write(0x4800, value);
+        }
+
+        let mut high = false;
+        let mut low = false;
+        for _ in 0..15 * 64 {
+            n163.clock();
+            high |= n163.output() > 0.0;
+            low |= n163.output() < 0.0;
+        }
+        assert!(high && low);
+    }
+}
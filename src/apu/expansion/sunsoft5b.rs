@@ -0,0 +1,281 @@
+use super::ExpansionAudio;
+use crate::state::impl_state;
+
+/// A channel at full volume is roughly as loud as an APU pulse channel at full volume.
+const SUNSOFT5B_SCALE: f32 = 0.15;
+
+#[derive(Clone, Debug, Default)]
+struct Tone {
+    period: u16,
+    timer: u32,
+    output: bool,
+}
+
+impl_state!(Tone {
+    period,
+    timer,
+    output
+});
+
+impl Tone {
+    /// The tone flips every 16 CPU cycles times the period.
+    fn clock(&mut self) {
+        if self.timer == 0 {
+            self.timer = 16 * self.period.max(1) as u32 - 1;
+            self.output = !self.output;
+        } else {
+            self.timer -= 1;
+        }
+    }
+}
+
+/// Sunsoft 5B, used by mapper 69. A variant of the Yamaha YM2149 (the AY-3-8910 in a lot of home
+/// computers) with three square wave channels, a noise generator and an envelope.
+#[derive(Clone, Debug)]
+pub struct Sunsoft5b {
+    register: u8,
+    tones: [Tone; 3],
+
+    noise_period: u8,
+    noise_timer: u32,
+    noise_shift: u32,
+
+    /// Register 7, a cleared bit enables the tone (bits 0-2) or the noise (bits 3-5).
+    mixer: u8,
+    volumes: [u8; 3],
+
+    envelope_period: u16,
+    envelope_timer: u32,
+    envelope_shape: u8,
+    envelope_step: u8,
+    envelope_attack: bool,
+    envelope_holding: bool,
+
+    /// Amplitudes of the 32 envelope levels, 1.5 dB apart.
+    levels: [f32; 32],
+}
+
+impl_state!(Sunsoft5b {
+    register,
+    tones,
+    noise_period,
+    noise_timer,
+    noise_shift,
+    mixer,
+    volumes,
+    envelope_period,
+    envelope_timer,
+    envelope_shape,
+    envelope_step,
+    envelope_attack,
+    envelope_holding
+});
+
+impl Sunsoft5b {
+    pub fn new() -> Sunsoft5b {
+        let mut levels = [0.0; 32];
+        for (i, level) in levels.iter_mut().enumerate().skip(1) {
+            *level = 10f32.powf(-((31 - i) as f32) * 1.5 / 20.0);
+        }
+
+        Sunsoft5b {
+            register: 0,
+            tones: Default::default(),
+            noise_period: 0,
+            noise_timer: 0,
+            noise_shift: 1,
+            mixer: 0xFF,
+            volumes: [0; 3],
+            envelope_period: 0,
+            envelope_timer: 0,
+            envelope_shape: 0,
+            envelope_step: 0,
+            envelope_attack: false,
+            envelope_holding: true,
+            levels,
+        }
+    }
+
+    fn write_register(&mut self, value: u8) {
+        match self.register {
+            0 | 2 | 4 => {
+                let tone = &mut self.tones[self.register as usize / 2];
+                tone.period = (tone.period & 0x0F00) | value as u16;
+            }
+            1 | 3 | 5 => {
+                let tone = &mut self.tones[self.register as usize / 2];
+                tone.period = (tone.period & 0x00FF) | ((value as u16 & 0b1111) << 8);
+            }
+            6 => self.noise_period = value & 0b1_1111,
+            7 => self.mixer = value,
+            8..=10 => self.volumes[self.register as usize - 8] = value & 0b1_1111,
+            11 => self.envelope_period = (self.envelope_period & 0xFF00) | value as u16,
+            12 => self.envelope_period = (self.envelope_period & 0x00FF) | (value as u16) << 8,
+            13 => {
+                self.envelope_shape = value & 0b1111;
+                self.envelope_attack = value & 0b0100 != 0;
+                self.envelope_step = 0;
+                self.envelope_holding = false;
+                self.envelope_timer = 0;
+            }
+            _ => {}
+        }
+    }
+
+    fn envelope_level(&self) -> u8 {
+        if self.envelope_attack {
+            self.envelope_step
+        } else {
+            31 - self.envelope_step
+        }
+    }
+
+    /// The envelope takes 32 steps, then depending on the shape it holds, restarts or changes
+    /// direction.
+    fn clock_envelope(&mut self) {
+        if self.envelope_timer > 0 {
+            self.envelope_timer -= 1;
+            return;
+        }
+        self.envelope_timer = 8 * self.envelope_period.max(1) as u32 - 1;
+
+        if self.envelope_holding {
+            return;
+        }
+
+        if self.envelope_step < 31 {
+            self.envelope_step += 1;
+            return;
+        }
+
+        let continue_flag = self.envelope_shape & 0b1000 != 0;
+        let alternate = self.envelope_shape & 0b0010 != 0;
+        let hold = self.envelope_shape & 0b0001 != 0;
+
+        if !continue_flag {
+            // Falls to zero and stays there.
+            self.envelope_attack = false;
+            self.envelope_holding = true;
+        } else if hold {
+            if alternate {
+                self.envelope_attack = !self.envelope_attack;
+            }
+            self.envelope_holding = true;
+        } else {
+            if alternate {
+                self.envelope_attack = !self.envelope_attack;
+            }
+            self.envelope_step = 0;
+        }
+    }
+
+    fn clock_noise(&mut self) {
+        if self.noise_timer > 0 {
+            self.noise_timer -= 1;
+            return;
+        }
+        self.noise_timer = 32 * self.noise_period.max(1) as u32 - 1;
+
+        // 17 bit LFSR with taps at bit 0 and 3.
+        let feedback = (self.noise_shift ^ (self.noise_shift >> 3)) & 1;
+        self.noise_shift = (self.noise_shift >> 1) | (feedback << 16);
+    }
+}
+
+impl Default for Sunsoft5b {
+    fn default() -> Self {
+        Sunsoft5b::new()
+    }
+}
+
+impl ExpansionAudio for Sunsoft5b {
+    fn write(&mut self, addr: u16, value: u8) {
+        match addr & 0xE000 {
+            0xC000 => self.register = value & 0b1111,
+            0xE000 => self.write_register(value),
+            _ => {}
+        }
+    }
+
+    fn clock(&mut self) {
+        for tone in self.tones.iter_mut() {
+            tone.clock();
+        }
+        self.clock_noise();
+        self.clock_envelope();
+    }
+
+    fn output(&self) -> f32 {
+        let noise = self.noise_shift & 1 == 1;
+
+        let mut sum = 0.0;
+        for (i, tone) in self.tones.iter().enumerate() {
+            let tone_disabled = self.mixer & (1 << i) != 0;
+            let noise_disabled = self.mixer & (1 << (i + 3)) != 0;
+            if !((tone.output || tone_disabled) && (noise || noise_disabled)) {
+                continue;
+            }
+
+            let volume = self.volumes[i];
+            let level = if volume & 0b1_0000 != 0 {
+                self.envelope_level()
+            } else if volume == 0 {
+                0
+            } else {
+                // The fixed volume has half the resolution of the envelope.
+                (volume & 0b1111) * 2 + 1
+            };
+            sum += self.levels[level as usize];
+        }
+
+        sum * SUNSOFT5B_SCALE
+    }
+
+    fn box_clone(&self) -> Box<dyn ExpansionAudio> {
+        Box::new(self.clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn write(chip: &mut Sunsoft5b, reg: u8, value: u8) {
+        chip.write(0xC000, reg);
+        chip.write(0xE000, value);
+    }
+
+    #[test]
+    fn test_tone() {
+        let mut chip = Sunsoft5b::new();
+        write(&mut chip, 0, 0x10);
+        write(&mut chip, 7, 0b1111_1110);
+        write(&mut chip, 8, 0x0F);
+
+        let mut toggles = 0;
+        let mut last = chip.output();
+        for _ in 0..16 * 0x10 * 10 {
+            chip.clock();
+            if chip.output() != last {
+                toggles += 1;
+                last = chip.output();
+            }
+        }
+        assert_eq!(toggles, 10);
+    }
+
+    #[test]
+    fn test_envelope_decays_and_holds() {
+        let mut chip = Sunsoft5b::new();
+        write(&mut chip, 7, 0b1111_1111);
+        write(&mut chip, 8, 0b1_0000);
+        write(&mut chip, 11, 1);
+        write(&mut chip, 13, 0b0000);
+
+        assert!(chip.output() > 0.1);
+        for _ in 0..8 * 40 {
+            chip.clock();
+        }
+        assert_eq!(chip.output(), 0.0);
+    }
+}
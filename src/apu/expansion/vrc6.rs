@@ -0,0 +1,256 @@
+use super::ExpansionAudio;
+use crate::state::impl_state;
+
+/// One step of the VRC6 channels is roughly as loud as one step of the APU pulse channels.
+const VRC6_SCALE: f32 = 0.0099;
+
+#[derive(Clone, Debug, Default)]
+struct Vrc6Pulse {
+    volume: u8,
+    duty: u8,
+    /// When set, the channel ignores the duty and outputs the volume constantly.
+    mode: bool,
+    enabled: bool,
+    period: u16,
+    timer: u16,
+    step: u8,
+}
+
+impl_state!(Vrc6Pulse {
+    volume,
+    duty,
+    mode,
+    enabled,
+    period,
+    timer,
+    step
+});
+
+impl Vrc6Pulse {
+    fn write(&mut self, reg: u16, value: u8) {
+        match reg {
+            0 => {
+                self.mode = value & 0b1000_0000 != 0;
+                self.duty = (value >> 4) & 0b111;
+                self.volume = value & 0b1111;
+            }
+            1 => {
+                self.period = (self.period & 0x0F00) | value as u16;
+            }
+            _ => {
+                self.period = (self.period & 0x00FF) | ((value as u16 & 0b1111) << 8);
+                self.enabled = value & 0b1000_0000 != 0;
+                if !self.enabled {
+                    self.step = 15;
+                }
+            }
+        }
+    }
+
+    fn clock(&mut self, shift: u8) {
+        if !self.enabled {
+            return;
+        }
+
+        if self.timer == 0 {
+            self.timer = self.period >> shift;
+            self.step = self.step.wrapping_sub(1) & 0b1111;
+        } else {
+            self.timer -= 1;
+        }
+    }
+
+    fn output(&self) -> u8 {
+        if self.enabled && (self.mode || self.step <= self.duty) {
+            self.volume
+        } else {
+            0
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+struct Vrc6Saw {
+    rate: u8,
+    enabled: bool,
+    period: u16,
+    timer: u16,
+    step: u8,
+    accumulator: u8,
+}
+
+impl_state!(Vrc6Saw {
+    rate,
+    enabled,
+    period,
+    timer,
+    step,
+    accumulator
+});
+
+impl Vrc6Saw {
+    fn write(&mut self, reg: u16, value: u8) {
+        match reg {
+            0 => {
+                self.rate = value & 0b0011_1111;
+            }
+            1 => {
+                self.period = (self.period & 0x0F00) | value as u16;
+            }
+            _ => {
+                self.period = (self.period & 0x00FF) | ((value as u16 & 0b1111) << 8);
+                self.enabled = value & 0b1000_0000 != 0;
+                if !self.enabled {
+                    self.accumulator = 0;
+                    self.step = 0;
+                }
+            }
+        }
+    }
+
+    /// The accumulator is increased on every other clock and reset after the seventh increase,
+    /// which gives a sawtooth of 7 steps.
+    fn clock(&mut self, shift: u8) {
+        if !self.enabled {
+            return;
+        }
+
+        if self.timer == 0 {
+            self.timer = self.period >> shift;
+            self.step += 1;
+            if self.step == 14 {
+                self.step = 0;
+                self.accumulator = 0;
+            } else if self.step.is_multiple_of(2) {
+                self.accumulator = self.accumulator.wrapping_add(self.rate);
+            }
+        } else {
+            self.timer -= 1;
+        }
+    }
+
+    /// Only the top five bits of the accumulator are output.
+    fn output(&self) -> u8 {
+        self.accumulator >> 3
+    }
+}
+
+/// Konami VRC6, used by mappers 24 and 26. Two pulse channels with eight duty cycles and a
+/// sawtooth channel.
+#[derive(Clone, Debug)]
+pub struct Vrc6 {
+    /// Mapper 26 has the A0 and A1 address lines swapped.
+    swap_lines: bool,
+    halt: bool,
+    /// How far the periods are shifted down, set by the frequency control register.
+    shift: u8,
+    pulse1: Vrc6Pulse,
+    pulse2: Vrc6Pulse,
+    saw: Vrc6Saw,
+}
+
+impl_state!(Vrc6 {
+    halt,
+    shift,
+    pulse1,
+    pulse2,
+    saw
+});
+
+impl Vrc6 {
+    pub fn new(swap_lines: bool) -> Vrc6 {
+        Vrc6 {
+            swap_lines,
+            halt: false,
+            shift: 0,
+            pulse1: Vrc6Pulse {
+                step: 15,
+                ..Default::default()
+            },
+            pulse2: Vrc6Pulse {
+                step: 15,
+                ..Default::default()
+            },
+            saw: Vrc6Saw::default(),
+        }
+    }
+}
+
+impl ExpansionAudio for Vrc6 {
+    fn write(&mut self, addr: u16, value: u8) {
+        let addr = if self.swap_lines {
+            (addr & 0xFFFC) | ((addr & 0b01) << 1) | ((addr & 0b10) >> 1)
+        } else {
+            addr
+        };
+
+        match addr & 0xF003 {
+            0x9000..=0x9002 => self.pulse1.write(addr & 0b11, value),
+            0x9003 => {
+                self.halt = value & 0b001 != 0;
+                self.shift = if value & 0b100 != 0 {
+                    8
+                } else if value & 0b010 != 0 {
+                    4
+                } else {
+                    0
+                };
+            }
+            0xA000..=0xA002 => self.pulse2.write(addr & 0b11, value),
+            0xB000..=0xB002 => self.saw.write(addr & 0b11, value),
+            _ => {}
+        }
+    }
+
+    fn clock(&mut self) {
+        if self.halt {
+            return;
+        }
+
+        self.pulse1.clock(self.shift);
+        self.pulse2.clock(self.shift);
+        self.saw.clock(self.shift);
+    }
+
+    fn output(&self) -> f32 {
+        let sum = self.pulse1.output() + self.pulse2.output() + self.saw.output();
+        sum as f32 * VRC6_SCALE
+    }
+
+    fn box_clone(&self) -> Box<dyn ExpansionAudio> {
+        Box::new(self.clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_pulse_duty() {
+        let mut vrc6 = Vrc6::new(false);
+        // Duty 7 (50%), volume 15, period 0x10.
+        vrc6.write(0x9000, 0b0111_1111);
+        vrc6.write(0x9001, 0x10);
+        vrc6.write(0x9002, 0b1000_0000);
+
+        let mut high = 0;
+        for _ in 0..17 * 16 {
+            vrc6.clock();
+            if vrc6.output() > 0.0 {
+                high += 1;
+            }
+        }
+        assert_eq!(high, 17 * 8);
+    }
+
+    #[test]
+    fn test_swapped_address_lines() {
+        let mut vrc6 = Vrc6::new(true);
+        // $9002 on mapper 24 is $9001 on mapper 26.
+        vrc6.write(0x9000, 0b1000_1111);
+        vrc6.write(0x9001, 0b1000_0000);
+
+        assert!(vrc6.output() > 0.0);
+    }
+}
@@ -0,0 +1,483 @@
+use std::f32::consts::PI;
+
+use super::ExpansionAudio;
+use crate::state::{impl_state, State, StateSync};
+
+/// The built in instruments of the VRC7. Instrument 0 is the custom one set through registers
+/// $00-$07.
+const PATCHES: [[u8; 8]; 15] = [
+    [0x03, 0x21, 0x05, 0x06, 0xE8, 0x81, 0x42, 0x27],
+    [0x13, 0x41, 0x14, 0x0D, 0xD8, 0xF6, 0x23, 0x12],
+    [0x11, 0x11, 0x08, 0x08, 0xFA, 0xB2, 0x20, 0x12],
+    [0x31, 0x61, 0x0C, 0x07, 0xA8, 0x64, 0x61, 0x27],
+    [0x32, 0x21, 0x1E, 0x06, 0xE1, 0x76, 0x01, 0x28],
+    [0x02, 0x01, 0x06, 0x00, 0xA3, 0xE2, 0xF4, 0xF4],
+    [0x21, 0x61, 0x1D, 0x07, 0x82, 0x81, 0x11, 0x07],
+    [0x23, 0x21, 0x22, 0x17, 0xA2, 0x72, 0x01, 0x17],
+    [0x35, 0x11, 0x25, 0x00, 0x40, 0x73, 0x72, 0x01],
+    [0xB5, 0x01, 0x0F, 0x0F, 0xA8, 0xA5, 0x51, 0x02],
+    [0x17, 0xC1, 0x24, 0x07, 0xF8, 0xF8, 0x22, 0x12],
+    [0x71, 0x23, 0x11, 0x06, 0x65, 0x74, 0x18, 0x16],
+    [0x01, 0x02, 0xD3, 0x05, 0xC9, 0x95, 0x03, 0x02],
+    [0x61, 0x63, 0x0C, 0x00, 0x94, 0xC0, 0x33, 0xF6],
+    [0x21, 0x72, 0x0D, 0x00, 0xC1, 0xD5, 0x56, 0x06],
+];
+
+/// Frequency multipliers selected by the lower four bits of the first two patch bytes.
+const MULTIPLIERS: [f32; 16] = [
+    0.5, 1.0, 2.0, 3.0, 4.0, 5.0, 6.0, 7.0, 8.0, 9.0, 10.0, 10.0, 12.0, 12.0, 15.0, 15.0,
+];
+
+/// Phase modulation applied by the modulator feeding back into itself, indexed by the feedback
+/// value of the patch.
+const FEEDBACK: [f32; 8] = [
+    0.0,
+    PI / 16.0,
+    PI / 8.0,
+    PI / 4.0,
+    PI / 2.0,
+    PI,
+    2.0 * PI,
+    4.0 * PI,
+];
+
+/// The chip generates a sample every 36 CPU cycles, which is 49.7 kHz.
+const CYCLES_PER_SAMPLE: u32 = 36;
+const SAMPLE_RATE: f32 = 1_789_773.0 / CYCLES_PER_SAMPLE as f32;
+
+/// Attenuation in dB at which an operator is considered silent.
+const MAX_ATTENUATION: f32 = 48.0;
+
+/// Roughly matches the relative volume of the VRC7 to the APU in Lagrange Point.
+const VRC7_SCALE: f32 = 0.12;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum EnvelopeState {
+    Attack,
+    Decay,
+    Sustain,
+    Release,
+}
+
+impl State for EnvelopeState {
+    fn sync(&mut self, s: &mut StateSync) {
+        let states = [
+            EnvelopeState::Attack,
+            EnvelopeState::Decay,
+            EnvelopeState::Sustain,
+            EnvelopeState::Release,
+        ];
+        let mut index = states.iter().position(|state| state == self).unwrap() as u8;
+        s.sync(&mut index);
+        *self = states[index as usize % states.len()];
+    }
+}
+
+/// The parameters of one operator, decoded from a patch.
+#[derive(Clone, Copy, Debug, Default)]
+struct OperatorPatch {
+    tremolo: bool,
+    vibrato: bool,
+    sustained: bool,
+    key_scale_rate: bool,
+    multiplier: f32,
+    key_scale_level: u8,
+    rectified: bool,
+    attack: u8,
+    decay: u8,
+    sustain_level: u8,
+    release: u8,
+}
+
+impl OperatorPatch {
+    fn decode(patch: &[u8; 8], carrier: bool) -> OperatorPatch {
+        let i = carrier as usize;
+        OperatorPatch {
+            tremolo: patch[i] & 0b1000_0000 != 0,
+            vibrato: patch[i] & 0b0100_0000 != 0,
+            sustained: patch[i] & 0b0010_0000 != 0,
+            key_scale_rate: patch[i] & 0b0001_0000 != 0,
+            multiplier: MULTIPLIERS[(patch[i] & 0b1111) as usize],
+            key_scale_level: patch[2 + i] >> 6,
+            rectified: patch[3] & (if carrier { 0b0001_0000 } else { 0b0000_1000 }) != 0,
+            attack: patch[4 + i] >> 4,
+            decay: patch[4 + i] & 0b1111,
+            sustain_level: patch[6 + i] >> 4,
+            release: patch[6 + i] & 0b1111,
+        }
+    }
+}
+
+/// One of the two sine operators of a channel. The envelope is tracked as attenuation in dB.
+#[derive(Clone, Debug)]
+struct Operator {
+    phase: f32,
+    state: EnvelopeState,
+    attenuation: f32,
+}
+
+impl_state!(Operator {
+    phase,
+    state,
+    attenuation
+});
+
+impl Operator {
+    fn new() -> Operator {
+        Operator {
+            phase: 0.0,
+            state: EnvelopeState::Release,
+            attenuation: MAX_ATTENUATION,
+        }
+    }
+
+    fn key_on(&mut self) {
+        self.phase = 0.0;
+        self.state = EnvelopeState::Attack;
+    }
+
+    fn key_off(&mut self) {
+        self.state = EnvelopeState::Release;
+    }
+
+    /// Advances the envelope by one sample. Every rate step doubles the speed, and the key scale
+    /// rate makes higher notes faster.
+    fn clock_envelope(&mut self, patch: &OperatorPatch, key_scale: u8, release: u8) {
+        let key_scale = if patch.key_scale_rate {
+            key_scale
+        } else {
+            key_scale >> 2
+        };
+
+        let rate = match self.state {
+            EnvelopeState::Attack => patch.attack,
+            EnvelopeState::Decay => patch.decay,
+            EnvelopeState::Sustain if patch.sustained => 0,
+            EnvelopeState::Sustain | EnvelopeState::Release => release,
+        };
+        if rate == 0 {
+            return;
+        }
+        let rate = (rate as f32 + key_scale as f32 / 4.0).min(15.0);
+
+        match self.state {
+            EnvelopeState::Attack => {
+                if rate >= 15.0 {
+                    self.attenuation = 0.0;
+                } else {
+                    let time = 2.826 / 2f32.powf(rate - 1.0);
+                    self.attenuation -= MAX_ATTENUATION / (time * SAMPLE_RATE);
+                }
+
+                if self.attenuation <= 0.0 {
+                    self.attenuation = 0.0;
+                    self.state = EnvelopeState::Decay;
+                }
+            }
+            _ => {
+                let time = 19.64 / 2f32.powf(rate - 1.0);
+                self.attenuation += MAX_ATTENUATION / (time * SAMPLE_RATE);
+                self.attenuation = self.attenuation.min(MAX_ATTENUATION);
+
+                let sustain_level = patch.sustain_level as f32 * 3.0;
+                if self.state == EnvelopeState::Decay && self.attenuation >= sustain_level {
+                    self.state = EnvelopeState::Sustain;
+                }
+            }
+        }
+    }
+
+    /// Returns the output in the range -1.0..1.0 for the given phase modulation in radians.
+    fn output(&self, modulation: f32, attenuation: f32, rectified: bool) -> f32 {
+        let total = self.attenuation + attenuation;
+        if total >= MAX_ATTENUATION {
+            return 0.0;
+        }
+
+        let value = (2.0 * PI * self.phase + modulation).sin();
+        if rectified && value < 0.0 {
+            return 0.0;
+        }
+        value * 10f32.powf(-total / 20.0)
+    }
+}
+
+#[derive(Clone, Debug)]
+struct Channel {
+    fnum: u16,
+    octave: u8,
+    key_on: bool,
+    sustain: bool,
+    instrument: u8,
+    volume: u8,
+
+    modulator: Operator,
+    carrier: Operator,
+    feedback: [f32; 2],
+}
+
+impl_state!(Channel {
+    fnum,
+    octave,
+    key_on,
+    sustain,
+    instrument,
+    volume,
+    modulator,
+    carrier,
+    feedback
+});
+
+impl Channel {
+    fn new() -> Channel {
+        Channel {
+            fnum: 0,
+            octave: 0,
+            key_on: false,
+            sustain: false,
+            instrument: 0,
+            volume: 0,
+            modulator: Operator::new(),
+            carrier: Operator::new(),
+            feedback: [0.0; 2],
+        }
+    }
+
+    fn frequency(&self) -> f32 {
+        self.fnum as f32 * SAMPLE_RATE * 2f32.powi(self.octave as i32) / 2f32.powi(19)
+    }
+
+    /// Used for the key scale rate, higher octaves have faster envelopes.
+    fn key_scale(&self) -> u8 {
+        (self.octave << 1) | (self.fnum >> 8) as u8
+    }
+
+    /// Attenuation from the key scale level, higher notes get quieter.
+    fn key_scale_attenuation(&self, level: u8) -> f32 {
+        if level == 0 {
+            return 0.0;
+        }
+        let octaves = self.octave as f32 + (self.fnum >> 5) as f32 / 16.0;
+        octaves * [0.0, 1.5, 3.0, 6.0][level as usize]
+    }
+
+    fn sample(&mut self, patch: &[u8; 8], lfo_am: f32, lfo_vibrato: f32) -> f32 {
+        let mod_patch = OperatorPatch::decode(patch, false);
+        let car_patch = OperatorPatch::decode(patch, true);
+
+        let release = if self.sustain { 5 } else { car_patch.release };
+        let key_scale = self.key_scale();
+        self.modulator
+            .clock_envelope(&mod_patch, key_scale, mod_patch.release);
+        self.carrier.clock_envelope(&car_patch, key_scale, release);
+
+        let frequency = self.frequency();
+        for (op, op_patch) in [
+            (&mut self.modulator, &mod_patch),
+            (&mut self.carrier, &car_patch),
+        ] {
+            let vibrato = if op_patch.vibrato { lfo_vibrato } else { 1.0 };
+            op.phase += frequency * op_patch.multiplier * vibrato / SAMPLE_RATE;
+            op.phase = op.phase.fract();
+        }
+
+        let total_level = (patch[2] & 0b0011_1111) as f32 * 0.75;
+        let mod_attenuation = total_level
+            + self.key_scale_attenuation(mod_patch.key_scale_level)
+            + if mod_patch.tremolo { lfo_am } else { 0.0 };
+        let feedback = FEEDBACK[(patch[3] & 0b111) as usize];
+        let modulation = (self.feedback[0] + self.feedback[1]) / 2.0 * feedback;
+        let modulator = self
+            .modulator
+            .output(modulation, mod_attenuation, mod_patch.rectified);
+        self.feedback = [self.feedback[1], modulator];
+
+        let car_attenuation = self.volume as f32 * 3.0
+            + self.key_scale_attenuation(car_patch.key_scale_level)
+            + if car_patch.tremolo { lfo_am } else { 0.0 };
+        self.carrier
+            .output(modulator * 4.0 * PI, car_attenuation, car_patch.rectified)
+    }
+}
+
+/// Konami VRC7, used by mapper 85. A cut down Yamaha YM2413 with six two-operator FM channels
+/// and 15 built in instruments.
+///
+/// This is a floating point approximation of the chip, the envelopes and the LFOs follow the
+/// documented rates but it isn't bit exact.
+#[derive(Clone, Debug)]
+pub struct Vrc7 {
+    register: u8,
+    custom_patch: [u8; 8],
+    channels: [Channel; 6],
+    silenced: bool,
+
+    cycle: u32,
+    tremolo_phase: f32,
+    vibrato_phase: f32,
+    output: f32,
+}
+
+impl_state!(Vrc7 {
+    register,
+    custom_patch,
+    channels,
+    silenced,
+    cycle,
+    tremolo_phase,
+    vibrato_phase,
+    output
+});
+
+impl Vrc7 {
+    pub fn new() -> Vrc7 {
+        Vrc7 {
+            register: 0,
+            custom_patch: [0; 8],
+            channels: [
+                Channel::new(),
+                Channel::new(),
+                Channel::new(),
+                Channel::new(),
+                Channel::new(),
+                Channel::new(),
+            ],
+            silenced: false,
+            cycle: 0,
+            tremolo_phase: 0.0,
+            vibrato_phase: 0.0,
+            output: 0.0,
+        }
+    }
+
+    fn write_register(&mut self, value: u8) {
+        let reg = self.register;
+        match reg {
+            0x00..=0x07 => self.custom_patch[reg as usize] = value,
+            0x10..=0x15 => {
+                let channel = &mut self.channels[(reg & 0x0F) as usize];
+                channel.fnum = (channel.fnum & 0x100) | value as u16;
+            }
+            0x20..=0x25 => {
+                let channel = &mut self.channels[(reg & 0x0F) as usize];
+                channel.fnum = (channel.fnum & 0xFF) | ((value as u16 & 1) << 8);
+                channel.octave = (value >> 1) & 0b111;
+                channel.sustain = value & 0b0010_0000 != 0;
+
+                let key_on = value & 0b0001_0000 != 0;
+                if key_on && !channel.key_on {
+                    channel.modulator.key_on();
+                    channel.carrier.key_on();
+                } else if !key_on && channel.key_on {
+                    channel.modulator.key_off();
+                    channel.carrier.key_off();
+                }
+                channel.key_on = key_on;
+            }
+            0x30..=0x35 => {
+                let channel = &mut self.channels[(reg & 0x0F) as usize];
+                channel.instrument = value >> 4;
+                channel.volume = value & 0b1111;
+            }
+            _ => {}
+        }
+    }
+
+    fn patch(&self, instrument: u8) -> [u8; 8] {
+        if instrument == 0 {
+            self.custom_patch
+        } else {
+            PATCHES[instrument as usize - 1]
+        }
+    }
+}
+
+impl Default for Vrc7 {
+    fn default() -> Self {
+        Vrc7::new()
+    }
+}
+
+impl ExpansionAudio for Vrc7 {
+    fn write(&mut self, addr: u16, value: u8) {
+        match addr & 0xF030 {
+            0x9010 => self.register = value,
+            0x9030 => self.write_register(value),
+            // The mapper control register, bit 6 silences and resets the sound chip.
+            0xE000 => self.silenced = value & 0b0100_0000 != 0,
+            _ => {}
+        }
+    }
+
+    fn clock(&mut self) {
+        self.cycle += 1;
+        if self.cycle < CYCLES_PER_SAMPLE {
+            return;
+        }
+        self.cycle = 0;
+
+        if self.silenced {
+            self.output = 0.0;
+            return;
+        }
+
+        // Tremolo is 3.7 Hz at 4.8 dB and vibrato 6.4 Hz at roughly 14 cents.
+        self.tremolo_phase = (self.tremolo_phase + 3.7 / SAMPLE_RATE).fract();
+        self.vibrato_phase = (self.vibrato_phase + 6.4 / SAMPLE_RATE).fract();
+        let lfo_am = (1.0 + (2.0 * PI * self.tremolo_phase).sin()) * 2.4;
+        let lfo_vibrato = 1.0 + (2.0 * PI * self.vibrato_phase).sin() * 0.008;
+
+        let mut sum = 0.0;
+        for i in 0..self.channels.len() {
+            let patch = self.patch(self.channels[i].instrument);
+            sum += self.channels[i].sample(&patch, lfo_am, lfo_vibrato);
+        }
+        self.output = sum / 6.0;
+    }
+
+    fn output(&self) -> f32 {
+        self.output * VRC7_SCALE
+    }
+
+    fn box_clone(&self) -> Box<dyn ExpansionAudio> {
+        Box::new(self.clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn write(vrc7: &mut Vrc7, reg: u8, value: u8) {
+        vrc7.write(0x9010, reg);
+        vrc7.write(0x9030, value);
+    }
+
+    #[test]
+    fn test_key_on_produces_sound() {
+        let mut vrc7 = Vrc7::new();
+        // Flute at full volume, A4.
+        write(&mut vrc7, 0x30, 0x40);
+        write(&mut vrc7, 0x10, 0x20);
+        write(&mut vrc7, 0x20, 0b0001_1001);
+
+        let mut peak: f32 = 0.0;
+        for _ in 0..200_000 {
+            vrc7.clock();
+            peak = peak.max(vrc7.output().abs());
+        }
+        assert!(peak > 0.01);
+    }
+
+    #[test]
+    fn test_silent_without_key_on() {
+        let mut vrc7 = Vrc7::new();
+        write(&mut vrc7, 0x30, 0x40);
+        write(&mut vrc7, 0x10, 0x20);
+
+        for _ in 0..20_000 {
+            vrc7.clock();
+            assert_eq!(vrc7.output(), 0.0);
+        }
+    }
+}
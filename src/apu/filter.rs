@@ -0,0 +1,51 @@
+use std::f32::consts::PI;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum FilterKind {
+    HighPass,
+    LowPass,
+}
+
+/// A first order RC filter. The NES has two high-pass filters and one low-pass filter between
+/// the APU and the audio output, and these are what gives it its characteristic sound.
+#[derive(Clone, Debug)]
+pub struct Filter {
+    kind: FilterKind,
+    alpha: f32,
+    prev_input: f32,
+    prev_output: f32,
+}
+
+impl Filter {
+    pub fn high_pass(cutoff: f32, sample_rate: u32) -> Filter {
+        let rc = 1.0 / (2.0 * PI * cutoff);
+        let dt = 1.0 / sample_rate as f32;
+        Filter::new(FilterKind::HighPass, rc / (rc + dt))
+    }
+
+    pub fn low_pass(cutoff: f32, sample_rate: u32) -> Filter {
+        let rc = 1.0 / (2.0 * PI * cutoff);
+        let dt = 1.0 / sample_rate as f32;
+        Filter::new(FilterKind::LowPass, dt / (rc + dt))
+    }
+
+    fn new(kind: FilterKind, alpha: f32) -> Filter {
+        Filter {
+            kind,
+            alpha,
+            prev_input: 0.0,
+            prev_output: 0.0,
+        }
+    }
+
+    pub fn process(&mut self, input: f32) -> f32 {
+        let output = match self.kind {
+            FilterKind::HighPass => self.alpha * (self.prev_output + input - self.prev_input),
+            FilterKind::LowPass => self.prev_output + self.alpha * (input - self.prev_output),
+        };
+
+        self.prev_input = input;
+        self.prev_output = output;
+        output
+    }
+}
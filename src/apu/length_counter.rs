@@ -0,0 +1,57 @@
+use crate::state::impl_state;
+
+/// Lookup table used when loading the length counter. The index is the top five bits written to
+/// the fourth register of a channel.
+const LENGTH_TABLE: [u8; 32] = [
+    10, 254, 20, 2, 40, 4, 80, 6, 160, 8, 60, 10, 14, 12, 26, 14, 12, 16, 24, 18, 48, 20, 96, 22,
+    192, 24, 72, 26, 16, 28, 32, 30,
+];
+
+/// The length counter silences a channel after a set amount of half frames. It is shared by the
+/// pulse, triangle and noise channels.
+#[derive(Clone, Debug, Default)]
+pub struct LengthCounter {
+    enabled: bool,
+    halt: bool,
+    counter: u8,
+}
+
+impl_state!(LengthCounter {
+    enabled,
+    halt,
+    counter
+});
+
+impl LengthCounter {
+    /// Enabling or disabling is controlled by $4015. Disabling a channel clears its counter
+    /// immediately.
+    pub fn set_enabled(&mut self, enabled: bool) {
+        self.enabled = enabled;
+        if !enabled {
+            self.counter = 0;
+        }
+    }
+
+    pub fn set_halt(&mut self, halt: bool) {
+        self.halt = halt;
+    }
+
+    /// Loads the counter from the top five bits of the written value. Writes are ignored while
+    /// the channel is disabled.
+    pub fn load(&mut self, value: u8) {
+        if self.enabled {
+            self.counter = LENGTH_TABLE[(value >> 3) as usize];
+        }
+    }
+
+    /// Clocked by the frame counter every half frame.
+    pub fn clock(&mut self) {
+        if !self.halt && self.counter > 0 {
+            self.counter -= 1;
+        }
+    }
+
+    pub fn active(&self) -> bool {
+        self.counter > 0
+    }
+}
@@ -0,0 +1,143 @@
+use super::filter::Filter;
+use super::resampler::Resampler;
+
+/// How many CPU cycles are collected before they are turned into samples. Keeps the buffer of
+/// pending impulses small.
+const FRAME_CLOCKS: u32 = 4096;
+
+/// Combines the five channels into a single signal, runs it through the same filters as the
+/// console and resamples it to the sample rate of the host.
+///
+/// The channels are not mixed linearly on the real hardware, two pulse channels at full volume
+/// are not twice as loud as one. The formulas from the NESdev wiki are precomputed into two
+/// lookup tables, one for the pulse channels and one for the triangle, noise and DMC.
+#[derive(Clone, Debug)]
+pub struct Mixer {
+    pulse_table: [f32; 31],
+    tnd_table: [f32; 203],
+
+    level: f32,
+    clock: u32,
+    resampler: Resampler,
+    filters: [Filter; 3],
+
+    samples: Vec<f32>,
+    max_samples: usize,
+}
+
+impl Mixer {
+    pub fn new(clock_rate: f64, sample_rate: u32) -> Mixer {
+        let mut pulse_table = [0.0; 31];
+        for (n, v) in pulse_table.iter_mut().enumerate().skip(1) {
+            *v = 95.52 / (8128.0 / n as f32 + 100.0);
+        }
+
+        let mut tnd_table = [0.0; 203];
+        for (n, v) in tnd_table.iter_mut().enumerate().skip(1) {
+            *v = 163.67 / (24329.0 / n as f32 + 100.0);
+        }
+
+        Mixer {
+            pulse_table,
+            tnd_table,
+            level: 0.0,
+            clock: 0,
+            resampler: Resampler::new(clock_rate, sample_rate),
+            filters: [
+                Filter::high_pass(90.0, sample_rate),
+                Filter::high_pass(440.0, sample_rate),
+                Filter::low_pass(14_000.0, sample_rate),
+            ],
+            samples: Vec::new(),
+            // If nobody pulls the samples we only keep the last second around.
+            max_samples: sample_rate as usize,
+        }
+    }
+
+    /// Looks up the combined output level of the five channels. The result is in the range
+    /// 0.0..1.0, not counting `expansion` which is the output of the sound chip on the cartridge
+    /// and is added linearly on top.
+    pub fn mix(
+        &self,
+        pulse1: u8,
+        pulse2: u8,
+        triangle: u8,
+        noise: u8,
+        dmc: u8,
+        expansion: f32,
+    ) -> f32 {
+        let pulse = self.pulse_table[(pulse1 + pulse2) as usize];
+        let tnd = self.tnd_table[3 * triangle as usize + 2 * noise as usize + dmc as usize];
+        pulse + tnd + expansion
+    }
+
+    /// Called once every CPU cycle with the current output level.
+    pub fn clock(&mut self, level: f32) {
+        if level != self.level {
+            self.resampler.add_delta(self.clock, level - self.level);
+            self.level = level;
+        }
+
+        self.clock += 1;
+        if self.clock == FRAME_CLOCKS {
+            self.end_frame();
+        }
+    }
+
+    fn end_frame(&mut self) {
+        let start = self.samples.len();
+        self.resampler.end_frame(self.clock, &mut self.samples);
+        self.clock = 0;
+
+        for sample in self.samples[start..].iter_mut() {
+            for filter in self.filters.iter_mut() {
+                *sample = filter.process(*sample);
+            }
+        }
+
+        if self.samples.len() > self.max_samples {
+            let excess = self.samples.len() - self.max_samples;
+            self.samples.drain(..excess);
+        }
+    }
+
+    /// Returns every sample produced since the last call.
+    pub fn take_samples(&mut self) -> Vec<f32> {
+        self.end_frame();
+        std::mem::take(&mut self.samples)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_mix_is_non_linear() {
+        let mixer = Mixer::new(1_789_773.0, 44_100);
+        let one = mixer.mix(15, 0, 0, 0, 0, 0.0);
+        let two = mixer.mix(15, 15, 0, 0, 0, 0.0);
+
+        assert!(two > one);
+        assert!(two < one * 2.0);
+    }
+
+    #[test]
+    fn test_mix_full_volume() {
+        let mixer = Mixer::new(1_789_773.0, 44_100);
+        assert_eq!(mixer.mix(0, 0, 0, 0, 0, 0.0), 0.0);
+        assert!((mixer.mix(15, 15, 15, 15, 127, 0.0) - 1.0).abs() < 0.01);
+    }
+
+    #[test]
+    fn test_high_pass_removes_dc() {
+        let mut mixer = Mixer::new(1_789_773.0, 44_100);
+        for _ in 0..1_789_773 {
+            mixer.clock(0.5);
+        }
+
+        let samples = mixer.take_samples();
+        assert!((samples.len() as i32 - 44_100).abs() <= 1);
+        assert!(samples.last().unwrap().abs() < 0.01);
+    }
+}
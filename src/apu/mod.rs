@@ -0,0 +1,419 @@
+mod dmc;
+mod envelope;
+pub mod expansion;
+mod filter;
+mod length_counter;
+mod mixer;
+mod noise;
+mod pulse;
+mod resampler;
+mod triangle;
+mod wav;
+
+use crate::state::{State, StateSync};
+use dmc::Dmc;
+use expansion::ExpansionAudio;
+use mixer::Mixer;
+use noise::Noise;
+use pulse::Pulse;
+use triangle::Triangle;
+pub use wav::write_wav;
+
+/// Clock rate of the CPU in an NTSC console. The APU is clocked from the same source.
+pub const NTSC_CPU_CLOCK: f64 = 1_789_773.0;
+
+pub const DEFAULT_SAMPLE_RATE: u32 = 44_100;
+
+/// The frame counter steps, in CPU cycles, at which the envelopes and the linear counter
+/// (quarter frames), and the length counters and sweeps (half frames) are clocked.
+const QUARTER_FRAME_1: u32 = 7457;
+const HALF_FRAME_1: u32 = 14913;
+const QUARTER_FRAME_3: u32 = 22371;
+const FOUR_STEP_END: u32 = 29829;
+const FIVE_STEP_END: u32 = 37281;
+
+/// The Audio Processing Unit. It is mapped to $4000-$4013, $4015 and $4017 and is clocked
+/// together with the CPU.
+///
+/// The channels are mixed and resampled to `sample_rate`, the frontend is expected to pull the
+/// samples with `take_samples` once every frame.
+#[derive(Clone, Debug)]
+pub struct Apu {
+    pulse1: Pulse,
+    pulse2: Pulse,
+    triangle: Triangle,
+    noise: Noise,
+    dmc: Dmc,
+    /// Sound chip on the cartridge, if there is one.
+    expansion: Option<Box<dyn ExpansionAudio>>,
+
+    cycle: u64,
+    frame_cycle: u32,
+    five_step_mode: bool,
+    irq_inhibit: bool,
+    frame_irq: bool,
+
+    sample_rate: u32,
+    mixer: Mixer,
+    muted: bool,
+}
+
+/// The mixer isn't saved, it only holds samples that haven't been taken yet and the state of the
+/// filters.
+impl State for Apu {
+    fn sync(&mut self, s: &mut StateSync) {
+        s.sync(&mut self.pulse1);
+        s.sync(&mut self.pulse2);
+        s.sync(&mut self.triangle);
+        s.sync(&mut self.noise);
+        s.sync(&mut self.dmc);
+        s.sync(&mut self.cycle);
+        s.sync(&mut self.frame_cycle);
+        s.sync(&mut self.five_step_mode);
+        s.sync(&mut self.irq_inhibit);
+        s.sync(&mut self.frame_irq);
+
+        let mut has_expansion = self.expansion.is_some();
+        s.sync(&mut has_expansion);
+        match &mut self.expansion {
+            Some(expansion) if has_expansion => expansion.sync(s),
+            None if !has_expansion => {}
+            _ => s.mismatch("sound chip on the cartridge"),
+        }
+    }
+}
+
+impl Apu {
+    pub fn new(sample_rate: u32) -> Apu {
+        Apu {
+            pulse1: Pulse::new(true),
+            pulse2: Pulse::new(false),
+            triangle: Triangle::default(),
+            noise: Noise::new(),
+            dmc: Dmc::new(),
+            expansion: None,
+            cycle: 0,
+            frame_cycle: 0,
+            five_step_mode: false,
+            irq_inhibit: false,
+            frame_irq: false,
+            sample_rate,
+            mixer: Mixer::new(NTSC_CPU_CLOCK, sample_rate),
+            muted: false,
+        }
+    }
+
+    pub fn write_register(&mut self, addr: u16, value: u8) {
+        match addr {
+            0x4000..=0x4003 => self.pulse1.write(addr - 0x4000, value),
+            0x4004..=0x4007 => self.pulse2.write(addr - 0x4004, value),
+            0x4008..=0x400B => self.triangle.write(addr - 0x4008, value),
+            0x400C..=0x400F => self.noise.write(addr - 0x400C, value),
+            0x4010..=0x4013 => self.dmc.write(addr - 0x4010, value),
+            0x4015 => {
+                self.pulse1.length.set_enabled(value & 0b0000_0001 != 0);
+                self.pulse2.length.set_enabled(value & 0b0000_0010 != 0);
+                self.triangle.length.set_enabled(value & 0b0000_0100 != 0);
+                self.noise.length.set_enabled(value & 0b0000_1000 != 0);
+                self.dmc.set_enabled(value & 0b0001_0000 != 0);
+                self.dmc.irq = false;
+            }
+            0x4017 => {
+                self.five_step_mode = value & 0b1000_0000 != 0;
+                self.irq_inhibit = value & 0b0100_0000 != 0;
+                if self.irq_inhibit {
+                    self.frame_irq = false;
+                }
+
+                // Writing to the frame counter resets the sequence, and in five step mode it
+                // also clocks the units immediately.
+                self.frame_cycle = 0;
+                if self.five_step_mode {
+                    self.clock_quarter_frame();
+                    self.clock_half_frame();
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// Plugs in the sound chip of the cartridge. It is clocked along with the APU and mixed in with
+    /// the other channels.
+    pub fn set_expansion_audio(&mut self, expansion: Option<Box<dyn ExpansionAudio>>) {
+        self.expansion = expansion;
+    }
+
+    /// Forwards a CPU write in cartridge space to the sound chip of the cartridge.
+    pub fn write_expansion(&mut self, addr: u16, value: u8) {
+        if let Some(expansion) = &mut self.expansion {
+            expansion.write(addr, value);
+        }
+    }
+
+    /// Reads a register of the sound chip of the cartridge. Returns `None` if there is no chip
+    /// or if the address isn't readable.
+    pub fn read_expansion(&mut self, addr: u16) -> Option<u8> {
+        self.expansion.as_mut()?.read(addr)
+    }
+
+    /// Reads $4015. Reading clears the frame interrupt flag.
+    pub fn read_status(&mut self) -> u8 {
+        let mut status = 0;
+        if self.pulse1.length.active() {
+            status |= 0b0000_0001;
+        }
+        if self.pulse2.length.active() {
+            status |= 0b0000_0010;
+        }
+        if self.triangle.length.active() {
+            status |= 0b0000_0100;
+        }
+        if self.noise.length.active() {
+            status |= 0b0000_1000;
+        }
+        if self.dmc.active() {
+            status |= 0b0001_0000;
+        }
+        if self.frame_irq {
+            status |= 0b0100_0000;
+        }
+        if self.dmc.irq {
+            status |= 0b1000_0000;
+        }
+
+        self.frame_irq = false;
+        status
+    }
+
+    /// Whether the APU is asserting the IRQ line, either from the frame counter or the DMC.
+    pub fn irq_pending(&self) -> bool {
+        self.frame_irq || self.dmc.irq
+    }
+
+    /// The DMC reads its samples straight from CPU memory. The CPU checks this after every
+    /// cycle and answers with `dmc_fill`.
+    pub fn dmc_pending_read(&self) -> Option<u16> {
+        self.dmc.pending_read()
+    }
+
+    pub fn dmc_fill(&mut self, value: u8) {
+        self.dmc.fill(value);
+    }
+
+    /// Advances the APU by one CPU cycle.
+    pub fn tick(&mut self) {
+        self.cycle += 1;
+
+        self.triangle.clock_timer();
+        self.noise.clock_timer();
+        self.dmc.clock_timer();
+        if self.cycle.is_multiple_of(2) {
+            self.pulse1.clock_timer();
+            self.pulse2.clock_timer();
+        }
+
+        self.clock_frame_counter();
+
+        let expansion = match &mut self.expansion {
+            Some(expansion) => {
+                expansion.clock();
+                expansion.output()
+            }
+            None => 0.0,
+        };
+
+        let level = if self.muted {
+            0.0
+        } else {
+            self.mixer.mix(
+                self.pulse1.output(),
+                self.pulse2.output(),
+                self.triangle.output(),
+                self.noise.output(),
+                self.dmc.output(),
+                expansion,
+            )
+        };
+        self.mixer.clock(level);
+    }
+
+    fn clock_frame_counter(&mut self) {
+        self.frame_cycle += 1;
+
+        match self.frame_cycle {
+            QUARTER_FRAME_1 | QUARTER_FRAME_3 => {
+                self.clock_quarter_frame();
+            }
+            HALF_FRAME_1 => {
+                self.clock_quarter_frame();
+                self.clock_half_frame();
+            }
+            FOUR_STEP_END if !self.five_step_mode => {
+                self.clock_quarter_frame();
+                self.clock_half_frame();
+                if !self.irq_inhibit {
+                    self.frame_irq = true;
+                }
+                self.frame_cycle = 0;
+            }
+            FIVE_STEP_END => {
+                self.clock_quarter_frame();
+                self.clock_half_frame();
+                self.frame_cycle = 0;
+            }
+            _ => {}
+        }
+    }
+
+    fn clock_quarter_frame(&mut self) {
+        self.pulse1.clock_quarter_frame();
+        self.pulse2.clock_quarter_frame();
+        self.triangle.clock_quarter_frame();
+        self.noise.clock_quarter_frame();
+    }
+
+    fn clock_half_frame(&mut self) {
+        self.pulse1.clock_half_frame();
+        self.pulse2.clock_half_frame();
+        self.triangle.clock_half_frame();
+        self.noise.clock_half_frame();
+    }
+
+    pub fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    /// Sets how fast the CPU is clocked, which is different on PAL consoles. The samples that
+    /// haven't been taken yet are thrown away.
+    pub fn set_cpu_clock(&mut self, clock_rate: f64) {
+        self.mixer = Mixer::new(clock_rate, self.sample_rate);
+    }
+
+    pub fn muted(&self) -> bool {
+        self.muted
+    }
+
+    /// While muted the channels keep running but only silence comes out.
+    pub fn set_muted(&mut self, muted: bool) {
+        self.muted = muted;
+    }
+
+    /// Returns the samples produced since the last call, in the range -1.0..1.0 at the sample
+    /// rate given to `new`.
+    pub fn take_samples(&mut self) -> Vec<f32> {
+        self.mixer.take_samples()
+    }
+}
+
+impl Default for Apu {
+    fn default() -> Self {
+        Apu::new(DEFAULT_SAMPLE_RATE)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_silent_by_default() {
+        let mut apu = Apu::default();
+        for _ in 0..30_000 {
+            apu.tick();
+        }
+
+        // The triangle sits at step 0 on power on which is a DC offset, the high-pass filters
+        // should have removed it after a few milliseconds.
+        let samples = apu.take_samples();
+        assert!(!samples.is_empty());
+        assert!(samples[samples.len() / 2..].iter().all(|s| s.abs() < 0.01));
+    }
+
+    #[test]
+    fn test_pulse_produces_sound() {
+        let mut apu = Apu::default();
+        apu.write_register(0x4015, 0b0000_0001);
+        // 50% duty, constant volume 15, period 0xFD which is roughly 440 Hz.
+        apu.write_register(0x4000, 0b1011_1111);
+        apu.write_register(0x4002, 0xFD);
+        apu.write_register(0x4003, 0b0000_1000);
+        for _ in 0..30_000 {
+            apu.tick();
+        }
+
+        let samples = apu.take_samples();
+        assert!(samples.iter().any(|s| *s > 0.05));
+        assert!(samples.iter().any(|s| *s < -0.05));
+    }
+
+    #[test]
+    fn test_status_reports_length_counters() {
+        let mut apu = Apu::default();
+        apu.write_register(0x4015, 0b0000_0101);
+        apu.write_register(0x4003, 0b0000_1000);
+        apu.write_register(0x400B, 0b0000_1000);
+        apu.write_register(0x4007, 0b0000_1000);
+
+        assert_eq!(apu.read_status() & 0b1111, 0b0101);
+    }
+
+    #[test]
+    fn test_expansion_audio_is_mixed() {
+        let mut apu = Apu::default();
+        apu.set_expansion_audio(expansion::for_mapper(24));
+        // VRC6 pulse 1 at constant volume.
+        apu.write_expansion(0x9000, 0b1000_1111);
+        apu.write_expansion(0x9002, 0b1000_0000);
+        for _ in 0..1000 {
+            apu.tick();
+        }
+
+        let samples = apu.take_samples();
+        assert!(samples.iter().any(|s| *s > 0.05));
+    }
+
+    #[test]
+    fn test_frame_irq() {
+        let mut apu = Apu::default();
+        for _ in 0..FOUR_STEP_END {
+            apu.tick();
+        }
+
+        assert!(apu.irq_pending());
+        assert_eq!(apu.read_status() & 0b0100_0000, 0b0100_0000);
+        assert!(!apu.irq_pending());
+    }
+
+    #[test]
+    fn test_save_state_includes_expansion_audio() {
+        use crate::state::{StateError, StateReader, StateWriter};
+
+        let mut apu = Apu::default();
+        apu.set_expansion_audio(expansion::for_mapper(24));
+        apu.write_register(0x4015, 0b0000_0001);
+        apu.write_register(0x4003, 0b0000_1000);
+        apu.write_expansion(0x9000, 0b1000_1111);
+        apu.write_expansion(0x9002, 0b1000_0000);
+
+        let mut writer = StateWriter::new();
+        writer.chunk(b"APU ", &mut apu);
+        let state = writer.finish();
+        let reader = StateReader::new(&state).unwrap();
+
+        let mut loaded = Apu::default();
+        loaded.set_expansion_audio(expansion::for_mapper(24));
+        reader.chunk(b"APU ", &mut loaded).unwrap();
+        assert_eq!(loaded.read_status(), apu.read_status());
+        for _ in 0..1000 {
+            apu.tick();
+            loaded.tick();
+        }
+        assert_eq!(loaded.take_samples(), apu.take_samples());
+
+        let mut without_expansion = Apu::default();
+        assert_eq!(
+            reader.chunk(b"APU ", &mut without_expansion),
+            Err(StateError::Mismatch("sound chip on the cartridge"))
+        );
+    }
+}
@@ -0,0 +1,99 @@
+use super::envelope::Envelope;
+use super::length_counter::LengthCounter;
+use crate::state::impl_state;
+
+/// Timer periods of the noise channel in CPU cycles.
+const NOISE_PERIODS: [u16; 16] = [
+    4, 8, 16, 32, 64, 96, 128, 160, 202, 254, 380, 508, 762, 1016, 2034, 4068,
+];
+
+/// The noise channel, $400C-$400F. Produces pseudo random output from a 15 bit linear feedback
+/// shift register.
+#[derive(Clone, Debug)]
+pub struct Noise {
+    pub length: LengthCounter,
+    envelope: Envelope,
+
+    /// When set, the feedback is taken from bit 6 instead of bit 1 which gives a short, metallic
+    /// sounding sequence.
+    mode: bool,
+    shift_register: u16,
+    timer_period: u16,
+    timer: u16,
+}
+
+impl_state!(Noise {
+    length,
+    envelope,
+    mode,
+    shift_register,
+    timer_period,
+    timer
+});
+
+impl Noise {
+    pub fn new() -> Noise {
+        Noise {
+            length: LengthCounter::default(),
+            envelope: Envelope::default(),
+            mode: false,
+            shift_register: 1,
+            timer_period: NOISE_PERIODS[0],
+            timer: 0,
+        }
+    }
+
+    /// Writes to one of the four registers of the channel, `reg` is in the range 0..4.
+    pub fn write(&mut self, reg: u16, value: u8) {
+        match reg {
+            0 => {
+                self.length.set_halt(value & 0b0010_0000 != 0);
+                self.envelope.write_control(value);
+            }
+            1 => {}
+            2 => {
+                self.mode = value & 0b1000_0000 != 0;
+                self.timer_period = NOISE_PERIODS[(value & 0b1111) as usize];
+            }
+            _ => {
+                self.length.load(value);
+                self.envelope.restart();
+            }
+        }
+    }
+
+    /// Clocked every CPU cycle since the period table is in CPU cycles.
+    pub fn clock_timer(&mut self) {
+        if self.timer == 0 {
+            self.timer = self.timer_period - 1;
+
+            let tap = if self.mode { 6 } else { 1 };
+            let feedback = (self.shift_register ^ (self.shift_register >> tap)) & 1;
+            self.shift_register = (self.shift_register >> 1) | (feedback << 14);
+        } else {
+            self.timer -= 1;
+        }
+    }
+
+    pub fn clock_quarter_frame(&mut self) {
+        self.envelope.clock();
+    }
+
+    pub fn clock_half_frame(&mut self) {
+        self.length.clock();
+    }
+
+    pub fn output(&self) -> u8 {
+        if self.shift_register & 1 == 1 || !self.length.active() {
+            0
+        } else {
+            self.envelope.output()
+        }
+    }
+}
+
+impl Default for Noise {
+    fn default() -> Self {
+        Noise::new()
+    }
+}
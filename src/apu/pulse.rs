@@ -0,0 +1,164 @@
+use super::envelope::Envelope;
+use super::length_counter::LengthCounter;
+use crate::state::impl_state;
+
+/// The four duty cycles of the pulse channels, 12.5%, 25%, 50% and 25% negated.
+const DUTY_TABLE: [[u8; 8]; 4] = [
+    [0, 1, 0, 0, 0, 0, 0, 0],
+    [0, 1, 1, 0, 0, 0, 0, 0],
+    [0, 1, 1, 1, 1, 0, 0, 0],
+    [1, 0, 0, 1, 1, 1, 1, 1],
+];
+
+/// One of the two square wave channels, $4000-$4003 and $4004-$4007.
+#[derive(Clone, Debug)]
+pub struct Pulse {
+    /// The first pulse channel negates using ones' complement in the sweep unit, which means it
+    /// subtracts one more than the second channel does.
+    ones_complement: bool,
+    /// The MMC5 has the same pulse channels but without the sweep unit.
+    has_sweep: bool,
+
+    pub length: LengthCounter,
+    envelope: Envelope,
+
+    duty: u8,
+    sequence: u8,
+    timer_period: u16,
+    timer: u16,
+
+    sweep_enabled: bool,
+    sweep_period: u8,
+    sweep_negate: bool,
+    sweep_shift: u8,
+    sweep_reload: bool,
+    sweep_divider: u8,
+}
+
+impl_state!(Pulse {
+    length,
+    envelope,
+    duty,
+    sequence,
+    timer_period,
+    timer,
+    sweep_enabled,
+    sweep_period,
+    sweep_negate,
+    sweep_shift,
+    sweep_reload,
+    sweep_divider
+});
+
+impl Pulse {
+    pub fn new(ones_complement: bool) -> Pulse {
+        Pulse {
+            ones_complement,
+            has_sweep: true,
+            length: LengthCounter::default(),
+            envelope: Envelope::default(),
+            duty: 0,
+            sequence: 0,
+            timer_period: 0,
+            timer: 0,
+            sweep_enabled: false,
+            sweep_period: 0,
+            sweep_negate: false,
+            sweep_shift: 0,
+            sweep_reload: false,
+            sweep_divider: 0,
+        }
+    }
+
+    pub fn without_sweep() -> Pulse {
+        Pulse {
+            has_sweep: false,
+            ..Pulse::new(false)
+        }
+    }
+
+    /// Writes to one of the four registers of the channel, `reg` is in the range 0..4.
+    pub fn write(&mut self, reg: u16, value: u8) {
+        match reg {
+            0 => {
+                self.duty = value >> 6;
+                self.length.set_halt(value & 0b0010_0000 != 0);
+                self.envelope.write_control(value);
+            }
+            1 if self.has_sweep => {
+                self.sweep_enabled = value & 0b1000_0000 != 0;
+                self.sweep_period = (value >> 4) & 0b111;
+                self.sweep_negate = value & 0b0000_1000 != 0;
+                self.sweep_shift = value & 0b111;
+                self.sweep_reload = true;
+            }
+            1 => {}
+            2 => {
+                self.timer_period = (self.timer_period & 0x0700) | value as u16;
+            }
+            _ => {
+                self.timer_period = (self.timer_period & 0x00FF) | ((value as u16 & 0b111) << 8);
+                self.length.load(value);
+                self.sequence = 0;
+                self.envelope.restart();
+            }
+        }
+    }
+
+    /// Clocked every APU cycle, which is every other CPU cycle.
+    pub fn clock_timer(&mut self) {
+        if self.timer == 0 {
+            self.timer = self.timer_period;
+            self.sequence = (self.sequence + 1) & 0b111;
+        } else {
+            self.timer -= 1;
+        }
+    }
+
+    pub fn clock_quarter_frame(&mut self) {
+        self.envelope.clock();
+    }
+
+    pub fn clock_half_frame(&mut self) {
+        self.length.clock();
+
+        if self.sweep_divider == 0 && self.sweep_enabled && self.sweep_shift > 0 && !self.muted() {
+            self.timer_period = self.target_period();
+        }
+
+        if self.sweep_divider == 0 || self.sweep_reload {
+            self.sweep_divider = self.sweep_period;
+            self.sweep_reload = false;
+        } else {
+            self.sweep_divider -= 1;
+        }
+    }
+
+    /// The period the sweep unit is continuously calculating, even when it is disabled.
+    fn target_period(&self) -> u16 {
+        let change = self.timer_period >> self.sweep_shift;
+        if self.sweep_negate {
+            let extra = if self.ones_complement { 1 } else { 0 };
+            self.timer_period.saturating_sub(change + extra)
+        } else {
+            self.timer_period + change
+        }
+    }
+
+    /// The sweep unit mutes the channel if the period is too low or if the target period would
+    /// overflow, regardless of whether the sweep is enabled.
+    fn muted(&self) -> bool {
+        self.has_sweep && (self.timer_period < 8 || self.target_period() > 0x07FF)
+    }
+
+    pub fn output(&self) -> u8 {
+        if !self.length.active()
+            || self.muted()
+            || DUTY_TABLE[self.duty as usize][self.sequence as usize] == 0
+        {
+            0
+        } else {
+            self.envelope.output()
+        }
+    }
+}
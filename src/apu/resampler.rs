@@ -0,0 +1,134 @@
+use std::f64::consts::PI;
+
+/// Number of output samples every band-limited step is spread over.
+const KERNEL_WIDTH: usize = 16;
+/// Number of sub-sample positions the kernel is precomputed for.
+const KERNEL_PHASES: usize = 64;
+/// Cutoff of the kernel relative to the Nyquist frequency of the output. Leaving a little room
+/// below Nyquist keeps the window from leaking aliases back into the audible range.
+const CUTOFF: f64 = 0.9;
+
+/// Converts the output of the APU, which changes at the CPU clock rate of ~1.79 MHz, to the
+/// sample rate of the host.
+///
+/// Simply picking every 40th or so value would alias badly since the channels are full of sharp
+/// edges. Instead every change in amplitude is treated as a step and added to the output as a
+/// windowed sinc impulse at its exact sub-sample position. Summing the impulses afterwards gives
+/// band-limited steps, which is the same idea as blip_buf.
+#[derive(Clone, Debug)]
+pub struct Resampler {
+    samples_per_clock: f64,
+    kernel: Vec<[f32; KERNEL_WIDTH]>,
+    /// Impulses that have been added but not yet turned into samples. Index 0 is the first
+    /// sample that has not been output.
+    deltas: Vec<f32>,
+    /// Position of clock 0 of the current frame, in output samples relative to `deltas`.
+    offset: f64,
+    integrator: f32,
+}
+
+impl Resampler {
+    pub fn new(clock_rate: f64, sample_rate: u32) -> Resampler {
+        let mut kernel = Vec::with_capacity(KERNEL_PHASES + 1);
+
+        for phase in 0..=KERNEL_PHASES {
+            let frac = phase as f64 / KERNEL_PHASES as f64;
+            let mut taps = [0.0; KERNEL_WIDTH];
+            let mut sum = 0.0;
+
+            for (k, tap) in taps.iter_mut().enumerate() {
+                // Distance in samples from the step, centered in the middle of the kernel.
+                let x = k as f64 - frac - (KERNEL_WIDTH / 2 - 1) as f64;
+                let sinc = if x == 0.0 {
+                    1.0
+                } else {
+                    (PI * CUTOFF * x).sin() / (PI * CUTOFF * x)
+                };
+
+                let w = (x + (KERNEL_WIDTH / 2) as f64) / KERNEL_WIDTH as f64;
+                let window = 0.42 - 0.5 * (2.0 * PI * w).cos() + 0.08 * (4.0 * PI * w).cos();
+
+                *tap = (sinc * window) as f32;
+                sum += *tap as f64;
+            }
+
+            // Every phase has to sum to exactly one, otherwise steps would not settle at the
+            // right level and the output would slowly drift.
+            for tap in taps.iter_mut() {
+                *tap /= sum as f32;
+            }
+
+            kernel.push(taps);
+        }
+
+        Resampler {
+            samples_per_clock: sample_rate as f64 / clock_rate,
+            kernel,
+            deltas: Vec::new(),
+            offset: 0.0,
+            integrator: 0.0,
+        }
+    }
+
+    /// Adds a change in amplitude of `delta` happening `clock` CPU cycles into the current frame.
+    pub fn add_delta(&mut self, clock: u32, delta: f32) {
+        let pos = self.offset + clock as f64 * self.samples_per_clock;
+        let index = pos as usize;
+        let phase = ((pos - index as f64) * KERNEL_PHASES as f64).round() as usize;
+
+        if self.deltas.len() < index + KERNEL_WIDTH {
+            self.deltas.resize(index + KERNEL_WIDTH, 0.0);
+        }
+
+        for (k, tap) in self.kernel[phase].iter().enumerate() {
+            self.deltas[index + k] += delta * tap;
+        }
+    }
+
+    /// Ends the current frame after `clocks` CPU cycles and appends every sample that is complete
+    /// to `out`. Clock 0 of the next frame starts where this one ended.
+    pub fn end_frame(&mut self, clocks: u32, out: &mut Vec<f32>) {
+        let end = self.offset + clocks as f64 * self.samples_per_clock;
+        let count = end as usize;
+
+        if self.deltas.len() < count {
+            self.deltas.resize(count, 0.0);
+        }
+
+        for delta in self.deltas.drain(..count) {
+            self.integrator += delta;
+            out.push(self.integrator);
+        }
+
+        self.offset = end - count as f64;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sample_count_matches_rate() {
+        let mut resampler = Resampler::new(1_789_773.0, 44_100);
+        let mut out = Vec::new();
+        for _ in 0..60 {
+            resampler.end_frame(29_830, &mut out);
+        }
+
+        let expected = 60.0 * 29_830.0 * 44_100.0 / 1_789_773.0;
+        assert!((out.len() as f64 - expected).abs() < 1.0);
+    }
+
+    #[test]
+    fn test_step_settles_at_delta() {
+        let mut resampler = Resampler::new(1_789_773.0, 44_100);
+        let mut out = Vec::new();
+        resampler.add_delta(100, 0.5);
+        resampler.end_frame(10_000, &mut out);
+
+        let last = *out.last().unwrap();
+        assert!((last - 0.5).abs() < 1e-4);
+        assert!(out[0].abs() < 1e-4);
+    }
+}
@@ -0,0 +1,92 @@
+use super::length_counter::LengthCounter;
+use crate::state::impl_state;
+
+/// The 32 step sequence the triangle channel walks through.
+const SEQUENCE: [u8; 32] = [
+    15, 14, 13, 12, 11, 10, 9, 8, 7, 6, 5, 4, 3, 2, 1, 0, 0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12,
+    13, 14, 15,
+];
+
+/// The triangle channel, $4008-$400B. It has no volume control, only a linear counter in
+/// addition to the length counter.
+#[derive(Clone, Debug, Default)]
+pub struct Triangle {
+    pub length: LengthCounter,
+
+    /// Doubles as the length counter halt flag.
+    control: bool,
+    linear_counter: u8,
+    linear_reload_value: u8,
+    linear_reload: bool,
+
+    timer_period: u16,
+    timer: u16,
+    sequence: u8,
+}
+
+impl_state!(Triangle {
+    length,
+    control,
+    linear_counter,
+    linear_reload_value,
+    linear_reload,
+    timer_period,
+    timer,
+    sequence
+});
+
+impl Triangle {
+    /// Writes to one of the four registers of the channel, `reg` is in the range 0..4.
+    pub fn write(&mut self, reg: u16, value: u8) {
+        match reg {
+            0 => {
+                self.control = value & 0b1000_0000 != 0;
+                self.length.set_halt(self.control);
+                self.linear_reload_value = value & 0b0111_1111;
+            }
+            1 => {}
+            2 => {
+                self.timer_period = (self.timer_period & 0x0700) | value as u16;
+            }
+            _ => {
+                self.timer_period = (self.timer_period & 0x00FF) | ((value as u16 & 0b111) << 8);
+                self.length.load(value);
+                self.linear_reload = true;
+            }
+        }
+    }
+
+    /// Unlike the other channels the triangle timer is clocked every CPU cycle.
+    pub fn clock_timer(&mut self) {
+        if self.timer == 0 {
+            self.timer = self.timer_period;
+            if self.length.active() && self.linear_counter > 0 {
+                self.sequence = (self.sequence + 1) & 0b1_1111;
+            }
+        } else {
+            self.timer -= 1;
+        }
+    }
+
+    pub fn clock_quarter_frame(&mut self) {
+        if self.linear_reload {
+            self.linear_counter = self.linear_reload_value;
+        } else if self.linear_counter > 0 {
+            self.linear_counter -= 1;
+        }
+
+        if !self.control {
+            self.linear_reload = false;
+        }
+    }
+
+    pub fn clock_half_frame(&mut self) {
+        self.length.clock();
+    }
+
+    /// The triangle keeps outputting its current step when it's silenced, which is why there is
+    /// no check for the counters here.
+    pub fn output(&self) -> u8 {
+        SEQUENCE[self.sequence as usize]
+    }
+}
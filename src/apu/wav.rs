@@ -0,0 +1,58 @@
+use std::io::{self, Write};
+
+/// Writes `samples` as a mono 16-bit PCM WAV file. The samples are expected to be in the range
+/// -1.0..1.0 like the ones returned by `Apu::take_samples`, anything outside is clipped.
+pub fn write_wav<W: Write>(writer: &mut W, sample_rate: u32, samples: &[f32]) -> io::Result<()> {
+    let data_len = samples.len() as u32 * 2;
+
+    writer.write_all(b"RIFF")?;
+    writer.write_all(&(36 + data_len).to_le_bytes())?;
+    writer.write_all(b"WAVE")?;
+
+    writer.write_all(b"fmt ")?;
+    writer.write_all(&16u32.to_le_bytes())?;
+    // Format 1 is uncompressed PCM.
+    writer.write_all(&1u16.to_le_bytes())?;
+    // One channel.
+    writer.write_all(&1u16.to_le_bytes())?;
+    writer.write_all(&sample_rate.to_le_bytes())?;
+    // Byte rate and block align.
+    writer.write_all(&(sample_rate * 2).to_le_bytes())?;
+    writer.write_all(&2u16.to_le_bytes())?;
+    // Bits per sample.
+    writer.write_all(&16u16.to_le_bytes())?;
+
+    writer.write_all(b"data")?;
+    writer.write_all(&data_len.to_le_bytes())?;
+    for sample in samples {
+        let value = (sample.clamp(-1.0, 1.0) * i16::MAX as f32) as i16;
+        writer.write_all(&value.to_le_bytes())?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_write_wav() {
+        let mut out = Vec::new();
+        write_wav(&mut out, 44_100, &[0.0, 1.0, -1.0, 2.0]).unwrap();
+
+        assert_eq!(out.len(), 44 + 8);
+        assert_eq!(&out[0..4], b"RIFF");
+        assert_eq!(u32::from_le_bytes(out[4..8].try_into().unwrap()), 44);
+        assert_eq!(&out[8..16], b"WAVEfmt ");
+        assert_eq!(u32::from_le_bytes(out[24..28].try_into().unwrap()), 44_100);
+        assert_eq!(&out[36..40], b"data");
+        assert_eq!(u32::from_le_bytes(out[40..44].try_into().unwrap()), 8);
+
+        let samples: Vec<i16> = out[44..]
+            .chunks(2)
+            .map(|b| i16::from_le_bytes([b[0], b[1]]))
+            .collect();
+        assert_eq!(samples, vec![0, i16::MAX, -i16::MAX, i16::MAX]);
+    }
+}
@@ -0,0 +1,355 @@
+//! Maps keyboard keys and gamepad buttons to the four controllers and to the hotkeys of the emulator.
+//!
+//! The bindings can be loaded from a file where every line binds one input to one action:
+//!
+//! ```text
+//! # Player 1
+//! p1.up = W
+//! p1.a = K
+//! p1.a = pad0:1
+//! p1.left = pad0:left
+//!
+//! reset = F1
+//! fast_forward = Tab
+//! ```
+//!
+//! Keys use the names of piston's `Key` enum (`W`, `Return`, `LShift`, `D1`, `F5`, ...).
+//! Gamepads are written as `pad<id>:<button number>`, or `pad<id>:up/down/left/right` for the
+//! hat. The same action can be bound to several inputs.
+
+use nes_emulator::joypad::Buttons;
+use piston::input::{Button, ControllerButton, ControllerHat, HatState, Key};
+use serde::de::IntoDeserializer;
+use serde::Deserialize;
+
+/// Something on the host that can be pressed.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Input {
+    Key(Key),
+    PadButton { id: u32, button: u8 },
+    PadHat { id: u32, direction: Buttons },
+}
+
+impl Input {
+    /// Turns a piston button into the inputs it represents. A hat pointing diagonally is two
+    /// inputs at once.
+    pub fn from_button(button: Button) -> Vec<Input> {
+        match button {
+            Button::Keyboard(key) => vec![Input::Key(key)],
+            Button::Controller(ControllerButton { id, button }) => {
+                vec![Input::PadButton { id, button }]
+            }
+            Button::Hat(ControllerHat { id, state, .. }) => hat_directions(state)
+                .iter()
+                .map(|&direction| Input::PadHat { id, direction })
+                .collect(),
+            Button::Mouse(_) => vec![],
+        }
+    }
+
+    fn parse(value: &str) -> Result<Input, String> {
+        if let Some(pad) = value.strip_prefix("pad") {
+            let (id, button) = pad
+                .split_once(':')
+                .ok_or_else(|| format!("Expected pad<id>:<button>, got {}", value))?;
+            let id = id
+                .parse()
+                .map_err(|_| format!("Invalid gamepad id in {}", value))?;
+
+            let direction = match button {
+                "up" => Some(Buttons::UP),
+                "down" => Some(Buttons::DOWN),
+                "left" => Some(Buttons::LEFT),
+                "right" => Some(Buttons::RIGHT),
+                _ => None,
+            };
+            if let Some(direction) = direction {
+                return Ok(Input::PadHat { id, direction });
+            }
+
+            let button = button
+                .parse()
+                .map_err(|_| format!("Invalid gamepad button in {}", value))?;
+            return Ok(Input::PadButton { id, button });
+        }
+
+        let key: Result<Key, serde::de::value::Error> = Key::deserialize(value.into_deserializer());
+        key.map(Input::Key)
+            .map_err(|_| format!("Unknown key {}", value))
+    }
+}
+
+/// The directions of the D-pad that are held for a hat state.
+fn hat_directions(state: HatState) -> &'static [Buttons] {
+    match state {
+        HatState::Centered => &[],
+        HatState::Up => &[Buttons::UP],
+        HatState::Down => &[Buttons::DOWN],
+        HatState::Left => &[Buttons::LEFT],
+        HatState::Right => &[Buttons::RIGHT],
+        HatState::RightUp => &[Buttons::RIGHT, Buttons::UP],
+        HatState::RightDown => &[Buttons::RIGHT, Buttons::DOWN],
+        HatState::LeftUp => &[Buttons::LEFT, Buttons::UP],
+        HatState::LeftDown => &[Buttons::LEFT, Buttons::DOWN],
+    }
+}
+
+/// What an input does when it's pressed.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Action {
+    /// Holds buttons on the controller in the given port.
+    Controller(usize, Buttons),
+    Reset,
+    Pause,
+    SaveState,
+    LoadState,
+    /// Runs the emulator as fast as possible while held.
+    FastForward,
+    /// Runs the emulator backwards while held.
+    Rewind,
+    /// Steps on a button of the Power Pad, 1 to 12.
+    PowerPad(u8),
+    /// Stops the CPU and opens the debugger in the terminal.
+    Break,
+}
+
+impl Action {
+    fn parse(name: &str) -> Result<Action, String> {
+        match name {
+            "reset" => return Ok(Action::Reset),
+            "pause" => return Ok(Action::Pause),
+            "save_state" => return Ok(Action::SaveState),
+            "load_state" => return Ok(Action::LoadState),
+            "fast_forward" => return Ok(Action::FastForward),
+            "rewind" => return Ok(Action::Rewind),
+            "break" => return Ok(Action::Break),
+            _ => {}
+        }
+
+        if let Some(button) = name.strip_prefix("power_pad.") {
+            return match button.parse() {
+                Ok(button @ 1..=12) => Ok(Action::PowerPad(button)),
+                _ => Err(format!("The Power Pad has buttons 1 to 12, not {}", button)),
+            };
+        }
+
+        let (player, button) = name
+            .split_once('.')
+            .ok_or_else(|| format!("Unknown action {}", name))?;
+        let port = match player {
+            "p1" => 0,
+            "p2" => 1,
+            "p3" => 2,
+            "p4" => 3,
+            _ => return Err(format!("Unknown player {}", player)),
+        };
+        let buttons = match button {
+            "a" => Buttons::A,
+            "b" => Buttons::B,
+            "select" => Buttons::SELECT,
+            "start" => Buttons::START,
+            "up" => Buttons::UP,
+            "down" => Buttons::DOWN,
+            "left" => Buttons::LEFT,
+            "right" => Buttons::RIGHT,
+            _ => return Err(format!("Unknown button {}", button)),
+        };
+
+        Ok(Action::Controller(port, buttons))
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Bindings {
+    bindings: Vec<(Input, Action)>,
+}
+
+impl Bindings {
+    pub fn load(path: &str) -> Result<Bindings, String> {
+        let text = std::fs::read_to_string(path)
+            .map_err(|err| format!("Failed to read {}: {}", path, err))?;
+        Bindings::parse(&text)
+    }
+
+    pub fn parse(text: &str) -> Result<Bindings, String> {
+        let mut bindings = Vec::new();
+
+        for (i, line) in text.lines().enumerate() {
+            let line = line.split('#').next().unwrap().trim();
+            if line.is_empty() {
+                continue;
+            }
+
+            let (action, input) = line
+                .split_once('=')
+                .ok_or_else(|| format!("Line {}: expected <action> = <input>", i + 1))?;
+            let action =
+                Action::parse(action.trim()).map_err(|e| format!("Line {}: {}", i + 1, e))?;
+            let input = Input::parse(input.trim()).map_err(|e| format!("Line {}: {}", i + 1, e))?;
+
+            bindings.push((input, action));
+        }
+
+        Ok(Bindings { bindings })
+    }
+
+    /// Every action bound to `input`.
+    pub fn actions(&self, input: Input) -> impl Iterator<Item = Action> + '_ {
+        self.bindings
+            .iter()
+            .filter(move |(bound, _)| *bound == input)
+            .map(|(_, action)| *action)
+    }
+
+    /// The state of all four controllers when `held` are the inputs currently held down.
+    pub fn controller_state(&self, held: &[Input]) -> [Buttons; 4] {
+        let mut state = [Buttons::empty(); 4];
+        for input in held {
+            for action in self.actions(*input) {
+                if let Action::Controller(port, buttons) = action {
+                    state[port].insert(buttons);
+                }
+            }
+        }
+        state
+    }
+}
+
+impl Default for Bindings {
+    /// WASD is the D-pad, K and J are A and B, and Enter and Right Shift are Start and Select.
+    /// The second controller is on the arrow keys and the numpad. Gamepad N is player N + 1. The
+    /// Power Pad is on 5-8, T-I and B-Comma.
+    fn default() -> Self {
+        let mut text = String::from(
+            "
+            p1.up = W
+            p1.down = S
+            p1.left = A
+            p1.right = D
+            p1.a = K
+            p1.b = J
+            p1.start = Return
+            p1.select = RShift
+
+            p2.up = Up
+            p2.down = Down
+            p2.left = Left
+            p2.right = Right
+            p2.a = NumPad2
+            p2.b = NumPad1
+            p2.start = NumPadEnter
+            p2.select = NumPad0
+
+            reset = F1
+            pause = P
+            save_state = F5
+            load_state = F7
+            fast_forward = Tab
+            rewind = Backspace
+            break = F12
+
+            power_pad.1 = D5
+            power_pad.2 = D6
+            power_pad.3 = D7
+            power_pad.4 = D8
+            power_pad.5 = T
+            power_pad.6 = Y
+            power_pad.7 = U
+            power_pad.8 = I
+            power_pad.9 = B
+            power_pad.10 = N
+            power_pad.11 = M
+            power_pad.12 = Comma
+            ",
+        );
+
+        for pad in 0..4 {
+            let player = pad + 1;
+            for direction in ["up", "down", "left", "right"] {
+                text += &format!("p{player}.{direction} = pad{pad}:{direction}\n");
+            }
+            for (button, number) in [("a", 1), ("b", 0), ("select", 6), ("start", 7)] {
+                text += &format!("p{player}.{button} = pad{pad}:{number}\n");
+            }
+        }
+
+        Bindings::parse(&text).unwrap()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let bindings = Bindings::parse(
+            "
+            # Comments and blank lines are ignored
+            p2.start = Return # trailing comments too
+            p1.a = pad1:3
+            p1.left = pad0:left
+            reset = F1
+            power_pad.12 = Comma
+            ",
+        )
+        .unwrap();
+
+        let actions: Vec<_> = bindings.actions(Input::Key(Key::Return)).collect();
+        assert_eq!(actions, vec![Action::Controller(1, Buttons::START)]);
+
+        let actions: Vec<_> = bindings.actions(Input::Key(Key::F1)).collect();
+        assert_eq!(actions, vec![Action::Reset]);
+
+        let actions: Vec<_> = bindings.actions(Input::Key(Key::Comma)).collect();
+        assert_eq!(actions, vec![Action::PowerPad(12)]);
+
+        let held = [
+            Input::PadButton { id: 1, button: 3 },
+            Input::PadHat {
+                id: 0,
+                direction: Buttons::LEFT,
+            },
+            Input::Key(Key::Return),
+        ];
+        assert_eq!(
+            bindings.controller_state(&held),
+            [
+                Buttons::A | Buttons::LEFT,
+                Buttons::START,
+                Buttons::empty(),
+                Buttons::empty()
+            ]
+        );
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        assert!(Bindings::parse("p5.a = K").is_err());
+        assert!(Bindings::parse("p1.turbo = K").is_err());
+        assert!(Bindings::parse("p1.a = NotAKey").is_err());
+        assert!(Bindings::parse("p1.a = pad:1").is_err());
+        assert!(Bindings::parse("p1.a").is_err());
+        assert!(Bindings::parse("power_pad.13 = K").is_err());
+    }
+
+    #[test]
+    fn test_default_bindings() {
+        let bindings = Bindings::default();
+        let held = [
+            Input::Key(Key::W),
+            Input::Key(Key::K),
+            Input::Key(Key::Up),
+            Input::PadButton { id: 3, button: 7 },
+        ];
+        assert_eq!(
+            bindings.controller_state(&held),
+            [
+                Buttons::UP | Buttons::A,
+                Buttons::UP,
+                Buttons::empty(),
+                Buttons::START
+            ]
+        );
+    }
+}
@@ -0,0 +1,200 @@
+//! Cartridges in the iNES format, the `.nes` files most ROMs come as.
+//!
+//! The file starts with a 16 byte header: `NES` followed by $1A, the size of the PRG ROM in 16 KB
+//! units, the size of the CHR ROM in 8 KB units and two bytes of flags. If the header has a
+//! trainer, 512 bytes of it come next, and then the PRG ROM followed by the CHR ROM.
+//!
+//! NES 2.0 headers are read as far as the mapper number and sizes go, the rest is ignored.
+
+use std::path::{Path, PathBuf};
+
+const HEADER_SIZE: usize = 16;
+const TRAINER_SIZE: usize = 512;
+const PRG_ROM_UNIT: usize = 16 * 1024;
+const CHR_ROM_UNIT: usize = 8 * 1024;
+/// The size of the PRG RAM at $6000-$7FFF when the header doesn't say.
+pub const DEFAULT_PRG_RAM_SIZE: usize = 8 * 1024;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Mirroring {
+    Horizontal,
+    Vertical,
+    FourScreen,
+}
+
+#[derive(Clone, Debug)]
+pub struct Cartridge {
+    pub prg_rom: Vec<u8>,
+    pub chr_rom: Vec<u8>,
+    pub mapper: u16,
+    pub mirroring: Mirroring,
+    /// Whether the PRG RAM is kept alive by a battery, which is how games save.
+    pub battery: bool,
+}
+
+impl Cartridge {
+    pub fn load(path: &Path) -> Result<Cartridge, String> {
+        let data = std::fs::read(path)
+            .map_err(|err| format!("Failed to read {}: {}", path.display(), err))?;
+        Cartridge::from_ines(&data)
+    }
+
+    pub fn from_ines(data: &[u8]) -> Result<Cartridge, String> {
+        if data.len() < HEADER_SIZE || &data[0..4] != b"NES\x1A" {
+            return Err("Not an iNES file".to_string());
+        }
+
+        let flags6 = data[6];
+        let flags7 = data[7];
+        let nes2 = flags7 & 0b0000_1100 == 0b0000_1000;
+
+        let mut mapper = (flags7 & 0xF0) as u16 | (flags6 >> 4) as u16;
+        let mut prg_units = data[4] as usize;
+        let mut chr_units = data[5] as usize;
+        if nes2 {
+            mapper |= ((data[8] & 0x0F) as u16) << 8;
+            prg_units |= ((data[9] & 0x0F) as usize) << 8;
+            chr_units |= ((data[9] >> 4) as usize) << 8;
+        }
+
+        let mirroring = if flags6 & 0b1000 != 0 {
+            Mirroring::FourScreen
+        } else if flags6 & 1 != 0 {
+            Mirroring::Vertical
+        } else {
+            Mirroring::Horizontal
+        };
+        let battery = flags6 & 0b10 != 0;
+        let trainer = flags6 & 0b100 != 0;
+
+        let prg_start = HEADER_SIZE + if trainer { TRAINER_SIZE } else { 0 };
+        let chr_start = prg_start + prg_units * PRG_ROM_UNIT;
+        let chr_end = chr_start + chr_units * CHR_ROM_UNIT;
+        if data.len() < chr_end {
+            return Err(format!(
+                "The file is {} bytes but the header says it should be {}",
+                data.len(),
+                chr_end
+            ));
+        }
+
+        Ok(Cartridge {
+            prg_rom: data[prg_start..chr_start].to_vec(),
+            chr_rom: data[chr_start..chr_end].to_vec(),
+            mapper,
+            mirroring,
+            battery,
+        })
+    }
+}
+
+/// The battery-backed PRG RAM of a cartridge, saved in a `.sav` file next to the ROM.
+///
+/// Only what has changed since the last save is written, so it's cheap to call `save` often.
+#[derive(Clone, Debug)]
+pub struct BatteryRam {
+    path: PathBuf,
+    saved: Vec<u8>,
+}
+
+impl BatteryRam {
+    /// The save file for the ROM at `rom_path`, `game.nes` saves to `game.sav`.
+    pub fn for_rom(rom_path: &Path) -> BatteryRam {
+        BatteryRam {
+            path: rom_path.with_extension("sav"),
+            saved: Vec::new(),
+        }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Reads the save file, if there is one.
+    pub fn load(&mut self) -> std::io::Result<Option<Vec<u8>>> {
+        match std::fs::read(&self.path) {
+            Ok(data) => {
+                self.saved = data.clone();
+                Ok(Some(data))
+            }
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(err),
+        }
+    }
+
+    /// Writes `ram` to the save file if it has changed since it was last saved or loaded.
+    /// Returns whether the file was written.
+    pub fn save(&mut self, ram: &[u8]) -> std::io::Result<bool> {
+        if ram == self.saved.as_slice() {
+            return Ok(false);
+        }
+
+        // Write to a temporary file first so a crash in the middle doesn't lose the save.
+        let temp = self.path.with_extension("sav.tmp");
+        std::fs::write(&temp, ram)?;
+        std::fs::rename(&temp, &self.path)?;
+        self.saved = ram.to_vec();
+        Ok(true)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ines(prg_units: u8, chr_units: u8, flags6: u8, flags7: u8) -> Vec<u8> {
+        let mut data = vec![b'N', b'E', b'S', 0x1A, prg_units, chr_units, flags6, flags7];
+        data.resize(HEADER_SIZE, 0);
+        if flags6 & 0b100 != 0 {
+            data.resize(data.len() + TRAINER_SIZE, 0xEE);
+        }
+        data.resize(data.len() + prg_units as usize * PRG_ROM_UNIT, 0xAA);
+        data.resize(data.len() + chr_units as usize * CHR_ROM_UNIT, 0xCC);
+        data
+    }
+
+    #[test]
+    fn test_parse_header() {
+        let cartridge = Cartridge::from_ines(&ines(2, 1, 0b0001_0011, 0b0100_0000)).unwrap();
+        assert_eq!(cartridge.prg_rom.len(), 32 * 1024);
+        assert_eq!(cartridge.chr_rom.len(), 8 * 1024);
+        assert_eq!(cartridge.mapper, 0x41);
+        assert_eq!(cartridge.mirroring, Mirroring::Vertical);
+        assert!(cartridge.battery);
+    }
+
+    #[test]
+    fn test_trainer_is_skipped() {
+        let cartridge = Cartridge::from_ines(&ines(1, 0, 0b0000_0100, 0)).unwrap();
+        assert!(cartridge.prg_rom.iter().all(|&b| b == 0xAA));
+        assert!(!cartridge.battery);
+    }
+
+    #[test]
+    fn test_bad_files() {
+        assert!(Cartridge::from_ines(b"not a rom").is_err());
+        let mut data = ines(2, 1, 0, 0);
+        data.truncate(data.len() - 1);
+        assert!(Cartridge::from_ines(&data).is_err());
+    }
+
+    #[test]
+    fn test_battery_ram() {
+        let dir = std::env::temp_dir().join(format!("battery-ram-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let rom = dir.join("game.nes");
+
+        let mut battery = BatteryRam::for_rom(&rom);
+        assert_eq!(battery.path(), dir.join("game.sav"));
+        assert_eq!(battery.load().unwrap(), None);
+
+        assert!(battery.save(&[1, 2, 3]).unwrap());
+        assert!(!battery.save(&[1, 2, 3]).unwrap());
+
+        let mut battery = BatteryRam::for_rom(&rom);
+        assert_eq!(battery.load().unwrap(), Some(vec![1, 2, 3]));
+        assert!(!battery.save(&[1, 2, 3]).unwrap());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,657 @@
+//! A small 6502 assembler, so tests and demos can be written as assembly instead of bytes.
+//!
+//! ```text
+//!         .org $8000
+//! count = 3
+//! start:  LDX #count      ; comments start with a semicolon
+//! loop:   DEX
+//!         BNE loop
+//!         STA ($10),Y
+//!         JMP (vector)
+//! vector: .word start
+//!         .byte 1, $02, %11, 'a', "text", <vector, >vector
+//! ```
+//!
+//! Numbers are decimal, hexadecimal with `$` or binary with `%`. Expressions can use labels, `*`
+//! for the address of the current instruction, `+ - * /`, and `<` and `>` for the low and high
+//! byte of a value. Mnemonics, directives and registers can be in either case, labels can't.
+//!
+//! The source is read twice. The first time works out where every label ends up, the second
+//! time writes the bytes. Addresses that are known on the first pass and fit in one byte use zero
+//! page addressing, labels that are defined further down always use absolute addressing.
+
+use std::collections::HashMap;
+use std::fmt;
+
+use super::addressing_mode::AddressingMode;
+use super::op_codes::{OpCode, OP_CODES};
+
+/// Where the program is put unless it starts with `.org`, the same address `CPU::load` uses.
+const DEFAULT_ORIGIN: u16 = 0x8000;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Program {
+    /// The address of the first byte.
+    pub origin: u16,
+    /// The machine code, gaps left by `.org` are filled with zeros.
+    pub bytes: Vec<u8>,
+    /// The address of every label and the value of every constant.
+    pub symbols: HashMap<String, u16>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct AssembleError {
+    /// The line the error is on, counting from 1.
+    pub line: usize,
+    pub message: String,
+}
+
+impl fmt::Display for AssembleError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Line {}: {}", self.line, self.message)
+    }
+}
+
+impl std::error::Error for AssembleError {}
+
+/// Assembles `source`, see the module documentation for the syntax.
+pub fn assemble(source: &str) -> Result<Program, AssembleError> {
+    let mut assembler = Assembler {
+        symbols: HashMap::new(),
+        statements: Vec::new(),
+    };
+    for (i, line) in source.lines().enumerate() {
+        assembler
+            .first_pass(i + 1, line)
+            .map_err(|message| AssembleError {
+                line: i + 1,
+                message,
+            })?;
+    }
+    assembler.second_pass()
+}
+
+/// What a line turned into on the first pass.
+enum Statement<'a> {
+    Bytes(Vec<&'a str>),
+    Words(Vec<&'a str>),
+    Instruction {
+        op: OpCode,
+        operand: Option<&'a str>,
+    },
+}
+
+struct Assembler<'a> {
+    symbols: HashMap<String, u16>,
+    /// Every statement with its line number and address.
+    statements: Vec<(usize, u32, Statement<'a>)>,
+}
+
+impl<'a> Assembler<'a> {
+    /// The address the next statement ends up at.
+    fn pc(&self) -> u32 {
+        match self.statements.last() {
+            Some((_, addr, statement)) => addr + statement.size(),
+            None => DEFAULT_ORIGIN as u32,
+        }
+    }
+
+    fn first_pass(&mut self, line: usize, text: &'a str) -> Result<(), String> {
+        let mut rest = strip_comment(text).trim();
+
+        // Constants, `name = value`.
+        if let Some((name, value)) = rest.split_once('=') {
+            let name = name.trim();
+            if is_identifier(name) {
+                let value = self
+                    .eval(value.trim(), self.pc())
+                    .map_err(|err| err.to_string())?;
+                return self.define(name, value);
+            }
+        }
+
+        // Labels, `name:`, can be on their own line or in front of something else.
+        if let Some((name, after)) = rest.split_once(':') {
+            if is_identifier(name.trim()) {
+                self.define(name.trim(), self.pc() as i64)?;
+                rest = after.trim();
+            }
+        }
+        if rest.is_empty() {
+            return Ok(());
+        }
+
+        let (word, args) = match rest.split_once(char::is_whitespace) {
+            Some((word, args)) => (word, args.trim()),
+            None => (rest, ""),
+        };
+        let statement = match word.to_ascii_lowercase().as_str() {
+            ".org" => {
+                let addr = self.eval(args, self.pc()).map_err(|err| err.to_string())?;
+                // Nothing is written for `.org`, the next statement just starts somewhere else.
+                // An empty list of bytes keeps track of where.
+                let addr = u16::try_from(addr).map_err(|_| format!("Invalid address {}", addr))?;
+                self.statements
+                    .push((line, addr as u32, Statement::Bytes(Vec::new())));
+                return Ok(());
+            }
+            ".byte" | ".db" => Statement::Bytes(split_args(args)),
+            ".word" | ".dw" => Statement::Words(split_args(args)),
+            directive if directive.starts_with('.') => {
+                return Err(format!("Unknown directive {}", word));
+            }
+            _ => self.instruction(word, args)?,
+        };
+        let pc = self.pc();
+        self.statements.push((line, pc, statement));
+        Ok(())
+    }
+
+    /// Picks the op code for an instruction from how the operand is written.
+    fn instruction(&self, name: &str, operand: &'a str) -> Result<Statement<'a>, String> {
+        let name = name.to_ascii_uppercase();
+        let find = |mode: AddressingMode, size: u16| {
+            OP_CODES
+                .iter()
+                .flatten()
+                .find(|op| op.name == name && op.addr_mode == mode && op.size == size)
+                .copied()
+        };
+        if !OP_CODES.iter().flatten().any(|op| op.name == name) {
+            return Err(format!("Unknown instruction {}", name));
+        }
+        let unsupported = || format!("{} can't be used with {}", name, operand);
+
+        // Implied and accumulator instructions.
+        if operand.is_empty() || operand.eq_ignore_ascii_case("a") {
+            let op = find(AddressingMode::NoneAddressing, 1).ok_or_else(unsupported)?;
+            return Ok(Statement::Instruction { op, operand: None });
+        }
+        // Branches take an address and turn it into an offset.
+        if let Some(op) = find(AddressingMode::NoneAddressing, 2) {
+            return Ok(Statement::Instruction {
+                op,
+                operand: Some(operand),
+            });
+        }
+
+        let compact: String = operand.split_whitespace().collect();
+        let upper = compact.to_ascii_uppercase();
+        let (mode, value) = if let Some(value) = operand.strip_prefix('#') {
+            (AddressingMode::Immediate, value.trim())
+        } else if upper.starts_with('(') && upper.ends_with(",X)") {
+            (AddressingMode::IndirectX, inner(operand, ','))
+        } else if upper.starts_with('(') && upper.ends_with("),Y") {
+            (AddressingMode::IndirectY, inner(operand, ')'))
+        } else if upper.starts_with('(') && upper.ends_with(')') {
+            (AddressingMode::Indirect, inner(operand, ')'))
+        } else {
+            let (value, zero_page, absolute) = if upper.ends_with(",X") {
+                (
+                    index_base(operand),
+                    AddressingMode::ZeroPageX,
+                    AddressingMode::AbsoluteX,
+                )
+            } else if upper.ends_with(",Y") {
+                (
+                    index_base(operand),
+                    AddressingMode::ZeroPageY,
+                    AddressingMode::AbsoluteY,
+                )
+            } else {
+                (operand, AddressingMode::ZeroPage, AddressingMode::Absolute)
+            };
+            let fits_zero_page = matches!(self.eval(value, self.pc()), Ok(0..=0xFF));
+            let mode = match (find(zero_page, 2), find(absolute, 3)) {
+                (Some(_), _) if fits_zero_page => zero_page,
+                (_, Some(_)) => absolute,
+                _ => zero_page,
+            };
+            (mode, value)
+        };
+
+        let size = match mode {
+            AddressingMode::Absolute
+            | AddressingMode::AbsoluteX
+            | AddressingMode::AbsoluteY
+            | AddressingMode::Indirect => 3,
+            _ => 2,
+        };
+        let op = find(mode, size).ok_or_else(unsupported)?;
+        Ok(Statement::Instruction {
+            op,
+            operand: Some(value),
+        })
+    }
+
+    fn define(&mut self, name: &str, value: i64) -> Result<(), String> {
+        let value = u16::try_from(value).map_err(|_| format!("{} is out of range", name))?;
+        if self.symbols.insert(name.to_string(), value).is_some() {
+            return Err(format!("{} is defined twice", name));
+        }
+        Ok(())
+    }
+
+    fn second_pass(self) -> Result<Program, AssembleError> {
+        let origin = self
+            .statements
+            .iter()
+            .find(|(_, _, statement)| statement.size() > 0)
+            .map_or(DEFAULT_ORIGIN as u32, |&(_, addr, _)| addr);
+        let mut bytes = Vec::new();
+
+        for (line, addr, statement) in &self.statements {
+            let error = |message| AssembleError {
+                line: *line,
+                message,
+            };
+            let encoded = self.encode(statement, *addr).map_err(error)?;
+            if encoded.is_empty() {
+                continue;
+            }
+            if *addr < origin + bytes.len() as u32 {
+                return Err(error(format!("${:04X} has already been written to", addr)));
+            }
+            if *addr + encoded.len() as u32 > 0x10000 {
+                return Err(error("The program goes past $FFFF".to_string()));
+            }
+            bytes.resize((addr - origin) as usize, 0);
+            bytes.extend(encoded);
+        }
+
+        Ok(Program {
+            origin: origin as u16,
+            bytes,
+            symbols: self.symbols,
+        })
+    }
+
+    fn encode(&self, statement: &Statement, addr: u32) -> Result<Vec<u8>, String> {
+        let eval = |text: &str| self.eval(text, addr).map_err(|err| err.to_string());
+        let mut bytes = Vec::new();
+        match statement {
+            Statement::Bytes(args) => {
+                for arg in args {
+                    match arg.strip_prefix('"').and_then(|s| s.strip_suffix('"')) {
+                        Some(text) => bytes.extend(text.bytes()),
+                        None => bytes.push(byte(eval(arg)?)?),
+                    }
+                }
+            }
+            Statement::Words(args) => {
+                for arg in args {
+                    bytes.extend(word(eval(arg)?)?.to_le_bytes());
+                }
+            }
+            Statement::Instruction { op, operand } => {
+                bytes.push(op.code);
+                match (op.addr_mode, operand) {
+                    (_, None) => {}
+                    (AddressingMode::NoneAddressing, Some(target)) => {
+                        let offset = eval(target)? - (addr as i64 + 2);
+                        let offset = i8::try_from(offset)
+                            .map_err(|_| format!("The branch to {} is too far away", target))?;
+                        bytes.push(offset as u8);
+                    }
+                    (_, Some(value)) if op.size == 3 => {
+                        bytes.extend(word(eval(value)?)?.to_le_bytes());
+                    }
+                    (AddressingMode::Immediate, Some(value)) => bytes.push(byte(eval(value)?)?),
+                    (_, Some(value)) => {
+                        let value = eval(value)?;
+                        let addr = u8::try_from(value)
+                            .map_err(|_| format!("${:X} isn't on the zero page", value))?;
+                        bytes.push(addr);
+                    }
+                }
+            }
+        }
+        Ok(bytes)
+    }
+
+    /// Evaluates an expression, `pc` is the value of `*`.
+    fn eval(&self, text: &str, pc: u32) -> Result<i64, ExprError> {
+        let mut parser = ExprParser {
+            text: text.trim(),
+            symbols: &self.symbols,
+            pc: pc as i64,
+        };
+        let value = parser.expr()?;
+        if !parser.text.is_empty() {
+            return Err(ExprError::Invalid(text.trim().to_string()));
+        }
+        Ok(value)
+    }
+}
+
+impl Statement<'_> {
+    fn size(&self) -> u32 {
+        match self {
+            Statement::Bytes(args) => args
+                .iter()
+                .map(
+                    |arg| match arg.strip_prefix('"').and_then(|s| s.strip_suffix('"')) {
+                        Some(text) => text.len() as u32,
+                        None => 1,
+                    },
+                )
+                .sum(),
+            Statement::Words(args) => args.len() as u32 * 2,
+            Statement::Instruction { op, .. } => op.size as u32,
+        }
+    }
+}
+
+/// A value that has to fit in a byte, negative numbers are allowed.
+fn byte(value: i64) -> Result<u8, String> {
+    match value {
+        -0x80..=0xFF => Ok(value as u8),
+        _ => Err(format!("{} doesn't fit in a byte", value)),
+    }
+}
+
+fn word(value: i64) -> Result<u16, String> {
+    match value {
+        -0x8000..=0xFFFF => Ok(value as u16),
+        _ => Err(format!("{} doesn't fit in a word", value)),
+    }
+}
+
+#[derive(Debug)]
+enum ExprError {
+    /// The label hasn't been defined, which is fine on the first pass.
+    Undefined(String),
+    Invalid(String),
+}
+
+impl fmt::Display for ExprError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ExprError::Undefined(name) => write!(f, "{} isn't defined", name),
+            ExprError::Invalid(text) => write!(f, "Invalid expression {}", text),
+        }
+    }
+}
+
+/// Parses and evaluates an expression at the same time. What's left to parse is kept in `text`.
+struct ExprParser<'a> {
+    text: &'a str,
+    symbols: &'a HashMap<String, u16>,
+    pc: i64,
+}
+
+impl ExprParser<'_> {
+    fn expr(&mut self) -> Result<i64, ExprError> {
+        let mut value = self.term()?;
+        loop {
+            if self.eat('+') {
+                value += self.term()?;
+            } else if self.eat('-') {
+                value -= self.term()?;
+            } else {
+                return Ok(value);
+            }
+        }
+    }
+
+    fn term(&mut self) -> Result<i64, ExprError> {
+        let mut value = self.unary()?;
+        loop {
+            if self.eat('*') {
+                value *= self.unary()?;
+            } else if self.eat('/') {
+                let divisor = self.unary()?;
+                if divisor == 0 {
+                    return Err(ExprError::Invalid("division by zero".to_string()));
+                }
+                value /= divisor;
+            } else {
+                return Ok(value);
+            }
+        }
+    }
+
+    fn unary(&mut self) -> Result<i64, ExprError> {
+        if self.eat('-') {
+            Ok(-self.unary()?)
+        } else if self.eat('<') {
+            Ok(self.unary()? & 0xFF)
+        } else if self.eat('>') {
+            Ok((self.unary()? >> 8) & 0xFF)
+        } else {
+            self.primary()
+        }
+    }
+
+    fn primary(&mut self) -> Result<i64, ExprError> {
+        if self.eat('*') {
+            return Ok(self.pc);
+        }
+        if let Some(rest) = self.text.strip_prefix('\'') {
+            let mut chars = rest.chars();
+            if let (Some(c), Some('\'')) = (chars.next(), chars.next()) {
+                self.text = chars.as_str().trim_start();
+                return Ok(c as i64);
+            }
+        }
+
+        let (radix, rest) = match self.text.chars().next() {
+            Some('$') => (16, &self.text[1..]),
+            Some('%') => (2, &self.text[1..]),
+            _ => (10, self.text),
+        };
+        let len = rest
+            .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_'))
+            .unwrap_or(rest.len());
+        let (token, after) = rest.split_at(len);
+        let invalid = || ExprError::Invalid(self.text.to_string());
+        if token.is_empty() {
+            return Err(invalid());
+        }
+
+        let value = if radix == 10 && !token.starts_with(|c: char| c.is_ascii_digit()) {
+            match self.symbols.get(token) {
+                Some(&value) => value as i64,
+                None => return Err(ExprError::Undefined(token.to_string())),
+            }
+        } else {
+            i64::from_str_radix(token, radix).map_err(|_| invalid())?
+        };
+        self.text = after.trim_start();
+        Ok(value)
+    }
+
+    /// Skips `c` if it's next.
+    fn eat(&mut self, c: char) -> bool {
+        match self.text.strip_prefix(c) {
+            Some(rest) => {
+                self.text = rest.trim_start();
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+/// Removes the comment at the end of a line, if there is one. Semicolons in quotes aren't
+/// comments.
+fn strip_comment(line: &str) -> &str {
+    let mut quote = None;
+    for (i, c) in line.char_indices() {
+        match (quote, c) {
+            (None, ';') => return &line[..i],
+            (None, '"' | '\'') => quote = Some(c),
+            (Some(q), _) if q == c => quote = None,
+            _ => {}
+        }
+    }
+    line
+}
+
+/// Splits the arguments of a directive on commas, except for the ones in strings.
+fn split_args(args: &str) -> Vec<&str> {
+    let mut parts = Vec::new();
+    let mut quote = None;
+    let mut start = 0;
+    for (i, c) in args.char_indices() {
+        match (quote, c) {
+            (None, ',') => {
+                parts.push(args[start..i].trim());
+                start = i + 1;
+            }
+            (None, '"' | '\'') => quote = Some(c),
+            (Some(q), _) if q == c => quote = None,
+            _ => {}
+        }
+    }
+    if !args.trim().is_empty() {
+        parts.push(args[start..].trim());
+    }
+    parts
+}
+
+/// The expression in `(expr,X)`, `(expr),Y` or `(expr)`, which ends at the last `end`.
+fn inner(operand: &str, end: char) -> &str {
+    let start = operand.find('(').unwrap() + 1;
+    let end = operand.rfind(end).unwrap();
+    operand[start..end].trim()
+}
+
+/// The expression in `expr,X` or `expr,Y`.
+fn index_base(operand: &str) -> &str {
+    operand[..operand.rfind(',').unwrap()].trim()
+}
+
+fn is_identifier(text: &str) -> bool {
+    text.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_')
+        && text.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::disassembler::disassemble;
+    use super::super::CPU;
+    use super::*;
+
+    fn assemble_bytes(source: &str) -> Vec<u8> {
+        assemble(source).unwrap().bytes
+    }
+
+    #[test]
+    fn test_every_op_code() {
+        // Everything the disassembler writes should assemble back into the same bytes.
+        for op in OP_CODES.iter().flatten() {
+            let mut memory = [0; 0x10000];
+            let operand = match op.addr_mode {
+                AddressingMode::Immediate
+                | AddressingMode::ZeroPage
+                | AddressingMode::ZeroPageX
+                | AddressingMode::ZeroPageY
+                | AddressingMode::IndirectX
+                | AddressingMode::IndirectY
+                | AddressingMode::NoneAddressing => [0x34, 0x00],
+                _ => [0x34, 0x12],
+            };
+            memory[0x8000] = op.code;
+            memory[0x8001..0x8003].copy_from_slice(&operand);
+            let (text, len) = disassemble(|addr| memory[addr as usize], 0x8000);
+
+            assert_eq!(
+                assemble_bytes(&text),
+                memory[0x8000..0x8000 + len as usize],
+                "{}",
+                text
+            );
+        }
+    }
+
+    #[test]
+    fn test_labels_and_directives() {
+        let program = assemble(
+            "
+            ; Counts down from three.
+                    .org $C000
+            count = 3
+            start:  ldx #count
+            loop:   DEX
+                    bne loop
+                    lda data+1, x
+                    sta $10
+                    jmp (vector)
+            vector: .word start, *
+            data:   .byte 1, %10, 'a', \"a;b\", <vector, >vector
+            ",
+        )
+        .unwrap();
+
+        assert_eq!(program.origin, 0xC000);
+        assert_eq!(program.symbols["start"], 0xC000);
+        assert_eq!(program.symbols["vector"], 0xC00D);
+        assert_eq!(
+            program.bytes,
+            vec![
+                0xA2, 0x03, // LDX #3
+                0xCA, // DEX
+                0xD0, 0xFD, // BNE loop
+                0xBD, 0x12, 0xC0, // LDA data+1,X, data isn't known yet so it's absolute
+                0x85, 0x10, // STA $10
+                0x6C, 0x0D, 0xC0, // JMP (vector)
+                0x00, 0xC0, 0x0D, 0xC0, // .word
+                0x01, 0x02, b'a', b'a', b';', b'b', 0x0D, 0xC0, // .byte
+            ]
+        );
+    }
+
+    #[test]
+    fn test_org_fills_gaps() {
+        let program = assemble(".org $0600\n.byte 1\n.org $0603\n.byte 2").unwrap();
+        assert_eq!(program.origin, 0x0600);
+        assert_eq!(program.bytes, vec![1, 0, 0, 2]);
+    }
+
+    #[test]
+    fn test_errors() {
+        let error = |source| assemble(source).unwrap_err();
+
+        assert_eq!(
+            error("nop\nfoo #1"),
+            AssembleError {
+                line: 2,
+                message: "Unknown instruction FOO".to_string()
+            }
+        );
+        assert_eq!(error("jmp nowhere").message, "nowhere isn't defined");
+        assert_eq!(error("lda #256").message, "256 doesn't fit in a byte");
+        assert_eq!(error("stx $1234,y").message, "$1234 isn't on the zero page");
+        assert_eq!(error("inx $10").message, "INX can't be used with $10");
+        assert_eq!(error("a: nop\na: nop").message, "a is defined twice");
+        assert_eq!(
+            error(".org $8000\nnop\n.org $8000\nnop").message,
+            "$8000 has already been written to"
+        );
+        assert_eq!(error(".org $8000\n.byte 0\n.org $8100\nbeq $8000").line, 4);
+    }
+
+    #[test]
+    fn test_run() {
+        let program = assemble(
+            "
+                    lda #0
+                    ldx #5
+            loop:   clc
+                    adc #3
+                    dex
+                    bne loop
+                    sta $10
+                    brk
+            ",
+        )
+        .unwrap();
+
+        let mut cpu = CPU::new();
+        cpu.load(program.bytes);
+        cpu.reset();
+        cpu.run();
+        assert_eq!(cpu.mem_read(0x10), 15);
+    }
+}
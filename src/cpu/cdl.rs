@@ -0,0 +1,217 @@
+//! A code/data logger, which keeps track of how every byte of the ROM was used, in the same
+//! `.cdl` format as FCEUX. Disassemblers use it to tell code from data, and bytes that are
+//! still 0 after playing through a game were never reached.
+//!
+//! The file is one byte for every byte of PRG ROM followed by one for every byte of CHR ROM.
+//! The bits of a PRG ROM byte are `xPdcAADC`:
+//!
+//! - `C` it was run as code.
+//! - `D` it was read as data.
+//! - `AA` which 8 KB of $8000-$FFFF it was at the last time, 0 is $8000 and 3 is $E000.
+//! - `c` it was jumped to by a `JMP ($nnnn)`.
+//! - `d` it was read through a pointer, like `LDA ($nn),Y`.
+//! - `P` it was played by the DMC as a sample.
+//!
+//! For CHR ROM the bits are `xxxxxxRD`, drawn by the PPU and read through $2007. There is no
+//! PPU yet, so the CHR ROM part is always 0, but it's there so FCEUX and other tools can read
+//! the file.
+
+use std::io;
+use std::path::Path;
+
+use super::addressing_mode::AddressingMode;
+use super::op_codes;
+use super::CPU;
+
+pub const CODE: u8 = 0x01;
+pub const DATA: u8 = 0x02;
+/// The two bits of the 8 KB window the byte was last seen in.
+pub const WINDOW: u8 = 0x0C;
+pub const INDIRECT_CODE: u8 = 0x10;
+pub const INDIRECT_DATA: u8 = 0x20;
+pub const PCM: u8 = 0x40;
+
+pub const CHR_DRAWN: u8 = 0x01;
+pub const CHR_READ: u8 = 0x02;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CodeDataLog {
+    prg: Vec<u8>,
+    chr: Vec<u8>,
+}
+
+impl CodeDataLog {
+    /// An empty log for a cartridge with ROMs of these sizes.
+    pub fn new(prg_rom_size: usize, chr_rom_size: usize) -> CodeDataLog {
+        CodeDataLog {
+            prg: vec![0; prg_rom_size],
+            chr: vec![0; chr_rom_size],
+        }
+    }
+
+    /// Reads a log made earlier, so a new session adds to it. The sizes have to match the
+    /// cartridge.
+    pub fn from_bytes(
+        data: &[u8],
+        prg_rom_size: usize,
+        chr_rom_size: usize,
+    ) -> Result<CodeDataLog, String> {
+        if data.len() != prg_rom_size + chr_rom_size {
+            return Err(format!(
+                "The code/data log is {} bytes, expected {} for this ROM",
+                data.len(),
+                prg_rom_size + chr_rom_size
+            ));
+        }
+        let (prg, chr) = data.split_at(prg_rom_size);
+        Ok(CodeDataLog {
+            prg: prg.to_vec(),
+            chr: chr.to_vec(),
+        })
+    }
+
+    /// Continues the log in `path` if there is one, otherwise starts a new one.
+    pub fn load(
+        path: &Path,
+        prg_rom_size: usize,
+        chr_rom_size: usize,
+    ) -> Result<CodeDataLog, String> {
+        match std::fs::read(path) {
+            Ok(data) => CodeDataLog::from_bytes(&data, prg_rom_size, chr_rom_size)
+                .map_err(|err| format!("{}: {}", path.display(), err)),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {
+                Ok(CodeDataLog::new(prg_rom_size, chr_rom_size))
+            }
+            Err(err) => Err(format!("Failed to read {}: {}", path.display(), err)),
+        }
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        [self.prg.as_slice(), self.chr.as_slice()].concat()
+    }
+
+    pub fn save(&self, path: &Path) -> io::Result<()> {
+        std::fs::write(path, self.to_bytes())
+    }
+
+    pub fn prg(&self) -> &[u8] {
+        &self.prg
+    }
+
+    pub fn chr(&self) -> &[u8] {
+        &self.chr
+    }
+
+    /// How many bytes of PRG ROM have any of `flags` set.
+    pub fn count(&self, flags: u8) -> usize {
+        self.prg.iter().filter(|&&byte| byte & flags != 0).count()
+    }
+
+    /// Marks the PRG ROM byte at `offset`, which was seen at `addr`.
+    fn mark(&mut self, offset: usize, addr: u16, flags: u8) {
+        let window = ((addr - 0x8000) / 0x2000) as u8;
+        if let Some(byte) = self.prg.get_mut(offset) {
+            *byte = (*byte & !WINDOW) | (window << 2) | flags;
+        }
+    }
+}
+
+impl CPU {
+    /// Starts logging to `log`, which needs to be the size of the inserted cartridge.
+    pub fn start_cdl(&mut self, log: CodeDataLog) {
+        self.cdl = Some(log);
+    }
+
+    pub fn cdl(&self) -> Option<&CodeDataLog> {
+        self.cdl.as_ref()
+    }
+
+    /// Stops logging and returns the log.
+    pub fn take_cdl(&mut self) -> Option<CodeDataLog> {
+        self.cdl.take()
+    }
+
+    /// Called by `mem_read` when logging. Reads of the instruction that is running are code,
+    /// everything else is data.
+    #[cold]
+    pub(super) fn cdl_read(&mut self, addr: u16) {
+        if self.prg_rom_offset(addr).is_none() {
+            return;
+        }
+        let op = op_codes::OP_CODES[self.peek(self.instruction_pc) as usize];
+        let size = op.map_or(1, |op| op.size);
+        let flags = if addr.wrapping_sub(self.instruction_pc) < size {
+            CODE
+        } else {
+            match op.map(|op| op.addr_mode) {
+                Some(AddressingMode::IndirectX | AddressingMode::IndirectY) => DATA | INDIRECT_DATA,
+                _ => DATA,
+            }
+        };
+        self.cdl_mark(addr, flags);
+    }
+
+    /// Adds `flags` to the byte of PRG ROM at `addr`, if there is one.
+    #[cold]
+    pub(super) fn cdl_mark(&mut self, addr: u16, flags: u8) {
+        let offset = self.prg_rom_offset(addr);
+        if let (Some(log), Some(offset)) = (&mut self.cdl, offset) {
+            log.mark(offset, addr, flags);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cartridge::{Cartridge, Mirroring};
+
+    #[test]
+    fn test_code_data_log() {
+        let mut prg_rom = vec![0; 0x8000];
+        let program = [
+            0xAD, 0x00, 0xA0, // LDA $A000
+            0xA9, 0x00, // LDA #$00
+            0x85, 0x10, // STA $10
+            0xA9, 0xA0, // LDA #$A0
+            0x85, 0x11, // STA $11
+            0xA0, 0x01, // LDY #$01
+            0xB1, 0x10, // LDA ($10),Y
+            0x6C, 0x10, 0xE0, // JMP ($E010)
+        ];
+        prg_rom[..program.len()].copy_from_slice(&program);
+        // The JMP goes to a BRK at $C000.
+        prg_rom[0x6010] = 0x00;
+        prg_rom[0x6011] = 0xC0;
+        prg_rom[0x7FFC] = 0x00;
+        prg_rom[0x7FFD] = 0x80;
+        let mut cpu = CPU::new();
+        cpu.load_cartridge(&Cartridge {
+            prg_rom,
+            chr_rom: vec![0; 0x2000],
+            mapper: 0,
+            mirroring: Mirroring::Horizontal,
+            battery: false,
+        })
+        .unwrap();
+
+        cpu.start_cdl(CodeDataLog::new(0x8000, 0x2000));
+        cpu.run();
+        let log = cpu.take_cdl().unwrap();
+        let prg = log.prg();
+
+        assert!(prg[..program.len()].iter().all(|&byte| byte == CODE));
+        assert_eq!(prg[0x2000], DATA | 1 << 2);
+        assert_eq!(prg[0x2001], DATA | INDIRECT_DATA | 1 << 2);
+        assert_eq!(prg[0x6010], DATA | 3 << 2);
+        assert_eq!(prg[0x4000], CODE | INDIRECT_CODE | 2 << 2);
+        assert_eq!(log.count(CODE), program.len() + 1);
+        assert_eq!(log.count(DATA), 4);
+
+        // The file is PRG ROM and then CHR ROM, and can be picked up again.
+        let bytes = log.to_bytes();
+        assert_eq!(bytes.len(), 0xA000);
+        assert_eq!(CodeDataLog::from_bytes(&bytes, 0x8000, 0x2000), Ok(log));
+        assert!(CodeDataLog::from_bytes(&bytes, 0x4000, 0x2000).is_err());
+    }
+}
@@ -0,0 +1,188 @@
+//! Turns machine code back into assembly, using the same syntax as most 6502 assemblers:
+//!
+//! ```text
+//! LDA #$01
+//! STA ($12),Y
+//! JMP ($1234)
+//! BNE $8010
+//! ASL A
+//! ```
+//!
+//! Memory is read through a function, so anything that holds 6502 code can be disassembled, not
+//! just the memory of a running CPU. Bytes that aren't an instruction the CPU knows are shown as
+//! `.db $XX`.
+//!
+//! The `_with_labels` versions show addresses that have a label as the label, see `Symbols`.
+
+use std::ops::RangeInclusive;
+
+use super::addressing_mode::AddressingMode;
+use super::op_codes;
+use super::symbols::Symbols;
+use super::CPU;
+
+/// Disassembles the instruction at `addr`, returns the text and how many bytes it takes up.
+pub fn disassemble<F>(read: F, addr: u16) -> (String, u16)
+where
+    F: Fn(u16) -> u8,
+{
+    disassemble_with_labels(read, |_| None, addr)
+}
+
+/// Same as `disassemble`, but addresses that `label` knows are shown as the label.
+pub fn disassemble_with_labels<F, L>(read: F, label: L, addr: u16) -> (String, u16)
+where
+    F: Fn(u16) -> u8,
+    L: Fn(u16) -> Option<String>,
+{
+    let code = read(addr);
+    let Some(op) = op_codes::OP_CODES[code as usize] else {
+        return (format!(".db ${:02X}", code), 1);
+    };
+
+    let byte = read(addr.wrapping_add(1));
+    let word = u16::from_le_bytes([byte, read(addr.wrapping_add(2))]);
+    let zero_page = label(byte as u16).unwrap_or_else(|| format!("${:02X}", byte));
+    let absolute = label(word).unwrap_or_else(|| format!("${:04X}", word));
+    let operand = match op.addr_mode {
+        AddressingMode::Immediate => format!("#${:02X}", byte),
+        AddressingMode::ZeroPage => zero_page,
+        AddressingMode::ZeroPageX => format!("{},X", zero_page),
+        AddressingMode::ZeroPageY => format!("{},Y", zero_page),
+        AddressingMode::Absolute => absolute,
+        AddressingMode::AbsoluteX => format!("{},X", absolute),
+        AddressingMode::AbsoluteY => format!("{},Y", absolute),
+        AddressingMode::Indirect => format!("({})", absolute),
+        AddressingMode::IndirectX => format!("({},X)", zero_page),
+        AddressingMode::IndirectY => format!("({}),Y", zero_page),
+        // Branches are the only two byte instructions without an addressing mode, the offset is
+        // from the instruction after the branch.
+        AddressingMode::NoneAddressing if op.size == 2 => {
+            let target = addr.wrapping_add(2).wrapping_add(byte as i8 as u16);
+            label(target).unwrap_or_else(|| format!("${:04X}", target))
+        }
+        AddressingMode::NoneAddressing => match op.code {
+            op_codes::ASL_ACCUMULATOR
+            | op_codes::LSR_ACCUMULATOR
+            | op_codes::ROL_ACCUMULATOR
+            | op_codes::ROR_ACCUMULATOR => "A".to_string(),
+            _ => return (op.name.to_string(), op.size),
+        },
+    };
+
+    (format!("{} {}", op.name, operand), op.size)
+}
+
+/// Disassembles every instruction that starts within `range`, one after another from the start
+/// of it. Returns the address and text of each.
+pub fn disassemble_range<F>(read: F, range: RangeInclusive<u16>) -> Vec<(u16, String)>
+where
+    F: Fn(u16) -> u8,
+{
+    disassemble_range_with_labels(read, |_| None, range)
+}
+
+/// Same as `disassemble_range`, but addresses that `label` knows are shown as the label.
+pub fn disassemble_range_with_labels<F, L>(
+    read: F,
+    label: L,
+    range: RangeInclusive<u16>,
+) -> Vec<(u16, String)>
+where
+    F: Fn(u16) -> u8,
+    L: Fn(u16) -> Option<String>,
+{
+    let mut lines = Vec::new();
+    // Counted in u32 so the last instruction in memory doesn't wrap around to $0000.
+    let mut addr = *range.start() as u32;
+    while addr <= *range.end() as u32 {
+        let (text, len) = disassemble_with_labels(&read, &label, addr as u16);
+        lines.push((addr as u16, text));
+        addr += len as u32;
+    }
+    lines
+}
+
+impl CPU {
+    /// Disassembles the instruction at `addr` without changing anything, see `disassemble`.
+    pub fn disassemble(&self, addr: u16) -> (String, u16) {
+        disassemble(|addr| self.peek(addr), addr)
+    }
+
+    /// Disassembles the instruction at `addr` with the labels in `symbols`.
+    pub fn disassemble_with_symbols(&self, addr: u16, symbols: &Symbols) -> (String, u16) {
+        disassemble_with_labels(
+            |addr| self.peek(addr),
+            |addr| symbols.label(self, addr).map(str::to_string),
+            addr,
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cpu::symbols::Location;
+
+    fn disassemble_bytes(bytes: &[u8]) -> (String, u16) {
+        let mut memory = [0; 0x10000];
+        memory[0x8000..0x8000 + bytes.len()].copy_from_slice(bytes);
+        disassemble(|addr| memory[addr as usize], 0x8000)
+    }
+
+    #[test]
+    fn test_addressing_modes() {
+        let cases: &[(&[u8], &str)] = &[
+            (&[0xA9, 0x01], "LDA #$01"),
+            (&[0xA5, 0x12], "LDA $12"),
+            (&[0xB5, 0x12], "LDA $12,X"),
+            (&[0xB6, 0x12], "LDX $12,Y"),
+            (&[0xAD, 0x34, 0x12], "LDA $1234"),
+            (&[0xBD, 0x34, 0x12], "LDA $1234,X"),
+            (&[0xB9, 0x34, 0x12], "LDA $1234,Y"),
+            (&[0x6C, 0x34, 0x12], "JMP ($1234)"),
+            (&[0xA1, 0x12], "LDA ($12,X)"),
+            (&[0xB1, 0x12], "LDA ($12),Y"),
+            (&[0xD0, 0x0E], "BNE $8010"),
+            (&[0x10, 0xFE], "BPL $8000"),
+            (&[0x0A], "ASL A"),
+            (&[0xE8], "INX"),
+            (&[0x02], ".db $02"),
+        ];
+        for &(bytes, text) in cases {
+            assert_eq!(
+                disassemble_bytes(bytes),
+                (text.to_string(), bytes.len() as u16)
+            );
+        }
+    }
+
+    #[test]
+    fn test_disassemble_range() {
+        let mut cpu = CPU::new();
+        cpu.load(vec![0xA2, 0x03, 0xCA, 0xD0, 0xFD, 0x00]);
+
+        assert_eq!(
+            disassemble_range(|addr| cpu.peek(addr), 0x8000..=0x8004),
+            vec![
+                (0x8000, "LDX #$03".to_string()),
+                (0x8002, "DEX".to_string()),
+                (0x8003, "BNE $8002".to_string()),
+            ]
+        );
+        assert_eq!(cpu.disassemble(0x8005), ("BRK".to_string(), 1));
+
+        let mut symbols = Symbols::new();
+        symbols.insert(Location::Cpu(0x8002), "loop");
+        assert_eq!(
+            cpu.disassemble_with_symbols(0x8003, &symbols),
+            ("BNE loop".to_string(), 2)
+        );
+
+        // The range can go all the way to the end of memory without wrapping around.
+        assert_eq!(
+            disassemble_range(|addr| cpu.peek(addr), 0xFFFE..=0xFFFF).len(),
+            2
+        );
+    }
+}
@@ -1,6 +1,17 @@
 mod addressing_mode;
+pub mod assembler;
+pub mod cdl;
+pub mod disassembler;
 mod op_codes;
-
+pub mod symbols;
+pub mod trace;
+pub mod watch;
+
+use crate::apu::Apu;
+use crate::cartridge::{Cartridge, DEFAULT_PRG_RAM_SIZE};
+use crate::joypad::{Buttons, Controllers};
+use crate::region::Region;
+use crate::state::{State, StateError, StateReader, StateSync, StateWriter};
 use addressing_mode::AddressingMode;
 
 bitflags::bitflags! {
@@ -16,6 +27,14 @@ bitflags::bitflags! {
     }
 }
 
+impl State for StatusFlags {
+    fn sync(&mut self, s: &mut StateSync) {
+        let mut bits = self.bits();
+        s.sync(&mut bits);
+        *self = StatusFlags::from_bits_truncate(bits);
+    }
+}
+
 #[derive(Clone, Debug)]
 pub struct CPU {
     pub reg_a: u8,
@@ -27,13 +46,61 @@ pub struct CPU {
     pub pc: u16,
     pub sp: u8,
 
-    memory: [u8; 0xFFFF],
+    /// Number of CPU cycles executed since power on.
+    pub cycles: u64,
+    pub apu: Apu,
+    /// The controllers plugged into the two ports, read through $4016 and $4017.
+    pub controllers: Controllers,
+    region: Region,
+
+    memory: [u8; 0x10000],
+    /// The RAM on the cartridge at $6000-$7FFF. Kept apart from the rest of the memory since
+    /// cartridges with a battery keep it when the power is off.
+    prg_ram: [u8; DEFAULT_PRG_RAM_SIZE],
+    /// The size of the PRG ROM of the inserted cartridge, 0 without one. After a cartridge is
+    /// inserted writes to $8000-$FFFF don't change the ROM.
+    prg_rom_size: usize,
+    /// Where the instruction that is running starts, for telling which instruction hit a
+    /// watchpoint.
+    instruction_pc: u16,
+    /// Cycles the running instruction takes on top of the ones in the op code table, for
+    /// crossing a page or taking a branch.
+    extra_cycles: u8,
+    watchpoints: Vec<watch::Watchpoint>,
+    /// Ids aren't reused, so an old id can't refer to a new watchpoint.
+    next_watch_id: usize,
+    watch_hits: Vec<watch::WatchHit>,
+    /// Marks the bytes of the ROM as code or data while it's there, see `cdl`.
+    cdl: Option<cdl::CodeDataLog>,
 }
 
 const STACK_OFFSET: u16 = 0x0100;
 const STACK_RESET: u8 = 0xfd;
 const PC_OFFSET: u16 = 0x8000;
 
+/// Number of CPU cycles in one NTSC frame. There is no PPU yet so this is what decides how long
+/// a frame is, see `Region::cycles_per_frame` for the other consoles.
+pub const CYCLES_PER_FRAME: u64 = 29_781;
+
+impl Default for CPU {
+    fn default() -> Self {
+        CPU::new()
+    }
+}
+
+/// The registers and counters, the memory and the rest of the machine have their own chunks.
+impl State for CPU {
+    fn sync(&mut self, s: &mut StateSync) {
+        s.sync(&mut self.reg_a);
+        s.sync(&mut self.reg_x);
+        s.sync(&mut self.reg_y);
+        s.sync(&mut self.status);
+        s.sync(&mut self.pc);
+        s.sync(&mut self.sp);
+        s.sync(&mut self.cycles);
+    }
+}
+
 impl CPU {
     pub fn new() -> CPU {
         CPU {
@@ -43,7 +110,19 @@ impl CPU {
             status: StatusFlags::INTERRUPT_DISABLE,
             pc: 0,
             sp: STACK_RESET,
-            memory: [0; 0xFFFF],
+            cycles: 0,
+            apu: Apu::default(),
+            controllers: Controllers::new(),
+            region: Region::Ntsc,
+            memory: [0; 0x10000],
+            prg_ram: [0; DEFAULT_PRG_RAM_SIZE],
+            prg_rom_size: 0,
+            instruction_pc: 0,
+            extra_cycles: 0,
+            watchpoints: Vec::new(),
+            next_watch_id: 1,
+            watch_hits: Vec::new(),
+            cdl: None,
         }
     }
 
@@ -97,15 +176,192 @@ impl CPU {
         self.pc = self.mem_read_u16(0xFFFC);
     }
 
-    pub fn mem_read(&self, addr: u16) -> u8 {
-        self.memory[addr as usize]
+    pub fn mem_read(&mut self, addr: u16) -> u8 {
+        let value = match addr {
+            0x4015 => self.apu.read_status(),
+            // The upper bits are open bus, which usually leaves 0x40 from the high byte of the
+            // address.
+            0x4016 => 0x40 | self.controllers.read(0, self.scanline()),
+            0x4017 => 0x40 | self.controllers.read(1, self.scanline()),
+            0x6000..=0x7FFF => self.prg_ram[(addr - 0x6000) as usize],
+            0x4020..=0xFFFF => self
+                .apu
+                .read_expansion(addr)
+                .unwrap_or(self.memory[addr as usize]),
+            _ => self.memory[addr as usize],
+        };
+        if !self.watchpoints.is_empty() {
+            self.watch(addr, value, watch::Access::Read);
+        }
+        if self.cdl.is_some() {
+            self.cdl_read(addr);
+        }
+        value
     }
 
     pub fn mem_write(&mut self, addr: u16, value: u8) {
-        self.memory[addr as usize] = value;
+        if !self.watchpoints.is_empty() {
+            self.watch(addr, value, watch::Access::Write);
+        }
+        match addr {
+            0x4000..=0x4013 | 0x4015 | 0x4017 => self.apu.write_register(addr, value),
+            0x4016 => self.controllers.write(value),
+            0x6000..=0x7FFF => self.prg_ram[(addr - 0x6000) as usize] = value,
+            // Sound chips on the cartridge have their registers somewhere in cartridge space.
+            0x4020..=0xFFFF => {
+                self.apu.write_expansion(addr, value);
+                if addr < 0x8000 || self.prg_rom_size == 0 {
+                    self.memory[addr as usize] = value;
+                }
+            }
+            _ => self.memory[addr as usize] = value,
+        }
     }
 
-    pub fn mem_read_u16(&self, addr: u16) -> u16 {
+    /// The op codes the CPU can run, in order.
+    pub fn implemented_op_codes() -> Vec<u8> {
+        (0..=255)
+            .filter(|&code| op_codes::OP_CODES[code as usize].is_some())
+            .collect()
+    }
+
+    /// Reads memory without any of the side effects a read can have, like clearing flags in the
+    /// APU or shifting the controllers. Meant for debugging, registers read as $FF.
+    pub fn peek(&self, addr: u16) -> u8 {
+        match addr {
+            0x4000..=0x401F => 0xFF,
+            0x6000..=0x7FFF => self.prg_ram[(addr - 0x6000) as usize],
+            _ => self.memory[addr as usize],
+        }
+    }
+
+    /// Writes memory without any of the side effects a write can have, and without hitting
+    /// watchpoints. Meant for debuggers and for the frontend. Returns false if `addr` can't be
+    /// written like that, which is the registers and the ROM.
+    pub fn poke(&mut self, addr: u16, value: u8) -> bool {
+        match addr {
+            0x4000..=0x401F => return false,
+            0x6000..=0x7FFF => self.prg_ram[(addr - 0x6000) as usize] = value,
+            0x8000..=0xFFFF if self.prg_rom_size != 0 => return false,
+            _ => self.memory[addr as usize] = value,
+        }
+        true
+    }
+
+    pub fn peek_u16(&self, addr: u16) -> u16 {
+        u16::from_le_bytes([self.peek(addr), self.peek(addr.wrapping_add(1))])
+    }
+
+    /// The scanline the PPU would be drawing right now, counting from the top of the picture.
+    /// Lines 240 and up are vblank. There is no PPU yet so this is worked out from the cycles
+    /// since the frame started.
+    pub fn scanline(&self) -> usize {
+        self.region
+            .scanline(self.cycles % self.region.cycles_per_frame())
+    }
+
+    /// The dot on the scanline the PPU would be drawing right now, see `scanline`.
+    pub fn dot(&self) -> usize {
+        self.region
+            .dot(self.cycles % self.region.cycles_per_frame())
+    }
+
+    pub fn region(&self) -> Region {
+        self.region
+    }
+
+    /// Switches to running like a console from another region, which changes how long a frame
+    /// is and the clock the APU is resampled from. The APU is otherwise still NTSC.
+    pub fn set_region(&mut self, region: Region) {
+        self.region = region;
+        self.apu.set_cpu_clock(region.cpu_clock());
+    }
+
+    /// The number of frames since power on.
+    pub fn frame(&self) -> u64 {
+        self.cycles / self.region.cycles_per_frame()
+    }
+
+    /// Saves the whole machine to a save state, see the `state` module for the format.
+    pub fn save_state(&self) -> Vec<u8> {
+        // Syncing needs mutable access since the same code is used for loading.
+        let mut cpu = self.clone();
+        let mut writer = StateWriter::new();
+        writer.chunk(b"CPU ", &mut cpu);
+        writer.chunk(b"RAM ", &mut cpu.memory);
+        writer.chunk(b"APU ", &mut cpu.apu);
+        writer.chunk(b"CTRL", &mut cpu.controllers);
+        writer.chunk(b"SRAM", &mut cpu.prg_ram);
+        writer.finish()
+    }
+
+    /// Loads a save state made by `save_state`. Nothing is changed if the state can't be loaded.
+    pub fn load_state(&mut self, data: &[u8]) -> Result<(), StateError> {
+        let reader = StateReader::new(data)?;
+        let mut cpu = self.clone();
+        reader.chunk(b"CPU ", &mut cpu)?;
+        reader.chunk(b"RAM ", &mut cpu.memory)?;
+        reader.chunk(b"APU ", &mut cpu.apu)?;
+        reader.chunk(b"CTRL", &mut cpu.controllers)?;
+        reader.chunk(b"SRAM", &mut cpu.prg_ram)?;
+        *self = cpu;
+        Ok(())
+    }
+
+    /// Inserts a cartridge and resets. Only mapper 0 (NROM) is supported, a 16 KB PRG ROM is
+    /// mirrored so it shows up at both $8000 and $C000.
+    pub fn load_cartridge(&mut self, cartridge: &Cartridge) -> Result<(), String> {
+        if cartridge.mapper != 0 {
+            return Err(format!("Mapper {} is not supported", cartridge.mapper));
+        }
+        if cartridge.prg_rom.len() != 0x4000 && cartridge.prg_rom.len() != 0x8000 {
+            return Err(format!(
+                "NROM needs 16 or 32 KB of PRG ROM, not {} bytes",
+                cartridge.prg_rom.len()
+            ));
+        }
+
+        for (i, byte) in self.memory[0x8000..].iter_mut().enumerate() {
+            *byte = cartridge.prg_rom[i % cartridge.prg_rom.len()];
+        }
+        self.prg_rom_size = cartridge.prg_rom.len();
+        self.reset();
+        Ok(())
+    }
+
+    /// The 16 KB bank of PRG ROM that `addr` reads from, `None` if it isn't in the ROM. NROM can't
+    /// switch banks, so this only tells $8000 and $C000 apart on 32 KB cartridges.
+    pub fn prg_bank(&self, addr: u16) -> Option<usize> {
+        self.prg_rom_offset(addr).map(|offset| offset / 0x4000)
+    }
+
+    /// Where in the PRG ROM `addr` reads from, `None` if it isn't in the ROM.
+    pub fn prg_rom_offset(&self, addr: u16) -> Option<usize> {
+        if addr < 0x8000 || self.prg_rom_size == 0 {
+            return None;
+        }
+        Some((addr as usize - 0x8000) % self.prg_rom_size)
+    }
+
+    /// The RAM at $6000-$7FFF, this is what gets saved for cartridges with a battery.
+    pub fn prg_ram(&self) -> &[u8] {
+        &self.prg_ram
+    }
+
+    /// Restores the RAM at $6000-$7FFF, usually from a save file. If `data` is too short the rest
+    /// is left as is.
+    pub fn load_prg_ram(&mut self, data: &[u8]) {
+        let len = data.len().min(self.prg_ram.len());
+        self.prg_ram[..len].copy_from_slice(&data[..len]);
+    }
+
+    /// Sets which buttons are held by `player`, 0 to 3. Player 3 and 4 need a multitap, see
+    /// `Controllers::set_multitap`.
+    pub fn set_buttons(&mut self, player: usize, buttons: Buttons) {
+        self.controllers.set_buttons(player, buttons);
+    }
+
+    pub fn mem_read_u16(&mut self, addr: u16) -> u16 {
         let lo = self.mem_read(addr) as u16;
         let hi = self.mem_read(addr + 1) as u16;
         (hi << 8) | lo
@@ -142,7 +398,7 @@ impl CPU {
 
     /// The NES emulator has multiple different addressing modes to access memeory. This function
     /// takes in an addressing mode and returns the memory location for the byte to be read.
-    fn get_op_addr(&self, mode: &AddressingMode) -> u16 {
+    fn get_op_addr(&mut self, mode: &AddressingMode) -> u16 {
         match mode {
             AddressingMode::Immediate => self.pc,
 
@@ -152,29 +408,25 @@ impl CPU {
 
             AddressingMode::ZeroPageX => {
                 let pos = self.mem_read(self.pc);
-                let addr = pos.wrapping_add(self.reg_x) as u16;
-                addr
+                pos.wrapping_add(self.reg_x) as u16
             }
             AddressingMode::ZeroPageY => {
                 let pos = self.mem_read(self.pc);
-                let addr = pos.wrapping_add(self.reg_y) as u16;
-                addr
+                pos.wrapping_add(self.reg_y) as u16
             }
 
             AddressingMode::AbsoluteX => {
                 let base = self.mem_read_u16(self.pc);
-                let addr = base.wrapping_add(self.reg_x as u16);
-                addr
+                self.index(base, self.reg_x)
             }
             AddressingMode::AbsoluteY => {
                 let base = self.mem_read_u16(self.pc);
-                let addr = base.wrapping_add(self.reg_y as u16);
-                addr
+                self.index(base, self.reg_y)
             }
             AddressingMode::IndirectX => {
                 let base = self.mem_read(self.pc);
 
-                let ptr: u8 = (base as u8).wrapping_add(self.reg_x);
+                let ptr: u8 = base.wrapping_add(self.reg_x);
                 let lo = self.mem_read(ptr as u16);
                 let hi = self.mem_read(ptr.wrapping_add(1) as u16);
 
@@ -184,11 +436,10 @@ impl CPU {
                 let base = self.mem_read(self.pc);
 
                 let lo = self.mem_read(base as u16);
-                let hi = self.mem_read((base as u8).wrapping_add(1) as u16);
+                let hi = self.mem_read(base.wrapping_add(1) as u16);
                 let deref_base = (hi as u16) << 8 | (lo as u16);
-                let deref = deref_base.wrapping_add(self.reg_y as u16);
 
-                deref
+                self.index(deref_base, self.reg_y)
             }
             AddressingMode::Indirect => {
                 panic!("mode: {:?} is only used for JMP instruction and should not be used with this function", mode);
@@ -199,6 +450,28 @@ impl CPU {
         }
     }
 
+    /// Adds an index register to an address. Instructions that only read take a cycle longer
+    /// when that crosses into another page, the others always take the extra cycle and have it
+    /// in the op code table already.
+    fn index(&mut self, base: u16, index: u8) -> u16 {
+        let addr = base.wrapping_add(index as u16);
+        if addr & 0xFF00 != base & 0xFF00 && self.reads_only() {
+            self.extra_cycles += 1;
+        }
+        addr
+    }
+
+    /// Whether the running instruction only reads its operand.
+    fn reads_only(&self) -> bool {
+        let op = op_codes::OP_CODES[self.peek(self.instruction_pc) as usize];
+        op.is_some_and(|op| {
+            matches!(
+                op.name,
+                "ADC" | "AND" | "CMP" | "EOR" | "LDA" | "LDX" | "LDY" | "ORA" | "SBC"
+            )
+        })
+    }
+
     pub fn run(&mut self) {
         self.run_with_callback(|_| {});
     }
@@ -217,7 +490,26 @@ impl CPU {
         }
     }
 
+    /// Runs the CPU until the end of the current frame, calling `callback` before every step just
+    /// like `run_with_callback`. Returns true if the program stopped before the frame was done.
+    pub fn run_frame_with_callback<F>(&mut self, mut callback: F) -> bool
+    where
+        F: FnMut(&mut CPU),
+    {
+        let cycles_per_frame = self.region.cycles_per_frame();
+        let frame_end = (self.frame() + 1) * cycles_per_frame;
+        while self.cycles < frame_end {
+            callback(self);
+            if self.run_step() {
+                return true;
+            }
+        }
+        false
+    }
+
     pub fn run_step(&mut self) -> bool {
+        self.instruction_pc = self.pc;
+        self.extra_cycles = 0;
         let op_code = self.mem_read(self.pc);
 
         let op = op_codes::OP_CODES[op_code as usize].unwrap_or_else(|| {
@@ -471,14 +763,32 @@ impl CPU {
             op_codes::TXA => self.txa(),
             op_codes::TXS => self.txs(),
             op_codes::TYA => self.tya(),
-            _ => panic!("Unimplemented opscode: {:02X}", op_code),
+            _ => panic!("Unimplemented opscode: {} ({:02X})", op.name, op_code),
         }
 
         if initial_pc == self.pc {
             self.pc += op.size - 1;
         }
 
-        return false;
+        self.tick(op.cycles + self.extra_cycles);
+
+        false
+    }
+
+    /// Advances the rest of the system by the amount of cycles the last instruction took.
+    fn tick(&mut self, cycles: u8) {
+        for _ in 0..cycles {
+            self.apu.tick();
+            if let Some(addr) = self.apu.dmc_pending_read() {
+                let value = self.mem_read(addr);
+                self.apu.dmc_fill(value);
+                if self.cdl.is_some() {
+                    self.cdl_mark(addr, cdl::PCM);
+                }
+            }
+        }
+
+        self.cycles += cycles as u64;
     }
 
     fn plp(&mut self) {
@@ -492,8 +802,9 @@ impl CPU {
         self.update_zero_and_negative_flags(v);
     }
 
+    /// The break flag and bit 5 are always set in the pushed copy.
     fn php(&mut self) {
-        self.push_stack(self.status.bits());
+        self.push_stack(self.status.bits() | 0b0011_0000);
     }
 
     fn pha(&mut self) {
@@ -501,9 +812,9 @@ impl CPU {
     }
 
     fn ora(&mut self, addr_mode: &AddressingMode) {
-        let addr = self.get_op_addr(&addr_mode);
+        let addr = self.get_op_addr(addr_mode);
         let value = self.mem_read(addr);
-        self.reg_a = value | self.reg_a;
+        self.reg_a |= value;
         self.update_zero_and_negative_flags(self.reg_a);
     }
 
@@ -531,6 +842,9 @@ impl CPU {
                     self.mem_read_u16(mem_address)
                 };
 
+                if self.cdl.is_some() {
+                    self.cdl_mark(indirect_ref, cdl::INDIRECT_CODE);
+                }
                 self.pc = indirect_ref;
             }
             _ => panic!("Unimplemented addressing mode: {:?}", addr_mode),
@@ -552,12 +866,13 @@ impl CPU {
         let data = self.mem_read(addr);
         let new_value = data.wrapping_add(1);
         self.mem_write(addr, new_value);
+        self.update_zero_and_negative_flags(new_value);
     }
 
     fn eor(&mut self, addr_mode: &AddressingMode) {
-        let addr = self.get_op_addr(&addr_mode);
+        let addr = self.get_op_addr(addr_mode);
         let value = self.mem_read(addr);
-        self.reg_a = value ^ self.reg_a;
+        self.reg_a ^= value;
         self.update_zero_and_negative_flags(self.reg_a);
     }
 
@@ -586,7 +901,7 @@ impl CPU {
     }
 
     fn dec(&mut self, addr_mode: &AddressingMode) {
-        let addr = self.get_op_addr(&addr_mode);
+        let addr = self.get_op_addr(addr_mode);
         let value = self.mem_read(addr);
         let new_value = value.wrapping_sub(1);
         self.mem_write(addr, new_value);
@@ -625,28 +940,32 @@ impl CPU {
         let addr = self.get_op_addr(&addr_mode);
         let value = self.mem_read(addr);
 
+        // Z comes from the AND, N and V are bits 7 and 6 of memory.
         let result = self.reg_a & value;
-
-        if value & 0b0100_0000 != 0 {
-            self.status.insert(StatusFlags::OVERFLOW);
-        } else {
-            self.status.remove(StatusFlags::OVERFLOW);
-        }
-
-        self.update_zero_and_negative_flags(result);
+        self.status.set(StatusFlags::ZERO, result == 0);
+        self.status
+            .set(StatusFlags::NEGATIVE, value & 0b1000_0000 != 0);
+        self.status
+            .set(StatusFlags::OVERFLOW, value & 0b0100_0000 != 0);
     }
 
     fn branch(&mut self, condition: bool) {
         if condition {
             let offset = self.mem_read(self.pc) as i8;
-            self.pc = self.pc.wrapping_add(offset as u16).wrapping_add(1);
+            let next = self.pc.wrapping_add(1);
+            self.pc = next.wrapping_add(offset as u16);
+            // A taken branch takes a cycle longer, and another one if it goes to another page.
+            self.extra_cycles += 1;
+            if self.pc & 0xFF00 != next & 0xFF00 {
+                self.extra_cycles += 1;
+            }
         }
     }
 
     fn and(&mut self, addr_mode: AddressingMode) {
         let addr = self.get_op_addr(&addr_mode);
         let value = self.mem_read(addr);
-        self.reg_a = value & self.reg_a;
+        self.reg_a &= value;
         self.update_zero_and_negative_flags(self.reg_a);
     }
 
@@ -659,7 +978,7 @@ impl CPU {
 
     fn asl_acc(&mut self) {
         self.update_carry(self.reg_a);
-        self.reg_a = self.reg_a << 1;
+        self.reg_a <<= 1;
         self.update_zero_and_negative_flags(self.reg_a);
     }
 
@@ -715,7 +1034,7 @@ impl CPU {
         let value = self.mem_read(addr);
 
         self.reg_x = value;
-        self.update_zero_and_negative_flags(self.reg_a);
+        self.update_zero_and_negative_flags(self.reg_x);
     }
 
     fn ldy(&mut self, addr_mode: AddressingMode) {
@@ -723,7 +1042,7 @@ impl CPU {
         let value = self.mem_read(addr);
 
         self.reg_y = value;
-        self.update_zero_and_negative_flags(self.reg_a);
+        self.update_zero_and_negative_flags(self.reg_y);
     }
 
     fn sta(&mut self, addr_mode: AddressingMode) {
@@ -753,7 +1072,7 @@ impl CPU {
     }
 
     fn rol(&mut self, addr_mode: &AddressingMode) {
-        let addr = self.get_op_addr(&addr_mode);
+        let addr = self.get_op_addr(addr_mode);
         let v = self.mem_read(addr);
 
         let mut new_value = v << 1;
@@ -774,7 +1093,7 @@ impl CPU {
     }
 
     fn lsr(&mut self, addr_mode: &AddressingMode) {
-        let addr = self.get_op_addr(&addr_mode);
+        let addr = self.get_op_addr(addr_mode);
         let v = self.mem_read(addr);
         let new_value = v >> 1;
         self.update_carry(v.reverse_bits());
@@ -794,7 +1113,7 @@ impl CPU {
     }
 
     fn ror(&mut self, addr_mode: &AddressingMode) {
-        let addr = self.get_op_addr(&addr_mode);
+        let addr = self.get_op_addr(addr_mode);
         let v = self.mem_read(addr);
 
         let mut new_value = v >> 1;
@@ -811,14 +1130,26 @@ impl CPU {
         self.pc = self.pop_stack_u16();
     }
 
+    /// Takes the interrupt for the BRK `run_step` stopped at, like a 6502 does: pushes the
+    /// address after its padding byte and the status with the break flag, and jumps through the
+    /// IRQ vector at $FFFE.
+    pub fn take_brk(&mut self) {
+        self.push_stack_u16(self.pc.wrapping_add(1));
+        self.push_stack(self.status.bits() | 0b0011_0000);
+        self.status.insert(StatusFlags::INTERRUPT_DISABLE);
+        self.pc = self.mem_read_u16(0xFFFE);
+        self.tick(7);
+    }
+
     fn rts(&mut self) {
         self.pc = self.pop_stack_u16() + 1;
     }
 
     fn sbc(&mut self, addr_mode: &AddressingMode) {
-        let addr = self.get_op_addr(&addr_mode);
+        let addr = self.get_op_addr(addr_mode);
         let data = self.mem_read(addr);
         self.add_to_register_a(((data as i8).wrapping_neg().wrapping_sub(1)) as u8);
+        self.update_zero_and_negative_flags(self.reg_a);
     }
 }
 
@@ -926,6 +1257,19 @@ mod tests {
         assert!(!cpu.status.contains(StatusFlags::OVERFLOW));
     }
 
+    #[test]
+    fn test_bit_negative_comes_from_memory() {
+        let mut cpu = CPU::new();
+        cpu.load(vec![op_codes::BIT_ABSOLUTE, 0x10, op_codes::BRK]);
+        cpu.reset();
+        cpu.mem_write(0x10, 0b1000_0000);
+        cpu.reg_a = 0b0000_0001;
+        cpu.run();
+
+        assert!(cpu.status.contains(StatusFlags::ZERO));
+        assert!(cpu.status.contains(StatusFlags::NEGATIVE));
+    }
+
     #[test]
     fn test_bit_absolute_overflow_set() {
         let mut cpu = CPU::new();
@@ -1177,9 +1521,60 @@ mod tests {
     }
 
     #[test]
-    #[ignore]
     fn test_inc_absolute() {
-        todo!()
+        let mut cpu = CPU::new();
+        cpu.load(vec![op_codes::INC_ABSOLUTE, 0x10, 0x00, op_codes::BRK]);
+        cpu.reset();
+        cpu.mem_write(0x10, 0xFF);
+        cpu.run();
+
+        assert_eq!(cpu.peek(0x10), 0x00);
+        assert!(cpu.status.contains(StatusFlags::ZERO));
+        assert!(!cpu.status.contains(StatusFlags::NEGATIVE));
+
+        cpu.load(vec![op_codes::INC_ABSOLUTE, 0x10, 0x00, op_codes::BRK]);
+        cpu.reset();
+        cpu.mem_write(0x10, 0x7F);
+        cpu.run();
+
+        assert_eq!(cpu.peek(0x10), 0x80);
+        assert!(!cpu.status.contains(StatusFlags::ZERO));
+        assert!(cpu.status.contains(StatusFlags::NEGATIVE));
+    }
+
+    #[test]
+    fn test_php_sets_break_and_bit_5() {
+        let mut cpu = CPU::new();
+        cpu.load(vec![op_codes::PHP, op_codes::BRK]);
+        cpu.reset();
+        cpu.status = StatusFlags::CARRY;
+        cpu.run();
+
+        assert_eq!(cpu.peek(0x01FD), 0b0011_0001);
+    }
+
+    #[test]
+    fn test_sbc_updates_zero_and_negative() {
+        let mut cpu = CPU::new();
+        cpu.load(vec![op_codes::SBC_IMMEDIATE, 0x05, op_codes::BRK]);
+        cpu.reset();
+        cpu.reg_a = 0x05;
+        cpu.status = StatusFlags::CARRY;
+        cpu.run();
+
+        assert_eq!(cpu.reg_a, 0x00);
+        assert!(cpu.status.contains(StatusFlags::ZERO));
+        assert!(cpu.status.contains(StatusFlags::CARRY));
+
+        cpu.load(vec![op_codes::SBC_IMMEDIATE, 0x01, op_codes::BRK]);
+        cpu.reset();
+        cpu.reg_a = 0x00;
+        cpu.status = StatusFlags::CARRY;
+        cpu.run();
+
+        assert_eq!(cpu.reg_a, 0xFF);
+        assert!(cpu.status.contains(StatusFlags::NEGATIVE));
+        assert!(!cpu.status.contains(StatusFlags::CARRY));
     }
 
     #[test]
@@ -1257,6 +1652,32 @@ mod tests {
         assert!(cpu.status.contains(StatusFlags::ZERO));
     }
 
+    #[test]
+    fn test_ldx_flags() {
+        let mut cpu = CPU::new();
+        cpu.load(vec![op_codes::LDX_IMMEDIATE, 0x80, op_codes::BRK]);
+        cpu.reset();
+        cpu.reg_a = 0x01;
+        cpu.run();
+
+        assert_eq!(cpu.reg_x, 0x80);
+        assert!(!cpu.status.contains(StatusFlags::ZERO));
+        assert!(cpu.status.contains(StatusFlags::NEGATIVE));
+    }
+
+    #[test]
+    fn test_ldy_flags() {
+        let mut cpu = CPU::new();
+        cpu.load(vec![op_codes::LDY_IMMEDIATE, 0x00, op_codes::BRK]);
+        cpu.reset();
+        cpu.reg_a = 0x80;
+        cpu.run();
+
+        assert_eq!(cpu.reg_y, 0x00);
+        assert!(cpu.status.contains(StatusFlags::ZERO));
+        assert!(!cpu.status.contains(StatusFlags::NEGATIVE));
+    }
+
     #[test]
     fn test_tax_move_a_to_x() {
         let mut cpu = CPU::new();
@@ -1271,6 +1692,27 @@ mod tests {
         assert_eq!(cpu.reg_x, 0x55);
     }
 
+    #[test]
+    fn test_extra_cycles() {
+        // Each program is run for one instruction after setting X to 1.
+        let cases: &[(&[u8], u64)] = &[
+            (&[0xBD, 0x00, 0x02], 4), // LDA $0200,X
+            (&[0xBD, 0xFF, 0x02], 5), // LDA $02FF,X crosses a page
+            (&[0x9D, 0xFF, 0x02], 5), // STA $02FF,X always takes 5
+            (&[0xD0, 0x02], 3),       // BNE taken
+            (&[0xF0, 0x02], 2),       // BEQ not taken
+            (&[0xD0, 0x80], 4),       // BNE taken to another page
+        ];
+        for &(program, cycles) in cases {
+            let mut cpu = CPU::new();
+            cpu.load(program.to_vec());
+            cpu.reset();
+            cpu.reg_x = 1;
+            cpu.run_step();
+            assert_eq!(cpu.cycles, cycles, "{:02X?}", program);
+        }
+    }
+
     #[test]
     fn test_0xe8_inx_increment_x() {
         let mut cpu = CPU::new();
@@ -1298,6 +1740,220 @@ mod tests {
         assert_eq!(cpu.reg_x, 1)
     }
 
+    #[test]
+    fn test_run_frame() {
+        let mut cpu = CPU::new();
+        // An infinite loop of NOPs.
+        cpu.load(vec![op_codes::NOP, op_codes::JMP_ABSOLUTE, 0x00, 0x80]);
+        cpu.reset();
+
+        assert!(!cpu.run_frame_with_callback(|_| {}));
+        assert!(cpu.cycles >= CYCLES_PER_FRAME);
+        assert!(!cpu.run_frame_with_callback(|_| {}));
+        assert!(cpu.cycles >= 2 * CYCLES_PER_FRAME);
+        assert!(cpu.cycles < 2 * CYCLES_PER_FRAME + 7);
+    }
+
+    #[test]
+    fn test_pal_frame() {
+        let mut cpu = CPU::new();
+        cpu.set_region(Region::Pal);
+        cpu.load(vec![op_codes::NOP, op_codes::JMP_ABSOLUTE, 0x00, 0x80]);
+        cpu.reset();
+
+        assert!(!cpu.run_frame_with_callback(|_| {}));
+        assert_eq!(cpu.frame(), 1);
+        assert!(cpu.cycles >= Region::Pal.cycles_per_frame());
+        assert!(cpu.cycles < Region::Pal.cycles_per_frame() + 7);
+    }
+
+    #[test]
+    fn test_save_and_load_state() {
+        let mut cpu = CPU::new();
+        // Counts up at $10 forever while playing a note.
+        cpu.load(vec![
+            op_codes::LDA_IMMEDIATE,
+            0x01,
+            op_codes::STA_ABSOLUTE,
+            0x15,
+            0x40,
+            op_codes::LDA_IMMEDIATE,
+            0xBF,
+            op_codes::STA_ABSOLUTE,
+            0x00,
+            0x40,
+            op_codes::LDA_IMMEDIATE,
+            0xFD,
+            op_codes::STA_ABSOLUTE,
+            0x02,
+            0x40,
+            op_codes::STA_ABSOLUTE,
+            0x03,
+            0x40,
+            op_codes::INC_ZERO_PAGE,
+            0x10,
+            op_codes::JMP_ABSOLUTE,
+            0x12,
+            0x80,
+        ]);
+        cpu.reset();
+        cpu.run_frame_with_callback(|_| {});
+        cpu.apu.take_samples();
+
+        let state = cpu.save_state();
+        let counter = cpu.mem_read(0x10);
+        let cycles = cpu.cycles;
+
+        cpu.run_frame_with_callback(|_| {});
+        let samples = cpu.apu.take_samples();
+        let after = (cpu.mem_read(0x10), cpu.pc, cpu.cycles);
+
+        cpu.load_state(&state).unwrap();
+        assert_eq!(cpu.mem_read(0x10), counter);
+        assert_eq!(cpu.cycles, cycles);
+
+        cpu.run_frame_with_callback(|_| {});
+        assert_eq!((cpu.mem_read(0x10), cpu.pc, cpu.cycles), after);
+        assert_eq!(cpu.apu.take_samples().len(), samples.len());
+    }
+
+    #[test]
+    fn test_load_bad_state() {
+        let mut cpu = CPU::new();
+        cpu.reg_a = 0x42;
+        let state = cpu.save_state();
+
+        assert_eq!(cpu.load_state(b"garbage"), Err(StateError::NotAState));
+        assert_eq!(
+            cpu.load_state(&state[..state.len() - 10]),
+            Err(StateError::Truncated)
+        );
+        assert_eq!(cpu.reg_a, 0x42);
+    }
+
+    #[test]
+    fn test_prg_ram() {
+        let mut cpu = CPU::new();
+        cpu.load(vec![
+            op_codes::LDA_IMMEDIATE,
+            0x42,
+            op_codes::STA_ABSOLUTE,
+            0x10,
+            0x60,
+            op_codes::BRK,
+        ]);
+        cpu.reset();
+        cpu.run();
+
+        assert_eq!(cpu.prg_ram()[0x10], 0x42);
+        assert_eq!(cpu.mem_read(0x6010), 0x42);
+
+        let state = cpu.save_state();
+        let mut other = CPU::new();
+        other.load_prg_ram(&[1, 2, 3]);
+        assert_eq!(other.mem_read(0x6002), 3);
+        other.load_state(&state).unwrap();
+        assert_eq!(other.prg_ram(), cpu.prg_ram());
+    }
+
+    #[test]
+    fn test_load_cartridge() {
+        let mut prg_rom = vec![0; 0x4000];
+        prg_rom[0] = op_codes::LDA_IMMEDIATE;
+        prg_rom[1] = 0x42;
+        prg_rom[2] = op_codes::STA_ABSOLUTE;
+        prg_rom[3] = 0x00;
+        prg_rom[4] = 0x80;
+        prg_rom[5] = op_codes::BRK;
+        // The reset vector, at $FFFC once the ROM is mirrored.
+        prg_rom[0x3FFC] = 0x00;
+        prg_rom[0x3FFD] = 0xC0;
+        let cartridge = Cartridge {
+            prg_rom,
+            chr_rom: Vec::new(),
+            mapper: 0,
+            mirroring: crate::cartridge::Mirroring::Horizontal,
+            battery: true,
+        };
+
+        let mut cpu = CPU::new();
+        cpu.load_cartridge(&cartridge).unwrap();
+        assert_eq!(cpu.pc, 0xC000);
+        cpu.run();
+
+        assert_eq!(cpu.reg_a, 0x42);
+        // The ROM can't be written to.
+        assert_eq!(cpu.mem_read(0x8000), op_codes::LDA_IMMEDIATE);
+        // A 16 KB ROM is the same bank at both addresses.
+        assert_eq!(cpu.prg_bank(0xC000), Some(0));
+        assert_eq!(cpu.prg_bank(0x8000), Some(0));
+        assert_eq!(cpu.prg_bank(0x6000), None);
+        assert_eq!(cpu.prg_rom_offset(0xC123), Some(0x0123));
+
+        // Poking leaves the ROM and the registers alone.
+        assert!(!cpu.poke(0x8000, 0xEA));
+        assert!(!cpu.poke(0x4015, 0x0F));
+        assert_eq!(cpu.peek(0x8000), op_codes::LDA_IMMEDIATE);
+        assert!(cpu.poke(0x6000, 0x42));
+        assert_eq!(cpu.prg_ram()[0], 0x42);
+
+        let unsupported = Cartridge {
+            mapper: 4,
+            ..cartridge
+        };
+        assert!(cpu.load_cartridge(&unsupported).is_err());
+    }
+
+    #[test]
+    fn test_sound_chip_registers() {
+        let mut cpu = CPU::new();
+        cpu.apu
+            .set_expansion_audio(crate::apu::expansion::for_mapper(19));
+        // The Namco 163 sound RAM, the address is written to $F800 and the data goes through
+        // $4800, the address goes up by one with every access.
+        cpu.mem_write(0xF800, 0x80);
+        cpu.mem_write(0x4800, 0x5A);
+        cpu.mem_write(0xF800, 0x80);
+        assert_eq!(cpu.mem_read(0x4800), 0x5A);
+
+        cpu.apu.set_expansion_audio(None);
+        assert_eq!(cpu.mem_read(0x4800), cpu.peek(0x4800));
+    }
+
+    #[test]
+    fn test_read_joypad() {
+        let mut cpu = CPU::new();
+        cpu.load(vec![
+            op_codes::LDA_IMMEDIATE,
+            0x01,
+            op_codes::STA_ABSOLUTE,
+            0x16,
+            0x40,
+            op_codes::LSR_ACCUMULATOR,
+            op_codes::STA_ABSOLUTE,
+            0x16,
+            0x40,
+            op_codes::LDA_ABSOLUTE,
+            0x16,
+            0x40,
+            op_codes::LDX_ABSOLUTE,
+            0x16,
+            0x40,
+            op_codes::LDY_ABSOLUTE,
+            0x17,
+            0x40,
+            op_codes::BRK,
+        ]);
+        cpu.reset();
+        cpu.set_buttons(0, Buttons::A);
+        cpu.set_buttons(1, Buttons::B);
+        cpu.run();
+
+        assert_eq!(cpu.reg_a & 1, 1);
+        assert_eq!(cpu.reg_x & 1, 0);
+        assert_eq!(cpu.reg_y & 1, 0);
+    }
+
     #[test]
     fn test_lda_from_memory() {
         let mut cpu = CPU::new();
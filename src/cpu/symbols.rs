@@ -0,0 +1,376 @@
+//! Labels for addresses, loaded from the symbol files of assemblers and other emulators, so the
+//! debugger, the disassembler and the trace logger can show `reset` instead of `$C000`.
+//!
+//! Three formats are supported:
+//!
+//! - `.dbg` files written by ld65 with `--dbgfile`, when building with cc65.
+//! - FCEUX's `.nl` files, one for RAM named `game.nes.ram.nl` and one for every 16 KB bank of
+//!   PRG ROM named `game.nes.0.nl`, `game.nes.1.nl` and so on.
+//! - Mesen's `.mlb` files.
+//!
+//! Labels in the PRG ROM are kept by where they are in the ROM rather than by address, so the
+//! label at $C000 depends on which bank is mapped there.
+
+use std::collections::HashMap;
+use std::path::Path;
+
+use super::CPU;
+
+/// The size of the header at the start of an iNES file.
+const INES_HEADER_SIZE: usize = 16;
+
+/// Size of a PRG ROM bank in `.nl` files.
+const BANK_SIZE: usize = 0x4000;
+
+/// Where a label is.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Location {
+    /// An address that is always the same memory, like RAM or a register.
+    Cpu(u16),
+    /// An offset into the PRG ROM.
+    PrgRom(usize),
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct Symbols {
+    labels: HashMap<Location, String>,
+}
+
+impl Symbols {
+    pub fn new() -> Symbols {
+        Symbols::default()
+    }
+
+    /// Loads the symbol files that sit next to a ROM: `game.dbg`, `game.mlb` and the `.nl`
+    /// files of `game.nes`. It's not an error if there aren't any.
+    pub fn for_rom(rom: &Path) -> Result<Symbols, String> {
+        let mut symbols = Symbols::new();
+        let mut paths = vec![rom.with_extension("dbg"), rom.with_extension("mlb")];
+        let nl = |suffix: &str| {
+            let mut name = rom.as_os_str().to_owned();
+            name.push(format!(".{}.nl", suffix));
+            Path::new(&name).to_path_buf()
+        };
+        paths.push(nl("ram"));
+        // Bank files are numbered from 0 without gaps.
+        paths.extend(
+            (0..)
+                .map(|bank| nl(&bank.to_string()))
+                .take_while(|p| p.exists()),
+        );
+
+        for path in paths.iter().filter(|p| p.exists()) {
+            symbols.load(path)?;
+        }
+        Ok(symbols)
+    }
+
+    /// Loads a symbol file, the format is picked from the extension.
+    pub fn load(&mut self, path: &Path) -> Result<(), String> {
+        let text = std::fs::read_to_string(path)
+            .map_err(|err| format!("Failed to read {}: {}", path.display(), err))?;
+        let name = path.file_name().unwrap_or_default().to_string_lossy();
+        let result = match path.extension().and_then(|ext| ext.to_str()) {
+            Some("dbg") => self.parse_dbg(&text),
+            Some("mlb") => self.parse_mlb(&text),
+            Some("nl") => {
+                // `game.nes.1.nl` is bank 1, `game.nes.ram.nl` is RAM.
+                let bank = name
+                    .strip_suffix(".nl")
+                    .and_then(|name| name.rsplit('.').next())
+                    .and_then(|bank| bank.parse().ok());
+                self.parse_nl(&text, bank)
+            }
+            _ => Err("Unknown symbol file, expected .dbg, .nl or .mlb".to_string()),
+        };
+        result.map_err(|err| format!("{}: {}", path.display(), err))
+    }
+
+    pub fn insert(&mut self, location: Location, name: &str) {
+        self.labels.insert(location, name.to_string());
+    }
+
+    pub fn len(&self) -> usize {
+        self.labels.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.labels.is_empty()
+    }
+
+    /// The label of `addr` with the banks that are mapped into `cpu` right now.
+    pub fn label(&self, cpu: &CPU, addr: u16) -> Option<&str> {
+        cpu.prg_rom_offset(addr)
+            .and_then(|offset| self.labels.get(&Location::PrgRom(offset)))
+            .or_else(|| self.labels.get(&Location::Cpu(addr)))
+            .map(String::as_str)
+    }
+
+    /// The address of the label `name`, if it can be seen by the CPU right now.
+    pub fn address(&self, cpu: &CPU, name: &str) -> Option<u16> {
+        self.labels
+            .iter()
+            .filter(|(_, label)| *label == name)
+            .find_map(|(location, _)| match *location {
+                Location::Cpu(addr) => Some(addr),
+                Location::PrgRom(offset) => (0x8000..=0xFFFF)
+                    .step_by(BANK_SIZE)
+                    .map(|bank| bank + (offset % BANK_SIZE) as u16)
+                    .find(|&addr| cpu.prg_rom_offset(addr) == Some(offset)),
+            })
+    }
+
+    /// Parses the debug info of ld65. Only labels are used, which are the `sym` lines with
+    /// `type=lab`. Segments that end up in an iNES file are in the PRG ROM.
+    pub fn parse_dbg(&mut self, text: &str) -> Result<(), String> {
+        struct Segment {
+            start: usize,
+            /// Where the segment is in the PRG ROM.
+            rom_offset: Option<usize>,
+        }
+        let mut segments = HashMap::new();
+        let mut symbols = Vec::new();
+
+        for (i, line) in text.lines().enumerate() {
+            let Some((kind, fields)) = line.split_once(char::is_whitespace) else {
+                continue;
+            };
+            let fields = dbg_fields(fields);
+            let number = |key: &str| -> Result<Option<usize>, String> {
+                match fields.get(key) {
+                    Some(value) => parse_dbg_number(value)
+                        .map(Some)
+                        .ok_or_else(|| format!("Line {}: invalid number {}", i + 1, value)),
+                    None => Ok(None),
+                }
+            };
+            let required =
+                |key: &str| number(key)?.ok_or_else(|| format!("Line {}: missing {}", i + 1, key));
+
+            match kind {
+                "seg" => {
+                    let start = required("start")?;
+                    let in_ines = fields
+                        .get("oname")
+                        .is_some_and(|name| name.to_ascii_lowercase().ends_with(".nes"));
+                    let rom_offset = match number("ooffs")? {
+                        Some(offset) if in_ines && start >= 0x8000 => {
+                            offset.checked_sub(INES_HEADER_SIZE)
+                        }
+                        _ => None,
+                    };
+                    segments.insert(required("id")?, Segment { start, rom_offset });
+                }
+                "sym" if fields.get("type") == Some(&"lab") => {
+                    let Some(name) = fields.get("name") else {
+                        return Err(format!("Line {}: missing name", i + 1));
+                    };
+                    symbols.push((name.to_string(), required("val")?, number("seg")?));
+                }
+                _ => {}
+            }
+        }
+
+        // Segments can come after the symbols in them.
+        for (name, value, segment) in symbols {
+            let segment = segment.and_then(|id| segments.get(&id));
+            let location = match segment {
+                Some(Segment {
+                    start,
+                    rom_offset: Some(offset),
+                }) if value >= *start => Location::PrgRom(offset + value - start),
+                _ => Location::Cpu(value as u16),
+            };
+            self.insert(location, &name);
+        }
+        Ok(())
+    }
+
+    /// Parses an FCEUX name list. `bank` is the PRG ROM bank the file is for, `None` for the
+    /// RAM file. Lines look like `$C000#reset#comment`, or `$0300/10#buffer#` for arrays.
+    pub fn parse_nl(&mut self, text: &str, bank: Option<usize>) -> Result<(), String> {
+        for (i, line) in text.lines().enumerate() {
+            // Comments can go on over several lines, those don't start with `$`.
+            let Some(line) = line.strip_prefix('$') else {
+                continue;
+            };
+            let mut parts = line.split('#');
+            let addr = parts.next().unwrap_or("");
+            let name = parts.next().unwrap_or("").trim();
+            let addr = addr.split('/').next().unwrap_or("");
+            let addr = u16::from_str_radix(addr, 16)
+                .map_err(|_| format!("Line {}: invalid address ${}", i + 1, addr))?;
+            if name.is_empty() {
+                continue;
+            }
+
+            let location = match bank {
+                Some(bank) if addr >= 0x8000 => {
+                    Location::PrgRom(bank * BANK_SIZE + addr as usize % BANK_SIZE)
+                }
+                _ => Location::Cpu(addr),
+            };
+            self.insert(location, name);
+        }
+        Ok(())
+    }
+
+    /// Parses Mesen's labels. Lines look like `P:0010:reset:comment`, where the letter is the
+    /// kind of memory and the number is the offset into it. Mesen 2 names the memory instead,
+    /// like `NesPrgRom:0010:reset`. Labels in memory this emulator doesn't have are skipped.
+    pub fn parse_mlb(&mut self, text: &str) -> Result<(), String> {
+        for (i, line) in text.lines().enumerate() {
+            let mut parts = line.trim_end().splitn(4, ':');
+            let (Some(kind), Some(offset), Some(name)) = (parts.next(), parts.next(), parts.next())
+            else {
+                continue;
+            };
+            // Labels can cover a range, like `P:0010-001F:table`.
+            let offset = offset.split('-').next().unwrap_or("");
+            let offset = usize::from_str_radix(offset, 16)
+                .map_err(|_| format!("Line {}: invalid offset {}", i + 1, offset))?;
+            if name.is_empty() {
+                continue;
+            }
+
+            let location = match kind {
+                "P" | "NesPrgRom" => Location::PrgRom(offset),
+                "R" | "NesInternalRam" | "G" | "NesMemory" => Location::Cpu(offset as u16),
+                "S" | "W" | "NesSaveRam" | "NesWorkRam" => Location::Cpu(0x6000 + offset as u16),
+                _ => continue,
+            };
+            self.insert(location, name);
+        }
+        Ok(())
+    }
+}
+
+/// Splits `id=0,name="reset",val=0x8000` into keys and values, without the quotes.
+fn dbg_fields(text: &str) -> HashMap<&str, &str> {
+    let mut fields = HashMap::new();
+    let mut rest = text.trim();
+    while let Some((key, value)) = rest.split_once('=') {
+        let (value, next) = match value.strip_prefix('"') {
+            Some(quoted) => {
+                let end = quoted.find('"').unwrap_or(quoted.len());
+                let after = quoted[end..].trim_start_matches('"');
+                (&quoted[..end], after.strip_prefix(',').unwrap_or(after))
+            }
+            None => value.split_once(',').unwrap_or((value, "")),
+        };
+        fields.insert(key.trim(), value);
+        rest = next;
+    }
+    fields
+}
+
+fn parse_dbg_number(text: &str) -> Option<usize> {
+    match text.strip_prefix("0x") {
+        Some(hex) => usize::from_str_radix(hex, 16).ok(),
+        None => text.parse().ok(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cartridge::{Cartridge, Mirroring};
+
+    /// A CPU with a 32 KB ROM, so $8000 and $C000 are different banks.
+    fn cpu_with_rom() -> CPU {
+        let mut prg_rom = vec![0; 0x8000];
+        prg_rom[0x7FFC] = 0x00;
+        prg_rom[0x7FFD] = 0x80;
+        let mut cpu = CPU::new();
+        cpu.load_cartridge(&Cartridge {
+            prg_rom,
+            chr_rom: Vec::new(),
+            mapper: 0,
+            mirroring: Mirroring::Horizontal,
+            battery: false,
+        })
+        .unwrap();
+        cpu
+    }
+
+    #[test]
+    fn test_dbg() {
+        let text = r#"version	major=2,minor=0
+seg	id=0,name="ZEROPAGE",start=0x000000,size=0x0002,addrsize=zeropage,type=rw
+seg	id=1,name="CODE",start=0x00C000,size=0x0100,addrsize=absolute,type=ro,oname="game, final.nes",ooffs=16400
+sym	id=0,name="frame",addrsize=zeropage,scope=0,def=1,val=0x1,seg=0,type=lab
+sym	id=1,name="reset",addrsize=absolute,scope=0,def=2,ref=5,val=0xC010,seg=1,type=lab
+sym	id=2,name="PPUCTRL",addrsize=absolute,scope=0,def=3,val=0x2000,type=equ
+"#;
+        let mut symbols = Symbols::new();
+        symbols.parse_dbg(text).unwrap();
+        let cpu = cpu_with_rom();
+
+        assert_eq!(symbols.len(), 2);
+        assert_eq!(symbols.label(&cpu, 0x0001), Some("frame"));
+        // $C000 is at $4010 in the file, which is $4000 into the ROM after the header.
+        assert_eq!(symbols.label(&cpu, 0xC010), Some("reset"));
+        assert_eq!(symbols.label(&cpu, 0x8010), None);
+        assert_eq!(symbols.address(&cpu, "reset"), Some(0xC010));
+        assert_eq!(symbols.label(&cpu, 0x2000), None);
+
+        assert!(symbols.parse_dbg("sym\tid=3,name=\"x\",type=lab").is_err());
+    }
+
+    #[test]
+    fn test_nl() {
+        let mut symbols = Symbols::new();
+        symbols
+            .parse_nl(
+                "$0300/10#buffer#The buffer\n\\more comment\n$0400##Only a comment\n",
+                None,
+            )
+            .unwrap();
+        symbols.parse_nl("$8000#bank0#\n", Some(0)).unwrap();
+        symbols.parse_nl("$C000#bank1#\n", Some(1)).unwrap();
+        let cpu = cpu_with_rom();
+
+        assert_eq!(symbols.label(&cpu, 0x0300), Some("buffer"));
+        assert_eq!(symbols.label(&cpu, 0x0400), None);
+        assert_eq!(symbols.label(&cpu, 0x8000), Some("bank0"));
+        assert_eq!(symbols.label(&cpu, 0xC000), Some("bank1"));
+        assert!(symbols.parse_nl("$XYZ#oops#\n", None).is_err());
+    }
+
+    #[test]
+    fn test_mlb() {
+        let mut symbols = Symbols::new();
+        symbols
+            .parse_mlb(
+                "P:4010:reset:Where it starts\nR:0010-001F:table\nS:0000:save\nG:2002:PPUSTATUS\nC:0000:tiles\nP:0020::comment\n",
+            )
+            .unwrap();
+        symbols.parse_mlb("NesPrgRom:0000:start\n").unwrap();
+        let cpu = cpu_with_rom();
+
+        assert_eq!(symbols.len(), 5);
+        assert_eq!(symbols.label(&cpu, 0xC010), Some("reset"));
+        assert_eq!(symbols.label(&cpu, 0x8000), Some("start"));
+        assert_eq!(symbols.label(&cpu, 0x0010), Some("table"));
+        assert_eq!(symbols.label(&cpu, 0x6000), Some("save"));
+        assert_eq!(symbols.label(&cpu, 0x2002), Some("PPUSTATUS"));
+    }
+
+    #[test]
+    fn test_for_rom() {
+        let dir = std::env::temp_dir().join(format!("symbols-test-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let rom = dir.join("game.nes");
+        std::fs::write(dir.join("game.nes.ram.nl"), "$0000#zero#\n").unwrap();
+        std::fs::write(dir.join("game.nes.0.nl"), "$8000#first#\n").unwrap();
+        std::fs::write(dir.join("game.nes.1.nl"), "$C000#second#\n").unwrap();
+        std::fs::write(dir.join("game.mlb"), "P:0001:after_first\n").unwrap();
+
+        let symbols = Symbols::for_rom(&rom).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+        let cpu = cpu_with_rom();
+        assert_eq!(symbols.len(), 4);
+        assert_eq!(symbols.label(&cpu, 0xC000), Some("second"));
+        assert_eq!(symbols.label(&cpu, 0x8001), Some("after_first"));
+    }
+}
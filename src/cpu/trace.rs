@@ -0,0 +1,405 @@
+//! Tracing what the CPU runs, one line per instruction.
+//!
+//! `CPU::trace` describes the next instruction, and a `Tracer` logs those lines as the CPU runs.
+//! It's called before every step with `run_with_callback`:
+//!
+//! ```no_run
+//! # use nes_emulator::cpu::{trace::Tracer, CPU};
+//! # let mut cpu = CPU::new();
+//! let mut tracer = Tracer::to_writer(std::io::stdout()).after_breakpoint(0xC000);
+//! cpu.run_with_callback(|cpu| tracer.log(cpu).unwrap());
+//! ```
+
+use std::collections::VecDeque;
+use std::io::{self, Write};
+use std::ops::RangeInclusive;
+
+use super::addressing_mode::AddressingMode;
+use super::op_codes;
+use super::symbols::Symbols;
+use super::CPU;
+
+/// Bit 5 of the status register doesn't exist in the CPU, but it always reads as set.
+const UNUSED_FLAG: u8 = 0b0010_0000;
+
+impl CPU {
+    /// Describes the instruction that is about to run and the state of the registers, in the same
+    /// format as the `nestest.log` made by Nintendulator:
+    ///
+    /// ```text
+    /// C000  4C F5 C5  JMP $C5F5                       A:00 X:00 Y:00 P:24 SP:FD PPU:  0, 21 CYC:7
+    /// ```
+    ///
+    /// Memory is read with `peek`, so tracing doesn't change anything.
+    pub fn trace(&self) -> String {
+        self.trace_line(None)
+    }
+
+    /// Same as `trace`, but the addresses in the operand that have a label in `symbols` are shown
+    /// as the label, like `JMP reset`.
+    pub fn trace_with_symbols(&self, symbols: &Symbols) -> String {
+        self.trace_line(Some(symbols))
+    }
+
+    fn trace_line(&self, symbols: Option<&Symbols>) -> String {
+        let pc = self.pc;
+        let code = self.peek(pc);
+        let op = op_codes::OP_CODES[code as usize];
+        let size = op.map_or(1, |op| op.size);
+
+        let bytes: Vec<String> = (0..size)
+            .map(|i| format!("{:02X}", self.peek(pc.wrapping_add(i))))
+            .collect();
+        let (name, operand) = match op {
+            Some(op) => (op.name, self.trace_operand(op, symbols)),
+            None => ("???", String::new()),
+        };
+
+        let asm = format!("{:04X}  {:8} {:>4} {}", pc, bytes.join(" "), name, operand);
+        format!(
+            "{:47} A:{:02X} X:{:02X} Y:{:02X} P:{:02X} SP:{:02X} PPU:{:3},{:3} CYC:{}",
+            asm,
+            self.reg_a,
+            self.reg_x,
+            self.reg_y,
+            self.status.bits() | UNUSED_FLAG,
+            self.sp,
+            self.scanline(),
+            self.dot(),
+            self.cycles
+        )
+    }
+
+    /// The operand of the instruction at the program counter, along with the address it ends up
+    /// using and the value there.
+    fn trace_operand(&self, op: op_codes::OpCode, symbols: Option<&Symbols>) -> String {
+        let arg = self.pc.wrapping_add(1);
+        let byte = self.peek(arg);
+        let word = self.peek_u16(arg);
+        let label = |addr: u16| symbols.and_then(|symbols| symbols.label(self, addr));
+        let zero_page = label(byte as u16).map_or_else(|| format!("${:02X}", byte), str::to_string);
+        let absolute = label(word).map_or_else(|| format!("${:04X}", word), str::to_string);
+
+        match op.addr_mode {
+            AddressingMode::Immediate => format!("#${:02X}", byte),
+            AddressingMode::ZeroPage => {
+                format!("{} = {:02X}", zero_page, self.peek(byte as u16))
+            }
+            AddressingMode::ZeroPageX | AddressingMode::ZeroPageY => {
+                let (register, index) = if op.addr_mode == AddressingMode::ZeroPageX {
+                    ("X", self.reg_x)
+                } else {
+                    ("Y", self.reg_y)
+                };
+                let addr = byte.wrapping_add(index) as u16;
+                format!(
+                    "{},{} @ {:02X} = {:02X}",
+                    zero_page,
+                    register,
+                    addr,
+                    self.peek(addr)
+                )
+            }
+            // Jumps go to the address instead of reading from it.
+            AddressingMode::Absolute
+                if op.code == op_codes::JMP_ABSOLUTE || op.code == op_codes::JSR =>
+            {
+                absolute
+            }
+            AddressingMode::Absolute => format!("{} = {:02X}", absolute, self.peek(word)),
+            AddressingMode::AbsoluteX | AddressingMode::AbsoluteY => {
+                let (register, index) = if op.addr_mode == AddressingMode::AbsoluteX {
+                    ("X", self.reg_x)
+                } else {
+                    ("Y", self.reg_y)
+                };
+                let addr = word.wrapping_add(index as u16);
+                format!(
+                    "{},{} @ {:04X} = {:02X}",
+                    absolute,
+                    register,
+                    addr,
+                    self.peek(addr)
+                )
+            }
+            AddressingMode::Indirect => {
+                // The high byte of the pointer doesn't carry over to the next page.
+                let hi_addr = (word & 0xFF00) | (word.wrapping_add(1) & 0x00FF);
+                let target = u16::from_le_bytes([self.peek(word), self.peek(hi_addr)]);
+                format!("({}) = {:04X}", absolute, target)
+            }
+            AddressingMode::IndirectX => {
+                let ptr = byte.wrapping_add(self.reg_x);
+                let addr = self.peek_zero_page_u16(ptr);
+                format!(
+                    "({},X) @ {:02X} = {:04X} = {:02X}",
+                    zero_page,
+                    ptr,
+                    addr,
+                    self.peek(addr)
+                )
+            }
+            AddressingMode::IndirectY => {
+                let base = self.peek_zero_page_u16(byte);
+                let addr = base.wrapping_add(self.reg_y as u16);
+                format!(
+                    "({}),Y = {:04X} @ {:04X} = {:02X}",
+                    zero_page,
+                    base,
+                    addr,
+                    self.peek(addr)
+                )
+            }
+            // Branches are the only two byte instructions without an addressing mode.
+            AddressingMode::NoneAddressing if op.size == 2 => {
+                let target = arg.wrapping_add(1).wrapping_add(byte as i8 as u16);
+                label(target).map_or_else(|| format!("${:04X}", target), str::to_string)
+            }
+            AddressingMode::NoneAddressing => match op.code {
+                op_codes::ASL_ACCUMULATOR
+                | op_codes::LSR_ACCUMULATOR
+                | op_codes::ROL_ACCUMULATOR
+                | op_codes::ROR_ACCUMULATOR => "A".to_string(),
+                _ => String::new(),
+            },
+        }
+    }
+
+    /// Reads a pointer from the zero page, the high byte wraps around to $00.
+    fn peek_zero_page_u16(&self, addr: u8) -> u16 {
+        u16::from_le_bytes([
+            self.peek(addr as u16),
+            self.peek(addr.wrapping_add(1) as u16),
+        ])
+    }
+}
+
+/// Where the lines of a `Tracer` end up.
+enum Output {
+    Writer(Box<dyn Write + Send>),
+    /// Only the last `capacity` lines are kept, so a long run doesn't use up all the memory.
+    Ring {
+        lines: VecDeque<String>,
+        capacity: usize,
+    },
+}
+
+/// Logs every instruction the CPU runs that gets through the filters.
+pub struct Tracer {
+    output: Output,
+    range: Option<RangeInclusive<u16>>,
+    bank: Option<usize>,
+    /// Nothing is logged until the CPU gets to this address.
+    breakpoint: Option<u16>,
+    started: bool,
+    symbols: Option<Symbols>,
+}
+
+impl Tracer {
+    /// Writes every line to `writer`, like a file or stdout.
+    pub fn to_writer<W: Write + Send + 'static>(writer: W) -> Tracer {
+        Tracer::new(Output::Writer(Box::new(writer)))
+    }
+
+    /// Keeps the last `capacity` lines in memory, meant for showing what led up to a crash.
+    pub fn ring_buffer(capacity: usize) -> Tracer {
+        Tracer::new(Output::Ring {
+            lines: VecDeque::with_capacity(capacity),
+            capacity,
+        })
+    }
+
+    fn new(output: Output) -> Tracer {
+        Tracer {
+            output,
+            range: None,
+            bank: None,
+            breakpoint: None,
+            started: true,
+            symbols: None,
+        }
+    }
+
+    /// Only logs instructions within `range`.
+    pub fn in_range(mut self, range: RangeInclusive<u16>) -> Tracer {
+        self.range = Some(range);
+        self
+    }
+
+    /// Only logs instructions in the PRG ROM bank `bank`, see `CPU::prg_bank`.
+    pub fn in_bank(mut self, bank: usize) -> Tracer {
+        self.bank = Some(bank);
+        self
+    }
+
+    /// Doesn't log anything until the CPU gets to `addr`, everything after that is logged.
+    pub fn after_breakpoint(mut self, addr: u16) -> Tracer {
+        self.breakpoint = Some(addr);
+        self.started = false;
+        self
+    }
+
+    /// Shows addresses with a label in `symbols` as the label, and the label of an instruction on
+    /// its own line before it.
+    pub fn with_symbols(mut self, symbols: Symbols) -> Tracer {
+        self.symbols = Some(symbols);
+        self
+    }
+
+    /// Logs the instruction the CPU is about to run, if it gets through the filters.
+    pub fn log(&mut self, cpu: &CPU) -> io::Result<()> {
+        if !self.started && self.breakpoint == Some(cpu.pc) {
+            self.started = true;
+        }
+        let in_range = self.range.as_ref().is_none_or(|r| r.contains(&cpu.pc));
+        let in_bank = self.bank.is_none() || cpu.prg_bank(cpu.pc) == self.bank;
+        if !(self.started && in_range && in_bank) {
+            return Ok(());
+        }
+
+        let (label, line) = match &self.symbols {
+            Some(symbols) => (
+                symbols
+                    .label(cpu, cpu.pc)
+                    .map(|label| format!("{}:", label)),
+                cpu.trace_with_symbols(symbols),
+            ),
+            None => (None, cpu.trace()),
+        };
+        if let Some(label) = label {
+            self.write_line(label)?;
+        }
+        self.write_line(line)
+    }
+
+    fn write_line(&mut self, line: String) -> io::Result<()> {
+        match &mut self.output {
+            Output::Writer(writer) => writeln!(writer, "{}", line),
+            Output::Ring { lines, capacity } => {
+                if *capacity > 0 {
+                    if lines.len() == *capacity {
+                        lines.pop_front();
+                    }
+                    lines.push_back(line);
+                }
+                Ok(())
+            }
+        }
+    }
+
+    /// The lines kept by a ring buffer, oldest first. Empty when writing to a writer.
+    pub fn lines(&self) -> impl Iterator<Item = &str> {
+        let lines = match &self.output {
+            Output::Ring { lines, .. } => Some(lines.iter().map(String::as_str)),
+            Output::Writer(_) => None,
+        };
+        lines.into_iter().flatten()
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        match &mut self.output {
+            Output::Writer(writer) => writer.flush(),
+            Output::Ring { .. } => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cpu::symbols::Location;
+
+    #[test]
+    fn test_trace() {
+        let mut cpu = CPU::new();
+        cpu.load(vec![
+            op_codes::LDA_INDIRECT_Y,
+            0x10,
+            op_codes::JMP_ABSOLUTE,
+            0xF5,
+            0xC5,
+        ]);
+        cpu.reset();
+        cpu.reg_y = 0x02;
+        cpu.mem_write_u16(0x10, 0x0200);
+        cpu.mem_write(0x0202, 0x5A);
+
+        assert_eq!(
+            cpu.trace(),
+            "8000  B1 10     LDA ($10),Y = 0200 @ 0202 = 5A  A:00 X:00 Y:02 P:20 SP:FD PPU:  0,  0 CYC:0"
+        );
+        cpu.run_step();
+        assert_eq!(
+            cpu.trace(),
+            "8002  4C F5 C5  JMP $C5F5                       A:5A X:00 Y:02 P:20 SP:FD PPU:  0, 15 CYC:5"
+        );
+    }
+
+    /// A writer that can be looked at after it's been given to the tracer.
+    #[derive(Clone, Default)]
+    struct Shared(std::sync::Arc<std::sync::Mutex<Vec<u8>>>);
+
+    impl Write for Shared {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    /// INX three times, then loops at $8003 while X counts down.
+    fn program() -> CPU {
+        let mut cpu = CPU::new();
+        cpu.load(vec![0xE8, 0xE8, 0xE8, 0xCA, 0xD0, 0xFD, 0x00]);
+        cpu.reset();
+        cpu
+    }
+
+    #[test]
+    fn test_tracer_filters() {
+        let output = Shared::default();
+        let mut tracer = Tracer::to_writer(output.clone())
+            .in_range(0x8003..=0x8004)
+            .after_breakpoint(0x8004);
+        let mut cpu = program();
+        cpu.run_with_callback(|cpu| tracer.log(cpu).unwrap());
+
+        let output = String::from_utf8(output.0.lock().unwrap().clone()).unwrap();
+        let pcs: Vec<&str> = output.lines().map(|line| &line[..4]).collect();
+        assert_eq!(pcs, ["8004", "8003", "8004", "8003", "8004"]);
+
+        // Raw programs aren't in a bank.
+        let mut tracer = Tracer::ring_buffer(10).in_bank(0);
+        let mut cpu = program();
+        cpu.run_with_callback(|cpu| tracer.log(cpu).unwrap());
+        assert_eq!(tracer.lines().count(), 0);
+    }
+
+    #[test]
+    fn test_ring_buffer() {
+        let mut tracer = Tracer::ring_buffer(2);
+        let mut cpu = program();
+        cpu.run_with_callback(|cpu| tracer.log(cpu).unwrap());
+
+        let lines: Vec<&str> = tracer.lines().collect();
+        assert_eq!(lines.len(), 2);
+        assert!(lines[0].starts_with("8004  D0 FD     BNE $8003"));
+        assert!(lines[1].starts_with("8006  00        BRK"));
+    }
+
+    #[test]
+    fn test_symbols() {
+        let mut symbols = Symbols::new();
+        symbols.insert(Location::Cpu(0x8003), "loop");
+        let mut tracer = Tracer::ring_buffer(4).with_symbols(symbols);
+        let mut cpu = program();
+        cpu.run_with_callback(|cpu| tracer.log(cpu).unwrap());
+
+        let lines: Vec<&str> = tracer.lines().collect();
+        assert_eq!(lines[0], "loop:");
+        assert!(lines[1].starts_with("8003  CA        DEX"));
+        assert!(lines[2].starts_with("8004  D0 FD     BNE loop"));
+    }
+}
@@ -0,0 +1,196 @@
+//! Watchpoints, which catch the CPU reading or writing memory.
+//!
+//! Every read and write that hits a watchpoint is kept as a `WatchHit` until it's taken with
+//! `CPU::take_watch_hits`, the debugger does that before every instruction. Without any
+//! watchpoints the only cost is checking that the list is empty.
+
+use std::fmt;
+use std::ops::RangeInclusive;
+
+use super::CPU;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Access {
+    Read,
+    Write,
+    /// Only used for watchpoints, a hit is always one or the other.
+    ReadWrite,
+}
+
+impl Access {
+    fn matches(self, access: Access) -> bool {
+        self == Access::ReadWrite || self == access
+    }
+}
+
+/// What happens when a watchpoint is hit.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum WatchAction {
+    /// Stops the CPU after the instruction.
+    Break,
+    /// Only logs the access and keeps going.
+    Log,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Watchpoint {
+    pub id: usize,
+    pub range: RangeInclusive<u16>,
+    pub access: Access,
+    pub action: WatchAction,
+}
+
+impl fmt::Display for Watchpoint {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}: ${:04X}", self.id, self.range.start())?;
+        if self.range.end() != self.range.start() {
+            write!(f, "-${:04X}", self.range.end())?;
+        }
+        let access = match self.access {
+            Access::Read => "reads",
+            Access::Write => "writes",
+            Access::ReadWrite => "reads and writes",
+        };
+        let action = match self.action {
+            WatchAction::Break => "break on",
+            WatchAction::Log => "log",
+        };
+        write!(f, " {} {}", action, access)
+    }
+}
+
+/// A read or write that hit a watchpoint.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct WatchHit {
+    /// The id of the watchpoint.
+    pub id: usize,
+    pub action: WatchAction,
+    /// Where the instruction that did it starts.
+    pub pc: u16,
+    pub addr: u16,
+    pub value: u8,
+    /// Either `Read` or `Write`.
+    pub access: Access,
+}
+
+impl fmt::Display for WatchHit {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let (verb, preposition) = match self.access {
+            Access::Write => ("wrote", "to"),
+            _ => ("read", "from"),
+        };
+        write!(
+            f,
+            "Watchpoint {}: ${:04X} {} ${:02X} {} ${:04X}",
+            self.id, self.pc, verb, self.value, preposition, self.addr
+        )
+    }
+}
+
+impl CPU {
+    /// Adds a watchpoint and returns its id.
+    pub fn add_watchpoint(
+        &mut self,
+        range: RangeInclusive<u16>,
+        access: Access,
+        action: WatchAction,
+    ) -> usize {
+        let id = self.next_watch_id;
+        self.next_watch_id += 1;
+        self.watchpoints.push(Watchpoint {
+            id,
+            range,
+            access,
+            action,
+        });
+        id
+    }
+
+    /// Removes a watchpoint, returns false if there isn't one with that id.
+    pub fn remove_watchpoint(&mut self, id: usize) -> bool {
+        let len = self.watchpoints.len();
+        self.watchpoints.retain(|w| w.id != id);
+        self.watchpoints.len() != len
+    }
+
+    pub fn watchpoints(&self) -> &[Watchpoint] {
+        &self.watchpoints
+    }
+
+    /// The watchpoints that were hit since the last time, oldest first.
+    pub fn take_watch_hits(&mut self) -> Vec<WatchHit> {
+        std::mem::take(&mut self.watch_hits)
+    }
+
+    /// Called by `mem_read` and `mem_write` when there are watchpoints.
+    #[cold]
+    pub(super) fn watch(&mut self, addr: u16, value: u8, access: Access) {
+        for watchpoint in &self.watchpoints {
+            if watchpoint.range.contains(&addr) && watchpoint.access.matches(access) {
+                self.watch_hits.push(WatchHit {
+                    id: watchpoint.id,
+                    action: watchpoint.action,
+                    pc: self.instruction_pc,
+                    addr,
+                    value,
+                    access,
+                });
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_watchpoints() {
+        let mut cpu = CPU::new();
+        // LDA #$05, STA $0200, LDA $0201, INC $0200, BRK
+        cpu.load(vec![
+            0xA9, 0x05, 0x8D, 0x00, 0x02, 0xAD, 0x01, 0x02, 0xEE, 0x00, 0x02, 0x00,
+        ]);
+        cpu.reset();
+        let writes = cpu.add_watchpoint(0x0200..=0x0200, Access::Write, WatchAction::Break);
+        let reads = cpu.add_watchpoint(0x0200..=0x02FF, Access::Read, WatchAction::Log);
+        cpu.run();
+
+        let hits = cpu.take_watch_hits();
+        let summary: Vec<_> = hits
+            .iter()
+            .map(|hit| (hit.id, hit.pc, hit.addr, hit.value, hit.access))
+            .collect();
+        assert_eq!(
+            summary,
+            vec![
+                (writes, 0x8002, 0x0200, 0x05, Access::Write),
+                (reads, 0x8005, 0x0201, 0x00, Access::Read),
+                (reads, 0x8008, 0x0200, 0x05, Access::Read),
+                (writes, 0x8008, 0x0200, 0x06, Access::Write),
+            ]
+        );
+        assert_eq!(
+            hits[0].to_string(),
+            "Watchpoint 1: $8002 wrote $05 to $0200"
+        );
+        assert!(cpu.take_watch_hits().is_empty());
+
+        // Looking at memory doesn't count, and neither does poking it.
+        cpu.peek(0x0200);
+        assert!(cpu.poke(0x0200, 0x07));
+        assert_eq!(cpu.peek(0x0200), 0x07);
+        assert!(cpu.take_watch_hits().is_empty());
+
+        assert!(cpu.remove_watchpoint(writes));
+        assert_eq!(cpu.watchpoints().len(), 1);
+        assert_eq!(cpu.watchpoints()[0].to_string(), "2: $0200-$02FF log reads");
+
+        // The id of the last watchpoint isn't given out again after it's removed.
+        assert!(cpu.remove_watchpoint(reads));
+        assert_eq!(
+            cpu.add_watchpoint(0x0300..=0x0300, Access::Write, WatchAction::Break),
+            3
+        );
+    }
+}
@@ -0,0 +1,957 @@
+//! A debugger that runs along with the CPU.
+//!
+//! `Debugger::check` is called before every instruction, usually from the callback of
+//! `CPU::run_with_callback`, and tells when the CPU should stop: at a breakpoint, or when a step
+//! is done. While stopped the CPU can be looked at and changed, and one of the stepping functions
+//! tells the debugger when to stop next. `Debugger::repl` does all of that from commands typed
+//! into a terminal.
+//!
+//! Breakpoints can have a condition, like `A == $20 && X > 3`. Conditions compare the registers
+//! `A`, `X`, `Y`, `SP`, `P` and `PC`, bytes of memory like `[$0300]`, and numbers, and can be
+//! combined with `&&`, `||`, `!` and parentheses. In the REPL labels work in place of numbers,
+//! like `[score] > 9`.
+//!
+//! Watchpoints on reads or writes of memory either stop the CPU after the instruction that hit
+//! them, or are logged and taken with `Debugger::take_log`.
+//!
+//! With a symbol file loaded through `Debugger::set_symbols`, labels can be used anywhere the
+//! REPL takes an address, and addresses are shown as labels.
+
+use crate::cpu::disassembler::disassemble_range_with_labels;
+use crate::cpu::symbols::Symbols;
+use crate::cpu::watch::{Access, WatchAction, WatchHit};
+use crate::cpu::CPU;
+
+use std::fmt;
+use std::io::{self, BufRead, Write};
+use std::str::FromStr;
+
+/// The op code of JSR, which is stepped over as a whole.
+const JSR: u8 = 0x20;
+
+/// How many instructions `dis` shows unless told otherwise.
+const DISASSEMBLE_LINES: usize = 10;
+
+/// How many bytes `mem` shows unless told otherwise.
+const MEMORY_BYTES: u16 = 64;
+
+const HELP: &str = "\
+Commands:
+  s, step [N]             Run N instructions [default: 1]
+  n, next                 Run an instruction, a JSR runs until the subroutine returns
+  out                     Run until the current subroutine returns
+  c, continue             Run until a breakpoint
+  to <ADDR>               Run until the CPU gets to ADDR
+  b, break <ADDR> [if <CONDITION>]
+  b, break if <CONDITION> Stop at ADDR, or anywhere, when the condition holds
+  delete <N>              Remove breakpoint N
+  bl, breakpoints         List the breakpoints
+  watch <ADDR>[-<END>] [r|w|rw] [log]
+                          Stop when memory is written to, or read with r, or log it instead
+  unwatch <N>             Remove watchpoint N
+  wl, watchpoints         List the watchpoints
+  r, regs                 Show the registers
+  mem <ADDR> [LEN]        Show memory
+  dis [ADDR] [N]          Disassemble N instructions from ADDR [default: PC]
+  set <REG> <VALUE>       Change A, X, Y, SP, P or PC
+  q, quit                 Quit the emulator
+Addresses can also be labels from a symbol file. An empty line runs the last command again.
+";
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Breakpoint {
+    pub id: usize,
+    /// Where to stop, `None` checks the condition before every instruction.
+    pub addr: Option<u16>,
+    pub condition: Option<Condition>,
+}
+
+impl Breakpoint {
+    fn hit(&self, cpu: &CPU) -> bool {
+        self.addr.is_none_or(|addr| addr == cpu.pc)
+            && self.condition.as_ref().is_none_or(|c| c.eval(cpu))
+    }
+}
+
+impl fmt::Display for Breakpoint {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:", self.id)?;
+        if let Some(addr) = self.addr {
+            write!(f, " ${:04X}", addr)?;
+        }
+        if let Some(condition) = &self.condition {
+            write!(f, " if {}", condition)?;
+        }
+        Ok(())
+    }
+}
+
+/// Why the debugger stopped the CPU.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Stop {
+    /// The breakpoint with this id was hit.
+    Breakpoint(usize),
+    /// A step, `out` or `to` is done.
+    Step,
+    /// `pause` was called.
+    Paused,
+    /// The last instruction hit a watchpoint.
+    Watchpoint(WatchHit),
+}
+
+/// When to stop next, besides the breakpoints.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Mode {
+    Running,
+    Paused,
+    /// Stops after this many more instructions.
+    Step(u32),
+    /// Stops when the JSR at the start returns, that is when the CPU gets to `addr` with the
+    /// stack back where it was.
+    StepOver {
+        addr: u16,
+        sp: u8,
+    },
+    /// Stops once the stack is above `sp`, which happens when the RTS of the current subroutine
+    /// pops the return address.
+    StepOut {
+        sp: u8,
+    },
+    RunTo(u16),
+}
+
+#[derive(Clone, Debug)]
+pub struct Debugger {
+    breakpoints: Vec<Breakpoint>,
+    next_id: usize,
+    mode: Mode,
+    /// The last command the REPL ran, an empty line runs it again.
+    last_command: String,
+    /// The watchpoints hit that only log.
+    log: Vec<WatchHit>,
+    symbols: Symbols,
+}
+
+impl Default for Debugger {
+    fn default() -> Self {
+        Debugger::new()
+    }
+}
+
+impl Debugger {
+    pub fn new() -> Debugger {
+        Debugger {
+            breakpoints: Vec::new(),
+            next_id: 1,
+            mode: Mode::Running,
+            last_command: String::new(),
+            log: Vec::new(),
+            symbols: Symbols::new(),
+        }
+    }
+
+    /// Adds a breakpoint and returns its id.
+    pub fn add_breakpoint(&mut self, addr: Option<u16>, condition: Option<Condition>) -> usize {
+        let id = self.next_id;
+        self.next_id += 1;
+        self.breakpoints.push(Breakpoint {
+            id,
+            addr,
+            condition,
+        });
+        id
+    }
+
+    /// Removes a breakpoint, returns false if there isn't one with that id.
+    pub fn remove_breakpoint(&mut self, id: usize) -> bool {
+        let len = self.breakpoints.len();
+        self.breakpoints.retain(|b| b.id != id);
+        self.breakpoints.len() != len
+    }
+
+    pub fn breakpoints(&self) -> &[Breakpoint] {
+        &self.breakpoints
+    }
+
+    /// Stops before the next instruction.
+    pub fn pause(&mut self) {
+        self.mode = Mode::Paused;
+    }
+
+    /// Runs until a breakpoint.
+    pub fn resume(&mut self) {
+        self.mode = Mode::Running;
+    }
+
+    /// Runs `count` instructions.
+    pub fn step_into(&mut self, count: u32) {
+        self.mode = Mode::Step(count.max(1));
+    }
+
+    /// Runs one instruction, or a whole subroutine if it's a JSR.
+    pub fn step_over(&mut self, cpu: &CPU) {
+        self.mode = if cpu.peek(cpu.pc) == JSR {
+            Mode::StepOver {
+                addr: cpu.pc.wrapping_add(3),
+                sp: cpu.sp,
+            }
+        } else {
+            Mode::Step(1)
+        };
+    }
+
+    /// Runs until the current subroutine returns.
+    pub fn step_out(&mut self, cpu: &CPU) {
+        self.mode = Mode::StepOut { sp: cpu.sp };
+    }
+
+    /// Runs until the CPU gets to `addr`.
+    pub fn run_to(&mut self, addr: u16) {
+        self.mode = Mode::RunTo(addr);
+    }
+
+    /// The labels the REPL uses for addresses.
+    pub fn set_symbols(&mut self, symbols: Symbols) {
+        self.symbols = symbols;
+    }
+
+    /// The watchpoints that were hit and only log, since the last time.
+    pub fn take_log(&mut self) -> Vec<WatchHit> {
+        std::mem::take(&mut self.log)
+    }
+
+    /// Called before every instruction, returns why the CPU should stop before running the
+    /// instruction at the program counter, if it should. The debugger keeps running after a
+    /// stop unless it's told how to step.
+    pub fn check(&mut self, cpu: &mut CPU) -> Option<Stop> {
+        let mut watch_stop = None;
+        for hit in cpu.take_watch_hits() {
+            match hit.action {
+                WatchAction::Break => watch_stop = watch_stop.or(Some(Stop::Watchpoint(hit))),
+                WatchAction::Log => self.log.push(hit),
+            }
+        }
+
+        let step_done = match &mut self.mode {
+            Mode::Running => false,
+            Mode::Paused => {
+                self.mode = Mode::Running;
+                return Some(Stop::Paused);
+            }
+            Mode::Step(count) => {
+                *count -= 1;
+                *count == 0
+            }
+            Mode::StepOver { addr, sp } => cpu.pc == *addr && cpu.sp >= *sp,
+            Mode::StepOut { sp } => cpu.sp > *sp,
+            Mode::RunTo(addr) => cpu.pc == *addr,
+        };
+
+        let stop = match self.breakpoints.iter().find(|b| b.hit(cpu)) {
+            Some(breakpoint) => Some(Stop::Breakpoint(breakpoint.id)),
+            None if watch_stop.is_some() => watch_stop,
+            None if step_done => Some(Stop::Step),
+            None => None,
+        };
+        if stop.is_some() {
+            self.mode = Mode::Running;
+        }
+        stop
+    }
+
+    /// Shows why the CPU stopped and reads commands from `input` until one of them lets the CPU
+    /// run again. Returns false if the emulator should quit, which also happens when `input`
+    /// ends.
+    pub fn repl<R: BufRead, W: Write>(
+        &mut self,
+        cpu: &mut CPU,
+        stop: Stop,
+        mut input: R,
+        mut output: W,
+    ) -> io::Result<bool> {
+        match stop {
+            Stop::Breakpoint(id) => writeln!(output, "Breakpoint {}", id)?,
+            Stop::Watchpoint(hit) => writeln!(output, "{}", hit)?,
+            Stop::Step | Stop::Paused => {}
+        }
+        if let Some(label) = self.symbols.label(cpu, cpu.pc) {
+            writeln!(output, "{}:", label)?;
+        }
+        writeln!(output, "{}", cpu.trace_with_symbols(&self.symbols))?;
+
+        loop {
+            write!(output, "> ")?;
+            output.flush()?;
+            let mut line = String::new();
+            if input.read_line(&mut line)? == 0 {
+                return Ok(false);
+            }
+            let line = match line.trim() {
+                "" => self.last_command.clone(),
+                line => line.to_string(),
+            };
+            self.last_command = line.clone();
+
+            match self.command(cpu, &line) {
+                Ok(Command::Run) => return Ok(true),
+                Ok(Command::Quit) => return Ok(false),
+                Ok(Command::Show(text)) => write!(output, "{}", text)?,
+                Err(err) => writeln!(output, "{}", err)?,
+            }
+        }
+    }
+
+    /// Runs a command from the REPL.
+    fn command(&mut self, cpu: &mut CPU, line: &str) -> Result<Command, String> {
+        let (name, args) = match line.split_once(char::is_whitespace) {
+            Some((name, args)) => (name, args.trim()),
+            None => (line, ""),
+        };
+        let mut words = args.split_whitespace();
+        let number = |word: Option<&str>, default: Option<u16>| match word {
+            Some(word) => parse_address(&self.symbols, cpu, word)
+                .ok_or_else(|| format!("Invalid number {}", word)),
+            None => default.ok_or_else(|| format!("{} needs an address", name)),
+        };
+
+        match name {
+            "s" | "step" => self.step_into(number(words.next(), Some(1))? as u32),
+            "n" | "next" => self.step_over(cpu),
+            "out" => self.step_out(cpu),
+            "c" | "continue" => self.resume(),
+            "to" => self.run_to(number(words.next(), None)?),
+            "b" | "break" => {
+                let (addr, condition) = match split_condition(args) {
+                    Some((addr, condition)) => (
+                        addr,
+                        Some(Condition::parse_with_symbols(
+                            condition,
+                            &self.symbols,
+                            cpu,
+                        )?),
+                    ),
+                    None => (args, None),
+                };
+                let addr = match addr {
+                    "" if condition.is_some() => None,
+                    addr => Some(parse_address(&self.symbols, cpu, addr).ok_or_else(|| {
+                        format!(
+                            "Invalid address {}, expected `break <ADDR> [if <COND>]`",
+                            addr
+                        )
+                    })?),
+                };
+                let id = self.add_breakpoint(addr, condition);
+                let breakpoint = self.breakpoints.iter().find(|b| b.id == id).unwrap();
+                return Ok(Command::Show(format!("Breakpoint {}\n", breakpoint)));
+            }
+            "delete" => {
+                let id = number(words.next(), None)? as usize;
+                if !self.remove_breakpoint(id) {
+                    return Err(format!("There is no breakpoint {}", id));
+                }
+                return Ok(Command::Show(String::new()));
+            }
+            "bl" | "breakpoints" => {
+                let list: String = self
+                    .breakpoints
+                    .iter()
+                    .map(|b| format!("{}\n", b))
+                    .collect();
+                return Ok(Command::Show(list));
+            }
+            "watch" => {
+                let range = words.next().unwrap_or("");
+                let (start, end) = range.split_once('-').unwrap_or((range, range));
+                let (start, end) = (number(Some(start), None)?, number(Some(end), None)?);
+                if start > end {
+                    return Err(format!("Invalid range {}", range));
+                }
+                let mut access = Access::Write;
+                let mut action = WatchAction::Break;
+                for word in words {
+                    match word {
+                        "r" => access = Access::Read,
+                        "w" => access = Access::Write,
+                        "rw" => access = Access::ReadWrite,
+                        "log" => action = WatchAction::Log,
+                        _ => return Err(format!("Unknown watch option {}", word)),
+                    }
+                }
+                let id = cpu.add_watchpoint(start..=end, access, action);
+                let watchpoint = cpu.watchpoints().iter().find(|w| w.id == id).unwrap();
+                return Ok(Command::Show(format!("Watchpoint {}\n", watchpoint)));
+            }
+            "unwatch" => {
+                let id = number(words.next(), None)? as usize;
+                if !cpu.remove_watchpoint(id) {
+                    return Err(format!("There is no watchpoint {}", id));
+                }
+                return Ok(Command::Show(String::new()));
+            }
+            "wl" | "watchpoints" => {
+                let list: String = cpu
+                    .watchpoints()
+                    .iter()
+                    .map(|w| format!("{}\n", w))
+                    .collect();
+                return Ok(Command::Show(list));
+            }
+            "r" | "regs" => {
+                let trace = cpu.trace_with_symbols(&self.symbols);
+                return Ok(Command::Show(format!("{}\n", trace)));
+            }
+            "mem" => {
+                let start = number(words.next(), None)?;
+                let len = number(words.next(), Some(MEMORY_BYTES))?;
+                return Ok(Command::Show(hex_dump(cpu, start, len)));
+            }
+            "dis" => {
+                let start = number(words.next(), Some(cpu.pc))?;
+                let count = number(words.next(), Some(DISASSEMBLE_LINES as u16))? as usize;
+                let label = |addr| self.symbols.label(cpu, addr);
+                let lines = disassemble_range_with_labels(
+                    |addr| cpu.peek(addr),
+                    |addr| label(addr).map(str::to_string),
+                    start..=0xFFFF,
+                );
+                let text = lines
+                    .iter()
+                    .take(count)
+                    .map(|(addr, text)| match label(*addr) {
+                        Some(label) => format!("{}:\n{:04X}  {}\n", label, addr, text),
+                        None => format!("{:04X}  {}\n", addr, text),
+                    })
+                    .collect();
+                return Ok(Command::Show(text));
+            }
+            "set" => {
+                let register = words.next().unwrap_or("").to_ascii_uppercase();
+                let value = number(words.next(), None)?;
+                match register.as_str() {
+                    "A" => cpu.reg_a = value as u8,
+                    "X" => cpu.reg_x = value as u8,
+                    "Y" => cpu.reg_y = value as u8,
+                    "SP" => cpu.sp = value as u8,
+                    "P" => cpu.status = crate::cpu::StatusFlags::from_bits_truncate(value as u8),
+                    "PC" => cpu.pc = value,
+                    _ => return Err(format!("Unknown register {}", register)),
+                }
+                return Ok(Command::Show(format!("{}\n", cpu.trace())));
+            }
+            "h" | "help" => return Ok(Command::Show(HELP.to_string())),
+            "q" | "quit" => return Ok(Command::Quit),
+            _ => return Err(format!("Unknown command {}, try help", name)),
+        }
+        Ok(Command::Run)
+    }
+}
+
+/// What the REPL does after a command.
+enum Command {
+    /// Lets the CPU run.
+    Run,
+    /// Shows the text and waits for the next command.
+    Show(String),
+    Quit,
+}
+
+/// Shows memory 16 bytes to a line.
+fn hex_dump(cpu: &CPU, start: u16, len: u16) -> String {
+    let mut text = String::new();
+    for line_start in (start as u32..start as u32 + len as u32).step_by(16) {
+        let line_end = (line_start + 16)
+            .min(start as u32 + len as u32)
+            .min(0x10000);
+        if line_start >= line_end {
+            break;
+        }
+        let bytes: Vec<String> = (line_start..line_end)
+            .map(|addr| format!("{:02X}", cpu.peek(addr as u16)))
+            .collect();
+        text += &format!("{:04X}  {}\n", line_start, bytes.join(" "));
+    }
+    text
+}
+
+/// Splits the arguments of `break` at the `if` word, the part before it is the address. `if`
+/// inside a label like `verify` doesn't count.
+fn split_condition(args: &str) -> Option<(&str, &str)> {
+    args.match_indices("if").find_map(|(i, _)| {
+        let (before, after) = (&args[..i], &args[i + 2..]);
+        let starts_word = before.is_empty() || before.ends_with(char::is_whitespace);
+        let ends_word = after.is_empty() || after.starts_with([' ', '\t', '(', '!', '[']);
+        (starts_word && ends_word).then(|| (before.trim(), after))
+    })
+}
+
+/// Parses an address that is either a number or a label.
+fn parse_address(symbols: &Symbols, cpu: &CPU, text: &str) -> Option<u16> {
+    parse_number(text).or_else(|| symbols.address(cpu, text))
+}
+
+/// Parses a number that is either decimal, hexadecimal starting with `$` or `0x`, or binary
+/// starting with `%`.
+fn parse_number(text: &str) -> Option<u16> {
+    if let Some(hex) = text.strip_prefix('$').or_else(|| text.strip_prefix("0x")) {
+        u16::from_str_radix(hex, 16).ok()
+    } else if let Some(binary) = text.strip_prefix('%') {
+        u16::from_str_radix(binary, 2).ok()
+    } else {
+        text.parse().ok()
+    }
+}
+
+/// Something a condition can compare.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Value {
+    A,
+    X,
+    Y,
+    Sp,
+    P,
+    Pc,
+    /// The byte at an address, read without side effects.
+    Memory(u16),
+    Number(u16),
+}
+
+impl Value {
+    fn read(self, cpu: &CPU) -> u16 {
+        match self {
+            Value::A => cpu.reg_a as u16,
+            Value::X => cpu.reg_x as u16,
+            Value::Y => cpu.reg_y as u16,
+            Value::Sp => cpu.sp as u16,
+            Value::P => cpu.status.bits() as u16,
+            Value::Pc => cpu.pc,
+            Value::Memory(addr) => cpu.peek(addr) as u16,
+            Value::Number(value) => value,
+        }
+    }
+}
+
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Value::A => write!(f, "A"),
+            Value::X => write!(f, "X"),
+            Value::Y => write!(f, "Y"),
+            Value::Sp => write!(f, "SP"),
+            Value::P => write!(f, "P"),
+            Value::Pc => write!(f, "PC"),
+            Value::Memory(addr) => write!(f, "[${:04X}]", addr),
+            Value::Number(value) => write!(f, "${:02X}", value),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Comparison {
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+}
+
+impl Comparison {
+    /// The operators, the longer ones first so `<=` isn't read as `<`.
+    const OPERATORS: [(&'static str, Comparison); 6] = [
+        ("==", Comparison::Eq),
+        ("!=", Comparison::Ne),
+        ("<=", Comparison::Le),
+        (">=", Comparison::Ge),
+        ("<", Comparison::Lt),
+        (">", Comparison::Gt),
+    ];
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Condition {
+    Compare(Value, Comparison, Value),
+    And(Box<Condition>, Box<Condition>),
+    Or(Box<Condition>, Box<Condition>),
+    Not(Box<Condition>),
+}
+
+impl Condition {
+    /// Parses a condition in which labels can be used in place of numbers.
+    pub fn parse_with_symbols(
+        text: &str,
+        symbols: &Symbols,
+        cpu: &CPU,
+    ) -> Result<Condition, String> {
+        Condition::parse(text, &|word| parse_address(symbols, cpu, word))
+    }
+
+    /// Parses a condition, `number` turns the words that aren't registers into numbers.
+    fn parse(text: &str, number: &dyn Fn(&str) -> Option<u16>) -> Result<Condition, String> {
+        let mut parser = ConditionParser {
+            text: text.trim(),
+            number,
+        };
+        let condition = parser.or()?;
+        if !parser.text.is_empty() {
+            return Err(format!("Unexpected {} in the condition", parser.text));
+        }
+        Ok(condition)
+    }
+
+    pub fn eval(&self, cpu: &CPU) -> bool {
+        match self {
+            Condition::Compare(a, comparison, b) => {
+                let (a, b) = (a.read(cpu), b.read(cpu));
+                match comparison {
+                    Comparison::Eq => a == b,
+                    Comparison::Ne => a != b,
+                    Comparison::Lt => a < b,
+                    Comparison::Le => a <= b,
+                    Comparison::Gt => a > b,
+                    Comparison::Ge => a >= b,
+                }
+            }
+            Condition::And(a, b) => a.eval(cpu) && b.eval(cpu),
+            Condition::Or(a, b) => a.eval(cpu) || b.eval(cpu),
+            Condition::Not(condition) => !condition.eval(cpu),
+        }
+    }
+}
+
+impl fmt::Display for Condition {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Condition::Compare(a, comparison, b) => {
+                let (op, _) = Comparison::OPERATORS
+                    .iter()
+                    .find(|(_, c)| c == comparison)
+                    .unwrap();
+                write!(f, "{} {} {}", a, op, b)
+            }
+            Condition::And(a, b) => write!(f, "({} && {})", a, b),
+            Condition::Or(a, b) => write!(f, "({} || {})", a, b),
+            Condition::Not(condition) => write!(f, "!({})", condition),
+        }
+    }
+}
+
+impl FromStr for Condition {
+    type Err = String;
+
+    fn from_str(text: &str) -> Result<Condition, String> {
+        Condition::parse(text, &parse_number)
+    }
+}
+
+/// Parses conditions, what's left to parse is kept in `text`.
+struct ConditionParser<'a> {
+    text: &'a str,
+    number: &'a dyn Fn(&str) -> Option<u16>,
+}
+
+impl ConditionParser<'_> {
+    fn or(&mut self) -> Result<Condition, String> {
+        let mut condition = self.and()?;
+        while self.eat("||") {
+            condition = Condition::Or(Box::new(condition), Box::new(self.and()?));
+        }
+        Ok(condition)
+    }
+
+    fn and(&mut self) -> Result<Condition, String> {
+        let mut condition = self.not()?;
+        while self.eat("&&") {
+            condition = Condition::And(Box::new(condition), Box::new(self.not()?));
+        }
+        Ok(condition)
+    }
+
+    fn not(&mut self) -> Result<Condition, String> {
+        if self.text.starts_with('!') && !self.text.starts_with("!=") {
+            self.eat("!");
+            return Ok(Condition::Not(Box::new(self.not()?)));
+        }
+        if self.eat("(") {
+            let condition = self.or()?;
+            if !self.eat(")") {
+                return Err("Missing ) in the condition".to_string());
+            }
+            return Ok(condition);
+        }
+
+        let a = self.value()?;
+        match Comparison::OPERATORS
+            .iter()
+            .find(|(op, _)| self.text.starts_with(op))
+        {
+            Some(&(op, comparison)) => {
+                self.eat(op);
+                Ok(Condition::Compare(a, comparison, self.value()?))
+            }
+            // A value on its own is true when it isn't zero.
+            None => Ok(Condition::Compare(a, Comparison::Ne, Value::Number(0))),
+        }
+    }
+
+    fn value(&mut self) -> Result<Value, String> {
+        if self.eat("[") {
+            let Value::Number(addr) = self.value()? else {
+                return Err("Expected an address in [ ]".to_string());
+            };
+            if !self.eat("]") {
+                return Err("Missing ] in the condition".to_string());
+            }
+            return Ok(Value::Memory(addr));
+        }
+
+        let len = self
+            .text
+            .find(|c: char| !(c.is_ascii_alphanumeric() || "$%_@.".contains(c)))
+            .unwrap_or(self.text.len());
+        let (word, rest) = self.text.split_at(len);
+        let value = match word.to_ascii_uppercase().as_str() {
+            "A" => Value::A,
+            "X" => Value::X,
+            "Y" => Value::Y,
+            "SP" | "S" => Value::Sp,
+            "P" => Value::P,
+            "PC" => Value::Pc,
+            "" => return Err("Expected a value in the condition".to_string()),
+            _ => {
+                Value::Number((self.number)(word).ok_or_else(|| format!("Invalid value {}", word))?)
+            }
+        };
+        self.text = rest.trim_start();
+        Ok(value)
+    }
+
+    /// Skips `token` if it's next.
+    fn eat(&mut self, token: &str) -> bool {
+        match self.text.strip_prefix(token) {
+            Some(rest) => {
+                self.text = rest.trim_start();
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cpu::symbols::Location;
+
+    /// Calls a subroutine at $8010 that calls another at $8020, then stops.
+    fn program() -> CPU {
+        let mut program = vec![0xEA; 0x30];
+        program[0x00..0x04].copy_from_slice(&[0x20, 0x10, 0x80, 0x00]); // JSR $8010, BRK
+        program[0x10..0x15].copy_from_slice(&[0xE8, 0x20, 0x20, 0x80, 0x60]); // INX, JSR $8020, RTS
+        program[0x20..0x23].copy_from_slice(&[0xC8, 0xC8, 0x60]); // INY, INY, RTS
+        let mut cpu = CPU::new();
+        cpu.load(program);
+        cpu.reset();
+        cpu
+    }
+
+    /// Runs until the debugger stops the CPU, or the program does.
+    fn run(debugger: &mut Debugger, cpu: &mut CPU) -> Option<Stop> {
+        loop {
+            if let Some(stop) = debugger.check(cpu) {
+                return Some(stop);
+            }
+            if cpu.run_step() {
+                return None;
+            }
+        }
+    }
+
+    #[test]
+    fn test_stepping() {
+        let mut cpu = program();
+        let mut debugger = Debugger::new();
+        debugger.pause();
+        assert_eq!(run(&mut debugger, &mut cpu), Some(Stop::Paused));
+        assert_eq!(cpu.pc, 0x8000);
+
+        debugger.step_into(1);
+        cpu.run_step();
+        assert_eq!(run(&mut debugger, &mut cpu), Some(Stop::Step));
+        assert_eq!(cpu.pc, 0x8010);
+
+        // Over the INX, then over the whole JSR.
+        debugger.step_over(&cpu);
+        cpu.run_step();
+        assert_eq!(run(&mut debugger, &mut cpu), Some(Stop::Step));
+        debugger.step_over(&cpu);
+        cpu.run_step();
+        assert_eq!(run(&mut debugger, &mut cpu), Some(Stop::Step));
+        assert_eq!((cpu.pc, cpu.reg_y), (0x8014, 2));
+
+        debugger.step_out(&cpu);
+        cpu.run_step();
+        assert_eq!(run(&mut debugger, &mut cpu), Some(Stop::Step));
+        assert_eq!(cpu.pc, 0x8003);
+    }
+
+    #[test]
+    fn test_step_out_of_nested_call() {
+        let mut cpu = program();
+        let mut debugger = Debugger::new();
+        debugger.run_to(0x8011);
+        assert_eq!(run(&mut debugger, &mut cpu), Some(Stop::Step));
+
+        // Stepping out of $8010 runs the whole call to $8020 on the way.
+        debugger.step_out(&cpu);
+        cpu.run_step();
+        assert_eq!(run(&mut debugger, &mut cpu), Some(Stop::Step));
+        assert_eq!((cpu.pc, cpu.reg_y), (0x8003, 2));
+    }
+
+    #[test]
+    fn test_breakpoints() {
+        let mut cpu = program();
+        let mut debugger = Debugger::new();
+        let id = debugger.add_breakpoint(Some(0x8021), None);
+        let conditional = debugger.add_breakpoint(None, Some("Y == 2 && X > 0".parse().unwrap()));
+
+        assert_eq!(run(&mut debugger, &mut cpu), Some(Stop::Breakpoint(id)));
+        assert_eq!(cpu.pc, 0x8021);
+        cpu.run_step();
+        assert_eq!(
+            run(&mut debugger, &mut cpu),
+            Some(Stop::Breakpoint(conditional))
+        );
+        assert_eq!(cpu.pc, 0x8022);
+
+        assert!(debugger.remove_breakpoint(conditional));
+        assert!(!debugger.remove_breakpoint(conditional));
+        cpu.run_step();
+        assert_eq!(run(&mut debugger, &mut cpu), None);
+    }
+
+    #[test]
+    fn test_conditions() {
+        let mut cpu = CPU::new();
+        cpu.reg_a = 0x20;
+        cpu.reg_x = 4;
+        cpu.mem_write(0x0300, 7);
+
+        let eval = |text: &str| text.parse::<Condition>().unwrap().eval(&cpu);
+        assert!(eval("A == $20 && X > 3"));
+        assert!(!eval("A != $20 || X <= 3"));
+        assert!(eval("[$0300] >= %111"));
+        assert!(eval("!(y) && (a == 32 || pc == 1)"));
+        assert!(eval("[$300]"));
+
+        let condition: Condition = "a==$20&&!(x<4)".parse().unwrap();
+        assert_eq!(condition.to_string(), "(A == $20 && !(X < $04))");
+        assert!("A ==".parse::<Condition>().is_err());
+        assert!("(A == 1".parse::<Condition>().is_err());
+        assert!("A == 1 B".parse::<Condition>().is_err());
+    }
+
+    #[test]
+    fn test_watchpoints() {
+        let mut cpu = program();
+        let mut debugger = Debugger::new();
+        let mut output = Vec::new();
+        let input = "watch $01F0-$01FF rw log\nwatch $0300\nwl\nc\n";
+        debugger
+            .repl(&mut cpu, Stop::Paused, input.as_bytes(), &mut output)
+            .unwrap();
+        let output = String::from_utf8(output).unwrap();
+        assert!(
+            output.contains("> 1: $01F0-$01FF log reads and writes\n2: $0300 break on writes\n")
+        );
+
+        // Nothing writes to $0300, but the JSRs and RTSs use the stack.
+        assert_eq!(run(&mut debugger, &mut cpu), None);
+        let log = debugger.take_log();
+        assert_eq!(log.len(), 8);
+        assert_eq!(log[0].to_string(), "Watchpoint 1: $8000 wrote $80 to $01FD");
+
+        // Stops right after the write.
+        let mut cpu = program();
+        let id = cpu.add_watchpoint(0x01FC..=0x01FC, Access::Write, WatchAction::Break);
+        let stop = run(&mut debugger, &mut cpu);
+        assert!(matches!(stop, Some(Stop::Watchpoint(hit)) if hit.id == id));
+        assert_eq!(cpu.pc, 0x8010);
+    }
+
+    #[test]
+    fn test_repl() {
+        let mut cpu = program();
+        let mut debugger = Debugger::new();
+        let input = "break $8020 if X == 1\nbl\nmem $8000 4\ndis $8010 2\nset a $42\nfoo\nc\n";
+        let mut output = Vec::new();
+
+        let running = debugger
+            .repl(&mut cpu, Stop::Paused, input.as_bytes(), &mut output)
+            .unwrap();
+        assert!(running);
+        assert_eq!(cpu.reg_a, 0x42);
+        let output = String::from_utf8(output).unwrap();
+        for expected in [
+            "Breakpoint 1: $8020 if X == $01\n",
+            "> 1: $8020 if X == $01\n",
+            "> 8000  20 10 80 00\n",
+            "> 8010  INX\n8011  JSR $8020\n",
+            "Unknown command foo, try help\n",
+        ] {
+            assert!(output.contains(expected), "{:?} in {}", expected, output);
+        }
+
+        assert_eq!(run(&mut debugger, &mut cpu), Some(Stop::Breakpoint(1)));
+
+        // An empty line runs `c` again, and the end of the input quits.
+        let mut output = Vec::new();
+        assert!(debugger
+            .repl(&mut cpu, Stop::Breakpoint(1), "\n".as_bytes(), &mut output)
+            .unwrap());
+        assert!(!debugger
+            .repl(&mut cpu, Stop::Step, "".as_bytes(), &mut output)
+            .unwrap());
+    }
+
+    #[test]
+    fn test_symbols() {
+        let mut cpu = program();
+        let mut debugger = Debugger::new();
+        let mut symbols = Symbols::new();
+        symbols.insert(Location::Cpu(0x8020), "sub");
+        symbols.insert(Location::Cpu(0x8010), "verify");
+        symbols.insert(Location::Cpu(0x0010), "lives");
+        debugger.set_symbols(symbols);
+
+        // `if` inside a label isn't where the condition starts.
+        let input = "b verify\nb verify if [lives] == 3\ndelete 1\ndelete 2\nbreak sub\ndis $8011 2\nto nowhere\nc\n";
+        let mut output = Vec::new();
+        debugger
+            .repl(&mut cpu, Stop::Paused, input.as_bytes(), &mut output)
+            .unwrap();
+        let output = String::from_utf8(output).unwrap();
+        for expected in [
+            "Breakpoint 1: $8010\n",
+            "Breakpoint 2: $8010 if [$0010] == $03\n",
+            "Breakpoint 3: $8020\n",
+            "> 8011  JSR sub\n",
+            "Invalid number nowhere\n",
+        ] {
+            assert!(output.contains(expected), "{:?} in {}", expected, output);
+        }
+
+        assert_eq!(run(&mut debugger, &mut cpu), Some(Stop::Breakpoint(3)));
+        let mut output = Vec::new();
+        debugger
+            .repl(&mut cpu, Stop::Breakpoint(3), "q\n".as_bytes(), &mut output)
+            .unwrap();
+        let output = String::from_utf8(output).unwrap();
+        assert!(
+            output.starts_with("Breakpoint 3\nsub:\n8020  "),
+            "{}",
+            output
+        );
+    }
+}
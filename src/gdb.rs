@@ -0,0 +1,497 @@
+//! A stub for GDB's remote serial protocol, so a debugger that knows the 6502 can be attached
+//! over TCP with `target remote localhost:<port>`.
+//!
+//! Every packet is `$<data>#<checksum>`, where the checksum is the sum of the data bytes as two
+//! hex digits, and is acknowledged with `+`. The stub supports reading and writing the registers
+//! (`g`, `G`, `p`, `P`) and memory (`m`, `M`), breakpoints and watchpoints (`Z0` to `Z4`),
+//! continuing (`c`), stepping (`s`) and stopping a running CPU with Ctrl-C. There's no standard
+//! register layout for the 6502, this one is A, X, Y, P and SP as one byte each, followed by PC
+//! as two bytes in little endian.
+
+use crate::cpu::watch::{Access, WatchAction};
+use crate::cpu::{StatusFlags, CPU};
+use crate::debugger::{Debugger, Stop};
+
+use std::io::{self, BufRead, BufReader, ErrorKind, Read, Write};
+use std::net::{TcpListener, TcpStream, ToSocketAddrs};
+
+/// Sent by GDB to stop a running CPU, outside of any packet.
+const INTERRUPT: u8 = 0x03;
+
+/// How many instructions run between checks for an interrupt from GDB.
+const POLL_INTERVAL: u32 = 1000;
+
+/// The signals in stop replies, a trap for breakpoints and steps and an interrupt for Ctrl-C.
+const SIGTRAP: u8 = 5;
+const SIGINT: u8 = 2;
+
+/// What the debugger sent.
+enum Packet {
+    Command(String),
+    Interrupt,
+}
+
+/// A breakpoint or watchpoint GDB asked for, so it can be found again when GDB removes it.
+struct Point {
+    /// 0 and 1 are breakpoints, 2 to 4 are write, read and access watchpoints.
+    kind: u8,
+    addr: u16,
+    /// The id of the breakpoint in the debugger or the watchpoint in the CPU.
+    id: usize,
+}
+
+pub struct GdbStub {
+    reader: BufReader<TcpStream>,
+    writer: TcpStream,
+    debugger: Debugger,
+    points: Vec<Point>,
+}
+
+impl GdbStub {
+    /// Waits for a debugger to connect on `addr`.
+    pub fn listen<A: ToSocketAddrs>(addr: A) -> io::Result<GdbStub> {
+        let (stream, _) = TcpListener::bind(addr)?.accept()?;
+        GdbStub::new(stream)
+    }
+
+    pub fn new(stream: TcpStream) -> io::Result<GdbStub> {
+        stream.set_nodelay(true)?;
+        Ok(GdbStub {
+            writer: stream.try_clone()?,
+            reader: BufReader::new(stream),
+            debugger: Debugger::new(),
+            points: Vec::new(),
+        })
+    }
+
+    /// Answers the debugger until it detaches or the connection closes.
+    pub fn serve(&mut self, cpu: &mut CPU) -> io::Result<()> {
+        self.serve_with_callback(cpu, |_| {})
+    }
+
+    /// Same as `serve`, but calls `callback` before every step like `CPU::run_with_callback`.
+    pub fn serve_with_callback<F>(&mut self, cpu: &mut CPU, mut callback: F) -> io::Result<()>
+    where
+        F: FnMut(&mut CPU),
+    {
+        loop {
+            let command = match self.read_packet()? {
+                Some(Packet::Command(command)) => command,
+                // Ctrl-C while already stopped.
+                Some(Packet::Interrupt) => {
+                    self.send(&format!("S{:02x}", SIGINT))?;
+                    continue;
+                }
+                None => return Ok(()),
+            };
+
+            let reply = match command.as_bytes().first() {
+                Some(b'?') => format!("S{:02x}", SIGTRAP),
+                Some(b'g') => encode(&registers(cpu)),
+                Some(b'G') => match decode(&command[1..]) {
+                    Some(bytes) if bytes.len() == 7 => {
+                        set_registers(cpu, &bytes);
+                        "OK".to_string()
+                    }
+                    _ => "E01".to_string(),
+                },
+                Some(b'p') => match usize::from_str_radix(&command[1..], 16) {
+                    Ok(n @ 0..=4) => encode(&registers(cpu)[n..n + 1]),
+                    Ok(5) => encode(&registers(cpu)[5..7]),
+                    _ => "E01".to_string(),
+                },
+                Some(b'P') => self.write_register(cpu, &command[1..]),
+                Some(b'm') => read_memory(cpu, &command[1..]),
+                Some(b'M') => write_memory(cpu, &command[1..]),
+                Some(b'Z') => self.insert_point(cpu, &command[1..]),
+                Some(b'z') => self.remove_point(cpu, &command[1..]),
+                Some(b'c') => {
+                    set_pc(cpu, &command[1..]);
+                    self.debugger.resume();
+                    self.run(cpu, &mut callback)?
+                }
+                Some(b's') => {
+                    set_pc(cpu, &command[1..]);
+                    self.debugger.step_into(1);
+                    self.run(cpu, &mut callback)?
+                }
+                Some(b'D') => {
+                    self.send("OK")?;
+                    return Ok(());
+                }
+                Some(b'k') => return Ok(()),
+                Some(b'H') => "OK".to_string(),
+                _ if command.starts_with("qSupported") => "PacketSize=4000".to_string(),
+                _ if command == "qAttached" => "1".to_string(),
+                // An empty reply tells GDB that the command isn't supported.
+                _ => String::new(),
+            };
+            self.send(&reply)?;
+        }
+    }
+
+    /// Runs the CPU until the debugger stops it, or GDB interrupts it. Returns the stop reply.
+    fn run<F>(&mut self, cpu: &mut CPU, callback: &mut F) -> io::Result<String>
+    where
+        F: FnMut(&mut CPU),
+    {
+        // The first instruction runs without checking the breakpoints, otherwise continuing
+        // from a breakpoint would stop right away.
+        callback(cpu);
+        let mut stopped = cpu.run_step();
+
+        self.reader.get_ref().set_nonblocking(true)?;
+        let mut steps = 0;
+        let reply = loop {
+            if stopped {
+                // A BRK.
+                break format!("S{:02x}", SIGTRAP);
+            }
+            match self.debugger.check(cpu) {
+                Some(Stop::Watchpoint(hit)) => {
+                    let kind = match self.points.iter().find(|p| p.kind >= 2 && p.id == hit.id) {
+                        Some(Point { kind: 3, .. }) => "rwatch",
+                        Some(Point { kind: 4, .. }) => "awatch",
+                        _ => "watch",
+                    };
+                    break format!("T{:02x}{}:{:04x};", SIGTRAP, kind, hit.addr);
+                }
+                Some(_) => break format!("S{:02x}", SIGTRAP),
+                None => {}
+            }
+
+            steps += 1;
+            if steps % POLL_INTERVAL == 0 && self.interrupted()? {
+                break format!("S{:02x}", SIGINT);
+            }
+            callback(cpu);
+            stopped = cpu.run_step();
+        };
+        self.reader.get_ref().set_nonblocking(false)?;
+        Ok(reply)
+    }
+
+    /// Checks for Ctrl-C without waiting, the connection is non-blocking while the CPU runs.
+    fn interrupted(&mut self) -> io::Result<bool> {
+        match self.reader.fill_buf() {
+            Ok([INTERRUPT, ..]) => {
+                self.reader.consume(1);
+                Ok(true)
+            }
+            Ok(_) => Ok(false),
+            Err(err) if err.kind() == ErrorKind::WouldBlock => Ok(false),
+            Err(err) => Err(err),
+        }
+    }
+
+    /// `P<n>=<value>`
+    fn write_register(&mut self, cpu: &mut CPU, args: &str) -> String {
+        let Some((n, value)) = args.split_once('=') else {
+            return "E01".to_string();
+        };
+        let mut regs = registers(cpu);
+        match (usize::from_str_radix(n, 16), decode(value)) {
+            (Ok(n @ 0..=4), Some(bytes)) if bytes.len() == 1 => regs[n] = bytes[0],
+            (Ok(5), Some(bytes)) if bytes.len() == 2 => regs[5..7].copy_from_slice(&bytes),
+            _ => return "E01".to_string(),
+        }
+        set_registers(cpu, &regs);
+        "OK".to_string()
+    }
+
+    /// `Z<kind>,<addr>,<length>`
+    fn insert_point(&mut self, cpu: &mut CPU, args: &str) -> String {
+        let Some((kind, addr, len)) = parse_point(args) else {
+            return "E01".to_string();
+        };
+        let end = addr.saturating_add(len.max(1) - 1);
+        let id = match kind {
+            0 | 1 => self.debugger.add_breakpoint(Some(addr), None),
+            2 => cpu.add_watchpoint(addr..=end, Access::Write, WatchAction::Break),
+            3 => cpu.add_watchpoint(addr..=end, Access::Read, WatchAction::Break),
+            4 => cpu.add_watchpoint(addr..=end, Access::ReadWrite, WatchAction::Break),
+            _ => return String::new(),
+        };
+        self.points.push(Point { kind, addr, id });
+        "OK".to_string()
+    }
+
+    /// `z<kind>,<addr>,<length>`
+    fn remove_point(&mut self, cpu: &mut CPU, args: &str) -> String {
+        let Some((kind, addr, _)) = parse_point(args) else {
+            return "E01".to_string();
+        };
+        let Some(i) = self
+            .points
+            .iter()
+            .position(|p| p.kind == kind && p.addr == addr)
+        else {
+            return "E01".to_string();
+        };
+        let point = self.points.remove(i);
+        if kind <= 1 {
+            self.debugger.remove_breakpoint(point.id);
+        } else {
+            cpu.remove_watchpoint(point.id);
+        }
+        "OK".to_string()
+    }
+
+    /// Reads the next packet and acknowledges it. Returns `None` when the connection closes.
+    fn read_packet(&mut self) -> io::Result<Option<Packet>> {
+        loop {
+            let Some(byte) = self.read_byte()? else {
+                return Ok(None);
+            };
+            match byte {
+                INTERRUPT => return Ok(Some(Packet::Interrupt)),
+                b'$' => {}
+                // Acknowledgements of our replies, and anything else between packets.
+                _ => continue,
+            }
+
+            let mut data = Vec::new();
+            loop {
+                match self.read_byte()? {
+                    Some(b'#') => break,
+                    Some(byte) => data.push(byte),
+                    None => return Ok(None),
+                }
+            }
+            let mut checksum = [0; 2];
+            self.reader.read_exact(&mut checksum)?;
+
+            let valid = std::str::from_utf8(&checksum)
+                .ok()
+                .and_then(|hex| u8::from_str_radix(hex, 16).ok())
+                == Some(checksum_of(&data));
+            if valid {
+                self.writer.write_all(b"+")?;
+                return Ok(Some(Packet::Command(
+                    String::from_utf8_lossy(&data).into_owned(),
+                )));
+            }
+            // Asks for the packet again.
+            self.writer.write_all(b"-")?;
+        }
+    }
+
+    fn read_byte(&mut self) -> io::Result<Option<u8>> {
+        let mut byte = [0];
+        match self.reader.read(&mut byte)? {
+            0 => Ok(None),
+            _ => Ok(Some(byte[0])),
+        }
+    }
+
+    fn send(&mut self, data: &str) -> io::Result<()> {
+        let packet = format!("${}#{:02x}", data, checksum_of(data.as_bytes()));
+        self.writer.write_all(packet.as_bytes())
+    }
+}
+
+fn checksum_of(data: &[u8]) -> u8 {
+    data.iter().fold(0, |sum: u8, &b| sum.wrapping_add(b))
+}
+
+fn encode(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn decode(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+/// The registers in the order GDB sees them.
+fn registers(cpu: &CPU) -> [u8; 7] {
+    let [pc_lo, pc_hi] = cpu.pc.to_le_bytes();
+    // Bit 5 doesn't exist in the CPU but always reads as set.
+    let p = cpu.status.bits() | 0x20;
+    [cpu.reg_a, cpu.reg_x, cpu.reg_y, p, cpu.sp, pc_lo, pc_hi]
+}
+
+fn set_registers(cpu: &mut CPU, regs: &[u8]) {
+    cpu.reg_a = regs[0];
+    cpu.reg_x = regs[1];
+    cpu.reg_y = regs[2];
+    cpu.status = StatusFlags::from_bits_truncate(regs[3]);
+    cpu.sp = regs[4];
+    cpu.pc = u16::from_le_bytes([regs[5], regs[6]]);
+}
+
+/// `c` and `s` can be given an address to continue from.
+fn set_pc(cpu: &mut CPU, addr: &str) {
+    if let Ok(addr) = u16::from_str_radix(addr, 16) {
+        cpu.pc = addr;
+    }
+}
+
+/// `<addr>,<length>`
+fn parse_range(args: &str) -> Option<(u16, u16)> {
+    let (addr, len) = args.split_once(',')?;
+    Some((
+        u16::from_str_radix(addr, 16).ok()?,
+        u16::from_str_radix(len, 16).ok()?,
+    ))
+}
+
+/// `<kind>,<addr>,<length>`
+fn parse_point(args: &str) -> Option<(u8, u16, u16)> {
+    let (kind, range) = args.split_once(',')?;
+    let (addr, len) = parse_range(range)?;
+    Some((kind.parse().ok()?, addr, len))
+}
+
+/// `m<addr>,<length>`, read without side effects.
+fn read_memory(cpu: &CPU, args: &str) -> String {
+    match parse_range(args) {
+        Some((addr, len)) => (0..len)
+            .map(|i| format!("{:02x}", cpu.peek(addr.wrapping_add(i))))
+            .collect(),
+        None => "E01".to_string(),
+    }
+}
+
+/// `M<addr>,<length>:<bytes>`, written without side effects. The ROM and the registers can't
+/// be written, the bytes before them are.
+fn write_memory(cpu: &mut CPU, args: &str) -> String {
+    let Some((range, data)) = args.split_once(':') else {
+        return "E01".to_string();
+    };
+    match (parse_range(range), decode(data)) {
+        (Some((addr, len)), Some(bytes)) if bytes.len() == len as usize => {
+            let written = bytes
+                .into_iter()
+                .enumerate()
+                .all(|(i, byte)| cpu.poke(addr.wrapping_add(i as u16), byte));
+            if written {
+                "OK".to_string()
+            } else {
+                "E01".to_string()
+            }
+        }
+        _ => "E01".to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    /// Stands in for GDB on the other end of the connection.
+    struct Client {
+        reader: BufReader<TcpStream>,
+        writer: TcpStream,
+    }
+
+    impl Client {
+        fn send(&mut self, data: &str) -> String {
+            self.send_without_reply(data);
+            self.reply()
+        }
+
+        /// For commands that don't reply until the CPU stops.
+        fn send_without_reply(&mut self, data: &str) {
+            let packet = format!("${}#{:02x}", data, checksum_of(data.as_bytes()));
+            self.writer.write_all(packet.as_bytes()).unwrap();
+            let mut ack = [0];
+            self.reader.read_exact(&mut ack).unwrap();
+            assert_eq!(ack[0], b'+');
+        }
+
+        fn reply(&mut self) -> String {
+            let mut packet = Vec::new();
+            self.reader.read_until(b'#', &mut packet).unwrap();
+            let mut checksum = [0; 2];
+            self.reader.read_exact(&mut checksum).unwrap();
+            self.writer.write_all(b"+").unwrap();
+
+            let data = &packet[1..packet.len() - 1];
+            assert_eq!(packet[0], b'$');
+            assert_eq!(
+                u8::from_str_radix(std::str::from_utf8(&checksum).unwrap(), 16).unwrap(),
+                checksum_of(data)
+            );
+            String::from_utf8(data.to_vec()).unwrap()
+        }
+    }
+
+    /// Starts a stub for `program` on a free port, returns the client and the thread the stub
+    /// runs on, which gives back the CPU when the client detaches.
+    fn connect(program: Vec<u8>) -> (Client, thread::JoinHandle<CPU>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let stub = thread::spawn(move || {
+            let mut cpu = CPU::new();
+            cpu.load(program);
+            cpu.reset();
+            let (stream, _) = listener.accept().unwrap();
+            GdbStub::new(stream).unwrap().serve(&mut cpu).unwrap();
+            cpu
+        });
+
+        let stream = TcpStream::connect(addr).unwrap();
+        let client = Client {
+            writer: stream.try_clone().unwrap(),
+            reader: BufReader::new(stream),
+        };
+        (client, stub)
+    }
+
+    #[test]
+    fn test_registers_and_memory() {
+        let (mut gdb, stub) = connect(vec![0xE8, 0x00]);
+        assert_eq!(gdb.send("qSupported:swbreak+"), "PacketSize=4000");
+        assert_eq!(gdb.send("?"), "S05");
+        assert_eq!(gdb.send("g"), "00000020fd0080");
+        assert_eq!(gdb.send("G0102032401fe00"), "OK");
+        assert_eq!(gdb.send("p5"), "fe00");
+        assert_eq!(gdb.send("P1=42"), "OK");
+        assert_eq!(gdb.send("p1"), "42");
+
+        assert_eq!(gdb.send("M0200,3:0a0b0c"), "OK");
+        assert_eq!(gdb.send("m0200,3"), "0a0b0c");
+        assert_eq!(gdb.send("M4015,1:0f"), "E01");
+        assert_eq!(gdb.send("m8000,2"), "e800");
+        assert_eq!(gdb.send("vMustReplyEmpty"), "");
+        assert_eq!(gdb.send("D"), "OK");
+
+        let cpu = stub.join().unwrap();
+        assert_eq!((cpu.reg_a, cpu.reg_x, cpu.pc), (0x01, 0x42, 0x00FE));
+    }
+
+    #[test]
+    fn test_breakpoints_and_stepping() {
+        // INX, INX, STA $0200, INX, JMP $8006
+        let (mut gdb, stub) = connect(vec![0xE8, 0xE8, 0x8D, 0x00, 0x02, 0xE8, 0x4C, 0x06, 0x80]);
+        assert_eq!(gdb.send("s"), "S05");
+        assert_eq!(gdb.send("p5"), "0180");
+
+        assert_eq!(gdb.send("Z0,8006,1"), "OK");
+        assert_eq!(gdb.send("Z2,0200,1"), "OK");
+        assert_eq!(gdb.send("c"), "T05watch:0200;");
+        assert_eq!(gdb.send("p5"), "0580");
+        // Continuing from the watchpoint stops at the breakpoint right after it.
+        assert_eq!(gdb.send("c"), "S05");
+        assert_eq!(gdb.send("p5"), "0680");
+        assert_eq!(gdb.send("z0,8006,1"), "OK");
+        assert_eq!(gdb.send("z0,8006,1"), "E01");
+
+        // The loop at $8006 runs until it's interrupted.
+        gdb.send_without_reply("c");
+        gdb.writer.write_all(&[INTERRUPT]).unwrap();
+        assert_eq!(gdb.reply(), "S02");
+        assert_eq!(gdb.send("p5"), "0680");
+        assert_eq!(gdb.send("D"), "OK");
+
+        let cpu = stub.join().unwrap();
+        assert_eq!(cpu.peek(0x0200), 0x00);
+    }
+}
@@ -0,0 +1,272 @@
+//! Running ROMs without a window, so test ROMs can be run automatically.
+//!
+//! Most of blargg's test ROMs report their progress through the cartridge RAM at $6000. Once
+//! $6001-$6003 holds the signature $DE $B0 $61 the byte at $6000 is the status: $80 while the
+//! test is running, $81 when the test wants the console to be reset, and anything below $80 is
+//! the final result, where 0 means passed. A null terminated text with the details is kept from
+//! $6004.
+//!
+//! Klaus Dormann's 6502 functional test works differently. It's a 64 KB image of memory that
+//! starts at $0400, and when a test fails it jumps to itself forever, the address of that jump tells
+//! which test failed. It ends in the same kind of trap at a known address when everything passed.
+
+use crate::cpu::CPU;
+
+/// The bytes at $6001-$6003 that tell that a test ROM is using the protocol.
+pub const SIGNATURE: [u8; 3] = [0xDE, 0xB0, 0x61];
+pub const STATUS_RUNNING: u8 = 0x80;
+pub const STATUS_NEEDS_RESET: u8 = 0x81;
+/// The reset button has to be held back for at least 100 ms after the test asks for it.
+const RESET_DELAY_FRAMES: u64 = 6;
+/// The exit code used when the frames run out before the test is done, the same one as the
+/// `timeout` command uses.
+pub const TIMED_OUT_EXIT_CODE: i32 = 124;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Outcome {
+    /// The program ran for all the frames, or stopped by itself, without using the protocol.
+    Finished,
+    /// The test reported a result, 0 means it passed.
+    Result(u8),
+    /// The test was still running when the frames ran out or the program stopped.
+    TimedOut,
+}
+
+impl Outcome {
+    /// The exit code for the emulator, 0 if nothing went wrong.
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            Outcome::Finished => 0,
+            Outcome::Result(code) => *code as i32,
+            Outcome::TimedOut => TIMED_OUT_EXIT_CODE,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Report {
+    pub outcome: Outcome,
+    /// How many frames were run.
+    pub frames: u64,
+    /// The text written by the test at $6004, empty if it doesn't use the protocol.
+    pub text: String,
+}
+
+/// Where the functional test starts, it's loaded at $0000.
+pub const FUNCTIONAL_TEST_START: u16 = 0x0400;
+/// The address the functional test gets stuck at when every test passed, when it's built with the
+/// default options like the `6502_functional_test.bin` in Klaus Dormann's repository.
+pub const FUNCTIONAL_TEST_SUCCESS: u16 = 0x3469;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Trap {
+    /// The program jumped or branched to itself at this address.
+    Trapped(u16),
+    /// The program was still running when the cycles ran out.
+    TimedOut,
+}
+
+/// The status at $6000, or `None` if the signature hasn't been written.
+pub fn status(cpu: &CPU) -> Option<u8> {
+    let ram = cpu.prg_ram();
+    (ram[1..4] == SIGNATURE).then_some(ram[0])
+}
+
+/// The text at $6004, up to the first null byte.
+pub fn text(cpu: &CPU) -> String {
+    if status(cpu).is_none() {
+        return String::new();
+    }
+    let text = &cpu.prg_ram()[4..];
+    let len = text.iter().position(|&b| b == 0).unwrap_or(text.len());
+    String::from_utf8_lossy(&text[..len]).into_owned()
+}
+
+/// Runs the CPU for at most `max_frames` frames, or until the test reports a result or the
+/// program stops.
+pub fn run(cpu: &mut CPU, max_frames: u64) -> Report {
+    run_with_callback(cpu, max_frames, |_| {})
+}
+
+/// Same as `run`, but calls `callback` before every step like `CPU::run_with_callback`.
+pub fn run_with_callback<F>(cpu: &mut CPU, max_frames: u64, mut callback: F) -> Report
+where
+    F: FnMut(&mut CPU),
+{
+    let mut reset_at = None;
+    let mut frames = 0;
+    let outcome = loop {
+        if frames == max_frames {
+            break end_outcome(cpu);
+        }
+        let stopped = cpu.run_frame_with_callback(&mut callback);
+        frames += 1;
+
+        match status(cpu) {
+            Some(STATUS_NEEDS_RESET) => match reset_at {
+                None => reset_at = Some(frames + RESET_DELAY_FRAMES),
+                Some(frame) if frames >= frame => {
+                    cpu.reset();
+                    reset_at = None;
+                }
+                Some(_) => {}
+            },
+            Some(code) if code < STATUS_RUNNING => break Outcome::Result(code),
+            _ => {}
+        }
+
+        if stopped {
+            break end_outcome(cpu);
+        }
+    };
+
+    Report {
+        outcome,
+        frames,
+        text: text(cpu),
+    }
+}
+
+/// Copies an image of memory to $0000 and starts the CPU at `start`. The vectors in the image are
+/// kept, so it can't be larger than 64 KB.
+///
+/// $6000-$7FFF goes to the PRG RAM, which is what the CPU reads there. Reads of $4015-$4017 still
+/// go to the APU and the controllers, the functional test doesn't use those addresses.
+pub fn load_flat(cpu: &mut CPU, image: &[u8], start: u16) -> Result<(), String> {
+    if image.len() > 0x10000 {
+        return Err(format!(
+            "The image is {} bytes, it has to fit in 64 KB",
+            image.len()
+        ));
+    }
+    cpu.load_at_addr(0x0000, image.to_vec());
+    if let Some(prg_ram) = image.get(0x6000..) {
+        cpu.load_prg_ram(&prg_ram[..prg_ram.len().min(0x2000)]);
+    }
+    cpu.reset();
+    cpu.pc = start;
+    Ok(())
+}
+
+/// Runs the CPU until the program gets stuck in a loop that jumps or branches to itself, or for
+/// at most `max_cycles` cycles. A BRK goes through the IRQ vector like on a 6502 instead of
+/// stopping the CPU, the functional test checks that it does.
+pub fn run_until_trap(cpu: &mut CPU, max_cycles: u64) -> Trap {
+    let end = cpu.cycles + max_cycles;
+    while cpu.cycles < end {
+        let pc = cpu.pc;
+        if cpu.run_step() {
+            cpu.take_brk();
+        }
+        if cpu.pc == pc {
+            return Trap::Trapped(pc);
+        }
+    }
+    Trap::TimedOut
+}
+
+/// What it means to get to the end without a result.
+fn end_outcome(cpu: &CPU) -> Outcome {
+    match status(cpu) {
+        Some(_) => Outcome::TimedOut,
+        None => Outcome::Finished,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cpu::StatusFlags;
+
+    /// Writes the signature, asks for a reset, and after the reset writes "ok" and passes. $00
+    /// tells the two runs apart since RAM survives a reset.
+    const NEEDS_RESET: &[u8] = &[
+        0xA5, 0x00, // LDA $00
+        0xD0, 0x1B, // BNE after_reset
+        0xA9, 0x01, // LDA #$01
+        0x85, 0x00, // STA $00
+        0xA9, 0xDE, 0x8D, 0x01, 0x60, // LDA #$DE, STA $6001
+        0xA9, 0xB0, 0x8D, 0x02, 0x60, // LDA #$B0, STA $6002
+        0xA9, 0x61, 0x8D, 0x03, 0x60, // LDA #$61, STA $6003
+        0xA9, 0x81, 0x8D, 0x00, 0x60, // LDA #$81, STA $6000
+        0x4C, 0x1C, 0x80, // JMP *
+        // after_reset:
+        0xA9, b'o', 0x8D, 0x04, 0x60, // LDA #'o', STA $6004
+        0xA9, b'k', 0x8D, 0x05, 0x60, // LDA #'k', STA $6005
+        0xA9, 0x00, 0x8D, 0x06, 0x60, // LDA #$00, STA $6006
+        0x8D, 0x00, 0x60, // STA $6000
+        0x4C, 0x31, 0x80, // JMP *
+    ];
+
+    #[test]
+    fn test_reset_and_pass() {
+        let mut cpu = CPU::new();
+        cpu.load(NEEDS_RESET.to_vec());
+        cpu.reset();
+
+        let report = run(&mut cpu, 60);
+        assert_eq!(report.outcome, Outcome::Result(0));
+        assert_eq!(report.text, "ok");
+        assert!(report.frames > RESET_DELAY_FRAMES);
+        assert_eq!(report.outcome.exit_code(), 0);
+    }
+
+    #[test]
+    fn test_without_protocol() {
+        let mut cpu = CPU::new();
+        // An infinite loop.
+        cpu.load(vec![0x4C, 0x00, 0x80]);
+        cpu.reset();
+
+        let report = run(&mut cpu, 3);
+        assert_eq!(report.outcome, Outcome::Finished);
+        assert_eq!(report.frames, 3);
+        assert_eq!(report.text, "");
+    }
+
+    #[test]
+    fn test_timed_out() {
+        let mut cpu = CPU::new();
+        cpu.load(vec![0x4C, 0x00, 0x80]);
+        cpu.load_prg_ram(&[STATUS_RUNNING, 0xDE, 0xB0, 0x61]);
+        cpu.reset();
+
+        let report = run(&mut cpu, 3);
+        assert_eq!(report.outcome, Outcome::TimedOut);
+        assert_eq!(report.outcome.exit_code(), TIMED_OUT_EXIT_CODE);
+    }
+
+    #[test]
+    fn test_run_until_trap() {
+        let mut image = vec![0; 0x10000];
+        image[0x0400..0x0407].copy_from_slice(&[
+            0xA2, 0x03, // LDX #$03
+            0xCA, // loop: DEX
+            0xD0, 0xFD, // BNE loop
+            0xF0, 0xFE, // BEQ *
+        ]);
+        let mut cpu = CPU::new();
+        load_flat(&mut cpu, &image, FUNCTIONAL_TEST_START).unwrap();
+        assert_eq!(run_until_trap(&mut cpu, 1000), Trap::Trapped(0x0405));
+
+        // A BRK goes through the IRQ vector, to a JMP to itself.
+        image[0x0405] = 0x00;
+        image[0x0410..0x0413].copy_from_slice(&[0x4C, 0x10, 0x04]);
+        image[0xFFFE..].copy_from_slice(&[0x10, 0x04]);
+        load_flat(&mut cpu, &image, FUNCTIONAL_TEST_START).unwrap();
+        assert_eq!(run_until_trap(&mut cpu, 1000), Trap::Trapped(0x0410));
+        assert_eq!(cpu.sp, 0xFA);
+        assert_eq!(cpu.peek_u16(0x01FC), 0x0407);
+        assert_eq!(cpu.peek(0x01FB) & 0b0011_0000, 0b0011_0000);
+        assert!(cpu.status.contains(StatusFlags::INTERRUPT_DISABLE));
+
+        image[0x0403..0x0405].copy_from_slice(&[0xD0, 0xFB]); // BNE back to LDX
+        load_flat(&mut cpu, &image, FUNCTIONAL_TEST_START).unwrap();
+        assert_eq!(run_until_trap(&mut cpu, 1000), Trap::TimedOut);
+
+        // Code at $6000 runs from the PRG RAM.
+        image[0x6000..0x6003].copy_from_slice(&[0x4C, 0x00, 0x60]); // JMP $6000
+        load_flat(&mut cpu, &image, 0x6000).unwrap();
+        assert_eq!(run_until_trap(&mut cpu, 1000), Trap::Trapped(0x6000));
+    }
+}
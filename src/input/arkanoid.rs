@@ -0,0 +1,130 @@
+use super::InputDevice;
+use std::any::Any;
+
+/// The lowest position the knob of a real Vaus controller reports.
+pub const MIN_POSITION: u8 = 0x62;
+/// The highest position the knob of a real Vaus controller reports.
+pub const MAX_POSITION: u8 = 0xF2;
+
+/// The Vaus controller that came with Arkanoid, a knob and a fire button.
+///
+/// The position of the knob is latched when the strobe is written and shifted out one bit per
+/// read, highest bit first and inverted. The NES version is plugged into the second port and
+/// uses D3 for the button and D4 for the position. The Famicom version is plugged into the
+/// expansion port and uses D1 of $4016 for the button and D1 of $4017 for the position.
+#[derive(Clone, Debug)]
+pub struct Arkanoid {
+    famicom: bool,
+    position: u8,
+    button: bool,
+    latch: u8,
+}
+
+impl Arkanoid {
+    pub fn new() -> Arkanoid {
+        Arkanoid {
+            famicom: false,
+            position: MIN_POSITION,
+            button: false,
+            latch: 0,
+        }
+    }
+
+    /// The Famicom version, for the expansion port.
+    pub fn famicom() -> Arkanoid {
+        Arkanoid {
+            famicom: true,
+            ..Arkanoid::new()
+        }
+    }
+
+    /// Sets the position of the knob, clamped to what the real controller can report.
+    pub fn set_position(&mut self, position: u8) {
+        self.position = position.clamp(MIN_POSITION, MAX_POSITION);
+    }
+
+    /// Turns the knob to a fraction of the way between the ends, 0.0 is all the way left and 1.0
+    /// is all the way right. This is handy for mapping the mouse to the knob.
+    pub fn set_fraction(&mut self, fraction: f64) {
+        let range = (MAX_POSITION - MIN_POSITION) as f64;
+        let position = MIN_POSITION as f64 + fraction.clamp(0.0, 1.0) * range;
+        self.set_position(position.round() as u8);
+    }
+
+    pub fn set_button(&mut self, pressed: bool) {
+        self.button = pressed;
+    }
+}
+
+impl Default for Arkanoid {
+    fn default() -> Self {
+        Arkanoid::new()
+    }
+}
+
+impl InputDevice for Arkanoid {
+    fn write(&mut self, value: u8) {
+        if value & 1 == 1 {
+            self.latch = !self.position;
+        }
+    }
+
+    fn read(&mut self, port: usize, _scanline: usize) -> u8 {
+        let button = self.button as u8;
+        if self.famicom && port == 0 {
+            return button << 1;
+        }
+
+        let bit = self.latch >> 7;
+        self.latch <<= 1;
+
+        if self.famicom {
+            bit << 1
+        } else {
+            button << 3 | bit << 4
+        }
+    }
+
+    fn box_clone(&self) -> Box<dyn InputDevice> {
+        Box::new(self.clone())
+    }
+
+    fn as_any_mut(&mut self) -> &mut dyn Any {
+        self
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn read_position(arkanoid: &mut Arkanoid, port: usize, shift: u8) -> u8 {
+        arkanoid.write(1);
+        arkanoid.write(0);
+        let mut value = 0;
+        for _ in 0..8 {
+            value = value << 1 | (arkanoid.read(port, 0) >> shift) & 1;
+        }
+        !value
+    }
+
+    #[test]
+    fn test_position_is_shifted_out_inverted() {
+        let mut arkanoid = Arkanoid::new();
+        arkanoid.set_position(0xA5);
+        assert_eq!(read_position(&mut arkanoid, 1, 4), 0xA5);
+
+        arkanoid.set_fraction(1.0);
+        assert_eq!(read_position(&mut arkanoid, 1, 4), MAX_POSITION);
+    }
+
+    #[test]
+    fn test_famicom_version() {
+        let mut arkanoid = Arkanoid::famicom();
+        arkanoid.set_position(0x80);
+        arkanoid.set_button(true);
+
+        assert_eq!(arkanoid.read(0, 0), 0x02);
+        assert_eq!(read_position(&mut arkanoid, 1, 1), 0x80);
+    }
+}
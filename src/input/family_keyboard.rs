@@ -0,0 +1,142 @@
+use super::InputDevice;
+use std::any::Any;
+
+/// The keys of the keyboard by row and column. Within a column the keys are in the order of
+/// the data lines D1-D4.
+///
+/// Letters and function keys have their usual names, the digits are `D0` to `D9` and the rest
+/// is named after what is printed on them.
+const MATRIX: [[[&str; 4]; 2]; 9] = [
+    [
+        ["RightBracket", "LeftBracket", "Return", "F8"],
+        ["Stop", "Yen", "RShift", "Kana"],
+    ],
+    [
+        ["Semicolon", "Colon", "At", "F7"],
+        ["Caret", "Minus", "Slash", "Underscore"],
+    ],
+    [["K", "L", "O", "F6"], ["D0", "P", "Comma", "Period"]],
+    [["J", "U", "I", "F5"], ["D8", "D9", "N", "M"]],
+    [["H", "G", "Y", "F4"], ["D6", "D7", "V", "B"]],
+    [["D", "R", "T", "F3"], ["D4", "D5", "C", "F"]],
+    [["A", "S", "W", "F2"], ["D3", "E", "Z", "X"]],
+    [
+        ["Ctrl", "Q", "Escape", "F1"],
+        ["D2", "D1", "Grph", "LShift"],
+    ],
+    [
+        ["Left", "Right", "Up", "ClrHome"],
+        ["Insert", "Delete", "Space", "Down"],
+    ],
+];
+
+/// The Family BASIC keyboard, plugged into the Famicom expansion port.
+///
+/// The keys are wired as a matrix of 9 rows with two columns of four keys. Writing $05 to $4016
+/// goes back to the first row, and the column is picked with bit 1 of $4016. Every time the
+/// column goes from 1 back to 0 the keyboard moves on to the next row. The four keys of the
+/// selected row and column are read on D1-D4 of $4017, where 0 means the key is held.
+#[derive(Clone, Debug, Default)]
+pub struct FamilyKeyboard {
+    /// Bit n of `keys[row][column]` is the key on D(n + 1).
+    keys: [[u8; 2]; 9],
+    enabled: bool,
+    row: usize,
+    column: usize,
+}
+
+impl FamilyKeyboard {
+    pub fn new() -> FamilyKeyboard {
+        FamilyKeyboard::default()
+    }
+
+    /// Presses or releases a key by the name it has in the matrix. Returns false if there is no
+    /// such key.
+    pub fn set_key(&mut self, name: &str, pressed: bool) -> bool {
+        for (row, columns) in MATRIX.iter().enumerate() {
+            for (column, keys) in columns.iter().enumerate() {
+                if let Some(bit) = keys.iter().position(|key| *key == name) {
+                    if pressed {
+                        self.keys[row][column] |= 1 << bit;
+                    } else {
+                        self.keys[row][column] &= !(1 << bit);
+                    }
+                    return true;
+                }
+            }
+        }
+        false
+    }
+}
+
+impl InputDevice for FamilyKeyboard {
+    fn write(&mut self, value: u8) {
+        self.enabled = value & 0b100 != 0;
+        if !self.enabled {
+            return;
+        }
+
+        let column = ((value >> 1) & 1) as usize;
+        if value & 1 == 1 {
+            self.row = 0;
+        } else if self.column == 1 && column == 0 {
+            self.row += 1;
+        }
+        self.column = column;
+    }
+
+    fn read(&mut self, port: usize, _scanline: usize) -> u8 {
+        if port == 0 || !self.enabled {
+            return 0;
+        }
+
+        let keys = self.keys.get(self.row).map_or(0, |row| row[self.column]);
+        !keys << 1 & 0b1_1110
+    }
+
+    fn box_clone(&self) -> Box<dyn InputDevice> {
+        Box::new(self.clone())
+    }
+
+    fn as_any_mut(&mut self) -> &mut dyn Any {
+        self
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Scans the keyboard the way Family BASIC does and returns the 18 nibbles.
+    fn scan(keyboard: &mut FamilyKeyboard) -> Vec<u8> {
+        let mut nibbles = Vec::new();
+        keyboard.write(0x05);
+        for _ in 0..9 {
+            keyboard.write(0x04);
+            nibbles.push(keyboard.read(1, 0) >> 1);
+            keyboard.write(0x06);
+            nibbles.push(keyboard.read(1, 0) >> 1);
+        }
+        nibbles
+    }
+
+    #[test]
+    fn test_matrix_scan() {
+        let mut keyboard = FamilyKeyboard::new();
+        assert!(keyboard.set_key("Return", true));
+        assert!(keyboard.set_key("Down", true));
+        assert!(!keyboard.set_key("NotAKey", true));
+
+        let nibbles = scan(&mut keyboard);
+        assert_eq!(nibbles[0], 0b1011);
+        assert_eq!(nibbles[17], 0b0111);
+        assert!(nibbles[1..17].iter().all(|&nibble| nibble == 0b1111));
+    }
+
+    #[test]
+    fn test_disabled_keyboard_reads_zero() {
+        let mut keyboard = FamilyKeyboard::new();
+        keyboard.write(0x00);
+        assert_eq!(keyboard.read(1, 0), 0);
+    }
+}
@@ -0,0 +1,37 @@
+mod arkanoid;
+mod family_keyboard;
+mod power_pad;
+mod zapper;
+
+pub use arkanoid::Arkanoid;
+pub use family_keyboard::FamilyKeyboard;
+pub use power_pad::PowerPad;
+pub use zapper::{Zapper, SCREEN_HEIGHT, SCREEN_WIDTH};
+
+use std::any::Any;
+use std::fmt::Debug;
+
+/// Something plugged into one of the controller ports or the Famicom expansion port.
+///
+/// Every device sees all writes to $4016, where the lowest three bits are the OUT lines (the
+/// first one is the strobe of a standard controller). Reads of $4016 and $4017 return the data
+/// lines D0-D4 of the device, the upper bits are open bus and filled in by the CPU.
+pub trait InputDevice: Debug + Send {
+    fn write(&mut self, value: u8);
+
+    /// Reads the data lines through `port`, 0 for $4016 and 1 for $4017. A device in one of the
+    /// front ports is only read through its own port, but the expansion port sees both.
+    /// `scanline` is the scanline the PPU is drawing, which matters for light guns.
+    fn read(&mut self, port: usize, scanline: usize) -> u8;
+
+    fn box_clone(&self) -> Box<dyn InputDevice>;
+
+    /// Lets the frontend get back to the actual device to feed it input.
+    fn as_any_mut(&mut self) -> &mut dyn Any;
+}
+
+impl Clone for Box<dyn InputDevice> {
+    fn clone(&self) -> Self {
+        self.box_clone()
+    }
+}
@@ -0,0 +1,91 @@
+use super::InputDevice;
+use std::any::Any;
+
+/// The order the buttons are shifted out on D3 and D4. The buttons are numbered 1 to 12 like on
+/// side B of the mat.
+const D3_ORDER: [u8; 8] = [2, 1, 5, 9, 6, 10, 11, 7];
+const D4_ORDER: [u8; 4] = [4, 3, 12, 8];
+
+/// The Power Pad (or Family Trainer), a mat with twelve buttons in a 4x3 grid that you step on.
+/// It's plugged into the second port.
+///
+/// Like a standard controller the buttons are latched by the strobe and shifted out one at a
+/// time, but on two data lines at once. D3 shifts out eight buttons and D4 four, after which
+/// both read 1.
+#[derive(Clone, Debug, Default)]
+pub struct PowerPad {
+    /// Bit n is button n + 1.
+    buttons: u16,
+    strobe: bool,
+    index: usize,
+}
+
+impl PowerPad {
+    pub fn new() -> PowerPad {
+        PowerPad::default()
+    }
+
+    /// Presses or releases `button`, 1 to 12.
+    pub fn set_button(&mut self, button: u8, pressed: bool) {
+        assert!(
+            (1..=12).contains(&button),
+            "The Power Pad has buttons 1 to 12"
+        );
+        let mask = 1 << (button - 1);
+        if pressed {
+            self.buttons |= mask;
+        } else {
+            self.buttons &= !mask;
+        }
+    }
+
+    fn pressed(&self, button: u8) -> u8 {
+        ((self.buttons >> (button - 1)) & 1) as u8
+    }
+}
+
+impl InputDevice for PowerPad {
+    fn write(&mut self, value: u8) {
+        self.strobe = value & 1 == 1;
+        if self.strobe {
+            self.index = 0;
+        }
+    }
+
+    fn read(&mut self, _port: usize, _scanline: usize) -> u8 {
+        let d3 = D3_ORDER.get(self.index).map_or(1, |&b| self.pressed(b));
+        let d4 = D4_ORDER.get(self.index).map_or(1, |&b| self.pressed(b));
+        if !self.strobe && self.index < D3_ORDER.len() {
+            self.index += 1;
+        }
+        d3 << 3 | d4 << 4
+    }
+
+    fn box_clone(&self) -> Box<dyn InputDevice> {
+        Box::new(self.clone())
+    }
+
+    fn as_any_mut(&mut self) -> &mut dyn Any {
+        self
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_shift_out_order() {
+        let mut pad = PowerPad::new();
+        pad.set_button(1, true);
+        pad.set_button(12, true);
+        pad.write(1);
+        pad.write(0);
+
+        let reads: Vec<u8> = (0..9).map(|_| pad.read(1, 0)).collect();
+        let d3: Vec<u8> = reads.iter().map(|value| value >> 3 & 1).collect();
+        let d4: Vec<u8> = reads.iter().map(|value| value >> 4 & 1).collect();
+        assert_eq!(d3, vec![0, 1, 0, 0, 0, 0, 0, 0, 1]);
+        assert_eq!(d4, vec![0, 0, 1, 0, 1, 1, 1, 1, 1]);
+    }
+}
@@ -0,0 +1,175 @@
+use crate::input::InputDevice;
+use std::any::Any;
+
+/// Width of the picture the NES outputs.
+pub const SCREEN_WIDTH: usize = 256;
+/// Height of the picture the NES outputs.
+pub const SCREEN_HEIGHT: usize = 240;
+
+/// How far from the cursor the light sensor can see, in pixels.
+const SENSOR_RADIUS: isize = 3;
+/// How many scanlines the sensor keeps reporting light after the beam has passed. The photodiode
+/// in the real Zapper stays on for somewhere between 19 and 26 scanlines.
+const SENSOR_DECAY: usize = 20;
+/// How bright a pixel has to be, out of 255, for the sensor to notice it.
+const BRIGHTNESS_THRESHOLD: u8 = 0x80;
+
+/// The NES Zapper light gun, plugged into the second port and read through $4017.
+///
+/// Bit 4 is the trigger and bit 3 is the light sensor, which reads 0 when it sees light. Games
+/// like Duck Hunt draw a white box where the target is for one frame after the trigger is pulled
+/// and check if the sensor lights up while the beam draws it.
+///
+/// The sensor looks at the picture around where the Zapper is aimed, but only at the scanlines
+/// the beam has drawn recently, so it needs both the picture and the scanline the PPU is on.
+/// The picture is given as the brightness of every pixel by the frontend.
+#[derive(Clone, Debug)]
+pub struct Zapper {
+    /// Where the Zapper is aimed, or None if it's pointing away from the screen.
+    aim: Option<(usize, usize)>,
+    trigger: bool,
+    brightness: Vec<u8>,
+}
+
+impl Default for Zapper {
+    fn default() -> Self {
+        Zapper::new()
+    }
+}
+
+impl Zapper {
+    pub fn new() -> Zapper {
+        Zapper {
+            aim: None,
+            trigger: false,
+            brightness: vec![0; SCREEN_WIDTH * SCREEN_HEIGHT],
+        }
+    }
+
+    /// Aims the Zapper at a pixel on the screen. Anything outside of the screen counts as
+    /// pointing away from it, which is how you reload in some games.
+    pub fn aim(&mut self, x: i32, y: i32) {
+        self.aim =
+            if (0..SCREEN_WIDTH as i32).contains(&x) && (0..SCREEN_HEIGHT as i32).contains(&y) {
+                Some((x as usize, y as usize))
+            } else {
+                None
+            };
+    }
+
+    pub fn set_trigger(&mut self, pulled: bool) {
+        self.trigger = pulled;
+    }
+
+    /// Sets the brightness of every pixel on the screen, row by row. This should be updated every
+    /// frame with what the PPU has drawn.
+    pub fn set_brightness(&mut self, brightness: &[u8]) {
+        assert_eq!(brightness.len(), SCREEN_WIDTH * SCREEN_HEIGHT);
+        self.brightness.copy_from_slice(brightness);
+    }
+
+    /// Whether the sensor sees light when the PPU is drawing `scanline`.
+    pub fn detects_light(&self, scanline: usize) -> bool {
+        let Some((x, y)) = self.aim else {
+            return false;
+        };
+
+        for dy in -SENSOR_RADIUS..=SENSOR_RADIUS {
+            for dx in -SENSOR_RADIUS..=SENSOR_RADIUS {
+                if dx * dx + dy * dy > SENSOR_RADIUS * SENSOR_RADIUS {
+                    continue;
+                }
+
+                let px = x as isize + dx;
+                let py = y as isize + dy;
+                if px < 0 || py < 0 || px >= SCREEN_WIDTH as isize || py >= SCREEN_HEIGHT as isize {
+                    continue;
+                }
+
+                // Only the rows the beam drew in the last few scanlines are still lit up.
+                let (px, py) = (px as usize, py as usize);
+                if py > scanline || scanline - py >= SENSOR_DECAY {
+                    continue;
+                }
+
+                if self.brightness[px + py * SCREEN_WIDTH] >= BRIGHTNESS_THRESHOLD {
+                    return true;
+                }
+            }
+        }
+
+        false
+    }
+}
+
+impl InputDevice for Zapper {
+    /// The Zapper doesn't listen to the strobe.
+    fn write(&mut self, _value: u8) {}
+
+    fn read(&mut self, _port: usize, scanline: usize) -> u8 {
+        let trigger = if self.trigger { 0x10 } else { 0 };
+        let light = if self.detects_light(scanline) {
+            0
+        } else {
+            0x08
+        };
+        trigger | light
+    }
+
+    fn box_clone(&self) -> Box<dyn InputDevice> {
+        Box::new(self.clone())
+    }
+
+    fn as_any_mut(&mut self) -> &mut dyn Any {
+        self
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn white_box(x: usize, y: usize, size: usize) -> Vec<u8> {
+        let mut brightness = vec![0; SCREEN_WIDTH * SCREEN_HEIGHT];
+        for py in y..y + size {
+            for px in x..x + size {
+                brightness[px + py * SCREEN_WIDTH] = 0xFF;
+            }
+        }
+        brightness
+    }
+
+    #[test]
+    fn test_trigger() {
+        let mut zapper = Zapper::new();
+        assert_eq!(zapper.read(1, 0) & 0x10, 0);
+        zapper.set_trigger(true);
+        assert_eq!(zapper.read(1, 0) & 0x10, 0x10);
+    }
+
+    #[test]
+    fn test_light_follows_the_beam() {
+        let mut zapper = Zapper::new();
+        zapper.set_brightness(&white_box(100, 100, 16));
+        zapper.aim(108, 108);
+
+        // The beam hasn't reached the box yet.
+        assert_eq!(zapper.read(1, 90) & 0x08, 0x08);
+        // The beam is drawing the box.
+        assert_eq!(zapper.read(1, 108) & 0x08, 0);
+        // The box was drawn long ago.
+        assert_eq!(zapper.read(1, 200) & 0x08, 0x08);
+    }
+
+    #[test]
+    fn test_no_light_outside_the_box() {
+        let mut zapper = Zapper::new();
+        zapper.set_brightness(&white_box(100, 100, 16));
+
+        zapper.aim(20, 108);
+        assert_eq!(zapper.read(1, 108) & 0x08, 0x08);
+
+        zapper.aim(-1, 108);
+        assert_eq!(zapper.read(1, 108) & 0x08, 0x08);
+    }
+}
@@ -0,0 +1,343 @@
+use crate::input::InputDevice;
+use crate::state::{impl_state, State, StateSync};
+use std::any::Any;
+
+bitflags::bitflags! {
+    /// The buttons of a standard controller, in the order they are shifted out.
+    #[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+    pub struct Buttons: u8 {
+        const A = 0b0000_0001;
+        const B = 0b0000_0010;
+        const SELECT = 0b0000_0100;
+        const START = 0b0000_1000;
+        const UP = 0b0001_0000;
+        const DOWN = 0b0010_0000;
+        const LEFT = 0b0100_0000;
+        const RIGHT = 0b1000_0000;
+    }
+}
+
+/// A standard NES controller.
+///
+/// Writing 1 to $4016 (the strobe) makes the controller continuously reload the state of the
+/// buttons. When the strobe goes back to 0 the buttons are shifted out one at a time, starting
+/// with A, on every read of $4016 for the first controller and $4017 for the second.
+#[derive(Clone, Debug, Default)]
+pub struct Joypad {
+    strobe: bool,
+    index: u8,
+    buttons: Buttons,
+}
+
+// The buttons are left out of save states, they're whatever is held right now.
+impl_state!(Joypad { strobe, index });
+
+impl Joypad {
+    pub fn new() -> Joypad {
+        Joypad::default()
+    }
+
+    pub fn set_buttons(&mut self, buttons: Buttons) {
+        self.buttons = buttons;
+    }
+
+    pub fn buttons(&self) -> Buttons {
+        self.buttons
+    }
+
+    pub fn write(&mut self, value: u8) {
+        self.strobe = value & 1 == 1;
+        if self.strobe {
+            self.index = 0;
+        }
+    }
+
+    /// Returns the next bit. After all eight buttons have been read an official controller
+    /// returns 1.
+    pub fn read(&mut self) -> u8 {
+        if self.index > 7 {
+            return 1;
+        }
+
+        let value = (self.buttons.bits() >> self.index) & 1;
+        if !self.strobe {
+            self.index += 1;
+        }
+        value
+    }
+}
+
+impl InputDevice for Joypad {
+    fn write(&mut self, value: u8) {
+        Joypad::write(self, value);
+    }
+
+    fn read(&mut self, _port: usize, _scanline: usize) -> u8 {
+        Joypad::read(self)
+    }
+
+    fn box_clone(&self) -> Box<dyn InputDevice> {
+        Box::new(self.clone())
+    }
+
+    fn as_any_mut(&mut self) -> &mut dyn Any {
+        self
+    }
+}
+
+/// How the third and fourth controller are connected, if at all.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Multitap {
+    /// Only the two controllers in the front ports.
+    #[default]
+    None,
+    /// The NES Four Score (or Satellite). Players 3 and 4 are shifted out after players 1 and 2
+    /// on the same data line, followed by a signature that tells the game the adapter is there.
+    FourScore,
+    /// Two extra controllers in the Famicom expansion port. Players 3 and 4 are read on bit 1
+    /// of $4016 and $4017 at the same time as players 1 and 2 are read on bit 0.
+    Famicom,
+}
+
+/// Signatures shifted out after the 16 controller bits of a Four Score, on $4016 and $4017. The
+/// signatures are usually written as $10 and $20, since games shift them into a byte from the
+/// top, but here they're stored in the order they're read like the buttons.
+const FOUR_SCORE_SIGNATURE: [u8; 2] = [0b0000_1000, 0b0000_0100];
+
+/// Everything plugged into the controller ports. Player 1 and 3 are read through $4016, player 2
+/// and 4 through $4017.
+///
+/// Standard controllers are plugged in unless another device is connected to the port, like a
+/// Zapper. The Famicom also has an expansion port for things like the keyboard, which is read
+/// through both $4016 and $4017 alongside the controllers.
+#[derive(Clone, Debug, Default)]
+pub struct Controllers {
+    multitap: Multitap,
+    joypads: [Joypad; 4],
+    devices: [Option<Box<dyn InputDevice>>; 2],
+    expansion: Option<Box<dyn InputDevice>>,
+    strobe: bool,
+    /// Number of bits read from each port by the Four Score since the strobe.
+    four_score_index: [u8; 2],
+}
+
+/// Only the standard controllers are saved, the other devices are mostly driven by the frontend.
+impl State for Controllers {
+    fn sync(&mut self, s: &mut StateSync) {
+        s.sync(&mut self.joypads);
+        s.sync(&mut self.strobe);
+        s.sync(&mut self.four_score_index);
+    }
+}
+
+impl Controllers {
+    pub fn new() -> Controllers {
+        Controllers::default()
+    }
+
+    pub fn multitap(&self) -> Multitap {
+        self.multitap
+    }
+
+    pub fn set_multitap(&mut self, multitap: Multitap) {
+        self.multitap = multitap;
+    }
+
+    /// Sets which buttons are held by `player`, 0 to 3. Player 3 and 4 are ignored unless a
+    /// multitap is connected.
+    pub fn set_buttons(&mut self, player: usize, buttons: Buttons) {
+        self.joypads[player].set_buttons(buttons);
+    }
+
+    pub fn buttons(&self, player: usize) -> Buttons {
+        self.joypads[player].buttons()
+    }
+
+    /// Plugs `device` into `port` instead of the standard controller, or puts the controller
+    /// back with `None`.
+    pub fn connect(&mut self, port: usize, device: Option<Box<dyn InputDevice>>) {
+        self.devices[port] = device;
+    }
+
+    /// Plugs `device` into the Famicom expansion port.
+    pub fn connect_expansion(&mut self, device: Option<Box<dyn InputDevice>>) {
+        self.expansion = device;
+    }
+
+    /// The device in `port`, if there is one and it's a `T`.
+    pub fn device_mut<T: InputDevice + 'static>(&mut self, port: usize) -> Option<&mut T> {
+        self.devices[port].as_mut()?.as_any_mut().downcast_mut()
+    }
+
+    /// The device in the expansion port, if there is one and it's a `T`.
+    pub fn expansion_mut<T: InputDevice + 'static>(&mut self) -> Option<&mut T> {
+        self.expansion.as_mut()?.as_any_mut().downcast_mut()
+    }
+
+    /// Writes $4016. The strobe is shared by both ports.
+    pub fn write(&mut self, value: u8) {
+        self.strobe = value & 1 == 1;
+        if self.strobe {
+            self.four_score_index = [0, 0];
+        }
+        for joypad in self.joypads.iter_mut() {
+            joypad.write(value);
+        }
+        for device in self
+            .devices
+            .iter_mut()
+            .chain([&mut self.expansion])
+            .flatten()
+        {
+            device.write(value);
+        }
+    }
+
+    /// Reads the data lines of `port`, 0 for $4016 and 1 for $4017, while the PPU is drawing
+    /// `scanline`. Only the lowest bits are driven, the rest is up to the caller.
+    pub fn read(&mut self, port: usize, scanline: usize) -> u8 {
+        let value = match &mut self.devices[port] {
+            Some(device) => device.read(port, scanline),
+            None => self.read_joypads(port),
+        };
+
+        match &mut self.expansion {
+            Some(device) => value | device.read(port, scanline),
+            None => value,
+        }
+    }
+
+    fn read_joypads(&mut self, port: usize) -> u8 {
+        match self.multitap {
+            Multitap::None => self.joypads[port].read(),
+            Multitap::Famicom => self.joypads[port].read() | self.joypads[port + 2].read() << 1,
+            Multitap::FourScore => {
+                let index = self.four_score_index[port];
+                let value = match index {
+                    0..=7 => self.joypads[port].buttons().bits() >> index,
+                    8..=15 => self.joypads[port + 2].buttons().bits() >> (index - 8),
+                    16..=23 => FOUR_SCORE_SIGNATURE[port] >> (index - 16),
+                    _ => 1,
+                };
+                if !self.strobe && index < 24 {
+                    self.four_score_index[port] += 1;
+                }
+                value & 1
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::input::FamilyKeyboard;
+    use crate::input::Zapper;
+
+    #[test]
+    fn test_shift_out_order() {
+        let mut joypad = Joypad::new();
+        joypad.set_buttons(Buttons::A | Buttons::START | Buttons::RIGHT);
+        joypad.write(1);
+        joypad.write(0);
+
+        let bits: Vec<u8> = (0..10).map(|_| joypad.read()).collect();
+        assert_eq!(bits, vec![1, 0, 0, 1, 0, 0, 0, 1, 1, 1]);
+    }
+
+    #[test]
+    fn test_strobe_keeps_returning_a() {
+        let mut joypad = Joypad::new();
+        joypad.set_buttons(Buttons::A);
+        joypad.write(1);
+
+        assert_eq!(joypad.read(), 1);
+        assert_eq!(joypad.read(), 1);
+
+        joypad.set_buttons(Buttons::B);
+        assert_eq!(joypad.read(), 0);
+    }
+
+    fn read_bits(controllers: &mut Controllers, port: usize, count: usize) -> Vec<u8> {
+        (0..count).map(|_| controllers.read(port, 0)).collect()
+    }
+
+    #[test]
+    fn test_four_score() {
+        let mut controllers = Controllers::new();
+        controllers.set_multitap(Multitap::FourScore);
+        controllers.set_buttons(0, Buttons::A);
+        controllers.set_buttons(1, Buttons::B);
+        controllers.set_buttons(2, Buttons::START);
+        controllers.set_buttons(3, Buttons::RIGHT);
+        controllers.write(1);
+        controllers.write(0);
+
+        let port0 = read_bits(&mut controllers, 0, 26);
+        assert_eq!(&port0[0..8], &[1, 0, 0, 0, 0, 0, 0, 0]);
+        assert_eq!(&port0[8..16], &[0, 0, 0, 1, 0, 0, 0, 0]);
+        assert_eq!(&port0[16..24], &[0, 0, 0, 1, 0, 0, 0, 0]);
+        assert_eq!(&port0[24..], &[1, 1]);
+
+        let port1 = read_bits(&mut controllers, 1, 24);
+        assert_eq!(&port1[0..8], &[0, 1, 0, 0, 0, 0, 0, 0]);
+        assert_eq!(&port1[8..16], &[0, 0, 0, 0, 0, 0, 0, 1]);
+        assert_eq!(&port1[16..24], &[0, 0, 1, 0, 0, 0, 0, 0]);
+    }
+
+    #[test]
+    fn test_famicom_expansion_port() {
+        let mut controllers = Controllers::new();
+        controllers.set_multitap(Multitap::Famicom);
+        controllers.set_buttons(0, Buttons::A);
+        controllers.set_buttons(2, Buttons::A | Buttons::B);
+        controllers.write(1);
+        controllers.write(0);
+
+        assert_eq!(read_bits(&mut controllers, 0, 3), vec![0b11, 0b10, 0b00]);
+        assert_eq!(read_bits(&mut controllers, 1, 1), vec![0b00]);
+    }
+
+    #[test]
+    fn test_no_multitap_ignores_players_3_and_4() {
+        let mut controllers = Controllers::new();
+        controllers.set_buttons(2, Buttons::A);
+        controllers.write(1);
+        controllers.write(0);
+
+        assert_eq!(
+            read_bits(&mut controllers, 0, 9),
+            vec![0, 0, 0, 0, 0, 0, 0, 0, 1]
+        );
+    }
+
+    #[test]
+    fn test_device_replaces_second_controller() {
+        let mut controllers = Controllers::new();
+        controllers.set_buttons(1, Buttons::A);
+        controllers.connect(1, Some(Box::new(Zapper::new())));
+        controllers
+            .device_mut::<Zapper>(1)
+            .unwrap()
+            .set_trigger(true);
+        controllers.write(1);
+        controllers.write(0);
+
+        assert_eq!(controllers.read(1, 0), 0x18);
+        assert!(controllers.device_mut::<Joypad>(1).is_none());
+    }
+
+    #[test]
+    fn test_expansion_port_is_read_with_controllers() {
+        let mut controllers = Controllers::new();
+        controllers.set_buttons(1, Buttons::A);
+        controllers.connect_expansion(Some(Box::new(FamilyKeyboard::new())));
+        controllers
+            .expansion_mut::<FamilyKeyboard>()
+            .unwrap()
+            .set_key("Return", true);
+        controllers.write(0x05);
+
+        assert_eq!(controllers.read(1, 0), 0b1_0111);
+    }
+}
@@ -1 +1,12 @@
+pub mod apu;
+pub mod cartridge;
 pub mod cpu;
+pub mod debugger;
+pub mod gdb;
+pub mod headless;
+pub mod input;
+pub mod joypad;
+pub mod movie;
+pub mod region;
+pub mod rewind;
+pub mod state;
@@ -1,7 +1,29 @@
+mod bindings;
+
+use bindings::{Action, Bindings, Input};
+use nes_emulator::apu::write_wav;
+use nes_emulator::cartridge::{BatteryRam, Cartridge};
+use nes_emulator::cpu::cdl::CodeDataLog;
+use nes_emulator::cpu::symbols::Symbols;
+use nes_emulator::cpu::trace::Tracer;
 use nes_emulator::cpu::CPU;
-use rand::Rng;
+use nes_emulator::debugger::Debugger;
+use nes_emulator::gdb::GdbStub;
+use nes_emulator::headless::{self, Outcome, Trap};
+use nes_emulator::input::{
+    Arkanoid, FamilyKeyboard, InputDevice, PowerPad, Zapper, SCREEN_HEIGHT, SCREEN_WIDTH,
+};
+use nes_emulator::joypad::{Buttons, Multitap};
+use nes_emulator::movie::{Commands, Movie, MovieFrame};
+use nes_emulator::region::Region;
+use nes_emulator::rewind::Rewind;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
 
-use std::sync::mpsc;
+use std::fs::File;
+use std::io::BufWriter;
+use std::path::{Path, PathBuf};
+use std::sync::{mpsc, Arc, Mutex};
 use std::thread;
 
 use glutin_window::GlutinWindow as Window;
@@ -16,7 +38,7 @@ use piston::window::WindowSettings;
 ///
 /// Raw assembly with code comments can be found here:
 /// https://gist.github.com/wkjagt/9043907
-const SNAKE_GAME: &'static [u8] = &[
+const SNAKE_GAME: &[u8] = &[
     0x20, 0x06, 0x06, 0x20, 0x38, 0x06, 0x20, 0x0d, 0x06, 0x20, 0x2a, 0x06, 0x60, 0xa9, 0x02, 0x85,
     0x02, 0xa9, 0x04, 0x85, 0x03, 0xa9, 0x11, 0x85, 0x10, 0xa9, 0x10, 0x85, 0x12, 0xa9, 0x0f, 0x85,
     0x14, 0xa9, 0x04, 0x85, 0x11, 0x85, 0x13, 0x85, 0x15, 0x60, 0xa5, 0xfe, 0x85, 0x00, 0xa5, 0xfe,
@@ -39,6 +61,119 @@ const SNAKE_GAME: &'static [u8] = &[
     0xea, 0xca, 0xd0, 0xfb, 0x60,
 ];
 
+/// The seed used without `--seed` when there is no window, so a capture, a headless run or a
+/// debugging session goes the same way every time.
+const DEFAULT_SEED: u64 = 0;
+
+/// Where the save state hotkeys save and load the state of the built-in snake game. Other games
+/// keep it next to the ROM, `game.nes` saves to `game.state`.
+const SNAKE_STATE_PATH: &str = "snake.state";
+
+/// How many frames go between saves of the battery-backed RAM, about five seconds.
+const BATTERY_SAVE_INTERVAL: u64 = 300;
+
+/// Width and height of the window in pixels, before it's scaled with `--scale`.
+const WINDOW_SIZE: f64 = 320.0;
+
+/// How many frames `--headless` runs for unless `--frames` says otherwise, a minute of NTSC.
+const HEADLESS_FRAMES: u64 = 3600;
+
+/// Where raw programs are loaded unless `--load-addr` says otherwise.
+const DEFAULT_LOAD_ADDR: u16 = 0x0600;
+
+const USAGE: &str = "\
+Usage: nes-emulator [OPTIONS] [ROM]
+
+Runs an iNES ROM, or the built in snake game if no ROM is given.
+
+Options:
+  --raw <CPU>              Run the ROM as a raw program for the CPU, which can only be 6502
+  --load-addr <ADDR>       Where the raw program is loaded and started [default: 0x0600]
+  --region <REGION>        ntsc, pal or dendy [default: ntsc]
+  --scale <N>              Scale the window by N [default: 1]
+  --mute                   Turn off the sound of --capture-audio, the window doesn't play
+                           sound yet
+  --load-state <FILE>      Start from a save state
+  --play <FILE>            Play back an FM2 movie
+  --record <FILE>          Record the input to an FM2 movie
+  --seed <N>               Seed for the random numbers of raw programs [default: 0 without a
+                           window, random with one]
+  --bindings <FILE>        Load the controls from a file
+  --multitap <TAP>         none, four-score or famicom [default: none]
+  --port2 <DEVICE>         controller, zapper, arkanoid or power-pad [default: controller]
+  --expansion <DEVICE>     none, arkanoid or family-keyboard [default: none]
+  --capture-audio <FILE>   Run without a window and write the sound to a WAV file
+  --headless               Run without a window until a test ROM reports its result, the exit
+                           code is the result
+  --frames <N>             How many frames to capture or run headless [default: 600 or 3600]
+  --trace <FILE>           Write every instruction run headless to a file
+  --trace-from <ADDR>      Start tracing once the CPU gets to this address
+  --debug                  Start in the debugger, which is used from the terminal
+  --symbols <FILE>         Load labels from a .dbg, .nl or .mlb file, the ones next to the ROM
+                           are loaded anyway
+  --gdb <PORT>             Run without a window and wait for GDB to attach on the port
+  --cdl <FILE>             Log which bytes of the ROM are code and which are data to an FCEUX
+                           .cdl file, adding to it if it's already there
+  --functional-test <FILE> Run Klaus Dormann's 6502 functional test until it gets stuck, the
+                           exit code is 0 if it got stuck where it passes
+  --start <ADDR>           Where the functional test starts [default: 0x0400]
+  --success <ADDR>         Where the functional test gets stuck when it passes [default: 0x3469]
+  -h, --help               Print this help
+";
+
+/// The options that are followed by a value, everything else that doesn't start with `--` is
+/// the ROM.
+const VALUE_OPTIONS: &[&str] = &[
+    "--raw",
+    "--load-addr",
+    "--region",
+    "--scale",
+    "--load-state",
+    "--play",
+    "--record",
+    "--seed",
+    "--bindings",
+    "--multitap",
+    "--port2",
+    "--expansion",
+    "--capture-audio",
+    "--frames",
+    "--trace",
+    "--trace-from",
+    "--symbols",
+    "--gdb",
+    "--cdl",
+    "--functional-test",
+    "--start",
+    "--success",
+];
+
+/// What the emulator runs.
+enum Game {
+    /// A 6502 program that is copied straight into memory and started, like the snake game. The
+    /// program is expected to follow the same conventions as the snake game: a random number is
+    /// put at $FE every step, the last pressed key at $FF, and the screen is 32x32 bytes at
+    /// $0200.
+    Raw {
+        program: Vec<u8>,
+        load_addr: u16,
+    },
+    Cartridge(Cartridge),
+}
+
+impl Game {
+    fn cartridge(&self) -> Option<&Cartridge> {
+        match self {
+            Game::Cartridge(cartridge) => Some(cartridge),
+            Game::Raw { .. } => None,
+        }
+    }
+
+    fn is_raw(&self) -> bool {
+        matches!(self, Game::Raw { .. })
+    }
+}
+
 #[derive(Copy, Clone)]
 struct NesMsg {
     screen_state: [u8; 32 * 32],
@@ -46,8 +181,28 @@ struct NesMsg {
 }
 
 #[derive(Copy, Clone)]
-struct WinMsg {
-    key: u8,
+enum WinMsg {
+    /// The buttons held on all four controllers.
+    Buttons([Buttons; 4]),
+    Reset,
+    /// Toggles between paused and running.
+    Pause,
+    SaveState,
+    LoadState,
+    FastForward(bool),
+    /// Steps back in time one frame at a time while held.
+    Rewind(bool),
+    /// Where the mouse is, in NES pixels.
+    Mouse(i32, i32),
+    MouseButton(bool),
+    PowerPad(u8, bool),
+    /// A key on the Family BASIC keyboard, by its name in the key matrix.
+    FamilyKey(&'static str, bool),
+    /// Stops in the debugger.
+    Break,
+    /// The window is closing. The NES thread answers with a game over message once it has saved
+    /// what needs saving.
+    Quit,
 }
 
 /// This function is used to get the latest message from a channel.
@@ -61,48 +216,780 @@ fn get_latest_message<T>(rx: &mpsc::Receiver<T>) -> Option<T> {
     latest
 }
 
+/// Returns the value following `name` in the command line arguments.
+fn arg_value<'a>(args: &'a [String], name: &str) -> Option<&'a String> {
+    let pos = args.iter().position(|arg| arg == name)?;
+    args.get(pos + 1)
+}
+
+/// The seed for the random numbers of raw programs from `--seed`, or `default` without it.
+fn seed_arg(args: &[String], default: impl FnOnce() -> u64) -> u64 {
+    arg_value(args, "--seed")
+        .map(|seed| seed.parse().expect("--seed must be a number"))
+        .unwrap_or_else(default)
+}
+
+/// Finds the ROM among the command line arguments, which is the first one that isn't an option.
+fn rom_arg(args: &[String]) -> Option<&String> {
+    let mut args = args.iter().skip(1);
+    while let Some(arg) = args.next() {
+        if VALUE_OPTIONS.contains(&arg.as_str()) {
+            args.next();
+        } else if !arg.starts_with('-') {
+            return Some(arg);
+        }
+    }
+    None
+}
+
+/// Parses a number that is either decimal or hexadecimal starting with `0x` or `$`.
+fn parse_number(text: &str) -> Option<u16> {
+    match text.strip_prefix("0x").or_else(|| text.strip_prefix('$')) {
+        Some(hex) => u16::from_str_radix(hex, 16).ok(),
+        None => text.parse().ok(),
+    }
+}
+
+/// Applies the input of a frame, either from the window or from a movie.
+fn apply_input(cpu: &mut CPU, input: &MovieFrame, game: &Game) {
+    if input.commands.contains(Commands::HARD_RESET) {
+        // Everything but the controllers and the frame count start over, the frame count is
+        // kept so the movie stays in step. The battery keeps the cartridge RAM alive too.
+        let controllers = cpu.controllers.clone();
+        let cycles = cpu.cycles;
+        let prg_ram = cpu.prg_ram().to_vec();
+        let cdl = cpu.take_cdl();
+        *cpu = power_on(game, cpu.region(), cpu.apu.muted());
+        cpu.controllers = controllers;
+        cpu.cycles = cycles;
+        if let Some(cdl) = cdl {
+            cpu.start_cdl(cdl);
+        }
+        if game.cartridge().is_some_and(|cartridge| cartridge.battery) {
+            cpu.load_prg_ram(&prg_ram);
+        }
+    } else if input.commands.contains(Commands::SOFT_RESET) {
+        cpu.reset();
+    }
+
+    for (player, buttons) in input.buttons.iter().enumerate() {
+        cpu.set_buttons(player, *buttons);
+    }
+
+    // The snake game doesn't read the controller, it reads the ASCII code of the last pressed
+    // key from this memory location.
+    if game.is_raw() {
+        if let Some(key) = snake_key(input.buttons[0]) {
+            cpu.poke(0xff, key);
+        }
+    }
+}
+
+/// Makes a GUID for a movie out of the seed.
+fn guid(seed: u64) -> String {
+    let mut rng = StdRng::seed_from_u64(seed);
+    let bytes: [u8; 16] = rng.gen();
+    let hex: String = bytes.iter().map(|b| format!("{:02X}", b)).collect();
+    format!(
+        "{}-{}-{}-{}-{}",
+        &hex[0..8],
+        &hex[8..12],
+        &hex[12..16],
+        &hex[16..20],
+        &hex[20..32]
+    )
+}
+
+/// Creates a CPU with the game loaded and ready to run.
+fn power_on(game: &Game, region: Region, mute: bool) -> CPU {
+    let mut cpu = CPU::new();
+    cpu.set_region(region);
+    cpu.apu.set_muted(mute);
+    match game {
+        Game::Raw { program, load_addr } => {
+            // Since program execution will start from whatever is in memory location 0xFFFC, we
+            // need to set that to the start of the program. Raw programs like the snake game
+            // differ from other NES games where execution starts at 0x8000.
+            cpu.load_at_addr(*load_addr, program.clone());
+            cpu.mem_write_u16(0xFFFC, *load_addr);
+            // Send the reset signal to the CPU signaling that a cartridge has been inserted.
+            cpu.reset();
+        }
+        // The cartridge was checked when it was loaded.
+        Game::Cartridge(cartridge) => cpu.load_cartridge(cartridge).unwrap(),
+    }
+    cpu
+}
+
+/// Saves the battery-backed RAM, if the cartridge has a battery.
+fn save_battery_ram(cpu: &CPU, battery: &mut Option<BatteryRam>) {
+    if let Some(battery) = battery {
+        if let Err(err) = battery.save(cpu.prg_ram()) {
+            eprintln!("Failed to write {}: {}", battery.path().display(), err);
+        }
+    }
+}
+
+/// Saves the code/data log, if there is one.
+fn save_cdl(cpu: &CPU, path: Option<&str>) {
+    if let (Some(cdl), Some(path)) = (cpu.cdl(), path) {
+        if let Err(err) = cdl.save(Path::new(path)) {
+            eprintln!("Failed to write {}: {}", path, err);
+        }
+    }
+}
+
+/// Runs the game without a window and prints the result. Returns the exit code.
+///
+/// The save file of the cartridge isn't loaded, test ROMs should always start from scratch.
+fn run_headless(
+    game: &Game,
+    region: Region,
+    frames: u64,
+    seed: u64,
+    mut tracer: Option<Tracer>,
+) -> i32 {
+    let mut rng = SnakeRng::new(seed);
+    let mut cpu = power_on(game, region, true);
+
+    let mut trace_result = Ok(());
+    let report = headless::run_with_callback(&mut cpu, frames, |cpu| {
+        if let (Some(tracer), Ok(())) = (&mut tracer, &trace_result) {
+            trace_result = tracer.log(cpu);
+        }
+        if game.is_raw() {
+            rng.step(cpu);
+        }
+    });
+    if let Some(tracer) = &mut tracer {
+        if let Err(err) = trace_result.and_then(|()| tracer.flush()) {
+            eprintln!("Failed to write the trace: {}", err);
+        }
+    }
+
+    if !report.text.is_empty() {
+        println!("{}", report.text.trim_end());
+    }
+    match report.outcome {
+        Outcome::Finished => println!("Ran for {} frames", report.frames),
+        Outcome::Result(0) => println!("Passed after {} frames", report.frames),
+        Outcome::Result(code) => {
+            println!("Failed with code {} after {} frames", code, report.frames)
+        }
+        Outcome::TimedOut => println!("Timed out after {} frames", report.frames),
+    }
+    report.outcome.exit_code()
+}
+
+/// Runs the game without a window, controlled by a debugger attached with GDB's remote protocol.
+/// Returns the exit code.
+fn run_gdb(game: &Game, region: Region, port: u16, seed: u64) -> i32 {
+    let mut rng = SnakeRng::new(seed);
+    let mut cpu = power_on(game, region, true);
+
+    println!("Waiting for GDB on 127.0.0.1:{}", port);
+    let result = GdbStub::listen(("127.0.0.1", port)).and_then(|mut stub| {
+        println!("GDB attached");
+        stub.serve_with_callback(&mut cpu, |cpu| {
+            if game.is_raw() {
+                rng.step(cpu);
+            }
+        })
+    });
+    match result {
+        Ok(()) => {
+            println!("GDB detached");
+            0
+        }
+        Err(err) => {
+            eprintln!("GDB connection failed: {}", err);
+            1
+        }
+    }
+}
+
+/// Runs Klaus Dormann's functional test without a window and prints where it got stuck. Returns
+/// the exit code.
+fn run_functional_test(path: &str, start: u16, success: u16, frames: u64) -> i32 {
+    let image = std::fs::read(path).unwrap_or_else(|err| {
+        eprintln!("Failed to read {}: {}", path, err);
+        std::process::exit(1);
+    });
+    let mut cpu = CPU::new();
+    cpu.apu.set_muted(true);
+    if let Err(err) = headless::load_flat(&mut cpu, &image, start) {
+        eprintln!("{}", err);
+        return 1;
+    }
+
+    let max_cycles = frames * cpu.region().cycles_per_frame();
+    let trap = headless::run_until_trap(&mut cpu, max_cycles);
+    match trap {
+        Trap::Trapped(pc) if pc == success => {
+            println!("Passed after {} cycles", cpu.cycles);
+            0
+        }
+        Trap::Trapped(pc) => {
+            println!("Failed, stuck at ${:04X} after {} cycles", pc, cpu.cycles);
+            1
+        }
+        Trap::TimedOut => {
+            println!("Timed out after {} cycles at ${:04X}", cpu.cycles, cpu.pc);
+            headless::TIMED_OUT_EXIT_CODE
+        }
+    }
+}
+
+/// Runs the game without opening a window for the given amount of frames and writes the output
+/// of the APU to a WAV file. This is used to compare the audio between builds.
+fn capture_audio(
+    path: &str,
+    frames: u32,
+    seed: u64,
+    mut cpu: CPU,
+    game: &Game,
+) -> std::io::Result<()> {
+    let mut rng = SnakeRng::new(seed);
+
+    let mut samples = Vec::new();
+    for _ in 0..frames {
+        let stopped = cpu.run_frame_with_callback(|cpu| {
+            if game.is_raw() {
+                rng.step(cpu);
+            }
+        });
+        samples.extend(cpu.apu.take_samples());
+
+        if stopped {
+            break;
+        }
+    }
+
+    let mut file = BufWriter::new(File::create(path)?);
+    write_wav(&mut file, cpu.apu.sample_rate(), &samples)
+}
+
 fn main() {
+    let args: Vec<String> = std::env::args().collect();
+    if args.iter().any(|arg| arg == "--help" || arg == "-h") {
+        print!("{}", USAGE);
+        return;
+    }
+
+    // `--functional-test 6502_functional_test.bin` runs the test without a window. It isn't a
+    // NES program so none of the other options matter.
+    if let Some(path) = arg_value(&args, "--functional-test") {
+        let address = |name: &str, default: u16| match arg_value(&args, name) {
+            Some(addr) => parse_number(addr).unwrap_or_else(|| {
+                eprintln!("Invalid address {} for {}", addr, name);
+                std::process::exit(1);
+            }),
+            None => default,
+        };
+        let start = address("--start", headless::FUNCTIONAL_TEST_START);
+        let success = address("--success", headless::FUNCTIONAL_TEST_SUCCESS);
+        let frames = arg_value(&args, "--frames")
+            .map(|frames| frames.parse().expect("--frames must be a number"))
+            .unwrap_or(HEADLESS_FRAMES);
+        std::process::exit(run_functional_test(path, start, success, frames));
+    }
+
+    // An iNES ROM can be given on the command line, or a raw program with `--raw 6502`.
+    // Otherwise the snake game is played. There is no PPU yet so nothing is drawn for ROMs.
+    let rom_path = rom_arg(&args).cloned();
+    let raw = match arg_value(&args, "--raw").map(String::as_str) {
+        None => false,
+        Some("6502") => true,
+        Some(other) => {
+            eprintln!("Unknown CPU {} for --raw, only 6502 is supported", other);
+            std::process::exit(1);
+        }
+    };
+    let game = match &rom_path {
+        Some(path) if !raw => {
+            let cartridge = Cartridge::load(Path::new(path)).unwrap_or_else(|err| {
+                eprintln!("{}", err);
+                std::process::exit(1);
+            });
+            // Check that the cartridge can be inserted before starting.
+            if let Err(err) = CPU::new().load_cartridge(&cartridge) {
+                eprintln!("{}", err);
+                std::process::exit(1);
+            }
+            Game::Cartridge(cartridge)
+        }
+        _ => {
+            let program = match &rom_path {
+                Some(path) => std::fs::read(path).unwrap_or_else(|err| {
+                    eprintln!("Failed to read {}: {}", path, err);
+                    std::process::exit(1);
+                }),
+                None => SNAKE_GAME.to_vec(),
+            };
+            let load_addr = match arg_value(&args, "--load-addr") {
+                Some(addr) => parse_number(addr).unwrap_or_else(|| {
+                    eprintln!("Invalid load address {}", addr);
+                    std::process::exit(1);
+                }),
+                None => DEFAULT_LOAD_ADDR,
+            };
+            if load_addr as usize + program.len() > 0x10000 {
+                eprintln!("The program doesn't fit in memory at ${:04X}", load_addr);
+                std::process::exit(1);
+            }
+            Game::Raw { program, load_addr }
+        }
+    };
+
+    let mut region = match arg_value(&args, "--region").map(String::as_str) {
+        None | Some("ntsc") => Region::Ntsc,
+        Some("pal") => Region::Pal,
+        Some("dendy") => Region::Dendy,
+        Some(other) => {
+            eprintln!("Unknown region {}, expected ntsc, pal or dendy", other);
+            std::process::exit(1);
+        }
+    };
+    let scale: f64 = match arg_value(&args, "--scale") {
+        Some(scale) => scale
+            .parse()
+            .ok()
+            .filter(|&scale: &f64| scale > 0.0)
+            .unwrap_or_else(|| {
+                eprintln!("Invalid scale {}", scale);
+                std::process::exit(1);
+            }),
+        None => 1.0,
+    };
+    let window_size = WINDOW_SIZE * scale;
+    let mute = args.iter().any(|arg| arg == "--mute");
+    let debug = args.iter().any(|arg| arg == "--debug");
+
+    // Labels for the debugger and the trace, from the symbol files next to the ROM and
+    // `--symbols game.dbg`.
+    let mut symbols = match &rom_path {
+        Some(path) => Symbols::for_rom(Path::new(path)).unwrap_or_else(|err| {
+            eprintln!("{}", err);
+            std::process::exit(1);
+        }),
+        None => Symbols::new(),
+    };
+    if let Some(path) = arg_value(&args, "--symbols") {
+        if let Err(err) = symbols.load(Path::new(path)) {
+            eprintln!("{}", err);
+            std::process::exit(1);
+        }
+    }
+
+    // The save state hotkeys use a file next to the ROM or the raw program.
+    let state_path = match &rom_path {
+        Some(path) => Path::new(path).with_extension("state"),
+        None => PathBuf::from(SNAKE_STATE_PATH),
+    };
+
+    // Cartridges with a battery keep their RAM in a save file next to the ROM.
+    let mut battery = rom_path.as_ref().and_then(|path| {
+        game.cartridge()
+            .filter(|cartridge| cartridge.battery)
+            .map(|_| BatteryRam::for_rom(Path::new(path)))
+    });
+    let battery_ram = battery.as_mut().and_then(|battery| match battery.load() {
+        Ok(ram) => ram,
+        Err(err) => {
+            eprintln!("Failed to read {}: {}", battery.path().display(), err);
+            std::process::exit(1);
+        }
+    });
+
+    // `--cdl game.cdl` logs which bytes of the ROM are code and which are data while playing,
+    // see `cdl.rs` for the format.
+    let cdl_path = arg_value(&args, "--cdl").cloned();
+    let cdl = cdl_path.as_ref().map(|path| {
+        let Some(cartridge) = game.cartridge() else {
+            eprintln!("--cdl needs a ROM");
+            std::process::exit(1);
+        };
+        let (prg_size, chr_size) = (cartridge.prg_rom.len(), cartridge.chr_rom.len());
+        CodeDataLog::load(Path::new(path), prg_size, chr_size).unwrap_or_else(|err| {
+            eprintln!("{}", err);
+            std::process::exit(1);
+        })
+    });
+
+    // `--bindings controls.cfg` replaces the default controls, see `bindings.rs` for the format.
+    let bindings = match arg_value(&args, "--bindings") {
+        Some(path) => Bindings::load(path).unwrap_or_else(|err| {
+            eprintln!("{}", err);
+            std::process::exit(1);
+        }),
+        None => Bindings::default(),
+    };
+
+    // `--multitap four-score` or `--multitap famicom` connects controllers 3 and 4 for games that
+    // support four players.
+    let multitap = match arg_value(&args, "--multitap").map(String::as_str) {
+        None | Some("none") => Multitap::None,
+        Some("four-score") => Multitap::FourScore,
+        Some("famicom") => Multitap::Famicom,
+        Some(other) => {
+            eprintln!(
+                "Unknown multitap {}, expected none, four-score or famicom",
+                other
+            );
+            std::process::exit(1);
+        }
+    };
+
+    // `--port2 zapper` plugs something else than a controller into the second port, and
+    // `--expansion family-keyboard` plugs something into the Famicom expansion port.
+    let port2: Option<Box<dyn InputDevice>> = match arg_value(&args, "--port2").map(String::as_str)
+    {
+        None | Some("controller") => None,
+        Some("zapper") => Some(Box::new(Zapper::new())),
+        Some("arkanoid") => Some(Box::new(Arkanoid::new())),
+        Some("power-pad") => Some(Box::new(PowerPad::new())),
+        Some(other) => {
+            eprintln!(
+                "Unknown device {}, expected controller, zapper, arkanoid or power-pad",
+                other
+            );
+            std::process::exit(1);
+        }
+    };
+    let expansion: Option<Box<dyn InputDevice>> =
+        match arg_value(&args, "--expansion").map(String::as_str) {
+            None | Some("none") => None,
+            Some("arkanoid") => Some(Box::new(Arkanoid::famicom())),
+            Some("family-keyboard") => Some(Box::new(FamilyKeyboard::new())),
+            Some(other) => {
+                eprintln!(
+                    "Unknown device {}, expected none, arkanoid or family-keyboard",
+                    other
+                );
+                std::process::exit(1);
+            }
+        };
+    // While the keyboard is plugged in every key goes to it instead of to the bindings.
+    let family_keyboard = arg_value(&args, "--expansion").is_some_and(|d| d == "family-keyboard");
+
+    // `--capture-audio out.wav --frames 600 --seed 1` runs headless and dumps the audio instead of
+    // opening a window.
+    if let Some(path) = arg_value(&args, "--capture-audio") {
+        let frames = arg_value(&args, "--frames")
+            .map(|frames| frames.parse().expect("--frames must be a number"))
+            .unwrap_or(600);
+        let seed = seed_arg(&args, || DEFAULT_SEED);
+        let cpu = power_on(&game, region, mute);
+        capture_audio(path, frames, seed, cpu, &game).expect("Failed to write WAV file");
+        return;
+    }
+
+    // `--gdb 1234` waits for `target remote localhost:1234` in GDB, see `gdb.rs`.
+    if let Some(port) = arg_value(&args, "--gdb") {
+        let port = port.parse().unwrap_or_else(|_| {
+            eprintln!("Invalid port {}", port);
+            std::process::exit(1);
+        });
+        let seed = seed_arg(&args, || DEFAULT_SEED);
+        std::process::exit(run_gdb(&game, region, port, seed));
+    }
+
+    // `--headless --frames 3600` runs without a window until a test ROM reports its result, and
+    // exits with the result as the status code. See `headless.rs` for how test ROMs report.
+    if args.iter().any(|arg| arg == "--headless") {
+        let frames = arg_value(&args, "--frames")
+            .map(|frames| frames.parse().expect("--frames must be a number"))
+            .unwrap_or(HEADLESS_FRAMES);
+        let seed = seed_arg(&args, || DEFAULT_SEED);
+        // `--trace trace.log --trace-from 0xC000` logs every instruction in the same format as
+        // nestest.log.
+        let tracer = arg_value(&args, "--trace").map(|path| {
+            let file = File::create(path).unwrap_or_else(|err| {
+                eprintln!("Failed to create {}: {}", path, err);
+                std::process::exit(1);
+            });
+            let tracer = Tracer::to_writer(BufWriter::new(file)).with_symbols(symbols.clone());
+            match arg_value(&args, "--trace-from") {
+                Some(addr) => match parse_number(addr) {
+                    Some(addr) => tracer.after_breakpoint(addr),
+                    None => {
+                        eprintln!("Invalid address {} for --trace-from", addr);
+                        std::process::exit(1);
+                    }
+                },
+                None => tracer,
+            }
+        });
+        std::process::exit(run_headless(&game, region, frames, seed, tracer));
+    }
+
+    // `--record movie.fm2` records the input to a movie, which is played back with
+    // `--play movie.fm2`. Both start from power on, or from `--load-state snake.state`.
+    let mut playback = arg_value(&args, "--play").map(|path| {
+        Movie::load(path).unwrap_or_else(|err| {
+            eprintln!("{}", err);
+            std::process::exit(1);
+        })
+    });
+    let record_path = arg_value(&args, "--record").cloned();
+    let mut start_state = arg_value(&args, "--load-state").map(|path| {
+        std::fs::read(path).unwrap_or_else(|err| {
+            eprintln!("Failed to read {}: {}", path, err);
+            std::process::exit(1);
+        })
+    });
+
+    // The snake game needs random numbers. A movie has to get the same numbers every time it's
+    // played so the seed is saved in the movie, otherwise it can be picked with `--seed`.
+    let seed = match &playback {
+        Some(movie) => movie
+            .extra("rngSeed")
+            .and_then(|seed| seed.parse().ok())
+            .unwrap_or(0),
+        None => seed_arg(&args, rand::random),
+    };
+
+    let mut multitap = multitap;
+    if let Some(movie) = &playback {
+        if movie.four_score {
+            multitap = Multitap::FourScore;
+        }
+        if movie.pal && region == Region::Ntsc {
+            region = Region::Pal;
+        }
+        if movie.savestate.is_some() {
+            start_state = movie.savestate.clone();
+        }
+    }
+
+    let recording = record_path.as_ref().map(|_| {
+        let rom_filename = match &rom_path {
+            Some(path) => Path::new(path)
+                .file_stem()
+                .map(|stem| stem.to_string_lossy().into_owned())
+                .unwrap_or_default(),
+            None => "snake".to_string(),
+        };
+        let mut movie = Movie::new(&rom_filename, &guid(seed));
+        movie.pal = region == Region::Pal;
+        movie.four_score = multitap == Multitap::FourScore;
+        movie.savestate = start_state.clone();
+        movie.set_extra("rngSeed", &seed.to_string());
+        Arc::new(Mutex::new(movie))
+    });
+    let movie_mode = playback.is_some() || recording.is_some();
+
     let (tx_nes, rx_nes) = mpsc::channel();
     let (tx_win, rx_win) = mpsc::channel();
 
     // The NES thread.
     // This thread runs the NES CPU and sends the screen state to the main thread.
+    let nes_recording = recording.clone();
     thread::spawn(move || {
-        let mut rng = rand::thread_rng();
-
-        let mut cpu = CPU::new();
-        // Since program execution will start from whatever is in memory location 0xFFFC, we need
-        // to set that to the start of the program. This snake game differs from other NES games
-        // where execution starts at 0x8000.
-        cpu.load_at_addr(0x0600, SNAKE_GAME.to_vec());
-        cpu.mem_write_u16(0xFFFC, 0x0600);
-        // Send the reset signal to the CPU signaling that a cartridge has been inserted.
-        cpu.reset();
+        let mut rng = SnakeRng::new(seed);
+        let mut cpu = power_on(&game, region, mute);
+        if let Some(ram) = &battery_ram {
+            cpu.load_prg_ram(ram);
+        }
+        if let Some(cdl) = cdl {
+            cpu.start_cdl(cdl);
+        }
+        cpu.controllers.set_multitap(multitap);
+        cpu.controllers.connect(1, port2);
+        cpu.controllers.connect_expansion(expansion);
+        if let Some(state) = &start_state {
+            if let Err(err) = cpu.load_state(state) {
+                eprintln!("Failed to load the save state: {}", err);
+                std::process::exit(1);
+            }
+        }
+
+        // The frame the movie starts on. The frame number is set to something that can't happen
+        // so the first frame is started on the first step.
+        let movie_start = cpu.frame();
+        let mut frame = u64::MAX;
+
+        let mut paused = false;
+        let mut fast_forward = false;
+        let mut rewinding = false;
+        let mut rewind = Rewind::default();
+
+        // `--debug` stops before the first instruction, F12 stops wherever the CPU is.
+        let mut debugger = Debugger::new();
+        debugger.set_symbols(symbols);
+        if debug {
+            debugger.pause();
+        }
+
+        // In movie mode the input from the window is only applied at the start of a frame, so
+        // it can be recorded and played back exactly.
+        let mut input = MovieFrame::default();
 
         cpu.run_with_callback(|cpu| {
+            // Handle the input from the window. While paused we stay in here and wait for the
+            // next message.
+            loop {
+                while let Ok(msg) = rx_win.try_recv() {
+                    match msg {
+                        WinMsg::Buttons(buttons) => {
+                            input.buttons = buttons;
+                            if !movie_mode {
+                                apply_input(cpu, &input, &game);
+                            }
+                        }
+                        WinMsg::Reset if movie_mode => input.commands |= Commands::SOFT_RESET,
+                        WinMsg::Reset => cpu.reset(),
+                        WinMsg::Pause => paused = !paused,
+                        WinMsg::Break => debugger.pause(),
+                        WinMsg::SaveState => {
+                            if let Err(err) = std::fs::write(&state_path, cpu.save_state()) {
+                                eprintln!("Failed to write {}: {}", state_path.display(), err);
+                            }
+                        }
+                        WinMsg::LoadState => match std::fs::read(&state_path) {
+                            Ok(state) => match cpu.load_state(&state) {
+                                Ok(()) => {
+                                    frame = cpu.frame();
+                                    if let Some(movie) = &nes_recording {
+                                        movie.lock().unwrap().rerecord_count += 1;
+                                    }
+                                }
+                                Err(err) => {
+                                    eprintln!("Failed to load {}: {}", state_path.display(), err)
+                                }
+                            },
+                            Err(err) => {
+                                eprintln!("Failed to read {}: {}", state_path.display(), err)
+                            }
+                        },
+                        WinMsg::FastForward(enabled) => fast_forward = enabled,
+                        WinMsg::Rewind(enabled) => {
+                            if rewinding && !enabled {
+                                if let Some(movie) = &nes_recording {
+                                    movie.lock().unwrap().rerecord_count += 1;
+                                }
+                            }
+                            rewinding = enabled;
+                        }
+                        WinMsg::Mouse(x, y) => {
+                            let controllers = &mut cpu.controllers;
+                            if let Some(zapper) = controllers.device_mut::<Zapper>(1) {
+                                zapper.aim(x, y);
+                            }
+                            let fraction = x as f64 / SCREEN_WIDTH as f64;
+                            if let Some(arkanoid) = controllers.device_mut::<Arkanoid>(1) {
+                                arkanoid.set_fraction(fraction);
+                            }
+                            if let Some(arkanoid) = controllers.expansion_mut::<Arkanoid>() {
+                                arkanoid.set_fraction(fraction);
+                            }
+                        }
+                        WinMsg::MouseButton(pressed) => {
+                            let controllers = &mut cpu.controllers;
+                            if let Some(zapper) = controllers.device_mut::<Zapper>(1) {
+                                zapper.set_trigger(pressed);
+                            }
+                            if let Some(arkanoid) = controllers.device_mut::<Arkanoid>(1) {
+                                arkanoid.set_button(pressed);
+                            }
+                            if let Some(arkanoid) = controllers.expansion_mut::<Arkanoid>() {
+                                arkanoid.set_button(pressed);
+                            }
+                        }
+                        WinMsg::PowerPad(button, pressed) => {
+                            if let Some(pad) = cpu.controllers.device_mut::<PowerPad>(1) {
+                                pad.set_button(button, pressed);
+                            }
+                        }
+                        WinMsg::FamilyKey(key, pressed) => {
+                            if let Some(keyboard) =
+                                cpu.controllers.expansion_mut::<FamilyKeyboard>()
+                            {
+                                keyboard.set_key(key, pressed);
+                            }
+                        }
+                        WinMsg::Quit => {
+                            save_battery_ram(cpu, &mut battery);
+                            save_cdl(cpu, cdl_path.as_deref());
+                            tx_nes
+                                .send(NesMsg {
+                                    screen_state: [0_u8; 32 * 32],
+                                    game_over: true,
+                                })
+                                .unwrap();
+                        }
+                    }
+                }
+
+                // Rewinding goes back one frame at a time at normal speed.
+                if rewinding {
+                    if let Some(state) = rewind.pop() {
+                        cpu.load_state(&state).unwrap();
+                        frame = cpu.frame();
+                    }
+                    tx_nes
+                        .send(NesMsg {
+                            screen_state: snake_screen(cpu),
+                            game_over: false,
+                        })
+                        .unwrap();
+                    std::thread::sleep(std::time::Duration::from_millis(16));
+                    continue;
+                }
+
+                if !paused {
+                    break;
+                }
+                std::thread::sleep(std::time::Duration::from_millis(10));
+            }
+
+            if cpu.frame() != frame {
+                frame = cpu.frame();
+
+                // Once a frame the state is saved for rewinding.
+                rewind.push(cpu.save_state());
+
+                // Save every now and then so not too much is lost if the emulator crashes.
+                if frame % BATTERY_SAVE_INTERVAL == 0 {
+                    save_battery_ram(cpu, &mut battery);
+                    save_cdl(cpu, cdl_path.as_deref());
+                }
+
+                // There is no PPU to draw a picture for the Zapper to look at, so the snake
+                // screen is scaled up to the size of a NES picture instead.
+                let screen_state = snake_screen(cpu);
+                if let Some(zapper) = cpu.controllers.device_mut::<Zapper>(1) {
+                    zapper.set_brightness(&snake_brightness(&screen_state));
+                }
+
+                // The movie frames count from where the movie started.
+                let index = frame.checked_sub(movie_start).map(|index| index as usize);
+                if let (Some(movie), Some(index)) = (&playback, index) {
+                    match movie.frames.get(index) {
+                        Some(movie_frame) => apply_input(cpu, movie_frame, &game),
+                        None => {
+                            println!("The movie has ended");
+                            playback = None;
+                        }
+                    }
+                } else if let (Some(movie), Some(index)) = (&nes_recording, index) {
+                    let mut movie = movie.lock().unwrap();
+                    movie.frames.truncate(index);
+                    movie.frames.push(input);
+                    apply_input(cpu, &input, &game);
+                    input.commands = Commands::empty();
+                }
+            }
+
             // This snake game requires us to insert a random number every step at this memory
             // location
             // This is just a unique quirk with this particular game and not a general NES thing.
-            cpu.mem_write(0xfe, rng.gen_range(1, 16));
+            if game.is_raw() {
+                rng.step(cpu);
+            }
 
-            // This checks if theres been any key presses and if so, inserts the key into the
-            // memory. The snake game reads from this memory location to get the key presses.
-            get_latest_message(&rx_win).map(|msg: WinMsg| {
-                cpu.mem_write(0xff, msg.key);
-            });
+            let screen_state = snake_screen(cpu);
 
-            // This snake game works differently from other NES games in that it doesn't use the
-            // PPU to draw to the screen. Instead, it writes the screen to memory.
-            //
-            // This is kind of a hack since this loop will only work for this particular game.
-            let mut screen_state = [0 as u8; 32 * 32];
-            for x in 0..32 {
-                for y in 0..32 {
-                    let i = 0x200 + x + y * 32;
-                    let color_idx = cpu.mem_read(i as u16);
-                    screen_state[x + y * 32] = color_idx;
-                }
-            }
             tx_nes
                 .send(NesMsg {
                     screen_state,
@@ -110,12 +997,34 @@ fn main() {
                 })
                 .unwrap();
 
-            std::thread::sleep(std::time::Duration::new(0, 70_000));
+            // The debugger reads its commands from the terminal while the CPU is stopped.
+            let stop = debugger.check(cpu);
+            for hit in debugger.take_log() {
+                println!("{}", hit);
+            }
+            if let Some(stop) = stop {
+                let stdin = std::io::stdin();
+                match debugger.repl(cpu, stop, stdin.lock(), std::io::stdout()) {
+                    Ok(true) => {}
+                    Ok(false) => {
+                        save_battery_ram(cpu, &mut battery);
+                        save_cdl(cpu, cdl_path.as_deref());
+                        std::process::exit(0);
+                    }
+                    Err(err) => eprintln!("The debugger failed: {}", err),
+                }
+            }
+
+            if !fast_forward {
+                std::thread::sleep(std::time::Duration::new(0, 70_000));
+            }
         });
 
+        save_battery_ram(&cpu, &mut battery);
+        save_cdl(&cpu, cdl_path.as_deref());
         tx_nes
             .send(NesMsg {
-                screen_state: [0 as u8; 32 * 32],
+                screen_state: [0_u8; 32 * 32],
                 game_over: true,
             })
             .unwrap();
@@ -123,7 +1032,7 @@ fn main() {
 
     // Boilerplate code for the window.
     let opengl = OpenGL::V3_2;
-    let mut window: Window = WindowSettings::new("NES Snake", [320, 320])
+    let mut window: Window = WindowSettings::new("NES Snake", [window_size, window_size])
         .samples(1)
         .graphics_api(opengl)
         .exit_on_esc(false)
@@ -132,6 +1041,8 @@ fn main() {
         .unwrap();
     let mut events = Events::new(EventSettings::new());
     let mut gl = GlGraphics::new(opengl);
+    let mut held: Vec<Input> = Vec::new();
+    let mut buttons = [Buttons::empty(); 4];
 
     // The main event loop for the window.
     while let Some(e) = events.next(&mut window) {
@@ -152,6 +1063,7 @@ fn main() {
             // snake game. This wont work for other roms.
             if let Some(msg) = msg {
                 gl.draw(args.viewport(), |c, gl| {
+                    let transform = c.transform.scale(scale, scale);
                     let screen_state = msg.screen_state;
                     clear([0.0, 0.0, 0.0, 1.0], gl);
 
@@ -163,7 +1075,7 @@ fn main() {
                             rectangle(
                                 color,
                                 [x as f64 * 10.0, y as f64 * 10.0, 10.0, 10.0],
-                                c.transform,
+                                transform,
                                 gl,
                             );
                         }
@@ -172,27 +1084,249 @@ fn main() {
             }
         }
 
-        // If a key is pressed, send the key to the NES thread.
-        // Since this snake game is made to work with only the NES cpu this code is specific to
-        // this game and wont work for other roms.
-        if let Some(Button::Keyboard(key)) = e.press_args() {
-            match key {
-                Key::W => {
-                    tx_win.send(WinMsg { key: 0x77 }).unwrap();
-                }
-                Key::S => {
-                    tx_win.send(WinMsg { key: 0x73 }).unwrap();
+        // Keep track of which inputs are held. Hotkeys are sent when they're pressed and the
+        // controllers are sent to the NES thread whenever they change.
+        if let Some(button) = e.button_args().filter(|_| family_keyboard) {
+            if let Button::Keyboard(key) = button.button {
+                if let Some(name) = family_key(key) {
+                    let pressed = button.state == ButtonState::Press;
+                    tx_win.send(WinMsg::FamilyKey(name, pressed)).unwrap();
                 }
-                Key::A => {
-                    tx_win.send(WinMsg { key: 0x61 }).unwrap();
+            }
+        } else if let Some(button) = e.button_args() {
+            // A hat reports its new direction rather than a press and a release, so forget the
+            // old direction first.
+            if let Button::Hat(hat) = button.button {
+                held.retain(|input| !matches!(input, Input::PadHat { id, .. } if *id == hat.id));
+            }
+
+            for input in Input::from_button(button.button) {
+                let pressed = button.state == ButtonState::Press;
+                let was_held = held.contains(&input);
+                if pressed && !was_held {
+                    held.push(input);
+                } else if !pressed {
+                    held.retain(|other| *other != input);
                 }
-                Key::D => {
-                    tx_win.send(WinMsg { key: 0x64 }).unwrap();
+
+                for action in bindings.actions(input) {
+                    let msg = match action {
+                        Action::Controller(..) => continue,
+                        Action::FastForward => WinMsg::FastForward(pressed),
+                        Action::Rewind => WinMsg::Rewind(pressed),
+                        Action::PowerPad(button) => WinMsg::PowerPad(button, pressed),
+                        _ if !pressed || was_held => continue,
+                        Action::Reset => WinMsg::Reset,
+                        Action::Pause => WinMsg::Pause,
+                        Action::SaveState => WinMsg::SaveState,
+                        Action::LoadState => WinMsg::LoadState,
+                        Action::Break => WinMsg::Break,
+                    };
+                    tx_win.send(msg).unwrap();
                 }
-                _ => {}
             }
         }
+
+        // The mouse is the Zapper or the Arkanoid knob. The window is scaled to the size of a NES
+        // picture.
+        if let Some([x, y]) = e.mouse_cursor_args() {
+            let x = x * SCREEN_WIDTH as f64 / window_size;
+            let y = y * SCREEN_HEIGHT as f64 / window_size;
+            tx_win.send(WinMsg::Mouse(x as i32, y as i32)).unwrap();
+        }
+        if let Some(button) = e.button_args() {
+            if button.button == Button::Mouse(MouseButton::Left) {
+                let pressed = button.state == ButtonState::Press;
+                tx_win.send(WinMsg::MouseButton(pressed)).unwrap();
+            }
+        }
+
+        let previous = buttons;
+        buttons = bindings.controller_state(&held);
+        if buttons != previous {
+            tx_win.send(WinMsg::Buttons(buttons)).unwrap();
+        }
+    }
+
+    // Give the NES thread a moment to save the battery-backed RAM. It might already have stopped,
+    // in which case the message can't be sent.
+    if tx_win.send(WinMsg::Quit).is_ok() {
+        let timeout = std::time::Duration::from_secs(1);
+        while let Ok(msg) = rx_nes.recv_timeout(timeout) {
+            if msg.game_over {
+                break;
+            }
+        }
+    }
+
+    // The movie is saved when the window is closed or the game ends.
+    if let (Some(movie), Some(path)) = (recording, record_path) {
+        if let Err(err) = movie.lock().unwrap().save(&path) {
+            eprintln!("Failed to write {}: {}", path, err);
+        }
+    }
+}
+
+/// Finds the key on the Family BASIC keyboard in the same place as `key`. Most keys have the same
+/// name, the rest are the closest thing on a modern keyboard.
+fn family_key(key: Key) -> Option<&'static str> {
+    let name = match key {
+        Key::Home => "ClrHome",
+        Key::LCtrl | Key::RCtrl => "Ctrl",
+        Key::LAlt => "Grph",
+        Key::RAlt => "Kana",
+        Key::Backslash => "Yen",
+        Key::Quote => "Colon",
+        Key::Backquote => "At",
+        Key::Equals => "Caret",
+        Key::Pause | Key::End => "Stop",
+        Key::Backspace => "Delete",
+        _ => {
+            let name = format!("{:?}", key);
+            return FAMILY_KEYS.iter().find(|key| **key == name).copied();
+        }
+    };
+    Some(name)
+}
+
+/// Keys on the Family BASIC keyboard that have the same name as in piston.
+const FAMILY_KEYS: &[&str] = &[
+    "A",
+    "B",
+    "C",
+    "D",
+    "E",
+    "F",
+    "G",
+    "H",
+    "I",
+    "J",
+    "K",
+    "L",
+    "M",
+    "N",
+    "O",
+    "P",
+    "Q",
+    "R",
+    "S",
+    "T",
+    "U",
+    "V",
+    "W",
+    "X",
+    "Y",
+    "Z",
+    "D0",
+    "D1",
+    "D2",
+    "D3",
+    "D4",
+    "D5",
+    "D6",
+    "D7",
+    "D8",
+    "D9",
+    "F1",
+    "F2",
+    "F3",
+    "F4",
+    "F5",
+    "F6",
+    "F7",
+    "F8",
+    "Return",
+    "Escape",
+    "Space",
+    "LShift",
+    "RShift",
+    "Left",
+    "Right",
+    "Up",
+    "Down",
+    "Insert",
+    "Delete",
+    "LeftBracket",
+    "RightBracket",
+    "Semicolon",
+    "Minus",
+    "Slash",
+    "Comma",
+    "Period",
+];
+
+/// Translates the D-pad to the ASCII codes of W, A, S and D that the snake game expects.
+fn snake_key(buttons: Buttons) -> Option<u8> {
+    if buttons.contains(Buttons::UP) {
+        Some(0x77)
+    } else if buttons.contains(Buttons::DOWN) {
+        Some(0x73)
+    } else if buttons.contains(Buttons::LEFT) {
+        Some(0x61)
+    } else if buttons.contains(Buttons::RIGHT) {
+        Some(0x64)
+    } else {
+        None
+    }
+}
+
+/// The random numbers the snake game reads from $FE. They only depend on the seed and the frame,
+/// that way they're the same in every mode, and when a movie is played back even if parts of it
+/// were rewound while recording.
+struct SnakeRng {
+    seed: u64,
+    frame: u64,
+    rng: StdRng,
+}
+
+impl SnakeRng {
+    fn new(seed: u64) -> SnakeRng {
+        SnakeRng {
+            seed,
+            // Can't happen, so the first step seeds for the frame the CPU is at.
+            frame: u64::MAX,
+            rng: StdRng::seed_from_u64(seed),
+        }
+    }
+
+    /// Writes the next random number to $FE, called before every instruction.
+    fn step(&mut self, cpu: &mut CPU) {
+        if cpu.frame() != self.frame {
+            self.frame = cpu.frame();
+            self.rng = StdRng::seed_from_u64(self.seed.wrapping_add(self.frame));
+        }
+        cpu.poke(0xfe, self.rng.gen_range(1, 16));
+    }
+}
+
+/// This snake game works differently from other NES games in that it doesn't use the PPU to draw
+/// to the screen. Instead, it writes the screen to memory.
+///
+/// This is kind of a hack since this will only work for this particular game.
+fn snake_screen(cpu: &CPU) -> [u8; 32 * 32] {
+    let mut screen_state = [0_u8; 32 * 32];
+    for x in 0..32 {
+        for y in 0..32 {
+            let i = 0x200 + x + y * 32;
+            let color_idx = cpu.peek(i as u16);
+            screen_state[x + y * 32] = color_idx;
+        }
+    }
+    screen_state
+}
+
+/// Scales the 32x32 snake screen up to a NES picture and returns the brightness of every pixel.
+fn snake_brightness(screen_state: &[u8; 32 * 32]) -> Vec<u8> {
+    let mut brightness = vec![0; SCREEN_WIDTH * SCREEN_HEIGHT];
+    for y in 0..SCREEN_HEIGHT {
+        for x in 0..SCREEN_WIDTH {
+            let cell = x * 32 / SCREEN_WIDTH + y * 32 / SCREEN_HEIGHT * 32;
+            let [r, g, b, _] = color(screen_state[cell]);
+            let luma = 0.299 * r + 0.587 * g + 0.114 * b;
+            brightness[x + y * SCREEN_WIDTH] = (luma * 255.0) as u8;
+        }
     }
+    brightness
 }
 
 /// Mapping the snake game's color palette to a format that piston can understand.
@@ -0,0 +1,354 @@
+//! Input movies in the FM2 format used by FCEUX.
+//!
+//! A movie is a header of `key value` lines followed by one line per frame with the buttons held
+//! on that frame, like `|0|R.D....A|........||`. The first field holds commands such as a reset,
+//! then comes a field for each controller port with the buttons in the order `RLDUTSBA`, where a
+//! `.` or a space is a button that isn't held, and last the Famicom expansion port which is
+//! always empty here.
+//!
+//! Movies that don't start from power on carry a save state in the `savestate` key. These are
+//! save states of this emulator, so such movies can't be played in FCEUX. Keys that aren't
+//! recognized are kept as they are, which is how `rngSeed` is stored for games that need random
+//! numbers from the frontend.
+
+use crate::joypad::Buttons;
+use std::fmt::Write as _;
+use std::io::{self, Write};
+
+/// The order of the buttons in an input line, each is written as its letter when held.
+const BUTTON_ORDER: [(Buttons, char); 8] = [
+    (Buttons::RIGHT, 'R'),
+    (Buttons::LEFT, 'L'),
+    (Buttons::DOWN, 'D'),
+    (Buttons::UP, 'U'),
+    (Buttons::START, 'T'),
+    (Buttons::SELECT, 'S'),
+    (Buttons::B, 'B'),
+    (Buttons::A, 'A'),
+];
+
+bitflags::bitflags! {
+    /// Things that happen at the start of a frame other than input.
+    #[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+    pub struct Commands: u8 {
+        const SOFT_RESET = 0b0000_0001;
+        /// Turning the power off and on again.
+        const HARD_RESET = 0b0000_0010;
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct MovieFrame {
+    pub commands: Commands,
+    /// The buttons held by each player. Players 3 and 4 are only written with a Four Score.
+    pub buttons: [Buttons; 4],
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Movie {
+    pub rom_filename: String,
+    pub guid: String,
+    pub rerecord_count: u32,
+    /// Whether the movie was recorded on a PAL console.
+    pub pal: bool,
+    pub four_score: bool,
+    pub comments: Vec<String>,
+    /// The save state the movie starts from, or None if it starts at power on.
+    pub savestate: Option<Vec<u8>>,
+    /// Keys in the header that aren't used by the emulator.
+    pub extra: Vec<(String, String)>,
+    pub frames: Vec<MovieFrame>,
+}
+
+impl Movie {
+    pub fn new(rom_filename: &str, guid: &str) -> Movie {
+        Movie {
+            rom_filename: rom_filename.to_string(),
+            guid: guid.to_string(),
+            rerecord_count: 0,
+            pal: false,
+            four_score: false,
+            comments: Vec::new(),
+            savestate: None,
+            extra: Vec::new(),
+            frames: Vec::new(),
+        }
+    }
+
+    /// Looks up a key that isn't used by the emulator.
+    pub fn extra(&self, key: &str) -> Option<&str> {
+        self.extra
+            .iter()
+            .find(|(k, _)| k == key)
+            .map(|(_, v)| v.as_str())
+    }
+
+    pub fn set_extra(&mut self, key: &str, value: &str) {
+        self.extra.retain(|(k, _)| k != key);
+        self.extra.push((key.to_string(), value.to_string()));
+    }
+
+    pub fn load(path: &str) -> Result<Movie, String> {
+        let text = std::fs::read_to_string(path)
+            .map_err(|err| format!("Failed to read {}: {}", path, err))?;
+        Movie::parse(&text)
+    }
+
+    pub fn parse(text: &str) -> Result<Movie, String> {
+        let mut movie = Movie::new("", "");
+
+        for (i, line) in text.lines().enumerate() {
+            let line = line.trim_end_matches('\r');
+            if line.is_empty() {
+                continue;
+            }
+
+            if line.starts_with('|') {
+                let frame = parse_frame(line, movie.four_score)
+                    .map_err(|err| format!("Line {}: {}", i + 1, err))?;
+                movie.frames.push(frame);
+                continue;
+            }
+
+            let (key, value) = line.split_once(' ').unwrap_or((line, ""));
+            match key {
+                "version" if value != "3" => {
+                    return Err(format!("Unsupported FM2 version {}", value));
+                }
+                // These are always written the same way by `write`.
+                "version" | "emuVersion" | "microphone" | "port0" | "port1" | "port2" => {}
+                "romFilename" => movie.rom_filename = value.to_string(),
+                "guid" => movie.guid = value.to_string(),
+                "rerecordCount" => {
+                    movie.rerecord_count = value
+                        .parse()
+                        .map_err(|_| format!("Invalid rerecordCount {}", value))?;
+                }
+                "palFlag" => movie.pal = value == "1",
+                "fourscore" => movie.four_score = value == "1",
+                "comment" => movie.comments.push(value.to_string()),
+                "savestate" => {
+                    let data = value.strip_prefix("base64:").unwrap_or(value);
+                    let state = base64_decode(data).ok_or("Invalid savestate")?;
+                    movie.savestate = Some(state);
+                }
+                _ => movie.extra.push((key.to_string(), value.to_string())),
+            }
+        }
+
+        Ok(movie)
+    }
+
+    pub fn write<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        writeln!(writer, "version 3")?;
+        writeln!(writer, "emuVersion 0")?;
+        writeln!(writer, "rerecordCount {}", self.rerecord_count)?;
+        writeln!(writer, "palFlag {}", self.pal as u8)?;
+        writeln!(writer, "romFilename {}", self.rom_filename)?;
+        writeln!(writer, "guid {}", self.guid)?;
+        writeln!(writer, "fourscore {}", self.four_score as u8)?;
+        writeln!(writer, "microphone 0")?;
+        // With a Four Score the ports are described by the fourscore key instead.
+        let port = if self.four_score { 0 } else { 1 };
+        writeln!(writer, "port0 {}", port)?;
+        writeln!(writer, "port1 {}", port)?;
+        writeln!(writer, "port2 0")?;
+        for (key, value) in &self.extra {
+            writeln!(writer, "{} {}", key, value)?;
+        }
+        for comment in &self.comments {
+            writeln!(writer, "comment {}", comment)?;
+        }
+        if let Some(state) = &self.savestate {
+            writeln!(writer, "savestate base64:{}", base64_encode(state))?;
+        }
+
+        for frame in &self.frames {
+            writeln!(writer, "{}", format_frame(frame, self.four_score))?;
+        }
+        Ok(())
+    }
+
+    pub fn save(&self, path: &str) -> io::Result<()> {
+        let mut file = io::BufWriter::new(std::fs::File::create(path)?);
+        self.write(&mut file)?;
+        file.flush()
+    }
+}
+
+fn parse_frame(line: &str, four_score: bool) -> Result<MovieFrame, String> {
+    let fields: Vec<&str> = line.split('|').collect();
+    // The line starts and ends with a |, so the first and last fields are empty. The commands
+    // and the expansion port come before and after the controllers.
+    let players = if four_score { 4 } else { 2 };
+    if fields.len() < players + 4 {
+        return Err(format!("Expected {} controllers in {}", players, line));
+    }
+
+    let commands = fields[1]
+        .trim()
+        .parse()
+        .map_err(|_| format!("Invalid commands in {}", line))?;
+    let mut frame = MovieFrame {
+        commands: Commands::from_bits_truncate(commands),
+        buttons: [Buttons::empty(); 4],
+    };
+
+    for (player, field) in fields[2..2 + players].iter().enumerate() {
+        // A port without a controller is left empty.
+        if field.is_empty() {
+            continue;
+        }
+        if field.chars().count() != 8 {
+            return Err(format!("Expected 8 buttons in {}", field));
+        }
+        for ((button, _), c) in BUTTON_ORDER.iter().zip(field.chars()) {
+            if c != '.' && c != ' ' {
+                frame.buttons[player].insert(*button);
+            }
+        }
+    }
+
+    Ok(frame)
+}
+
+fn format_frame(frame: &MovieFrame, four_score: bool) -> String {
+    let players = if four_score { 4 } else { 2 };
+    let mut line = format!("|{}|", frame.commands.bits());
+    for buttons in &frame.buttons[..players] {
+        for (button, c) in BUTTON_ORDER {
+            line.push(if buttons.contains(button) { c } else { '.' });
+        }
+        line.push('|');
+    }
+    // The expansion port.
+    line.push('|');
+    line
+}
+
+const BASE64: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+fn base64_encode(data: &[u8]) -> String {
+    let mut out = String::new();
+    for chunk in data.chunks(3) {
+        let bytes = [
+            chunk[0],
+            *chunk.get(1).unwrap_or(&0),
+            *chunk.get(2).unwrap_or(&0),
+        ];
+        let n = (bytes[0] as u32) << 16 | (bytes[1] as u32) << 8 | bytes[2] as u32;
+        for i in 0..4 {
+            if i <= chunk.len() {
+                let index = (n >> (18 - i * 6)) & 0x3F;
+                out.write_char(BASE64[index as usize] as char).unwrap();
+            } else {
+                out.push('=');
+            }
+        }
+    }
+    out
+}
+
+fn base64_decode(text: &str) -> Option<Vec<u8>> {
+    let text = text.trim().trim_end_matches('=');
+    let mut out = Vec::new();
+    let mut n = 0_u32;
+    let mut bits = 0;
+    for c in text.bytes() {
+        let value = BASE64.iter().position(|&b| b == c)? as u32;
+        n = n << 6 | value;
+        bits += 6;
+        if bits >= 8 {
+            bits -= 8;
+            out.push((n >> bits) as u8);
+        }
+    }
+    Some(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FCEUX_MOVIE: &str = "version 3
+emuVersion 22020
+rerecordCount 5
+palFlag 0
+romFilename Super Mario Bros.
+romChecksum base64:jjYwGG411HcjG/j9UOVM3Q==
+guid 2C2F2D5D-0E9B-4A2C-A2E5-0E1B2C3D4E5F
+fourscore 0
+microphone 0
+port0 1
+port1 1
+port2 0
+comment author someone
+|0|........|........||
+|1|........|........||
+|0|R......A|.L......||
+|0|...UTSB.|        ||
+";
+
+    #[test]
+    fn test_parse_fceux_movie() {
+        let movie = Movie::parse(FCEUX_MOVIE).unwrap();
+        assert_eq!(movie.rom_filename, "Super Mario Bros.");
+        assert_eq!(movie.rerecord_count, 5);
+        assert_eq!(movie.comments, vec!["author someone"]);
+        assert_eq!(
+            movie.extra("romChecksum"),
+            Some("base64:jjYwGG411HcjG/j9UOVM3Q==")
+        );
+        assert_eq!(movie.frames.len(), 4);
+        assert_eq!(movie.frames[1].commands, Commands::SOFT_RESET);
+        assert_eq!(movie.frames[2].buttons[0], Buttons::RIGHT | Buttons::A);
+        assert_eq!(movie.frames[2].buttons[1], Buttons::LEFT);
+        assert_eq!(
+            movie.frames[3].buttons[0],
+            Buttons::UP | Buttons::START | Buttons::SELECT | Buttons::B
+        );
+        assert_eq!(movie.frames[3].buttons[1], Buttons::empty());
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let mut movie = Movie::new("snake", "00000000-0000-0000-0000-000000000000");
+        movie.four_score = true;
+        movie.savestate = Some(vec![0, 1, 2, 3, 254, 255, 7]);
+        movie.set_extra("rngSeed", "1234");
+        movie.frames.push(MovieFrame {
+            commands: Commands::empty(),
+            buttons: [Buttons::A, Buttons::B, Buttons::START, Buttons::RIGHT],
+        });
+        movie.frames.push(MovieFrame {
+            commands: Commands::HARD_RESET,
+            buttons: [Buttons::empty(); 4],
+        });
+
+        let mut text = Vec::new();
+        movie.write(&mut text).unwrap();
+        let text = String::from_utf8(text).unwrap();
+        assert!(text.contains("|0|.......A|......B.|....T...|R.......||"));
+
+        let loaded = Movie::parse(&text).unwrap();
+        assert_eq!(loaded, movie);
+        assert_eq!(loaded.extra("rngSeed"), Some("1234"));
+    }
+
+    #[test]
+    fn test_base64() {
+        for data in [&b""[..], b"f", b"fo", b"foo", b"foob", b"fooba", b"foobar"] {
+            assert_eq!(base64_decode(&base64_encode(data)).unwrap(), data);
+        }
+        assert_eq!(base64_encode(b"foobar"), "Zm9vYmFy");
+        assert_eq!(base64_encode(b"fo"), "Zm8=");
+    }
+
+    #[test]
+    fn test_bad_movies() {
+        assert!(Movie::parse("version 2\n").is_err());
+        assert!(Movie::parse("|0|........|\n").is_err());
+        assert!(Movie::parse("|0|....|........||\n").is_err());
+        assert!(Movie::parse("|x|........|........||\n").is_err());
+    }
+}
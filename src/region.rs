@@ -0,0 +1,88 @@
+//! The different kinds of consoles. They run the same games but at different speeds.
+//!
+//! PAL consoles have a slower CPU and more scanlines per frame, so they run at 50 frames per
+//! second instead of 60. The Dendy is a Famicom clone sold in Russia that has the scanlines of a
+//! PAL console but divides its clock like an NTSC one, which makes most NTSC games run at the
+//! right speed on it.
+
+/// The PPU draws 341 dots per scanline on every console.
+const DOTS_PER_SCANLINE: u64 = 341;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Region {
+    #[default]
+    Ntsc,
+    Pal,
+    Dendy,
+}
+
+impl Region {
+    /// How many times a second the CPU is clocked.
+    pub fn cpu_clock(self) -> f64 {
+        match self {
+            Region::Ntsc => 1_789_773.0,
+            Region::Pal => 1_662_607.0,
+            Region::Dendy => 1_773_448.0,
+        }
+    }
+
+    pub fn scanlines_per_frame(self) -> u64 {
+        match self {
+            Region::Ntsc => 262,
+            Region::Pal | Region::Dendy => 312,
+        }
+    }
+
+    /// Number of CPU cycles in one frame, rounded up. NTSC and PAL frames are half a cycle longer
+    /// than this every other frame.
+    pub fn cycles_per_frame(self) -> u64 {
+        let dots = self.scanlines_per_frame() * DOTS_PER_SCANLINE;
+        (dots * self.cycles_divisor()).div_ceil(self.dots_multiplier())
+    }
+
+    /// The scanline the PPU is on `cycles` CPU cycles into a frame.
+    pub fn scanline(self, cycles: u64) -> usize {
+        (self.cycles_to_dots(cycles) / DOTS_PER_SCANLINE) as usize
+    }
+
+    /// The dot on the scanline the PPU is on `cycles` CPU cycles into a frame.
+    pub fn dot(self, cycles: u64) -> usize {
+        (self.cycles_to_dots(cycles) % DOTS_PER_SCANLINE) as usize
+    }
+
+    /// The PPU draws three dots per CPU cycle, except on PAL where it draws 3.2.
+    fn cycles_to_dots(self, cycles: u64) -> u64 {
+        cycles * self.dots_multiplier() / self.cycles_divisor()
+    }
+
+    fn dots_multiplier(self) -> u64 {
+        match self {
+            Region::Ntsc | Region::Dendy => 3,
+            Region::Pal => 16,
+        }
+    }
+
+    fn cycles_divisor(self) -> u64 {
+        match self {
+            Region::Ntsc | Region::Dendy => 1,
+            Region::Pal => 5,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cycles_per_frame() {
+        assert_eq!(Region::Ntsc.cycles_per_frame(), 29_781);
+        assert_eq!(Region::Pal.cycles_per_frame(), 33_248);
+        assert_eq!(Region::Dendy.cycles_per_frame(), 35_464);
+
+        assert_eq!(Region::Ntsc.scanline(0), 0);
+        assert_eq!(Region::Ntsc.scanline(114), 1);
+        assert_eq!(Region::Pal.scanline(107), 1);
+        assert_eq!(Region::Pal.scanline(33_247), 311);
+    }
+}
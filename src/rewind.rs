@@ -0,0 +1,230 @@
+//! Rewinding, by keeping a save state for every frame.
+//!
+//! Only the newest state is kept as is. For every older state the buffer keeps how it differs
+//! from the state after it, XORed together. Most of the machine doesn't change in a frame so the
+//! difference is mostly zeros, which are squeezed out by a simple run-length encoding. When the
+//! buffer is full the oldest states are thrown away.
+
+use std::collections::VecDeque;
+
+/// Enough for several minutes of most games.
+pub const DEFAULT_CAPACITY: usize = 64 * 1024 * 1024;
+
+/// The difference between a state and the state after it.
+#[derive(Clone, Debug)]
+struct Delta {
+    /// Length of the older state, in case the states have different lengths.
+    len: usize,
+    compressed: Vec<u8>,
+}
+
+#[derive(Clone, Debug)]
+pub struct Rewind {
+    capacity: usize,
+    newest: Option<Vec<u8>>,
+    /// Oldest first.
+    deltas: VecDeque<Delta>,
+    used: usize,
+}
+
+impl Default for Rewind {
+    fn default() -> Self {
+        Rewind::new(DEFAULT_CAPACITY)
+    }
+}
+
+impl Rewind {
+    /// Creates a buffer that uses at most `capacity` bytes for older states.
+    pub fn new(capacity: usize) -> Rewind {
+        Rewind {
+            capacity,
+            newest: None,
+            deltas: VecDeque::new(),
+            used: 0,
+        }
+    }
+
+    /// Adds a state to the buffer, usually once every frame.
+    pub fn push(&mut self, state: Vec<u8>) {
+        if let Some(newest) = self.newest.take() {
+            let delta = Delta {
+                len: newest.len(),
+                compressed: compress(&xor(&newest, &state)),
+            };
+            self.used += delta.compressed.len();
+            self.deltas.push_back(delta);
+
+            while self.used > self.capacity {
+                match self.deltas.pop_front() {
+                    Some(oldest) => self.used -= oldest.compressed.len(),
+                    None => break,
+                }
+            }
+        }
+        self.newest = Some(state);
+    }
+
+    /// Steps back one state and returns it. The newest state is thrown away, so the returned
+    /// state is the newest one afterwards. Returns `None` when there is nothing to go back to.
+    pub fn pop(&mut self) -> Option<Vec<u8>> {
+        let delta = self.deltas.pop_back()?;
+        self.used -= delta.compressed.len();
+
+        let newest = self.newest.as_ref()?;
+        let mut previous = xor(newest, &decompress(&delta.compressed));
+        previous.truncate(delta.len);
+
+        self.newest = Some(previous.clone());
+        Some(previous)
+    }
+
+    /// How many states back it's possible to go.
+    pub fn len(&self) -> usize {
+        self.deltas.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.deltas.is_empty()
+    }
+
+    /// Bytes used by the older states.
+    pub fn memory_used(&self) -> usize {
+        self.used
+    }
+
+    pub fn clear(&mut self) {
+        self.newest = None;
+        self.deltas.clear();
+        self.used = 0;
+    }
+}
+
+/// XORs two states together. The shorter one is padded with zeros.
+fn xor(a: &[u8], b: &[u8]) -> Vec<u8> {
+    let len = a.len().max(b.len());
+    (0..len)
+        .map(|i| a.get(i).unwrap_or(&0) ^ b.get(i).unwrap_or(&0))
+        .collect()
+}
+
+/// Run-length encodes the zeros in `data`. The output is a list of pairs of the number of zeros
+/// and the number of bytes that follow them as is, both as variable length integers, followed by
+/// those bytes.
+fn compress(data: &[u8]) -> Vec<u8> {
+    let mut out = Vec::new();
+    let mut i = 0;
+    while i < data.len() {
+        let zeros = data[i..].iter().take_while(|&&b| b == 0).count();
+        i += zeros;
+
+        // A single zero between two non-zero bytes is cheaper to keep as a literal.
+        let start = i;
+        while i < data.len() && (data[i] != 0 || data.get(i + 1).is_some_and(|&b| b != 0)) {
+            i += 1;
+        }
+
+        write_varint(&mut out, zeros);
+        write_varint(&mut out, i - start);
+        out.extend_from_slice(&data[start..i]);
+    }
+    out
+}
+
+fn decompress(mut data: &[u8]) -> Vec<u8> {
+    let mut out = Vec::new();
+    while !data.is_empty() {
+        let zeros = read_varint(&mut data);
+        let literals = read_varint(&mut data);
+        out.resize(out.len() + zeros, 0);
+        out.extend_from_slice(&data[..literals]);
+        data = &data[literals..];
+    }
+    out
+}
+
+fn write_varint(out: &mut Vec<u8>, mut value: usize) {
+    while value >= 0x80 {
+        out.push(value as u8 | 0x80);
+        value >>= 7;
+    }
+    out.push(value as u8);
+}
+
+fn read_varint(data: &mut &[u8]) -> usize {
+    let mut value = 0;
+    let mut shift = 0;
+    loop {
+        let byte = data[0];
+        *data = &data[1..];
+        value |= ((byte & 0x7F) as usize) << shift;
+        if byte & 0x80 == 0 {
+            return value;
+        }
+        shift += 7;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_compress_round_trip() {
+        let mut data = vec![0; 1000];
+        data[0] = 1;
+        data[500] = 2;
+        data[501] = 0;
+        data[502] = 3;
+        data[999] = 4;
+
+        let compressed = compress(&data);
+        assert!(compressed.len() < 20);
+        assert_eq!(decompress(&compressed), data);
+
+        assert_eq!(decompress(&compress(&[])), Vec::<u8>::new());
+        assert_eq!(decompress(&compress(&[0, 0, 0])), vec![0, 0, 0]);
+    }
+
+    #[test]
+    fn test_steps_back_in_order() {
+        let mut rewind = Rewind::default();
+        for frame in 0..10_u8 {
+            let mut state = vec![0; 4096];
+            state[100] = frame;
+            state[200] = frame * 2;
+            rewind.push(state);
+        }
+        assert_eq!(rewind.len(), 9);
+
+        for frame in (0..9).rev() {
+            let state = rewind.pop().unwrap();
+            assert_eq!(state[100], frame);
+            assert_eq!(state[200], frame * 2);
+        }
+        assert!(rewind.pop().is_none());
+    }
+
+    #[test]
+    fn test_oldest_states_are_dropped() {
+        let mut rewind = Rewind::new(100);
+        for frame in 0..1000_u32 {
+            rewind.push(frame.to_le_bytes().repeat(8));
+        }
+
+        assert!(rewind.memory_used() <= 100);
+        assert!(rewind.len() > 1);
+        let state = rewind.pop().unwrap();
+        assert_eq!(state, 998_u32.to_le_bytes().repeat(8));
+    }
+
+    #[test]
+    fn test_states_of_different_lengths() {
+        let mut rewind = Rewind::default();
+        rewind.push(vec![1, 2, 3]);
+        rewind.push(vec![1, 2, 3, 4, 5]);
+        rewind.push(vec![9]);
+
+        assert_eq!(rewind.pop(), Some(vec![1, 2, 3, 4, 5]));
+        assert_eq!(rewind.pop(), Some(vec![1, 2, 3]));
+    }
+}
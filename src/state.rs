@@ -0,0 +1,371 @@
+//! Save states.
+//!
+//! A save state starts with a header, the magic bytes `NESS` followed by the version of the
+//! format as a little endian u16. After that comes a list of chunks, each one is a 4 byte id, the
+//! length of the data as a little endian u32 and then the data. Every part of the emulator has its
+//! own chunk, so new parts can be added later without breaking old states and chunks that aren't
+//! recognized are skipped.
+//!
+//! The same `sync` method is used both for saving and loading a part, that way the two can't get
+//! out of step with each other.
+
+use std::fmt;
+
+const MAGIC: &[u8; 4] = b"NESS";
+/// The current version of the format. This is bumped whenever the contents of a chunk change.
+pub const VERSION: u16 = 1;
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum StateError {
+    /// The data doesn't start with the magic bytes, so it's not a save state.
+    NotAState,
+    /// The state was made by a newer version of the emulator.
+    UnsupportedVersion(u16),
+    MissingChunk([u8; 4]),
+    /// A chunk or the state itself ended too early.
+    Truncated,
+    /// The state was saved with something plugged in that isn't plugged in now, or the other
+    /// way around. For example a sound chip on the cartridge.
+    Mismatch(&'static str),
+}
+
+impl fmt::Display for StateError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            StateError::NotAState => write!(f, "Not a save state"),
+            StateError::UnsupportedVersion(version) => write!(
+                f,
+                "Save state version {} is newer than the supported version {}",
+                version, VERSION
+            ),
+            StateError::MissingChunk(id) => write!(
+                f,
+                "Save state is missing the {} chunk",
+                String::from_utf8_lossy(id)
+            ),
+            StateError::Truncated => write!(f, "Save state is truncated"),
+            StateError::Mismatch(what) => write!(f, "Save state doesn't match the {}", what),
+        }
+    }
+}
+
+impl std::error::Error for StateError {}
+
+/// Something that can be saved in a save state.
+pub trait State {
+    /// Writes the state to `s` when saving, or reads it back when loading.
+    fn sync(&mut self, s: &mut StateSync);
+}
+
+/// Either writes values to a buffer or reads them back, depending on what it was created for.
+pub struct StateSync<'a> {
+    mode: Mode<'a>,
+    error: Option<StateError>,
+}
+
+enum Mode<'a> {
+    Save(Vec<u8>),
+    Load(&'a [u8]),
+}
+
+impl<'a> StateSync<'a> {
+    pub fn saver() -> StateSync<'a> {
+        StateSync {
+            mode: Mode::Save(Vec::new()),
+            error: None,
+        }
+    }
+
+    pub fn loader(data: &'a [u8]) -> StateSync<'a> {
+        StateSync {
+            mode: Mode::Load(data),
+            error: None,
+        }
+    }
+
+    pub fn is_loading(&self) -> bool {
+        matches!(self.mode, Mode::Load(_))
+    }
+
+    pub fn sync<T: State>(&mut self, value: &mut T) {
+        value.sync(self);
+    }
+
+    /// Writes `bytes`, or fills it with the next bytes when loading.
+    pub fn bytes(&mut self, bytes: &mut [u8]) {
+        match &mut self.mode {
+            Mode::Save(data) => data.extend_from_slice(bytes),
+            Mode::Load(data) => {
+                if data.len() < bytes.len() {
+                    self.error = Some(StateError::Truncated);
+                    *data = &[];
+                    return;
+                }
+                let (head, tail) = data.split_at(bytes.len());
+                bytes.copy_from_slice(head);
+                *data = tail;
+            }
+        }
+    }
+
+    /// Flags the state as not matching what is being loaded into.
+    pub fn mismatch(&mut self, what: &'static str) {
+        self.error.get_or_insert(StateError::Mismatch(what));
+    }
+
+    /// The saved data, or the first error that happened while loading.
+    pub fn finish(self) -> Result<Vec<u8>, StateError> {
+        match (self.error, self.mode) {
+            (Some(error), _) => Err(error),
+            (None, Mode::Save(data)) => Ok(data),
+            (None, Mode::Load(_)) => Ok(Vec::new()),
+        }
+    }
+}
+
+macro_rules! impl_state_for_numbers {
+    ($($ty:ty),*) => {
+        $(
+            impl State for $ty {
+                fn sync(&mut self, s: &mut StateSync) {
+                    let mut bytes = self.to_le_bytes();
+                    s.bytes(&mut bytes);
+                    *self = <$ty>::from_le_bytes(bytes);
+                }
+            }
+        )*
+    };
+}
+
+impl_state_for_numbers!(u8, u16, u32, u64, i8, i16, i32, i64, f32, f64);
+
+impl State for usize {
+    fn sync(&mut self, s: &mut StateSync) {
+        let mut value = *self as u64;
+        s.sync(&mut value);
+        *self = value as usize;
+    }
+}
+
+impl State for bool {
+    fn sync(&mut self, s: &mut StateSync) {
+        let mut value = *self as u8;
+        s.sync(&mut value);
+        *self = value != 0;
+    }
+}
+
+impl<T: State + Default> State for Option<T> {
+    fn sync(&mut self, s: &mut StateSync) {
+        let mut present = self.is_some();
+        s.sync(&mut present);
+        if !present {
+            *self = None;
+            return;
+        }
+        s.sync(self.get_or_insert_with(T::default));
+    }
+}
+
+impl<T: State, const N: usize> State for [T; N] {
+    fn sync(&mut self, s: &mut StateSync) {
+        for value in self.iter_mut() {
+            s.sync(value);
+        }
+    }
+}
+
+/// Implements `State` for a struct by syncing the given fields in order.
+macro_rules! impl_state {
+    ($ty:ty { $($field:ident),* $(,)? }) => {
+        impl $crate::state::State for $ty {
+            fn sync(&mut self, s: &mut $crate::state::StateSync) {
+                $( s.sync(&mut self.$field); )*
+            }
+        }
+    };
+}
+
+pub(crate) use impl_state;
+
+/// Builds a save state out of chunks.
+pub struct StateWriter {
+    data: Vec<u8>,
+}
+
+impl Default for StateWriter {
+    fn default() -> Self {
+        StateWriter::new()
+    }
+}
+
+impl StateWriter {
+    pub fn new() -> StateWriter {
+        let mut data = MAGIC.to_vec();
+        data.extend_from_slice(&VERSION.to_le_bytes());
+        StateWriter { data }
+    }
+
+    /// Adds a chunk with the state of `value`.
+    pub fn chunk<T: State + ?Sized>(&mut self, id: &[u8; 4], value: &mut T) {
+        let mut s = StateSync::saver();
+        value.sync(&mut s);
+        let chunk = s.finish().expect("Saving can't fail");
+
+        self.data.extend_from_slice(id);
+        self.data
+            .extend_from_slice(&(chunk.len() as u32).to_le_bytes());
+        self.data.extend_from_slice(&chunk);
+    }
+
+    pub fn finish(self) -> Vec<u8> {
+        self.data
+    }
+}
+
+/// Reads the chunks of a save state.
+pub struct StateReader<'a> {
+    version: u16,
+    chunks: Vec<([u8; 4], &'a [u8])>,
+}
+
+impl<'a> StateReader<'a> {
+    pub fn new(data: &'a [u8]) -> Result<StateReader<'a>, StateError> {
+        if data.len() < 6 || &data[0..4] != MAGIC {
+            return Err(StateError::NotAState);
+        }
+        let version = u16::from_le_bytes([data[4], data[5]]);
+        if version > VERSION {
+            return Err(StateError::UnsupportedVersion(version));
+        }
+
+        let mut chunks = Vec::new();
+        let mut rest = &data[6..];
+        while !rest.is_empty() {
+            if rest.len() < 8 {
+                return Err(StateError::Truncated);
+            }
+            let id = [rest[0], rest[1], rest[2], rest[3]];
+            let len = u32::from_le_bytes([rest[4], rest[5], rest[6], rest[7]]) as usize;
+            rest = &rest[8..];
+            if rest.len() < len {
+                return Err(StateError::Truncated);
+            }
+            chunks.push((id, &rest[..len]));
+            rest = &rest[len..];
+        }
+
+        Ok(StateReader { version, chunks })
+    }
+
+    /// The version of the format the state was saved with.
+    pub fn version(&self) -> u16 {
+        self.version
+    }
+
+    pub fn has_chunk(&self, id: &[u8; 4]) -> bool {
+        self.chunks.iter().any(|(chunk, _)| chunk == id)
+    }
+
+    /// Loads the chunk with the given id into `value`.
+    pub fn chunk<T: State + ?Sized>(&self, id: &[u8; 4], value: &mut T) -> Result<(), StateError> {
+        let (_, data) = self
+            .chunks
+            .iter()
+            .find(|(chunk, _)| chunk == id)
+            .ok_or(StateError::MissingChunk(*id))?;
+
+        let mut s = StateSync::loader(data);
+        value.sync(&mut s);
+        s.finish().map(|_| ())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Debug, Default, PartialEq)]
+    struct Example {
+        a: u8,
+        b: u16,
+        c: bool,
+        d: f32,
+        e: [i16; 3],
+        f: Option<u8>,
+    }
+
+    impl_state!(Example { a, b, c, d, e, f });
+
+    fn example() -> Example {
+        Example {
+            a: 1,
+            b: 0x1234,
+            c: true,
+            d: 0.5,
+            e: [-1, 2, -3],
+            f: Some(7),
+        }
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let mut writer = StateWriter::new();
+        writer.chunk(b"TEST", &mut example());
+        let data = writer.finish();
+
+        let reader = StateReader::new(&data).unwrap();
+        assert_eq!(reader.version(), VERSION);
+        let mut loaded = Example::default();
+        reader.chunk(b"TEST", &mut loaded).unwrap();
+        assert_eq!(loaded, example());
+    }
+
+    #[test]
+    fn test_unknown_chunks_are_skipped() {
+        let mut writer = StateWriter::new();
+        writer.chunk(b"NEW!", &mut 0xDEAD_BEEF_u32);
+        writer.chunk(b"TEST", &mut example());
+        let data = writer.finish();
+
+        let reader = StateReader::new(&data).unwrap();
+        let mut loaded = Example::default();
+        reader.chunk(b"TEST", &mut loaded).unwrap();
+        assert_eq!(loaded, example());
+        assert_eq!(
+            reader.chunk(b"GONE", &mut loaded),
+            Err(StateError::MissingChunk(*b"GONE"))
+        );
+    }
+
+    #[test]
+    fn test_bad_states() {
+        assert_eq!(
+            StateReader::new(b"NOPE\x01\x00").err(),
+            Some(StateError::NotAState)
+        );
+        assert_eq!(
+            StateReader::new(b"NESS\xFF\xFF").err(),
+            Some(StateError::UnsupportedVersion(0xFFFF))
+        );
+
+        let mut writer = StateWriter::new();
+        writer.chunk(b"TEST", &mut example());
+        let data = writer.finish();
+        assert_eq!(
+            StateReader::new(&data[..data.len() - 1]).err(),
+            Some(StateError::Truncated)
+        );
+
+        // A chunk that is shorter than what is being loaded.
+        let mut writer = StateWriter::new();
+        writer.chunk(b"TEST", &mut 1_u8);
+        let data = writer.finish();
+        let reader = StateReader::new(&data).unwrap();
+        let mut loaded = Example::default();
+        assert_eq!(
+            reader.chunk(b"TEST", &mut loaded),
+            Err(StateError::Truncated)
+        );
+    }
+}
@@ -0,0 +1,33 @@
+//! Runs Klaus Dormann's 6502 functional test from
+//! https://github.com/Klaus2m5/6502_65C02_functional_tests.
+//!
+//! The binary isn't part of the repository, put it in `tests/roms/` and run the test with
+//! `--ignored`. The NES CPU doesn't have decimal mode, so `6502_functional_test.bin` has to be
+//! assembled from `6502_functional_test.a65` with `disable_decimal = 1`, the one in `bin_files/`
+//! tests decimal mode and fails. With the other options left at their defaults it ends at
+//! $3469. When it fails the address it got stuck at can be looked up in the listing.
+//!
+//! The decimal test from the same repository isn't run, there is no decimal mode to test.
+
+use nes_emulator::cpu::CPU;
+use nes_emulator::headless::{self, Trap};
+
+use std::path::Path;
+
+const ROMS: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/roms");
+
+/// The functional test takes about 100 million cycles.
+const MAX_CYCLES: u64 = 200_000_000;
+
+#[test]
+#[ignore = "needs tests/roms/6502_functional_test.bin built with disable_decimal = 1"]
+fn functional_test() {
+    let name = "6502_functional_test.bin";
+    let image = std::fs::read(Path::new(ROMS).join(name))
+        .unwrap_or_else(|err| panic!("Failed to read {} from {}: {}", name, ROMS, err));
+
+    let mut cpu = CPU::new();
+    headless::load_flat(&mut cpu, &image, headless::FUNCTIONAL_TEST_START).unwrap();
+    let trap = headless::run_until_trap(&mut cpu, MAX_CYCLES);
+    assert_eq!(trap, Trap::Trapped(headless::FUNCTIONAL_TEST_SUCCESS));
+}
@@ -0,0 +1,58 @@
+//! Runs kevtris' nestest.nes and compares every instruction with the log from Nintendulator.
+//!
+//! The ROM and the log aren't part of the repository, put `nestest.nes` and `nestest.log` in
+//! `tests/roms/` and run the test with `cargo test --test nestest -- --ignored`.
+//!
+//! The CPU only has the official instructions. The official tests come first in nestest, and
+//! the log marks the unofficial instructions with a `*`, so the comparison stops at the first
+//! one and only the result of the official tests is checked.
+
+use nes_emulator::cartridge::Cartridge;
+use nes_emulator::cpu::{StatusFlags, CPU};
+
+use std::path::Path;
+
+const ROMS: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/roms");
+
+#[test]
+#[ignore = "needs tests/roms/nestest.nes and nestest.log"]
+fn nestest() {
+    let roms = Path::new(ROMS);
+    let (Ok(cartridge), Ok(log)) = (
+        Cartridge::load(&roms.join("nestest.nes")),
+        std::fs::read_to_string(roms.join("nestest.log")),
+    ) else {
+        panic!("nestest.nes and nestest.log aren't in {}", ROMS);
+    };
+
+    let mut cpu = CPU::new();
+    cpu.load_cartridge(&cartridge).unwrap();
+    // In automation mode the tests start at $C000 instead of the reset vector. The log starts
+    // after the 7 cycles the reset takes.
+    cpu.pc = 0xC000;
+    cpu.sp = 0xFD;
+    cpu.status = StatusFlags::INTERRUPT_DISABLE;
+    cpu.cycles = 7;
+
+    let mut previous = String::new();
+    for (i, expected) in log.lines().enumerate() {
+        if expected.get(15..16) == Some("*") {
+            break;
+        }
+        let actual = cpu.trace();
+        assert!(
+            actual == expected,
+            "nestest differs from the log at line {}\n  previous: {}\n  expected: {}\n  actual:   {}",
+            i + 1,
+            previous,
+            expected,
+            actual
+        );
+
+        assert!(!cpu.run_step(), "The CPU stopped at line {}", i + 1);
+        previous = actual;
+    }
+
+    // The result of the official tests is at $02, zero means every test passed.
+    assert_eq!(cpu.peek(0x02), 0, "Official instructions failed");
+}
@@ -0,0 +1,306 @@
+//! Runs the 6502 tests from Tom Harte's SingleStepTests, a JSON file per op code with 10 000
+//! cases each. Every case has the registers and memory before and after a single instruction,
+//! and the bus activity of every cycle in between.
+//!
+//! The tests aren't part of the repository. Put the JSON files from the `6502/v1` directory of
+//! https://github.com/SingleStepTests/ProcessorTests in `tests/roms/6502/`, or point
+//! `PROCESSOR_TESTS` at them, and run the test with `--ignored`.
+//!
+//! The CPU doesn't model the bus one cycle at a time, it leaves out the dummy reads and the dummy
+//! write of read-modify-write instructions. So the bus activity is checked as far as the CPU can
+//! report it through watchpoints: the writes have to be the same once the dummy write is left out,
+//! and the reads have to be on the bus in the same order. Besides that the number of cycles is
+//! checked. `BRK` stops the CPU instead of going through the IRQ vector, so it isn't tested.
+
+use nes_emulator::cpu::watch::{Access, WatchAction};
+use nes_emulator::cpu::{StatusFlags, CPU};
+use serde_json::Value;
+
+use std::path::PathBuf;
+
+const DEFAULT_DIR: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/roms/6502");
+
+/// The break flag and bit 5 only exist when the status is pushed to the stack.
+const IGNORED_FLAGS: u8 = 0b0011_0000;
+
+/// The op codes the CPU runs differently from a 6502 on purpose, `BRK` stops it.
+const UNSUPPORTED: [u8; 1] = [0x00];
+
+/// How many failing cases are shown for each op code.
+const MAX_REPORTED: usize = 3;
+
+#[derive(Debug, PartialEq)]
+struct State {
+    pc: u16,
+    s: u8,
+    a: u8,
+    x: u8,
+    y: u8,
+    p: u8,
+    ram: Vec<(u16, u8)>,
+}
+
+impl State {
+    fn parse(value: &Value) -> Option<State> {
+        let field = |name: &str| value.get(name)?.as_u64();
+        let ram = value
+            .get("ram")?
+            .as_array()?
+            .iter()
+            .map(|entry| {
+                Some((
+                    entry.get(0)?.as_u64()? as u16,
+                    entry.get(1)?.as_u64()? as u8,
+                ))
+            })
+            .collect::<Option<Vec<_>>>()?;
+
+        Some(State {
+            pc: field("pc")? as u16,
+            s: field("s")? as u8,
+            a: field("a")? as u8,
+            x: field("x")? as u8,
+            y: field("y")? as u8,
+            p: field("p")? as u8,
+            ram,
+        })
+    }
+
+    /// Reads the same registers and memory locations as `self` from the CPU.
+    fn read(&self, cpu: &CPU) -> State {
+        State {
+            pc: cpu.pc,
+            s: cpu.sp,
+            a: cpu.reg_a,
+            x: cpu.reg_x,
+            y: cpu.reg_y,
+            p: cpu.status.bits() & !IGNORED_FLAGS,
+            ram: self
+                .ram
+                .iter()
+                .map(|&(addr, _)| (addr, cpu.peek(addr)))
+                .collect(),
+        }
+    }
+
+    /// The addresses where the CPU doesn't have plain memory, the APU and the controllers.
+    fn touches_registers(&self) -> bool {
+        self.ram
+            .iter()
+            .any(|&(addr, _)| (0x4000..=0x401F).contains(&addr))
+    }
+}
+
+/// A read or a write on the bus.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+struct BusAccess {
+    addr: u16,
+    value: u8,
+    write: bool,
+}
+
+impl BusAccess {
+    fn parse(cycle: &Value) -> Option<BusAccess> {
+        Some(BusAccess {
+            addr: cycle.get(0)?.as_u64()? as u16,
+            value: cycle.get(1)?.as_u64()? as u8,
+            write: cycle.get(2)?.as_str()? == "write",
+        })
+    }
+}
+
+/// Compares what the CPU did on the bus with the cycles of the case.
+fn check_bus(expected: &[BusAccess], actual: &[BusAccess]) -> Result<(), String> {
+    // A read-modify-write instruction writes the value it read back first, the CPU doesn't.
+    let writes: Vec<_> = expected.iter().filter(|access| access.write).collect();
+    let expected_writes: Vec<_> = writes
+        .iter()
+        .enumerate()
+        .filter(|&(i, write)| writes.get(i + 1).is_none_or(|next| next.addr != write.addr))
+        .map(|(_, &&write)| write)
+        .collect();
+    let actual_writes: Vec<_> = actual
+        .iter()
+        .copied()
+        .filter(|access| access.write)
+        .collect();
+    if actual_writes != expected_writes {
+        return Err(format!(
+            "expected the writes {:02X?}, got {:02X?}",
+            expected_writes, actual_writes
+        ));
+    }
+
+    // The dummy reads are missing, but the rest have to be there in the same order.
+    let mut expected_reads = expected.iter().filter(|access| !access.write);
+    for read in actual.iter().filter(|access| !access.write) {
+        if !expected_reads.any(|expected| expected == read) {
+            return Err(format!(
+                "read ${:02X} from ${:04X}, which isn't on the bus at that point",
+                read.value, read.addr
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// Runs a single case, returns what went wrong if it failed. Returns `Ok(false)` if the case
+/// can't be run on the NES.
+fn run_case(case: &Value) -> Result<bool, String> {
+    let name = case.get("name").and_then(Value::as_str).unwrap_or("?");
+    let (Some(initial), Some(expected), Some(cycles)) = (
+        case.get("initial").and_then(State::parse),
+        case.get("final").and_then(State::parse),
+        case.get("cycles").and_then(Value::as_array),
+    ) else {
+        return Err(format!("{}: the case couldn't be parsed", name));
+    };
+
+    let Some(bus) = cycles
+        .iter()
+        .map(BusAccess::parse)
+        .collect::<Option<Vec<_>>>()
+    else {
+        return Err(format!("{}: the cycles couldn't be parsed", name));
+    };
+    let bus_touches_registers = bus
+        .iter()
+        .any(|access| (0x4000..=0x401F).contains(&access.addr));
+    if initial.touches_registers() || expected.touches_registers() || bus_touches_registers {
+        return Ok(false);
+    }
+
+    let mut cpu = CPU::new();
+    for &(addr, value) in &initial.ram {
+        cpu.mem_write(addr, value);
+    }
+    cpu.pc = initial.pc;
+    cpu.sp = initial.s;
+    cpu.reg_a = initial.a;
+    cpu.reg_x = initial.x;
+    cpu.reg_y = initial.y;
+    cpu.status = StatusFlags::from_bits_truncate(initial.p);
+    cpu.add_watchpoint(0x0000..=0xFFFF, Access::ReadWrite, WatchAction::Log);
+
+    let stopped = cpu.run_step();
+    let accesses: Vec<_> = cpu
+        .take_watch_hits()
+        .iter()
+        .map(|hit| BusAccess {
+            addr: hit.addr,
+            value: hit.value,
+            write: hit.access == Access::Write,
+        })
+        .collect();
+
+    let expected = State {
+        p: expected.p & !IGNORED_FLAGS,
+        ..expected
+    };
+    let actual = expected.read(&cpu);
+    let mut errors = Vec::new();
+    if stopped {
+        errors.push("the CPU stopped".to_string());
+    }
+    if actual != expected {
+        errors.push(format!("expected {:02X?}, got {:02X?}", expected, actual));
+    }
+    if let Err(err) = check_bus(&bus, &accesses) {
+        errors.push(err);
+    }
+    if cpu.cycles != cycles.len() as u64 {
+        errors.push(format!(
+            "expected {} cycles, took {}",
+            cycles.len(),
+            cpu.cycles
+        ));
+    }
+
+    if errors.is_empty() {
+        Ok(true)
+    } else {
+        Err(format!("{}: {}", name, errors.join(", ")))
+    }
+}
+
+#[test]
+#[ignore = "needs the SingleStepTests in tests/roms/6502 or PROCESSOR_TESTS"]
+fn processor_tests() {
+    let dir = std::env::var_os("PROCESSOR_TESTS")
+        .map(PathBuf::from)
+        .unwrap_or_else(|| PathBuf::from(DEFAULT_DIR));
+    assert!(dir.is_dir(), "{} doesn't exist", dir.display());
+
+    let mut failures = Vec::new();
+    let mut passed = 0;
+    let mut skipped = 0;
+    let mut missing = Vec::new();
+    for code in CPU::implemented_op_codes() {
+        if UNSUPPORTED.contains(&code) {
+            continue;
+        }
+        let path = dir.join(format!("{:02x}.json", code));
+        // Makes it possible to only download the files for a few op codes.
+        if !path.exists() {
+            missing.push(format!("{:02X}", code));
+            continue;
+        }
+        let text = match std::fs::read_to_string(&path) {
+            Ok(text) => text,
+            Err(err) => {
+                failures.push(format!(
+                    "{:02X}: failed to read {}: {}",
+                    code,
+                    path.display(),
+                    err
+                ));
+                continue;
+            }
+        };
+        let cases: Vec<Value> = match serde_json::from_str(&text) {
+            Ok(cases) => cases,
+            Err(err) => {
+                failures.push(format!(
+                    "{:02X}: failed to parse {}: {}",
+                    code,
+                    path.display(),
+                    err
+                ));
+                continue;
+            }
+        };
+
+        let mut failed = Vec::new();
+        for case in &cases {
+            match run_case(case) {
+                Ok(true) => passed += 1,
+                Ok(false) => skipped += 1,
+                Err(err) => failed.push(err),
+            }
+        }
+        if !failed.is_empty() {
+            failures.push(format!(
+                "{:02X}: {} of {} cases failed\n    {}",
+                code,
+                failed.len(),
+                cases.len(),
+                failed[..failed.len().min(MAX_REPORTED)].join("\n    ")
+            ));
+        }
+    }
+
+    println!("{} cases passed, {} skipped", passed, skipped);
+    println!(
+        "Not supported: {}",
+        UNSUPPORTED.map(|code| format!("{:02X}", code)).join(" ")
+    );
+    if !missing.is_empty() {
+        println!("No tests for {}", missing.join(" "));
+    }
+    assert!(
+        failures.is_empty(),
+        "{} op codes failed:\n{}",
+        failures.len(),
+        failures.join("\n")
+    );
+}